@@ -0,0 +1,57 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use rustlox::compiler::CompileOptions;
+use rustlox::vm::VM;
+
+// The standard clox benchmarks, rewritten with objects where the originals
+// use classes. Each one runs as a whole script, compilation included.
+const BENCHMARKS: &[(&str, &str)] = &[
+    ("binary_trees", include_str!("lox/binary_trees.lox")),
+    ("closures", include_str!("lox/closures.lox")),
+    ("equality", include_str!("lox/equality.lox")),
+    ("fib", include_str!("lox/fib.lox")),
+    ("instantiation", include_str!("lox/instantiation.lox")),
+    ("invocation", include_str!("lox/invocation.lox")),
+    ("properties", include_str!("lox/properties.lox")),
+    ("string_concat", include_str!("lox/string_concat.lox")),
+    ("string_equality", include_str!("lox/string_equality.lox")),
+    ("zoo", include_str!("lox/zoo.lox")),
+];
+
+fn benchmarks(c: &mut Criterion) {
+    let options = CompileOptions::default();
+    let mut vm = VM::builder().build();
+    let mut group = c.benchmark_group("lox");
+    group.sample_size(20);
+    for (name, source) in BENCHMARKS {
+        let source = source.to_string();
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                if vm.interpret(&source, &options).is_err() {
+                    panic!("{} failed", name);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+// What a tiny script or an embedder pays for a fresh VM: building it and
+// running an empty script, then one that looks up a native.
+fn startup(c: &mut Criterion) {
+    let options = CompileOptions::default();
+    let mut group = c.benchmark_group("startup");
+    for (name, source) in [("empty", ""), ("native", "clock;")] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut vm = VM::builder().build();
+                if vm.interpret(source, &options).is_err() {
+                    panic!("{} failed", name);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, benchmarks, startup);
+criterion_main!(benches);
@@ -0,0 +1,16 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rustlox::compiler::{self, CompileOptions};
+use rustlox::scanner;
+use std::sync::Arc;
+
+// Parses and compiles without running, so every input finishes quickly.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(source) = std::str::from_utf8(data) {
+        let source = Arc::from(source);
+        let tokens = scanner::scan_tokens(&source);
+        if !tokens.is_empty() {
+            compiler::compile(&source, tokens, &CompileOptions::default()).ok();
+        }
+    }
+});
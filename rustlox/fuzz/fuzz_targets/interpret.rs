@@ -0,0 +1,18 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rustlox::compiler::CompileOptions;
+use rustlox::vm::VM;
+
+// Scripts can loop forever, so run this target with libFuzzer's `-timeout`
+// set to a few seconds and treat timeouts separately from crashes.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(source) = std::str::from_utf8(data) {
+        let mut vm = VM::builder()
+            .max_call_depth(64)
+            .stack_size(1 << 12)
+            .max_interned_bytes(1 << 20)
+            .build();
+        vm.interpret(&source.to_string(), &CompileOptions::default())
+            .ok();
+    }
+});
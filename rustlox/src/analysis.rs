@@ -0,0 +1,576 @@
+use crate::expr::{Expr, Pattern};
+use crate::scanner::Token;
+use crate::stmt::Stmt;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Default)]
+struct Declarations<'a> {
+    counts: HashMap<&'a str, usize>,
+    assigned: HashSet<&'a str>,
+    void_functions: HashSet<&'a str>,
+}
+
+impl<'a> Declarations<'a> {
+    fn declare(&mut self, name: &'a str) {
+        *self.counts.entry(name).or_insert(0) += 1;
+    }
+
+    fn statements(&mut self, statements: &[Stmt<'a>]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Stmt<'a>) {
+        match statement {
+            Stmt::Block(block) => self.statements(&block.statements),
+            Stmt::Break(stmt) => {
+                if let Some(value) = &stmt.value {
+                    self.expression(value);
+                }
+            }
+            Stmt::Continue(_) => (),
+            Stmt::Expression(stmt) => self.expression(&stmt.expression),
+            Stmt::For(stmt) => {
+                if let Some(initializer) = &stmt.initializer {
+                    self.statement(initializer);
+                }
+                if let Some(condition) = &stmt.condition {
+                    self.expression(condition);
+                }
+                if let Some(increment) = &stmt.increment {
+                    self.expression(increment);
+                }
+                self.statement(stmt.body);
+            }
+            Stmt::Function(function) => {
+                self.declare(function.name.lexeme);
+                for param in &function.params {
+                    self.declare(param.lexeme);
+                }
+                if !returns_value(&function.body) {
+                    self.void_functions.insert(function.name.lexeme);
+                }
+                self.statements(&function.body);
+            }
+            Stmt::If(stmt) => {
+                self.expression(&stmt.condition);
+                self.statement(stmt.then_branch);
+                if let Some(else_branch) = &stmt.else_branch {
+                    self.statement(else_branch);
+                }
+            }
+            Stmt::Print(stmt) => self.expression(&stmt.expression),
+            Stmt::Return(stmt) => {
+                if let Some(value) = &stmt.value {
+                    self.expression(value);
+                }
+            }
+            Stmt::Var(stmt) => {
+                for declarator in &stmt.declarators {
+                    self.declare(declarator.name.lexeme);
+                    if let Some(initializer) = &declarator.initializer {
+                        self.expression(initializer);
+                    }
+                }
+            }
+            Stmt::Loop(stmt) => self.statement(stmt.body),
+            Stmt::While(stmt) => {
+                self.expression(&stmt.condition);
+                self.statement(stmt.body);
+            }
+        }
+    }
+
+    fn expression(&mut self, expression: &Expr<'a>) {
+        match expression {
+            Expr::Assign(expr) => {
+                self.assigned.insert(expr.name.lexeme);
+                self.expression(expr.value);
+            }
+            Expr::Binary(expr) => {
+                self.expression(expr.left);
+                self.expression(expr.right);
+            }
+            Expr::Call(expr) => {
+                self.expression(expr.callee);
+                for arg in &expr.args {
+                    self.expression(arg);
+                }
+            }
+            Expr::Comma(expr) => {
+                self.expression(expr.left);
+                self.expression(expr.right);
+            }
+            Expr::ComparisonChain(expr) => {
+                for operand in &expr.operands {
+                    self.expression(operand);
+                }
+            }
+            Expr::Get(expr) => self.expression(expr.object),
+            Expr::Grouping(expr) => self.expression(expr.expr),
+            Expr::If(expr) => {
+                self.expression(expr.condition);
+                self.expression(expr.then_branch);
+                self.expression(expr.else_branch);
+            }
+            Expr::Index(expr) => {
+                self.expression(expr.object);
+                self.expression(expr.index);
+            }
+            Expr::Literal(_) | Expr::Variable(_) => (),
+            Expr::Loop(expr) => self.statement(expr.body),
+            Expr::Logical(expr) => {
+                self.expression(expr.left);
+                self.expression(expr.right);
+            }
+            Expr::LogicalAssign(expr) => {
+                self.assigned.insert(expr.name.lexeme);
+                self.expression(expr.value);
+            }
+            Expr::Match(expr) => {
+                self.expression(expr.subject);
+                for arm in &expr.arms {
+                    match &arm.pattern {
+                        Pattern::Binding(name) => self.declare(name.lexeme),
+                        Pattern::Else(_) => (),
+                        Pattern::Value(value) => self.expression(value),
+                    }
+                    self.expression(&arm.body);
+                }
+            }
+            Expr::Object(expr) => {
+                for value in &expr.values {
+                    self.expression(value);
+                }
+            }
+            Expr::Set(expr) => {
+                self.expression(expr.object);
+                self.expression(expr.value);
+            }
+            Expr::LogicalSet(expr) => {
+                self.expression(expr.object);
+                self.expression(expr.value);
+            }
+            Expr::SetLiteral(expr) => {
+                for element in &expr.elements {
+                    self.expression(element);
+                }
+            }
+            Expr::Tuple(expr) => {
+                for element in &expr.elements {
+                    self.expression(element);
+                }
+            }
+            Expr::Unary(expr) => self.expression(expr.right),
+        }
+    }
+}
+
+fn returns_value(statements: &[Stmt]) -> bool {
+    statements.iter().any(statement_returns_value)
+}
+
+fn statement_returns_value(statement: &Stmt) -> bool {
+    match statement {
+        Stmt::Block(block) => returns_value(&block.statements),
+        Stmt::For(stmt) => statement_returns_value(stmt.body),
+        Stmt::If(stmt) => {
+            statement_returns_value(stmt.then_branch)
+                || stmt
+                    .else_branch
+                    .as_ref()
+                    .is_some_and(|branch| statement_returns_value(branch))
+        }
+        Stmt::Return(stmt) => stmt.value.is_some(),
+        Stmt::Loop(stmt) => statement_returns_value(stmt.body),
+        Stmt::While(stmt) => statement_returns_value(stmt.body),
+        _ => false,
+    }
+}
+
+/// Names the script declares as globals, with `var` or `fun` outside any
+/// block or function.
+pub fn global_declarations<'a>(statements: &[Stmt<'a>]) -> HashSet<&'a str> {
+    let mut globals = HashSet::new();
+    for statement in statements {
+        match statement {
+            Stmt::Function(function) => {
+                globals.insert(function.name.lexeme);
+            }
+            Stmt::Var(stmt) => {
+                globals.extend(stmt.declarators.iter().map(|d| d.name.lexeme));
+            }
+            _ => (),
+        }
+    }
+    globals
+}
+
+/// Names of functions that only ever return the implicit `nil`. Names that are
+/// redeclared or reassigned anywhere are left out, since a call through them
+/// might not reach the analysed function.
+pub fn void_functions<'a>(statements: &[Stmt<'a>]) -> HashSet<&'a str> {
+    let mut declarations = Declarations::default();
+    declarations.statements(statements);
+
+    let Declarations {
+        counts,
+        assigned,
+        void_functions,
+    } = declarations;
+
+    void_functions
+        .into_iter()
+        .filter(|name| counts.get(name) == Some(&1) && !assigned.contains(name))
+        .collect()
+}
+
+// For each local, the assignments to it whose value it may still hold, by
+// where they are in the source.
+type Reaching = HashMap<usize, HashSet<usize>>;
+
+fn merge(into: &mut Reaching, from: Reaching) {
+    for (local, stores) in from {
+        into.entry(local).or_default().extend(stores);
+    }
+}
+
+struct Store {
+    local: usize,
+    read: bool,
+}
+
+#[derive(Default)]
+struct LoopExits<'a> {
+    label: Option<&'a str>,
+    breaks: Reaching,
+    continues: Reaching,
+}
+
+// Follows the script in the order it runs to find the assignments that no
+// read ever sees. Code that can't be reached has nothing reaching it.
+#[derive(Default)]
+struct Liveness<'a> {
+    // Locals are known by where they're declared, so going around a loop
+    // again finds the same ones.
+    scopes: Vec<Vec<(&'a str, usize)>>,
+    // The first scope of the function being followed. Locals in the ones
+    // before it belong to the functions it's nested in.
+    function_start: usize,
+    captured: HashSet<usize>,
+    stores: HashMap<usize, Store>,
+    reaching: Reaching,
+    loops: Vec<LoopExits<'a>>,
+}
+
+impl<'a> Liveness<'a> {
+    fn resolve(&mut self, name: &str) -> Option<usize> {
+        let (depth, local) = self
+            .scopes
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(depth, scope)| {
+                let (_, local) = scope.iter().rev().find(|(local, _)| *local == name)?;
+                Some((depth, *local))
+            })?;
+        if depth < self.function_start {
+            // The closure can read or assign it whenever it's called.
+            self.captured.insert(local);
+            return None;
+        }
+        Some(local)
+    }
+
+    // Declarations outside any block or function are globals, and any
+    // function can read those.
+    fn declare(&mut self, name: &'a Token<'a>) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.push((name.lexeme, name.offset));
+            self.reaching.remove(&name.offset);
+        }
+    }
+
+    fn read(&mut self, name: &str) {
+        if let Some(local) = self.resolve(name) {
+            for &store in self.reaching.get(&local).into_iter().flatten() {
+                self.stores.get_mut(&store).unwrap().read = true;
+            }
+        }
+    }
+
+    fn assign(&mut self, name: &Token) {
+        if let Some(local) = self.resolve(name.lexeme) {
+            self.stores
+                .entry(name.offset)
+                .or_insert(Store { local, read: false });
+            self.reaching
+                .insert(local, std::iter::once(name.offset).collect());
+        }
+    }
+
+    fn statements(&mut self, statements: &[Stmt<'a>]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Stmt<'a>) {
+        match statement {
+            Stmt::Block(block) => {
+                self.scopes.push(Vec::new());
+                self.statements(&block.statements);
+                self.scopes.pop();
+            }
+            Stmt::Break(stmt) => {
+                if let Some(value) = &stmt.value {
+                    self.expression(value);
+                }
+                let reaching = std::mem::take(&mut self.reaching);
+                if let Some(exits) = self.target(stmt.label) {
+                    merge(&mut exits.breaks, reaching);
+                }
+            }
+            Stmt::Continue(stmt) => {
+                let reaching = std::mem::take(&mut self.reaching);
+                if let Some(exits) = self.target(stmt.label) {
+                    merge(&mut exits.continues, reaching);
+                }
+            }
+            Stmt::Expression(stmt) => self.expression(&stmt.expression),
+            Stmt::For(stmt) => {
+                self.scopes.push(Vec::new());
+                if let Some(initializer) = stmt.initializer {
+                    self.statement(initializer);
+                }
+                self.looping(
+                    stmt.label,
+                    stmt.condition.as_ref(),
+                    stmt.body,
+                    stmt.increment.as_ref(),
+                );
+                self.scopes.pop();
+            }
+            Stmt::Function(function) => {
+                self.declare(function.name);
+                let reaching = std::mem::take(&mut self.reaching);
+                let loops = std::mem::take(&mut self.loops);
+                let function_start = std::mem::replace(&mut self.function_start, self.scopes.len());
+                self.scopes.push(Vec::new());
+                for param in &function.params {
+                    self.declare(param);
+                }
+                self.statements(&function.body);
+                self.scopes.pop();
+                self.function_start = function_start;
+                self.loops = loops;
+                self.reaching = reaching;
+            }
+            Stmt::If(stmt) => {
+                self.expression(&stmt.condition);
+                let skipped = self.reaching.clone();
+                self.statement(stmt.then_branch);
+                let taken = std::mem::replace(&mut self.reaching, skipped);
+                if let Some(else_branch) = stmt.else_branch {
+                    self.statement(else_branch);
+                }
+                merge(&mut self.reaching, taken);
+            }
+            Stmt::Loop(stmt) => self.looping(stmt.label, None, stmt.body, None),
+            Stmt::Print(stmt) => self.expression(&stmt.expression),
+            Stmt::Return(stmt) => {
+                if let Some(value) = &stmt.value {
+                    self.expression(value);
+                }
+                self.reaching.clear();
+            }
+            Stmt::Var(stmt) => {
+                for declarator in &stmt.declarators {
+                    if let Some(initializer) = &declarator.initializer {
+                        self.expression(initializer);
+                    }
+                    self.declare(declarator.name);
+                }
+            }
+            Stmt::While(stmt) => self.looping(stmt.label, Some(&stmt.condition), stmt.body, None),
+        }
+    }
+
+    // The loop a `break` or `continue` leaves.
+    fn target(&mut self, label: Option<&Token>) -> Option<&mut LoopExits<'a>> {
+        match label {
+            Some(label) => self
+                .loops
+                .iter_mut()
+                .rev()
+                .find(|exits| exits.label == Some(label.lexeme)),
+            None => self.loops.last_mut(),
+        }
+    }
+
+    // Goes around the loop until the assignments that come back to its start
+    // stop changing.
+    fn looping(
+        &mut self,
+        label: Option<&'a Token<'a>>,
+        condition: Option<&Expr<'a>>,
+        body: &Stmt<'a>,
+        increment: Option<&Expr<'a>>,
+    ) {
+        let entry = std::mem::take(&mut self.reaching);
+        let mut back = Reaching::new();
+        loop {
+            self.reaching = entry.clone();
+            merge(&mut self.reaching, back.clone());
+            let mut exit = Reaching::new();
+            if let Some(condition) = condition {
+                self.expression(condition);
+                exit = self.reaching.clone();
+            }
+
+            self.loops.push(LoopExits {
+                label: label.map(|label| label.lexeme),
+                ..LoopExits::default()
+            });
+            self.statement(body);
+            let exits = self.loops.pop().unwrap();
+            merge(&mut self.reaching, exits.continues);
+            if let Some(increment) = increment {
+                self.expression(increment);
+            }
+            merge(&mut exit, exits.breaks);
+
+            let next = std::mem::take(&mut self.reaching);
+            if next == back {
+                self.reaching = exit;
+                return;
+            }
+            back = next;
+        }
+    }
+
+    fn expression(&mut self, expression: &Expr<'a>) {
+        match expression {
+            Expr::Assign(expr) => {
+                self.expression(expr.value);
+                self.assign(expr.name);
+            }
+            Expr::Binary(expr) => {
+                self.expression(expr.left);
+                self.expression(expr.right);
+            }
+            Expr::Call(expr) => {
+                self.expression(expr.callee);
+                for arg in &expr.args {
+                    self.expression(arg);
+                }
+            }
+            Expr::Comma(expr) => {
+                self.expression(expr.left);
+                self.expression(expr.right);
+            }
+            Expr::ComparisonChain(expr) => {
+                // Each comparison after the first may be skipped.
+                self.expression(&expr.operands[0]);
+                let mut skipped = Reaching::new();
+                for operand in &expr.operands[1..] {
+                    merge(&mut skipped, self.reaching.clone());
+                    self.expression(operand);
+                }
+                merge(&mut self.reaching, skipped);
+            }
+            Expr::Get(expr) => self.expression(expr.object),
+            Expr::Grouping(expr) => self.expression(expr.expr),
+            Expr::If(expr) => {
+                self.expression(expr.condition);
+                let skipped = self.reaching.clone();
+                self.expression(expr.then_branch);
+                let taken = std::mem::replace(&mut self.reaching, skipped);
+                self.expression(expr.else_branch);
+                merge(&mut self.reaching, taken);
+            }
+            Expr::Index(expr) => {
+                self.expression(expr.object);
+                self.expression(expr.index);
+            }
+            Expr::Literal(_) => (),
+            Expr::Loop(expr) => self.looping(None, None, expr.body, None),
+            Expr::Logical(expr) => {
+                self.expression(expr.left);
+                let skipped = self.reaching.clone();
+                self.expression(expr.right);
+                merge(&mut self.reaching, skipped);
+            }
+            Expr::LogicalAssign(expr) => {
+                self.read(expr.name.lexeme);
+                let skipped = self.reaching.clone();
+                self.expression(expr.value);
+                self.assign(expr.name);
+                merge(&mut self.reaching, skipped);
+            }
+            Expr::Match(expr) => {
+                self.expression(expr.subject);
+                let mut matched = Reaching::new();
+                for arm in &expr.arms {
+                    self.scopes.push(Vec::new());
+                    match &arm.pattern {
+                        Pattern::Binding(name) => self.declare(name),
+                        Pattern::Else(_) => (),
+                        Pattern::Value(value) => self.expression(value),
+                    }
+                    let unmatched = self.reaching.clone();
+                    self.expression(&arm.body);
+                    merge(
+                        &mut matched,
+                        std::mem::replace(&mut self.reaching, unmatched),
+                    );
+                    self.scopes.pop();
+                }
+                merge(&mut self.reaching, matched);
+            }
+            Expr::Object(expr) => {
+                for value in &expr.values {
+                    self.expression(value);
+                }
+            }
+            Expr::Set(expr) => {
+                self.expression(expr.object);
+                self.expression(expr.value);
+            }
+            Expr::LogicalSet(expr) => {
+                self.expression(expr.object);
+                let skipped = self.reaching.clone();
+                self.expression(expr.value);
+                merge(&mut self.reaching, skipped);
+            }
+            Expr::SetLiteral(expr) => {
+                for element in &expr.elements {
+                    self.expression(element);
+                }
+            }
+            Expr::Tuple(expr) => {
+                for element in &expr.elements {
+                    self.expression(element);
+                }
+            }
+            Expr::Unary(expr) => self.expression(expr.right),
+            Expr::Variable(expr) => self.read(expr.name.lexeme),
+        }
+    }
+}
+
+/// Offsets of the assignments to locals whose value is never read, because
+/// the local is assigned again or goes out of scope first on every path.
+/// Locals a closure captures are left out, since calling it can read them at
+/// any time.
+pub fn dead_stores(statements: &[Stmt]) -> HashSet<usize> {
+    let mut liveness = Liveness::default();
+    liveness.statements(statements);
+    liveness
+        .stores
+        .iter()
+        .filter(|(_, store)| !store.read && !liveness.captured.contains(&store.local))
+        .map(|(&offset, _)| offset)
+        .collect()
+}
@@ -0,0 +1,35 @@
+use std::cell::RefCell;
+
+// Hands out references to values that live as long as the arena. Values go
+// into chunks that are never resized, so a reference stays valid while more
+// values are added, and everything is freed at once when the arena is
+// dropped. Each chunk is twice the size of the one before it.
+pub struct Arena<T> {
+    chunks: RefCell<Vec<Vec<T>>>,
+}
+
+const INITIAL_CHUNK: usize = 64;
+
+impl<T> Default for Arena<T> {
+    fn default() -> Arena<T> {
+        Arena {
+            chunks: RefCell::new(vec![Vec::with_capacity(INITIAL_CHUNK)]),
+        }
+    }
+}
+
+impl<T> Arena<T> {
+    pub fn alloc(&self, value: T) -> &T {
+        let mut chunks = self.chunks.borrow_mut();
+        let full = chunks.last().unwrap();
+        if full.len() == full.capacity() {
+            let capacity = full.capacity() * 2;
+            chunks.push(Vec::with_capacity(capacity));
+        }
+        let chunk = chunks.last_mut().unwrap();
+        chunk.push(value);
+        // Pushing within the capacity doesn't move the chunk's buffer, and
+        // chunks are only dropped along with the arena.
+        unsafe { &*chunk.as_ptr().add(chunk.len() - 1) }
+    }
+}
@@ -1,15 +1,36 @@
+use crate::names::Names;
 use crate::value::*;
 use std::convert::TryFrom;
 use std::convert::TryInto;
+use std::fmt::Write;
 use std::result::Result;
+use std::sync::Arc;
+
+// The largest index `Op::ConstantLong` can hold.
+const MAX_LONG_CONSTANT: usize = (1 << 24) - 1;
 
 #[repr(u8)]
+#[derive(Debug)]
 pub enum Op {
     Constant,
+    // Like `Constant`, with a 24-bit index for chunks with more than 256
+    // constants.
+    ConstantLong,
     Nil,
     True,
     False,
+    // Common integer literals, which skip the constant table.
+    Zero,
+    One,
+    MinusOne,
+    // Any other integer that fits in a signed byte operand.
+    SmallInt,
     Pop,
+    // Pops as many values as its operand says, for locals leaving scope.
+    PopN,
+    // Copy the top of the stack, and exchange the top two values.
+    Dup,
+    Swap,
     GetLocal,
     SetLocal,
     GetGlobal,
@@ -17,7 +38,16 @@ pub enum Op {
     SetGlobal,
     GetUpvalue,
     SetUpvalue,
+    // 16-bit versions of the above, for functions with more than 256 locals
+    // or upvalues.
+    GetLocalLong,
+    SetLocalLong,
+    GetUpvalueLong,
+    SetUpvalueLong,
+    GetProperty,
+    SetProperty,
     Equal,
+    Identical,
     Greater,
     Less,
     Add,
@@ -26,13 +56,48 @@ pub enum Op {
     Divide,
     Not,
     Negate,
-    Print,
+    Type,
+    Index,
+    Tuple,
+    Set,
+    Object,
+    // Like `Object`, with a 24-bit constant index.
+    ObjectLong,
+    Union,
+    Intersection,
     Jump,
     JumpIfFalse,
+    JumpIfTrue,
+    // 32-bit versions of the jumps, for code too long for 16-bit offsets.
+    JumpLong,
+    JumpIfFalseLong,
+    JumpIfTrueLong,
+    LoopLong,
     Loop,
+    // Compare the top two values, pop them, and jump on the result, for
+    // conditions that are comparisons. `<=` is a jump if greater, and so on.
+    LessJumpIfFalse,
+    LessJumpIfTrue,
+    GreaterJumpIfFalse,
+    GreaterJumpIfTrue,
+    EqualJumpIfFalse,
+    EqualJumpIfTrue,
     Call,
+    CallKeywords,
+    // `object.name(args)`, which calls the property without pushing it
+    // first. A constant index for the name, then the argument count.
+    Invoke,
     Closure,
+    // Like `Closure`, with a 24-bit constant index.
+    ClosureLong,
     CloseUpvalue,
+    // Superinstructions the fusion pass substitutes for common sequences.
+    // `GetLocal; Constant; Add`:
+    AddLocalConstant,
+    // `GetLocal; SmallInt; Add` and the like, with a signed byte operand:
+    AddLocalSmallInt,
+    // `GetLocal; GetLocal; LessJumpIfFalse`:
+    LessLocalsJumpIfFalse,
     Return,
 }
 
@@ -42,10 +107,18 @@ impl TryFrom<u8> for Op {
     fn try_from(v: u8) -> Result<Self, Self::Error> {
         match v {
             x if x == Op::Constant as u8 => Ok(Op::Constant),
+            x if x == Op::ConstantLong as u8 => Ok(Op::ConstantLong),
             x if x == Op::Nil as u8 => Ok(Op::Nil),
             x if x == Op::True as u8 => Ok(Op::True),
             x if x == Op::False as u8 => Ok(Op::False),
+            x if x == Op::Zero as u8 => Ok(Op::Zero),
+            x if x == Op::One as u8 => Ok(Op::One),
+            x if x == Op::MinusOne as u8 => Ok(Op::MinusOne),
+            x if x == Op::SmallInt as u8 => Ok(Op::SmallInt),
             x if x == Op::Pop as u8 => Ok(Op::Pop),
+            x if x == Op::PopN as u8 => Ok(Op::PopN),
+            x if x == Op::Dup as u8 => Ok(Op::Dup),
+            x if x == Op::Swap as u8 => Ok(Op::Swap),
             x if x == Op::GetLocal as u8 => Ok(Op::GetLocal),
             x if x == Op::SetLocal as u8 => Ok(Op::SetLocal),
             x if x == Op::GetGlobal as u8 => Ok(Op::GetGlobal),
@@ -53,7 +126,14 @@ impl TryFrom<u8> for Op {
             x if x == Op::SetGlobal as u8 => Ok(Op::SetGlobal),
             x if x == Op::GetUpvalue as u8 => Ok(Op::GetUpvalue),
             x if x == Op::SetUpvalue as u8 => Ok(Op::SetUpvalue),
+            x if x == Op::GetLocalLong as u8 => Ok(Op::GetLocalLong),
+            x if x == Op::SetLocalLong as u8 => Ok(Op::SetLocalLong),
+            x if x == Op::GetUpvalueLong as u8 => Ok(Op::GetUpvalueLong),
+            x if x == Op::SetUpvalueLong as u8 => Ok(Op::SetUpvalueLong),
+            x if x == Op::GetProperty as u8 => Ok(Op::GetProperty),
+            x if x == Op::SetProperty as u8 => Ok(Op::SetProperty),
             x if x == Op::Equal as u8 => Ok(Op::Equal),
+            x if x == Op::Identical as u8 => Ok(Op::Identical),
             x if x == Op::Greater as u8 => Ok(Op::Greater),
             x if x == Op::Less as u8 => Ok(Op::Less),
             x if x == Op::Add as u8 => Ok(Op::Add),
@@ -62,13 +142,37 @@ impl TryFrom<u8> for Op {
             x if x == Op::Divide as u8 => Ok(Op::Divide),
             x if x == Op::Not as u8 => Ok(Op::Not),
             x if x == Op::Negate as u8 => Ok(Op::Negate),
-            x if x == Op::Print as u8 => Ok(Op::Print),
+            x if x == Op::Type as u8 => Ok(Op::Type),
+            x if x == Op::Index as u8 => Ok(Op::Index),
+            x if x == Op::Tuple as u8 => Ok(Op::Tuple),
+            x if x == Op::Set as u8 => Ok(Op::Set),
+            x if x == Op::Object as u8 => Ok(Op::Object),
+            x if x == Op::ObjectLong as u8 => Ok(Op::ObjectLong),
+            x if x == Op::Union as u8 => Ok(Op::Union),
+            x if x == Op::Intersection as u8 => Ok(Op::Intersection),
             x if x == Op::Jump as u8 => Ok(Op::Jump),
             x if x == Op::JumpIfFalse as u8 => Ok(Op::JumpIfFalse),
+            x if x == Op::JumpIfTrue as u8 => Ok(Op::JumpIfTrue),
+            x if x == Op::JumpLong as u8 => Ok(Op::JumpLong),
+            x if x == Op::JumpIfFalseLong as u8 => Ok(Op::JumpIfFalseLong),
+            x if x == Op::JumpIfTrueLong as u8 => Ok(Op::JumpIfTrueLong),
+            x if x == Op::LoopLong as u8 => Ok(Op::LoopLong),
             x if x == Op::Loop as u8 => Ok(Op::Loop),
+            x if x == Op::LessJumpIfFalse as u8 => Ok(Op::LessJumpIfFalse),
+            x if x == Op::LessJumpIfTrue as u8 => Ok(Op::LessJumpIfTrue),
+            x if x == Op::GreaterJumpIfFalse as u8 => Ok(Op::GreaterJumpIfFalse),
+            x if x == Op::GreaterJumpIfTrue as u8 => Ok(Op::GreaterJumpIfTrue),
+            x if x == Op::EqualJumpIfFalse as u8 => Ok(Op::EqualJumpIfFalse),
+            x if x == Op::EqualJumpIfTrue as u8 => Ok(Op::EqualJumpIfTrue),
             x if x == Op::Call as u8 => Ok(Op::Call),
+            x if x == Op::CallKeywords as u8 => Ok(Op::CallKeywords),
+            x if x == Op::Invoke as u8 => Ok(Op::Invoke),
             x if x == Op::Closure as u8 => Ok(Op::Closure),
+            x if x == Op::ClosureLong as u8 => Ok(Op::ClosureLong),
             x if x == Op::CloseUpvalue as u8 => Ok(Op::CloseUpvalue),
+            x if x == Op::AddLocalConstant as u8 => Ok(Op::AddLocalConstant),
+            x if x == Op::AddLocalSmallInt as u8 => Ok(Op::AddLocalSmallInt),
+            x if x == Op::LessLocalsJumpIfFalse as u8 => Ok(Op::LessLocalsJumpIfFalse),
             x if x == Op::Return as u8 => Ok(Op::Return),
             _ => {
                 if v < Op::Return as u8 {
@@ -88,11 +192,68 @@ impl TryFrom<&u8> for Op {
     }
 }
 
+// Where in the script a byte of code came from. The column is 0 if it isn't
+// known.
+#[derive(Default, Debug, Copy, Clone, PartialEq)]
+pub struct Position {
+    pub line: i32,
+    pub column: i32,
+}
+
+// The position of each byte of code, stored as runs of bytes from the same
+// position since consecutive bytes usually share one.
+#[derive(Default, Debug)]
+pub struct Lines {
+    runs: Vec<(Position, usize)>,
+}
+
+impl Lines {
+    pub fn push(&mut self, position: Position) {
+        match self.runs.last_mut() {
+            Some((last, length)) if *last == position => *length += 1,
+            _ => self.runs.push((position, 1)),
+        }
+    }
+
+    pub fn get(&self, offset: usize) -> Position {
+        let mut end = 0;
+        for &(position, length) in &self.runs {
+            end += length;
+            if offset < end {
+                return position;
+            }
+        }
+        panic!("No line for offset {}.", offset);
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        let mut end = 0;
+        for (i, (_, length)) in self.runs.iter_mut().enumerate() {
+            if end + *length >= len {
+                *length = len - end;
+                let kept = if *length == 0 { i } else { i + 1 };
+                self.runs.truncate(kept);
+                return;
+            }
+            end += *length;
+        }
+    }
+
+    // The position of every byte, in order.
+    pub fn iter(&self) -> impl Iterator<Item = Position> + '_ {
+        self.runs
+            .iter()
+            .flat_map(|&(position, length)| std::iter::repeat_n(position, length))
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct Chunk {
     pub code: Vec<u8>,
     pub constants: Vec<Value>,
-    pub lines: Vec<i32>,
+    pub lines: Lines,
+    // The script the chunk was compiled from, for showing where errors are.
+    pub source: Option<Arc<str>>,
 }
 
 impl Chunk {
@@ -100,135 +261,454 @@ impl Chunk {
         Default::default()
     }
 
-    pub fn write(&mut self, byte: u8, line: i32) {
+    pub fn write(&mut self, byte: u8, position: Position) {
         self.code.push(byte);
-        self.lines.push(line);
+        self.lines.push(position);
+    }
+
+    pub fn line_at(&self, offset: usize) -> i32 {
+        self.lines.get(offset).line
+    }
+
+    pub fn position_at(&self, offset: usize) -> Position {
+        self.lines.get(offset)
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        self.lines.truncate(len);
     }
 
-    pub fn add_constant(&mut self, value: Value) -> Result<u8, &'static str> {
+    // The offset of the instruction after the one at `offset`.
+    pub fn next_instruction(&self, offset: usize) -> usize {
+        let operands = match self.code[offset].try_into() {
+            Ok(
+                Op::Nil
+                | Op::True
+                | Op::False
+                | Op::Zero
+                | Op::One
+                | Op::MinusOne
+                | Op::Pop
+                | Op::Dup
+                | Op::Swap
+                | Op::Equal
+                | Op::Identical
+                | Op::Greater
+                | Op::Less
+                | Op::Add
+                | Op::Subtract
+                | Op::Multiply
+                | Op::Divide
+                | Op::Not
+                | Op::Negate
+                | Op::Type
+                | Op::Index
+                | Op::Union
+                | Op::Intersection
+                | Op::CloseUpvalue
+                | Op::Return,
+            )
+            | Err(_) => 0,
+            Ok(
+                Op::Constant
+                | Op::SmallInt
+                | Op::PopN
+                | Op::GetLocal
+                | Op::SetLocal
+                | Op::GetUpvalue
+                | Op::SetUpvalue
+                | Op::Tuple
+                | Op::Set
+                | Op::Object
+                | Op::Call,
+            ) => 1,
+            Ok(
+                Op::GetGlobal
+                | Op::DefineGlobal
+                | Op::SetGlobal
+                | Op::GetProperty
+                | Op::SetProperty
+                | Op::GetLocalLong
+                | Op::SetLocalLong
+                | Op::GetUpvalueLong
+                | Op::SetUpvalueLong
+                | Op::Jump
+                | Op::JumpIfFalse
+                | Op::JumpIfTrue
+                | Op::Loop
+                | Op::LessJumpIfFalse
+                | Op::LessJumpIfTrue
+                | Op::GreaterJumpIfFalse
+                | Op::GreaterJumpIfTrue
+                | Op::EqualJumpIfFalse
+                | Op::EqualJumpIfTrue
+                | Op::AddLocalConstant
+                | Op::AddLocalSmallInt,
+            ) => 2,
+            Ok(Op::ConstantLong | Op::ObjectLong | Op::Invoke) => 3,
+            Ok(
+                Op::JumpLong
+                | Op::JumpIfFalseLong
+                | Op::JumpIfTrueLong
+                | Op::LoopLong
+                | Op::LessLocalsJumpIfFalse,
+            ) => 4,
+            Ok(Op::CallKeywords) => 2 + 2 * self.code[offset + 2] as usize,
+            Ok(Op::Closure) => 1 + 3 * self.closure_function(offset + 1, 1).upvalue_count,
+            Ok(Op::ClosureLong) => 3 + 3 * self.closure_function(offset + 1, 3).upvalue_count,
+        };
+        offset + 1 + operands
+    }
+
+    // The function a closure instruction makes, from its constant index of
+    // `width` bytes at `offset`.
+    fn closure_function(&self, offset: usize, width: usize) -> &Function {
+        match &self.constants[self.constant_index(offset, width)] {
+            Value::Function(function) => function,
+            _ => panic!("Expected function."),
+        }
+    }
+
+    fn constant_index(&self, offset: usize, width: usize) -> usize {
+        self.code[offset..offset + width]
+            .iter()
+            .fold(0, |index, &byte| (index << 8) | byte as usize)
+    }
+
+    pub fn add_constant(&mut self, value: Value) -> Result<usize, &'static str> {
+        if self.constants.len() > MAX_LONG_CONSTANT {
+            return Err("Too many constants in one chunk.");
+        }
         self.constants.push(value);
-        (self.constants.len() - 1)
-            .try_into()
-            .or(Err("Too many constants in one chunk."))
+        Ok(self.constants.len() - 1)
     }
 }
 
 impl Chunk {
-    #![cfg(feature = "trace-execution")]
-
-    pub fn disassemble(&self, name: &'static str) {
-        println!("== {} ==", name);
-
+    pub fn disassembly(&self, names: &Names) -> String {
+        let mut out = String::new();
         let mut offset: usize = 0;
 
         while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset)
+            offset = self.write_instruction(&mut out, offset, names)
         }
+
+        out
+    }
+
+    pub fn disassemble_instruction(&self, offset: usize, names: &Names) -> String {
+        let mut out = String::new();
+        self.write_instruction(&mut out, offset, names);
+        out
     }
 
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        print!("{:04} ", offset);
+    fn write_instruction(&self, out: &mut String, offset: usize, names: &Names) -> usize {
+        write!(out, "{:04} ", offset).unwrap();
 
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
-            print!("   | ")
+        let line = self.line_at(offset);
+        if offset > 0 && line == self.line_at(offset - 1) {
+            write!(out, "   | ").unwrap();
         } else {
-            print!("{:4} ", self.lines[offset]);
+            write!(out, "{:4} ", line).unwrap();
         }
 
         let instruction = *self.code.get(offset).expect("Expect instruction");
         match instruction.try_into() {
-            Ok(Op::Constant) => self.constant_instruction("OP_CONSTANT", offset),
-            Ok(Op::Nil) => self.simple_instruction("OP_NIL", offset),
-            Ok(Op::True) => self.simple_instruction("OP_TRUE", offset),
-            Ok(Op::False) => self.simple_instruction("OP_FALSE", offset),
-            Ok(Op::Pop) => self.simple_instruction("OP_POP", offset),
-            Ok(Op::GetLocal) => self.byte_instruction("OP_GET_LOCAL", offset),
-            Ok(Op::SetLocal) => self.byte_instruction("OP_SET_LOCAL", offset),
-            Ok(Op::GetGlobal) => self.constant_instruction("OP_GET_GLOBAL", offset),
-            Ok(Op::DefineGlobal) => self.constant_instruction("OP_DEFINE_GLOBAL", offset),
-            Ok(Op::SetGlobal) => self.constant_instruction("OP_SET_GLOBAL", offset),
-            Ok(Op::GetUpvalue) => self.byte_instruction("OP_GET_UPVALUE", offset),
-            Ok(Op::SetUpvalue) => self.byte_instruction("OP_SET_UPVALUE", offset),
-            Ok(Op::Equal) => self.simple_instruction("OP_EQUAL", offset),
-            Ok(Op::Greater) => self.simple_instruction("OP_GREATER", offset),
-            Ok(Op::Less) => self.simple_instruction("OP_LESS", offset),
-            Ok(Op::Add) => self.simple_instruction("OP_ADD", offset),
-            Ok(Op::Subtract) => self.simple_instruction("OP_SUBTRACT", offset),
-            Ok(Op::Multiply) => self.simple_instruction("OP_MULTIPLY", offset),
-            Ok(Op::Divide) => self.simple_instruction("OP_DIVIDE", offset),
-            Ok(Op::Not) => self.simple_instruction("OP_NOT", offset),
-            Ok(Op::Negate) => self.simple_instruction("OP_NEGATE", offset),
-            Ok(Op::Print) => self.simple_instruction("OP_PRINT", offset),
-            Ok(Op::Jump) => self.jump_instruction("OP_JUMP", 1, offset),
-            Ok(Op::JumpIfFalse) => self.jump_instruction("OP_JUMP_IF_FALSE", 1, offset),
-            Ok(Op::Loop) => self.jump_instruction("OP_LOOP", -1, offset),
-            Ok(Op::Call) => self.byte_instruction("OP_CALL", offset),
-            Ok(Op::Closure) => {
-                let mut offset = offset + 1;
-                let constant = self.code[offset];
-                offset += 1;
-                print!("{:16} {:4} ", "OP_CLOSURE", constant);
-                self.constants[constant as usize].println();
-
-                let function = match &self.constants[constant as usize] {
-                    Value::Function(fun) => fun,
-                    _ => panic!("Expected function."),
-                };
-                for _ in 0..function.upvalue_count {
-                    let is_local = self.code[offset];
-                    offset += 1;
-                    let index = self.code[offset];
-                    offset += 1;
-                    println!(
-                        "{:04}      |                     {} {}",
-                        offset - 2,
-                        if is_local == 1 { "local" } else { "upvalue" },
-                        index
-                    );
+            Ok(Op::Constant) => self.constant_instruction(out, "OP_CONSTANT", offset),
+            Ok(Op::ConstantLong) => self.constant_long_instruction(out, "OP_CONSTANT_LONG", offset),
+            Ok(Op::Nil) => self.simple_instruction(out, "OP_NIL", offset),
+            Ok(Op::True) => self.simple_instruction(out, "OP_TRUE", offset),
+            Ok(Op::False) => self.simple_instruction(out, "OP_FALSE", offset),
+            Ok(Op::Zero) => self.simple_instruction(out, "OP_ZERO", offset),
+            Ok(Op::One) => self.simple_instruction(out, "OP_ONE", offset),
+            Ok(Op::MinusOne) => self.simple_instruction(out, "OP_MINUS_ONE", offset),
+            Ok(Op::SmallInt) => {
+                let value = self.code[offset + 1] as i8;
+                writeln!(out, "{:16} {:4}", "OP_SMALL_INT", value).unwrap();
+                offset + 2
+            }
+            Ok(Op::Pop) => self.simple_instruction(out, "OP_POP", offset),
+            Ok(Op::PopN) => self.byte_instruction(out, "OP_POP_N", offset),
+            Ok(Op::Dup) => self.simple_instruction(out, "OP_DUP", offset),
+            Ok(Op::Swap) => self.simple_instruction(out, "OP_SWAP", offset),
+            Ok(Op::GetLocal) => self.byte_instruction(out, "OP_GET_LOCAL", offset),
+            Ok(Op::SetLocal) => self.byte_instruction(out, "OP_SET_LOCAL", offset),
+            Ok(Op::GetGlobal) => self.name_instruction(out, "OP_GET_GLOBAL", offset, names),
+            Ok(Op::DefineGlobal) => self.name_instruction(out, "OP_DEFINE_GLOBAL", offset, names),
+            Ok(Op::SetGlobal) => self.name_instruction(out, "OP_SET_GLOBAL", offset, names),
+            Ok(Op::GetUpvalue) => self.byte_instruction(out, "OP_GET_UPVALUE", offset),
+            Ok(Op::SetUpvalue) => self.byte_instruction(out, "OP_SET_UPVALUE", offset),
+            Ok(Op::GetLocalLong) => self.short_instruction(out, "OP_GET_LOCAL_LONG", offset),
+            Ok(Op::SetLocalLong) => self.short_instruction(out, "OP_SET_LOCAL_LONG", offset),
+            Ok(Op::GetUpvalueLong) => self.short_instruction(out, "OP_GET_UPVALUE_LONG", offset),
+            Ok(Op::SetUpvalueLong) => self.short_instruction(out, "OP_SET_UPVALUE_LONG", offset),
+            Ok(Op::GetProperty) => self.name_instruction(out, "OP_GET_PROPERTY", offset, names),
+            Ok(Op::SetProperty) => self.name_instruction(out, "OP_SET_PROPERTY", offset, names),
+            Ok(Op::Equal) => self.simple_instruction(out, "OP_EQUAL", offset),
+            Ok(Op::Identical) => self.simple_instruction(out, "OP_IDENTICAL", offset),
+            Ok(Op::Greater) => self.simple_instruction(out, "OP_GREATER", offset),
+            Ok(Op::Less) => self.simple_instruction(out, "OP_LESS", offset),
+            Ok(Op::Add) => self.simple_instruction(out, "OP_ADD", offset),
+            Ok(Op::Subtract) => self.simple_instruction(out, "OP_SUBTRACT", offset),
+            Ok(Op::Multiply) => self.simple_instruction(out, "OP_MULTIPLY", offset),
+            Ok(Op::Divide) => self.simple_instruction(out, "OP_DIVIDE", offset),
+            Ok(Op::Not) => self.simple_instruction(out, "OP_NOT", offset),
+            Ok(Op::Negate) => self.simple_instruction(out, "OP_NEGATE", offset),
+            Ok(Op::Type) => self.simple_instruction(out, "OP_TYPE", offset),
+            Ok(Op::Index) => self.simple_instruction(out, "OP_INDEX", offset),
+            Ok(Op::Tuple) => self.byte_instruction(out, "OP_TUPLE", offset),
+            Ok(Op::Set) => self.byte_instruction(out, "OP_SET", offset),
+            Ok(Op::Object) => self.constant_instruction(out, "OP_OBJECT", offset),
+            Ok(Op::ObjectLong) => self.constant_long_instruction(out, "OP_OBJECT_LONG", offset),
+            Ok(Op::Union) => self.simple_instruction(out, "OP_UNION", offset),
+            Ok(Op::Intersection) => self.simple_instruction(out, "OP_INTERSECTION", offset),
+            Ok(Op::Jump) => self.jump_instruction(out, "OP_JUMP", 1, offset),
+            Ok(Op::JumpIfFalse) => self.jump_instruction(out, "OP_JUMP_IF_FALSE", 1, offset),
+            Ok(Op::JumpIfTrue) => self.jump_instruction(out, "OP_JUMP_IF_TRUE", 1, offset),
+            Ok(Op::Loop) => self.jump_instruction(out, "OP_LOOP", -1, offset),
+            Ok(Op::LessJumpIfFalse) => {
+                self.jump_instruction(out, "OP_LESS_JUMP_IF_FALSE", 1, offset)
+            }
+            Ok(Op::LessJumpIfTrue) => self.jump_instruction(out, "OP_LESS_JUMP_IF_TRUE", 1, offset),
+            Ok(Op::GreaterJumpIfFalse) => {
+                self.jump_instruction(out, "OP_GREATER_JUMP_IF_FALSE", 1, offset)
+            }
+            Ok(Op::GreaterJumpIfTrue) => {
+                self.jump_instruction(out, "OP_GREATER_JUMP_IF_TRUE", 1, offset)
+            }
+            Ok(Op::EqualJumpIfFalse) => {
+                self.jump_instruction(out, "OP_EQUAL_JUMP_IF_FALSE", 1, offset)
+            }
+            Ok(Op::EqualJumpIfTrue) => {
+                self.jump_instruction(out, "OP_EQUAL_JUMP_IF_TRUE", 1, offset)
+            }
+            Ok(Op::JumpLong) => self.long_jump_instruction(out, "OP_JUMP_LONG", 1, offset),
+            Ok(Op::JumpIfFalseLong) => {
+                self.long_jump_instruction(out, "OP_JUMP_IF_FALSE_LONG", 1, offset)
+            }
+            Ok(Op::JumpIfTrueLong) => {
+                self.long_jump_instruction(out, "OP_JUMP_IF_TRUE_LONG", 1, offset)
+            }
+            Ok(Op::LoopLong) => self.long_jump_instruction(out, "OP_LOOP_LONG", -1, offset),
+            Ok(Op::Call) => self.byte_instruction(out, "OP_CALL", offset),
+            Ok(Op::CallKeywords) => {
+                let arg_count = self.code[offset + 1];
+                let keyword_count = self.code[offset + 2] as usize;
+                writeln!(out, "{:16} {:4}", "OP_CALL_KEYWORDS", arg_count).unwrap();
+                let keywords = &self.code[offset + 3..offset + 3 + 2 * keyword_count];
+                for (i, bytes) in keywords.chunks(2).enumerate() {
+                    let index = u16::from_be_bytes([bytes[0], bytes[1]]);
+                    writeln!(
+                        out,
+                        "{:04}      |                     keyword {} '{}'",
+                        offset + 3 + 2 * i,
+                        index,
+                        names.name(index.into())
+                    )
+                    .unwrap();
                 }
-
-                return offset;
+                offset + 3 + 2 * keyword_count
+            }
+            Ok(Op::Invoke) => {
+                let index = u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]]);
+                let arg_count = self.code[offset + 3];
+                writeln!(
+                    out,
+                    "{:16} ({} args) {:4} '{}'",
+                    "OP_INVOKE",
+                    arg_count,
+                    index,
+                    names.name(index.into())
+                )
+                .unwrap();
+                offset + 4
+            }
+            Ok(Op::Closure) => self.closure_instruction(out, "OP_CLOSURE", 1, offset),
+            Ok(Op::ClosureLong) => self.closure_instruction(out, "OP_CLOSURE_LONG", 3, offset),
+            Ok(Op::CloseUpvalue) => self.simple_instruction(out, "OP_CLOSE_UPVALUE", offset),
+            Ok(Op::AddLocalConstant) => {
+                let slot = self.code[offset + 1];
+                let constant = self.code[offset + 2];
+                writeln!(
+                    out,
+                    "{:16} {:4} '{}'",
+                    "OP_ADD_LOCAL_CONSTANT", slot, self.constants[constant as usize]
+                )
+                .unwrap();
+                offset + 3
+            }
+            Ok(Op::AddLocalSmallInt) => {
+                let value = self.code[offset + 2] as i8;
+                writeln!(
+                    out,
+                    "{:16} {:4} {:4}",
+                    "OP_ADD_LOCAL_SMALL_INT",
+                    self.code[offset + 1],
+                    value
+                )
+                .unwrap();
+                offset + 3
             }
-            Ok(Op::CloseUpvalue) => self.simple_instruction("OP_CLOSE_UPVALUE", offset),
-            Ok(Op::Return) => self.simple_instruction("OP_RETURN", offset),
+            Ok(Op::LessLocalsJumpIfFalse) => {
+                let jump = u16::from_be_bytes([self.code[offset + 3], self.code[offset + 4]]);
+                writeln!(
+                    out,
+                    "{:16} {:4} {:4} -> {}",
+                    "OP_LESS_LOCALS_JUMP_IF_FALSE",
+                    self.code[offset + 1],
+                    self.code[offset + 2],
+                    offset + 5 + jump as usize
+                )
+                .unwrap();
+                offset + 5
+            }
+            Ok(Op::Return) => self.simple_instruction(out, "OP_RETURN", offset),
             Err(v) => {
-                println!("Unknown opcode {}", v);
+                writeln!(out, "Unknown opcode {}", v).unwrap();
                 offset + 1
             }
         }
     }
 
-    fn simple_instruction(&self, name: &'static str, offset: usize) -> usize {
-        println!("{}", name);
+    fn simple_instruction(&self, out: &mut String, name: &'static str, offset: usize) -> usize {
+        writeln!(out, "{}", name).unwrap();
         offset + 1
     }
 
-    fn constant_instruction(&self, name: &'static str, offset: usize) -> usize {
+    fn constant_instruction(&self, out: &mut String, name: &'static str, offset: usize) -> usize {
         let constant = *self
             .code
             .get(offset + 1)
             .expect("Could not get constant index");
-        print!("{:16} {:4} '", name, constant);
-        &self.constants[constant as usize].print();
-        println!("'");
-        return offset + 2;
+        writeln!(
+            out,
+            "{:16} {:4} '{}'",
+            name, constant, self.constants[constant as usize]
+        )
+        .unwrap();
+        offset + 2
     }
 
-    fn byte_instruction(&self, name: &'static str, offset: usize) -> usize {
+    fn byte_instruction(&self, out: &mut String, name: &'static str, offset: usize) -> usize {
         let slot = self.code[offset + 1];
-        println!("{:16} {:4}", name, slot);
-        return offset + 2;
+        writeln!(out, "{:16} {:4}", name, slot).unwrap();
+        offset + 2
+    }
+
+    fn short_instruction(&self, out: &mut String, name: &'static str, offset: usize) -> usize {
+        let slot = u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]]);
+        writeln!(out, "{:16} {:4}", name, slot).unwrap();
+        offset + 3
+    }
+
+    fn name_instruction(
+        &self,
+        out: &mut String,
+        name: &'static str,
+        offset: usize,
+        names: &Names,
+    ) -> usize {
+        let index = u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]]);
+        writeln!(
+            out,
+            "{:16} {:4} '{}'",
+            name,
+            index,
+            names.name(index.into())
+        )
+        .unwrap();
+        offset + 3
+    }
+
+    fn constant_long_instruction(
+        &self,
+        out: &mut String,
+        name: &'static str,
+        offset: usize,
+    ) -> usize {
+        let constant = self.constant_index(offset + 1, 3);
+        writeln!(
+            out,
+            "{:16} {:4} '{}'",
+            name, constant, self.constants[constant]
+        )
+        .unwrap();
+        offset + 4
+    }
+
+    // A closure instruction with a constant index of `width` bytes, then a
+    // line for each upvalue it captures.
+    fn closure_instruction(
+        &self,
+        out: &mut String,
+        name: &'static str,
+        width: usize,
+        offset: usize,
+    ) -> usize {
+        let constant = self.constant_index(offset + 1, width);
+        writeln!(
+            out,
+            "{:16} {:4} {}",
+            name, constant, self.constants[constant]
+        )
+        .unwrap();
+
+        let mut offset = offset + 1 + width;
+        for _ in 0..self.closure_function(offset - width, width).upvalue_count {
+            let is_local = self.code[offset];
+            let index = u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]]);
+            offset += 3;
+            writeln!(
+                out,
+                "{:04}      |                     {} {}",
+                offset - 3,
+                if is_local == 1 { "local" } else { "upvalue" },
+                index
+            )
+            .unwrap();
+        }
+        offset
+    }
+
+    fn long_jump_instruction(
+        &self,
+        out: &mut String,
+        name: &'static str,
+        sign: i64,
+        offset: usize,
+    ) -> usize {
+        let jump = self.code[offset + 1..offset + 5]
+            .iter()
+            .fold(0, |jump, &byte| (jump << 8) | byte as i64);
+        writeln!(
+            out,
+            "{:16} {:4} -> {}",
+            name,
+            offset,
+            offset as i64 + 5 + sign * jump
+        )
+        .unwrap();
+        offset + 5
     }
 
-    fn jump_instruction(&self, name: &'static str, sign: i32, offset: usize) -> usize {
+    fn jump_instruction(
+        &self,
+        out: &mut String,
+        name: &'static str,
+        sign: i32,
+        offset: usize,
+    ) -> usize {
         let mut jump: u16 = (self.code[offset + 1] as u16) << 8;
         jump |= self.code[offset + 2] as u16;
-        println!(
+        writeln!(
+            out,
             "{:16} {:4} -> {}",
             name,
             offset,
             offset as i32 + 3 + sign * jump as i32
-        );
-        return offset + 3;
+        )
+        .unwrap();
+        offset + 3
     }
 }
@@ -1,88 +1,182 @@
+use crate::analysis;
+use crate::bigint::BigInt;
 use crate::chunk::*;
 use crate::expr::{self, Expr};
+use crate::names::Names;
+use crate::native;
+use crate::output;
 use crate::parser;
+use crate::passes;
+use crate::printer;
+use crate::resolver;
 use crate::scanner::{Token, TokenKind};
+use crate::snippet;
 use crate::stmt::{self, Stmt};
 use crate::string;
 use crate::value::*;
-use crate::vm::InterpretError;
-use std::cell::RefCell;
+use crate::vm::{self, InterpretError};
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::convert::TryFrom;
 use std::convert::TryInto;
-use std::rc::Rc;
+use std::ops::Range;
+use std::sync::{Arc, OnceLock};
 
-#[derive(Copy, Clone)]
 struct Local<'a> {
     name: &'a str,
     depth: Option<usize>,
     is_captured: bool,
+    slot: u16,
+    // Temporaries that were on the stack below this local when it was
+    // initialized; they're live again once it goes out of scope.
+    temporaries: usize,
+    // Where the script declared it. Locals the compiler adds itself have
+    // nothing to warn about.
+    declaration: Option<&'a Token<'a>>,
+    is_parameter: bool,
+    read: bool,
+    assignments: Vec<&'a Token<'a>>,
 }
 
 #[derive(Copy, Clone)]
 struct Upvalue {
-    index: u8,
+    index: u16,
     is_local: bool,
 }
 
 struct Compiler<'a> {
-    enclosing: Option<Rc<RefCell<Compiler<'a>>>>,
     function: Function,
 
     locals: Vec<Local<'a>>,
     scope_depth: usize,
     upvalues: Vec<Upvalue>,
+    // Intermediate values pushed above the last initialized local, such as the
+    // left operand of a binary expression while the right one is compiled.
+    temporaries: usize,
 }
 
 type CompileResult<T> = Result<T, InterpretError>;
 
+#[derive(Clone, Debug)]
+pub struct CompileOptions {
+    // Print the tree the parser builds, before anything is compiled.
+    pub dump_ast: bool,
+    pub dump_ir: bool,
+    // Print the finished code of every function once compiling succeeds.
+    pub disassemble: bool,
+    // Accept the old `print value;` statement alongside the `print` native.
+    pub print_statement: bool,
+    // Make `+` reject a string and a number instead of converting the number.
+    pub strict_concat: bool,
+    // Reject assignments to globals the script never declares. A script can
+    // also opt in with a leading `"use strict";`.
+    pub strict: bool,
+    // Count and time every instruction and function call, for a report on
+    // exit.
+    pub profile: bool,
+    // Where to write the call stacks sampled while the script runs.
+    pub sample: Option<String>,
+    // Count how often each line of the script runs.
+    pub coverage: bool,
+    // Print how much the heap holds on exit.
+    pub heap_stats: bool,
+    // Compile the body of each top-level function on its first call instead
+    // of up front. Errors in a body are reported when it's compiled.
+    pub lazy: bool,
+    // Warn about unused locals and parameters, code after a `return`, and
+    // assignments whose value is never read.
+    pub warnings: bool,
+    // Report warnings as errors, and fail to compile if there are any.
+    // Implies `warnings`.
+    pub deny_warnings: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            dump_ast: false,
+            dump_ir: false,
+            disassemble: false,
+            print_statement: true,
+            strict_concat: false,
+            strict: false,
+            profile: false,
+            sample: None,
+            coverage: false,
+            heap_stats: false,
+            lazy: false,
+            warnings: false,
+            deny_warnings: false,
+        }
+    }
+}
+
 impl<'a> Compiler<'a> {
-    fn new(enclosing: Option<Rc<RefCell<Compiler<'a>>>>, name: &str) -> Compiler<'a> {
+    fn new(name: &str) -> Compiler<'a> {
         Compiler {
-            enclosing,
             function: Function {
                 arity: 0,
-                chunk: Rc::new(Chunk::new()),
+                chunk: Arc::new(Chunk::new()),
                 name: string::Handle::from_str(name),
+                params: Arc::new([]),
                 upvalue_count: 0,
+                lazy: None,
+                identity: 0,
             },
             scope_depth: 0,
             locals: vec![Local {
                 depth: Some(0),
                 name: "",
                 is_captured: false,
+                slot: 0,
+                temporaries: 0,
+                declaration: None,
+                is_parameter: false,
+                read: false,
+                assignments: Vec::new(),
             }],
             upvalues: Vec::new(),
+            temporaries: 0,
         }
     }
 }
 
 impl<'a> Compiler<'a> {
-    fn with_enclosing<T, F: FnOnce(&Compiler) -> T>(&self, f: F) -> T {
-        let enclosing = self.enclosing.as_ref().unwrap().borrow();
-        f(&enclosing)
-    }
-
-    fn with_enclosing_mut<T, F: FnOnce(&mut Compiler) -> T>(&self, f: F) -> T {
-        let mut enclosing = self.enclosing.as_ref().unwrap().borrow_mut();
-        f(&mut enclosing)
-    }
-
-    fn resolve_local(&self, name: &str) -> Result<Option<u8>, &'static str> {
+    fn resolve_local(&self, name: &str) -> Result<Option<usize>, &'static str> {
         for (i, local) in self.locals.iter().enumerate().rev() {
             if local.name == name {
                 if local.depth.is_none() {
                     return Err("Can't read local variable in its own initializer.");
                 }
-                return Ok(Some(i as u8));
+                return Ok(Some(i));
             }
         }
 
         Ok(None)
     }
 
-    fn add_upvalue(&mut self, index: u8, is_local: bool) -> Result<u8, &'static str> {
+    fn next_slot(&self) -> usize {
+        let top = self
+            .locals
+            .iter()
+            .rev()
+            .find(|local| local.depth.is_some())
+            .map_or(0, |local| local.slot as usize);
+        top + 1 + self.temporaries
+    }
+
+    fn pop_local(&mut self) -> Option<Local<'a>> {
+        let local = self.locals.pop()?;
+        if local.depth.is_some() {
+            self.temporaries = local.temporaries;
+        }
+        Some(local)
+    }
+
+    fn add_upvalue(&mut self, index: u16, is_local: bool) -> Result<u16, &'static str> {
         for (upvalue_index, upvalue) in self.upvalues.iter().enumerate() {
             if upvalue.index == index && upvalue.is_local == is_local {
-                return Ok(upvalue_index as u8);
+                return Ok(upvalue_index as u16);
             }
         }
 
@@ -92,73 +186,245 @@ impl<'a> Compiler<'a> {
             .try_into()
             .or(Err("Too many closure variables in function."))
     }
+}
 
-    fn resolve_upvalue(&mut self, name: &str) -> Result<Option<u8>, &'static str> {
-        if self.enclosing.is_none() {
-            return Ok(None);
-        }
+// Resolves `name` as an upvalue of the last compiler in `compilers`, whose
+// enclosing functions' compilers come before it.
+fn resolve_upvalue(compilers: &mut [Compiler], name: &str) -> Result<Option<u16>, &'static str> {
+    let (current, enclosing) = match compilers.split_last_mut() {
+        Some((current, enclosing)) if !enclosing.is_empty() => (current, enclosing),
+        _ => return Ok(None),
+    };
+
+    let parent = enclosing.last_mut().unwrap();
+    if let Some(local) = parent.resolve_local(name)? {
+        // The closure might read it, so it isn't warned about.
+        parent.locals[local].is_captured = true;
+        parent.locals[local].read = true;
+        let slot = parent.locals[local].slot;
+        return Ok(Some(current.add_upvalue(slot, true)?));
+    }
 
-        if let Some(local) = self.with_enclosing(|c| c.resolve_local(name))? {
-            self.with_enclosing_mut(|c| c.locals[local as usize].is_captured = true);
-            return Ok(Some(self.add_upvalue(local, true)?));
-        }
+    if let Some(upvalue) = resolve_upvalue(enclosing, name)? {
+        return Ok(Some(current.add_upvalue(upvalue, false)?));
+    }
 
-        if let Some(upvalue) = self.with_enclosing_mut(|c| c.resolve_upvalue(name))? {
-            return Ok(Some(self.add_upvalue(upvalue, false)?));
-        }
+    Ok(None)
+}
 
-        Ok(None)
+struct Loop<'a> {
+    label: Option<&'a str>,
+    start: usize,
+    scope_depth: usize,
+    // Outer and per-iteration slots of each of a for loop's variables.
+    variables: Vec<(u16, u16)>,
+    // Where a loop expression keeps the value it will produce.
+    result: Option<u16>,
+    breaks: Vec<usize>,
+}
+
+// A top-level function whose body hasn't been compiled yet. It keeps the
+// script's source and where its declaration's tokens are in it, since the
+// tokens are gone by the time it's called.
+#[derive(Debug)]
+pub struct Lazy {
+    source: Arc<str>,
+    tokens: Vec<(TokenKind, i32, i32, Range<usize>)>,
+    context: Arc<LazyContext>,
+    chunk: OnceLock<Arc<Chunk>>,
+}
+
+// What compiling the rest of the script found out that a body depends on.
+#[derive(Debug)]
+struct LazyContext {
+    options: CompileOptions,
+    void_functions: HashSet<String>,
+    declared_globals: Option<HashSet<String>>,
+}
+
+impl Lazy {
+    pub fn chunk(&self, names: &mut Names) -> Result<Arc<Chunk>, InterpretError> {
+        if let Some(chunk) = self.chunk.get() {
+            return Ok(Arc::clone(chunk));
+        }
+        let tokens: Vec<Token> = self
+            .tokens
+            .iter()
+            .map(|(kind, line, column, range)| Token {
+                kind: *kind,
+                line: *line,
+                column: *column,
+                offset: range.start,
+                lexeme: &self.source[range.clone()],
+            })
+            .collect();
+        let nodes = parser::Nodes::default();
+        let context = &self.context;
+        let statements = parser::parse_tokens(
+            &self.source,
+            &tokens,
+            &nodes,
+            context.options.print_statement,
+        )
+        .ok_or(InterpretError::CompileError)?;
+        let function = match statements.first() {
+            Some(Stmt::Function(function)) => function,
+            _ => {
+                return Err(InterpretError::InternalError(
+                    "Expected a function declaration.",
+                ))
+            }
+        };
+        let compiled = compile_twice(
+            names,
+            |long_jumps, quiet| {
+                CompilerWrapper::new(
+                    &self.source,
+                    &tokens,
+                    context.void_functions.iter().map(String::as_str).collect(),
+                    context
+                        .declared_globals
+                        .as_ref()
+                        .map(|globals| globals.iter().map(String::as_str).collect()),
+                    &context.options,
+                    long_jumps,
+                    quiet,
+                )
+            },
+            |compiler| {
+                if compiler.lints {
+                    compiler.dead_stores = analysis::dead_stores(&statements);
+                }
+                let body = compiler.function_body(function)?;
+                if compiler.denied {
+                    return Err(InterpretError::CompileError);
+                }
+                Ok(body.function)
+            },
+        )?;
+        Ok(Arc::clone(self.chunk.get_or_init(|| compiled.chunk)))
     }
 }
 
 struct CompilerWrapper<'a> {
-    current: Option<Rc<RefCell<Compiler<'a>>>>,
-    current_line: i32,
-    loop_start: usize,
-    breaks: Vec<(usize, usize)>,
-    loop_depth: usize,
+    source: &'a Arc<str>,
+    tokens: &'a [Token<'a>],
+    // The compiler for the function being compiled, after those of the
+    // functions it's nested in.
+    compilers: Vec<Compiler<'a>>,
+    position: Position,
+    loops: Vec<Loop<'a>>,
+    void_functions: HashSet<&'a str>,
+    // In strict mode, the globals that assignments may target.
+    declared_globals: Option<HashSet<&'a str>>,
+    dump_ir: bool,
+    // Emit forward jumps with 32-bit offsets. Only used once a 16-bit one
+    // turned out to be too short.
+    long_jumps: bool,
+    // A quiet compiler reports nothing, and only notes that it had something
+    // to report.
+    quiet: bool,
+    suppressed: bool,
+    lints: bool,
+    deny_warnings: bool,
+    denied: bool,
+    // Where the assignments whose value is never read are, when linting.
+    dead_stores: HashSet<usize>,
+    jump_too_long: bool,
+    // Set when top-level functions are compiled lazily.
+    lazy: Option<Arc<LazyContext>>,
+    // The VM's, lent to the compiler by `compile_twice`.
+    names: Names,
+    // Constant expressions compile to their values. Only turned off to show
+    // the code before folding.
+    fold: bool,
+    // The code of each function before folding, in the order they're
+    // finished, recorded by a compiler that doesn't fold for one that dumps
+    // its IR.
+    raw_ir: VecDeque<String>,
 }
 
 impl<'a> CompilerWrapper<'a> {
-    pub fn new() -> CompilerWrapper<'a> {
+    pub fn new(
+        source: &'a Arc<str>,
+        tokens: &'a [Token<'a>],
+        void_functions: HashSet<&'a str>,
+        declared_globals: Option<HashSet<&'a str>>,
+        options: &CompileOptions,
+        long_jumps: bool,
+        quiet: bool,
+    ) -> CompilerWrapper<'a> {
         CompilerWrapper {
-            current: Some(Rc::new(RefCell::new(Compiler::new(None, "")))),
-            current_line: 0,
-            loop_start: 0,
-            breaks: Vec::new(),
-            loop_depth: 0,
+            source,
+            tokens,
+            compilers: vec![Compiler::new("")],
+            position: Position::default(),
+            loops: Vec::new(),
+            void_functions,
+            declared_globals,
+            dump_ir: options.dump_ir && !quiet,
+            long_jumps,
+            quiet,
+            suppressed: options.dump_ir && quiet,
+            lints: options.warnings || options.deny_warnings,
+            deny_warnings: options.deny_warnings,
+            denied: false,
+            dead_stores: HashSet::new(),
+            jump_too_long: false,
+            lazy: None,
+            names: Names::default(),
+            fold: true,
+            raw_ir: VecDeque::new(),
         }
     }
 
-    fn with_current_chunk<T, F: FnOnce(&Chunk) -> T>(&self, f: F) -> T {
-        let current = self.current.as_ref().unwrap().borrow();
-        f(&current.function.chunk)
+    fn current(&self) -> &Compiler<'a> {
+        self.compilers.last().unwrap()
     }
 
-    fn with_current_chunk_mut<T, F: FnOnce(&mut Chunk) -> T>(&mut self, f: F) -> T {
-        let mut current = self.current.as_ref().unwrap().borrow_mut();
-        let chunk = Rc::get_mut(&mut current.function.chunk).unwrap();
-        f(chunk)
+    fn current_mut(&mut self) -> &mut Compiler<'a> {
+        self.compilers.last_mut().unwrap()
     }
 
-    fn with_current_function_mut<T, F: FnOnce(&mut Function) -> T>(&mut self, f: F) -> T {
-        let mut current = self.current.as_ref().unwrap().borrow_mut();
-        f(&mut current.function)
+    fn current_chunk(&self) -> &Chunk {
+        &self.current().function.chunk
     }
 
-    fn with_current<T, F: FnOnce(&Compiler) -> T>(&self, f: F) -> T {
-        let current = self.current.as_ref().unwrap().borrow();
-        f(&current)
+    fn current_chunk_mut(&mut self) -> &mut Chunk {
+        Arc::get_mut(&mut self.current_mut().function.chunk).unwrap()
     }
 
-    fn with_current_mut<T, F: FnOnce(&mut Compiler) -> T>(&mut self, f: F) -> T {
-        let mut current = self.current.as_ref().unwrap().borrow_mut();
-        f(&mut current)
+    fn with_temporary<T, F: FnOnce(&mut Self) -> CompileResult<T>>(
+        &mut self,
+        f: F,
+    ) -> CompileResult<T> {
+        self.with_temporaries(1, f)
+    }
+
+    fn with_temporaries<T, F: FnOnce(&mut Self) -> CompileResult<T>>(
+        &mut self,
+        count: usize,
+        f: F,
+    ) -> CompileResult<T> {
+        self.current_mut().temporaries += count;
+        // An error abandons the statement, possibly inside a function whose
+        // compiler is still current, so there's nothing to restore.
+        let result = f(self)?;
+        self.current_mut().temporaries -= count;
+        Ok(result)
     }
 
     fn emit_byte(&mut self, byte: u8) {
-        let line = self.current_line;
-        self.with_current_chunk_mut(|chunk| chunk.write(byte, line))
+        let position = self.position;
+        self.current_chunk_mut().write(byte, position)
+    }
+
+    // Code emitted from here on is reported at `token`.
+    fn locate(&mut self, token: &Token) {
+        self.position = Position {
+            line: token.line,
+            column: token.column,
+        };
     }
 
     fn emit_op(&mut self, op: Op) {
@@ -175,24 +441,61 @@ impl<'a> CompilerWrapper<'a> {
         self.emit_byte(byte2);
     }
 
+    // Names always take two bytes. Local and upvalue accesses switch to their
+    // long form when the slot doesn't fit in a byte.
+    fn emit_variable_op(&mut self, op: Op, arg: u16) {
+        let wide_op = match op {
+            Op::GetLocal if arg > u8::MAX as u16 => Op::GetLocalLong,
+            Op::SetLocal if arg > u8::MAX as u16 => Op::SetLocalLong,
+            Op::GetUpvalue if arg > u8::MAX as u16 => Op::GetUpvalueLong,
+            Op::SetUpvalue if arg > u8::MAX as u16 => Op::SetUpvalueLong,
+            Op::GetGlobal
+            | Op::DefineGlobal
+            | Op::SetGlobal
+            | Op::GetProperty
+            | Op::SetProperty
+            | Op::Invoke => op,
+            op => return self.emit_bytes(op as u8, arg as u8),
+        };
+        self.emit_op(wide_op);
+        self.emit_bytes((arg >> 8) as u8, arg as u8);
+    }
+
+    // The loop's start is already known, so it can pick the short or long
+    // form right away.
     fn emit_loop(&mut self, loop_start: usize) -> CompileResult<()> {
-        self.emit_op(Op::Loop);
+        let distance = self.get_current_len() - loop_start;
+        if let Ok(offset) = u16::try_from(distance + 3) {
+            self.emit_op(Op::Loop);
+            self.emit_bytes((offset >> 8) as u8, offset as u8);
+            return Ok(());
+        }
 
-        let offset: u16 = self
-            .with_current_chunk(|chunk| chunk.code.len() - loop_start + 2)
+        let offset: u32 = (distance + 5)
             .try_into()
             .or_else(|_| self.error(None, "Loop body too large."))?;
-
-        self.emit_byte((offset >> 8) as u8 & 0xff);
-        self.emit_byte(offset as u8 & 0xff);
+        self.emit_op(Op::LoopLong);
+        for byte in offset.to_be_bytes() {
+            self.emit_byte(byte);
+        }
         Ok(())
     }
 
     fn emit_jump(&mut self, instruction: Op) -> usize {
-        self.emit_op(instruction);
-        self.emit_byte(0xff);
-        self.emit_byte(0xff);
-        return self.with_current_chunk(|chunk| chunk.code.len() - 2);
+        if !self.long_jumps {
+            self.emit_op(instruction);
+            self.emit_bytes(0xff, 0xff);
+            return self.get_current_len() - 2;
+        }
+        self.emit_op(match instruction {
+            Op::JumpIfFalse => Op::JumpIfFalseLong,
+            Op::JumpIfTrue => Op::JumpIfTrueLong,
+            _ => Op::JumpLong,
+        });
+        for _ in 0..4 {
+            self.emit_byte(0xff);
+        }
+        self.get_current_len() - 4
     }
 
     fn emit_return(&mut self) {
@@ -200,180 +503,313 @@ impl<'a> CompilerWrapper<'a> {
         self.emit_op(Op::Return);
     }
 
-    fn make_constant(&mut self, value: Value, lexeme: &str) -> CompileResult<u8> {
-        self.with_current_chunk_mut(|chunk| chunk.add_constant(value))
+    fn make_constant(&mut self, value: Value, lexeme: &str) -> CompileResult<usize> {
+        let value = match value {
+            Value::String(string) => Value::String(self.names.strings.intern(string.as_str())),
+            Value::Symbol(symbol) => Value::Symbol(self.names.strings.intern(symbol.as_str())),
+            value => value,
+        };
+        self.current_chunk_mut()
+            .add_constant(value)
             .or_else(|message| self.error(Some(lexeme), message))
     }
 
+    // Emits `op` with an 8-bit constant index, or its `long` form with a
+    // 24-bit one once the chunk has more than 256 constants.
+    fn emit_constant_op(&mut self, op: Op, long: Op, constant: usize) {
+        match u8::try_from(constant) {
+            Ok(constant) => self.emit_bytes(op as u8, constant),
+            Err(_) => {
+                self.emit_byte(long as u8);
+                self.emit_byte((constant >> 16) as u8);
+                self.emit_bytes((constant >> 8) as u8, constant as u8);
+            }
+        }
+    }
+
     fn emit_constant(&mut self, value: Value, lexeme: &str) -> CompileResult<()> {
+        // Folded expressions can produce values with their own instructions.
+        let op = match value {
+            Value::Nil => Some(Op::Nil),
+            Value::Bool(true) => Some(Op::True),
+            Value::Bool(false) => Some(Op::False),
+            Value::Int(0) => Some(Op::Zero),
+            Value::Int(1) => Some(Op::One),
+            Value::Int(-1) => Some(Op::MinusOne),
+            _ => None,
+        };
+        if let Some(op) = op {
+            self.emit_op(op);
+            return Ok(());
+        }
+        if let Value::Int(n) = value {
+            if let Ok(n) = i8::try_from(n) {
+                self.emit_bytes(Op::SmallInt as u8, n as u8);
+                return Ok(());
+            }
+        }
         let constant = self.make_constant(value, lexeme)?;
-        self.emit_bytes(Op::Constant as u8, constant);
+        self.emit_constant_op(Op::Constant, Op::ConstantLong, constant);
         Ok(())
     }
 
     fn patch_jump(&mut self, offset: usize) -> CompileResult<()> {
-        let jump: u16 = self
-            .with_current_chunk(|chunk| chunk.code.len() - offset - 2)
-            .try_into()
-            .or_else(|_| self.error(None, "Too much code to jump over."))?;
+        if self.long_jumps {
+            let jump: u32 = (self.get_current_len() - offset - 4)
+                .try_into()
+                .or_else(|_| self.error(None, "Too much code to jump over."))?;
+            self.current_chunk_mut().code[offset..offset + 4].copy_from_slice(&jump.to_be_bytes());
+            return Ok(());
+        }
 
-        self.with_current_chunk_mut(|chunk| chunk.code[offset] = ((jump >> 8) & 0xff) as u8);
-        self.with_current_chunk_mut(|chunk| chunk.code[offset + 1] = (jump & 0xff) as u8);
+        // `compile` starts over with long jumps when this happens.
+        let jump = match u16::try_from(self.get_current_len() - offset - 2) {
+            Ok(jump) => jump,
+            Err(_) => {
+                self.jump_too_long = true;
+                return Err(InterpretError::CompileError);
+            }
+        };
+        self.current_chunk_mut().code[offset..offset + 2].copy_from_slice(&jump.to_be_bytes());
         Ok(())
     }
 
     #[inline(always)]
     fn get_current_len(&self) -> usize {
-        self.with_current_chunk(|chunk| chunk.code.len())
+        self.current_chunk().code.len()
     }
 
-    fn identifier_constant(&mut self, name: &str) -> CompileResult<u8> {
-        self.make_constant(Value::String(string::Handle::from_str(name)), name)
+    // Names share a constant, so a chunk that keeps using the same few
+    // globals and properties doesn't run out of short indices.
+    fn name_index(&mut self, name: &str) -> CompileResult<u16> {
+        u16::try_from(self.names.index(name)).or_else(|_| self.error(Some(name), "Too many names."))
     }
 
-    fn add_local(&mut self, name: Token<'a>) -> CompileResult<()> {
-        if self.current.as_ref().unwrap().borrow().locals.len() >= u8::MAX as usize {
+    fn add_local(&mut self, name: &'a Token<'a>) -> CompileResult<()> {
+        let slot = self.current().next_slot();
+        if slot >= u16::MAX as usize {
             self.error(None, "Too many local variables in function.")?;
         }
 
-        self.current
-            .as_ref()
-            .unwrap()
-            .borrow_mut()
-            .locals
-            .push(Local {
-                name: name.lexeme,
-                depth: None,
-                is_captured: false,
-            });
+        self.current_mut().locals.push(Local {
+            name: name.lexeme,
+            depth: None,
+            is_captured: false,
+            slot: slot as u16,
+            temporaries: 0,
+            declaration: Some(name),
+            is_parameter: false,
+            read: false,
+            assignments: Vec::new(),
+        });
         Ok(())
     }
 
     fn declare_variable(&mut self, name: &'a Token<'a>) -> CompileResult<()> {
-        if self.current.as_ref().unwrap().borrow().scope_depth == 0 {
+        // The resolver has already rejected duplicates.
+        if self.current().scope_depth == 0 {
             return Ok(());
         }
 
-        let mut unique = true;
-        for local in self.current.as_ref().unwrap().borrow().locals.iter().rev() {
-            if local.depth.is_some()
-                && local.depth.unwrap() < self.current.as_ref().unwrap().borrow().scope_depth
-            {
-                break;
-            }
-
-            if name.lexeme == local.name {
-                unique = false;
-                break;
-            }
-        }
-
-        if !unique {
-            return self.error(
-                Some(name.lexeme),
-                "Already variable with this name in this scope.",
-            );
-        }
-
-        self.add_local(*name)
+        self.add_local(name)
     }
 
-    fn parse_variable(&mut self, token: &'a Token<'a>) -> CompileResult<u8> {
-        self.current_line = token.line;
+    fn parse_variable(&mut self, token: &'a Token<'a>) -> CompileResult<u16> {
+        self.locate(token);
         self.declare_variable(token)?;
-        if self.current.as_ref().unwrap().borrow().scope_depth > 0 {
+        if self.current().scope_depth > 0 {
             return Ok(0);
         }
 
-        self.identifier_constant(token.lexeme)
+        self.name_index(token.lexeme)
     }
 
     fn mark_initialized(&mut self) {
-        self.with_current_mut(|current| {
-            if current.scope_depth == 0 {
-                return;
-            }
-            let depth = current.scope_depth;
-            current.locals.last_mut().unwrap().depth = Some(depth);
-        })
+        let current = self.current_mut();
+        if current.scope_depth == 0 {
+            return;
+        }
+        let depth = current.scope_depth;
+        // A function is marked before its body is compiled, so it can call
+        // itself, and again once it's defined.
+        if current.locals.last().unwrap().depth.is_some() {
+            return;
+        }
+        let temporaries = std::mem::take(&mut current.temporaries);
+        let local = current.locals.last_mut().unwrap();
+        local.depth = Some(depth);
+        local.temporaries = temporaries;
     }
 
-    fn define_variable(&mut self, global: u8) {
-        if self.current.as_ref().unwrap().borrow().scope_depth > 0 {
+    fn define_variable(&mut self, global: u16) {
+        if self.current().scope_depth > 0 {
             self.mark_initialized();
             return;
         }
 
-        self.emit_bytes(Op::DefineGlobal as u8, global)
+        self.emit_variable_op(Op::DefineGlobal, global)
     }
 
-    fn patch_breaks(&mut self) -> CompileResult<()> {
-        while self
-            .breaks
-            .last()
-            .map_or(false, |(_, depth)| *depth == self.loop_depth)
-        {
-            let (jump, _) = self.breaks.pop().unwrap();
+    fn end_loop(&mut self) -> CompileResult<()> {
+        let enclosing = self.loops.pop().unwrap();
+        for jump in enclosing.breaks {
             self.patch_jump(jump)?;
         }
 
         Ok(())
     }
 
+    // The loop a `break` or `continue` targets: the named one, or else the
+    // innermost. The parser has already rejected unknown labels.
+    fn target_loop(&self, label: Option<&Token<'a>>) -> usize {
+        match label {
+            Some(label) => self
+                .loops
+                .iter()
+                .rposition(|enclosing| enclosing.label == Some(label.lexeme))
+                .unwrap(),
+            None => self.loops.len() - 1,
+        }
+    }
+
     fn end_compiler(&mut self) -> Compiler<'a> {
         self.emit_return();
-        let mut compiler = Rc::try_unwrap(std::mem::take(&mut self.current).unwrap())
-            .ok()
-            .unwrap()
-            .into_inner();
+        let mut compiler = self.compilers.pop().unwrap();
+        for local in &compiler.locals {
+            self.warn_unused(local);
+        }
         {
-            #![cfg(feature = "trace-execution")]
-            let function = &compiler.function;
-            function.chunk.disassemble(function.get_name());
+            let name = compiler.function.get_name().to_string();
+            let chunk = Arc::get_mut(&mut compiler.function.chunk).unwrap();
+            chunk.source = Some(Arc::clone(self.source));
+            let mut raw = None;
+            if !self.fold {
+                self.raw_ir.push_back(chunk.disassembly(&self.names));
+            } else if self.dump_ir {
+                raw = self.raw_ir.pop_front();
+            }
+            passes::run(chunk, &name, self.dump_ir, raw.as_deref(), &self.names);
         }
-        self.current = std::mem::take(&mut compiler.enclosing);
         compiler
     }
 
     fn begin_scope(&mut self) {
-        self.with_current_mut(|current| current.scope_depth += 1)
+        self.current_mut().scope_depth += 1
     }
 
-    fn end_scope(&mut self) {
-        let ops = self.with_current_mut(|current| {
-            let mut ops = Vec::<Op>::new();
-            current.scope_depth -= 1;
-
-            while let Some(local) = current.locals.last() {
-                if local.depth.unwrap() > current.scope_depth {
-                    ops.push(if local.is_captured {
-                        Op::CloseUpvalue
-                    } else {
-                        Op::Pop
-                    });
-                    current.locals.pop();
+    // Emits the pops for locals deeper than `depth` without removing them from
+    // the compiler, for jumps that leave their scopes early. The topmost `skip`
+    // locals are assumed to be discarded already. Returns the number discarded.
+    fn discard_locals(&mut self, depth: usize, skip: usize) -> usize {
+        let ops = self
+            .current()
+            .locals
+            .iter()
+            .rev()
+            .skip(skip)
+            .take_while(|local| local.depth.is_some_and(|d| d > depth))
+            .map(|local| {
+                if local.is_captured {
+                    Op::CloseUpvalue
                 } else {
-                    break;
+                    Op::Pop
                 }
-            }
+            })
+            .collect::<Vec<Op>>();
 
-            ops
-        });
+        let count = ops.len();
+        self.emit_local_ops(ops);
+        skip + count
+    }
 
+    // Emits the ops that discard locals, topmost first. Each run of two or
+    // more pops becomes a single `PopN`.
+    fn emit_local_ops(&mut self, ops: Vec<Op>) {
+        let mut pops: u8 = 0;
         for op in ops {
-            self.emit_op(op);
+            if let Op::Pop = op {
+                if pops == u8::MAX {
+                    self.emit_pops(pops);
+                    pops = 0;
+                }
+                pops += 1;
+            } else {
+                self.emit_pops(pops);
+                pops = 0;
+                self.emit_op(op);
+            }
         }
+        self.emit_pops(pops);
     }
 
-    fn compile(
-        &mut self,
-        statements: std::vec::IntoIter<Stmt<'a>>,
-    ) -> Result<Function, InterpretError> {
+    fn emit_pops(&mut self, count: u8) {
+        match count {
+            0 => (),
+            1 => self.emit_op(Op::Pop),
+            _ => self.emit_bytes(Op::PopN as u8, count),
+        }
+    }
+
+    fn end_scope(&mut self) {
+        let current = self.current_mut();
+        let mut ops = Vec::<Op>::new();
+        let mut popped = Vec::new();
+        current.scope_depth -= 1;
+
+        while let Some(local) = current.locals.last() {
+            if local.depth.is_some_and(|depth| depth > current.scope_depth) {
+                ops.push(if local.is_captured {
+                    Op::CloseUpvalue
+                } else {
+                    Op::Pop
+                });
+                popped.extend(current.pop_local());
+            } else {
+                break;
+            }
+        }
+
+        for local in popped.iter().rev() {
+            self.warn_unused(local);
+        }
+        self.emit_local_ops(ops);
+    }
+
+    // Names starting with `_` are meant to go unused.
+    fn warn_unused(&mut self, local: &Local<'a>) {
+        let declaration = match local.declaration {
+            Some(declaration) if self.lints && !local.name.starts_with('_') => declaration,
+            _ => return,
+        };
+        if !local.read {
+            if local.is_parameter {
+                self.warning(declaration, "Unused parameter.");
+            } else if local.assignments.is_empty() {
+                self.warning(declaration, "Unused local variable.");
+            }
+        }
+        for &assignment in &local.assignments {
+            if !local.read || self.dead_stores.contains(&assignment.offset) {
+                self.warning(assignment, "Assigned value is never read.");
+            }
+        }
+    }
+
+    fn compile(&mut self, statements: &[Stmt<'a>]) -> Result<Function, InterpretError> {
+        if self.lints {
+            self.dead_stores = analysis::dead_stores(statements);
+        }
         let mut error = false;
-        for statement in statements {
-            match self.statement(&statement) {
-                Ok(_) => (),
-                Err(_) => error = true,
+        let mut statements = statements.iter().peekable();
+        while let Some(statement) = statements.next() {
+            let result = match statement {
+                Stmt::Expression(statement) if statements.peek().is_none() => {
+                    self.script_result(&statement.expression)
+                }
+                statement => self.statement(statement),
+            };
+            if result.is_err() {
+                error = true;
             }
         }
 
@@ -381,17 +817,57 @@ impl<'a> CompilerWrapper<'a> {
             return Err(InterpretError::CompileError);
         }
         let compiler = self.end_compiler();
+        if self.denied {
+            return Err(InterpretError::CompileError);
+        }
         Ok(compiler.function)
     }
 
     fn error<T>(&mut self, lexeme: Option<&str>, message: &'static str) -> CompileResult<T> {
+        if self.quiet {
+            self.suppressed = true;
+            return Err(InterpretError::CompileError);
+        }
+        if let Some((lex, span)) =
+            lexeme.and_then(|lex| Some((lex, snippet::span(self.source, lex)?)))
+        {
+            report_error(self.source, span, lex, message);
+            return Err(InterpretError::CompileError);
+        }
+        // Errors without a lexeme are about the code being emitted.
+        let Position { line, column } = self.position;
+        output::err(format_args!("[line {}:{}] Error", line, column));
         if let Some(lex) = lexeme {
-            eprint!("Error at '{}': ", lex);
+            output::err(format_args!(" at '{}'", lex));
+        }
+        output::err(format_args!(": {}\n", message));
+        let span = snippet::locate(self.source, line, column);
+        output::diagnostic(span.unwrap_or(0..0), message, true);
+        if let Some(snippet) = snippet::position(self.source, line, column) {
+            output::err(format_args!("{}", snippet));
         }
-        eprintln!("{}", message);
         Err(InterpretError::CompileError)
     }
 
+    fn warning(&mut self, token: &Token, message: &str) {
+        if self.quiet {
+            self.suppressed = true;
+            return;
+        }
+        let kind = if self.deny_warnings {
+            self.denied = true;
+            "Error"
+        } else {
+            "Warning"
+        };
+        output::err(format_args!(
+            "[line {}:{}] {} at '{}': {}\n",
+            token.line, token.column, kind, token.lexeme, message
+        ));
+        output::err(format_args!("{}", snippet::at(self.source, token.span())));
+        output::diagnostic(token.span(), message, self.deny_warnings);
+    }
+
     fn statement(&mut self, statement: &Stmt<'a>) -> CompileResult<()> {
         match statement {
             Stmt::Block(statement) => self.block_statement(statement),
@@ -404,69 +880,189 @@ impl<'a> CompilerWrapper<'a> {
             Stmt::Print(statement) => self.print_statement(statement),
             Stmt::Return(statement) => self.return_statement(statement),
             Stmt::Var(statement) => self.var_declaration(statement),
+            Stmt::Loop(statement) => self.loop_statement(statement),
             Stmt::While(statement) => self.while_statement(statement),
         }
     }
 
     fn block_statement(&mut self, statement: &stmt::Block<'a>) -> CompileResult<()> {
         self.begin_scope();
-        for stmt in &statement.statements {
-            self.statement(stmt)?;
-        }
+        self.statements(&statement.statements)?;
         self.end_scope();
-        self.current_line = statement.brace.line;
+        self.locate(statement.brace);
+        Ok(())
+    }
+
+    // Statements after one that always exits can't run. They're still
+    // compiled so they report the same errors, but their code is dropped.
+    fn statements(&mut self, statements: &[Stmt<'a>]) -> CompileResult<()> {
+        let exit = statements.iter().position(always_exits);
+        let (live, dead) = statements.split_at(exit.map_or(statements.len(), |i| i + 1));
+        for statement in live {
+            self.statement(statement)?;
+        }
+        if dead.is_empty() {
+            return Ok(());
+        }
+        if let Some(keyword) = live.last().and_then(exit_keyword).filter(|_| self.lints) {
+            self.warning(keyword, "Code after this statement is unreachable.");
+        }
+
+        let start = self.get_current_len();
+        let constants = self.current_chunk().constants.len();
+        for statement in dead {
+            self.statement(statement)?;
+        }
+        let chunk = self.current_chunk_mut();
+        chunk.truncate(start);
+        chunk.constants.truncate(constants);
+        for enclosing in &mut self.loops {
+            enclosing.breaks.retain(|&jump| jump < start);
+        }
         Ok(())
     }
 
     fn expression_statement(&mut self, statement: &stmt::Expression<'a>) -> CompileResult<()> {
-        self.expression(&statement.expression)?;
+        self.discarded_expression(&statement.expression)
+    }
+
+    // A call whose result is immediately popped is the one place a function
+    // without a return value is meant to be used.
+    fn discarded_expression(&mut self, expression: &Expr<'a>) -> CompileResult<()> {
+        match expression {
+            Expr::Call(call) => self.call(call)?,
+            expression => self.expression(expression)?,
+        }
         self.emit_op(Op::Pop);
         Ok(())
     }
 
-    fn break_statement(&mut self, statement: &stmt::Break) -> CompileResult<()> {
-        self.current_line = statement.keyword.line;
+    // The script's final expression statement is returned instead of popped,
+    // so it becomes the REPL result and the process exit value.
+    fn script_result(&mut self, expression: &Expr<'a>) -> CompileResult<()> {
+        match expression {
+            Expr::Call(call) => self.call(call)?,
+            expression => self.expression(expression)?,
+        }
+        self.emit_op(Op::Return);
+        Ok(())
+    }
+
+    fn break_statement(&mut self, statement: &stmt::Break<'a>) -> CompileResult<()> {
+        self.locate(statement.keyword);
+        let target = self.target_loop(statement.label);
+        if let Some(value) = &statement.value {
+            self.expression(value)?;
+            let result = self.loops[target].result.unwrap();
+            self.emit_variable_op(Op::SetLocal, result);
+            self.emit_op(Op::Pop);
+        }
+        self.discard_locals(self.loops[target].scope_depth, 0);
         let jump = self.emit_jump(Op::Jump);
-        let depth = self.loop_depth;
-        self.breaks.push((jump, depth));
+        self.loops[target].breaks.push(jump);
         Ok(())
     }
 
-    fn continue_statement(&mut self, statement: &stmt::Continue) -> CompileResult<()> {
-        self.current_line = statement.keyword.line;
-        self.emit_loop(self.loop_start)?;
+    fn continue_statement(&mut self, statement: &stmt::Continue<'a>) -> CompileResult<()> {
+        self.locate(statement.keyword);
+        let target = self.target_loop(statement.label);
+        let Loop {
+            start, scope_depth, ..
+        } = self.loops[target];
+        let variables = self.loops[target].variables.clone();
+        let mut discarded = 0;
+        if !variables.is_empty() {
+            discarded = self.discard_locals(scope_depth + 1, 0);
+            self.copy_loop_variables(&variables);
+        }
+        self.discard_locals(scope_depth, discarded);
+        self.emit_loop(start)?;
         Ok(())
     }
 
     fn function(&mut self, function: &stmt::Function<'a>) -> CompileResult<()> {
-        self.current_line = function.name.line;
-        self.current = Some(Rc::new(RefCell::new(Compiler::new(
-            Some(self.current.as_ref().unwrap().clone()),
-            function.name.lexeme,
-        ))));
-        self.with_current_function_mut(|fun| fun.arity = function.params.len());
+        self.locate(function.name);
+        if let Some(lazy) = self.lazy_function(function) {
+            let constant =
+                self.make_constant(Value::Function(Arc::new(lazy)), function.name.lexeme)?;
+            self.emit_constant_op(Op::Closure, Op::ClosureLong, constant);
+            return Ok(());
+        }
+
+        let compiler = self.function_body(function)?;
+        // The declaration runs where it starts, not where its body ends.
+        self.locate(function.name);
+        let name = compiler.function.name.clone();
+        let constant =
+            self.make_constant(Value::Function(Arc::new(compiler.function)), name.as_str())?;
+        self.emit_constant_op(Op::Closure, Op::ClosureLong, constant);
+
+        for Upvalue { index, is_local } in compiler.upvalues {
+            self.emit_byte(is_local.into());
+            self.emit_bytes((index >> 8) as u8, index as u8);
+        }
+        Ok(())
+    }
+
+    fn function_body(&mut self, function: &stmt::Function<'a>) -> CompileResult<Compiler<'a>> {
+        self.compilers.push(Compiler::new(function.name.lexeme));
+        let fun = &mut self.current_mut().function;
+        fun.arity = function.params.len();
+        fun.params = function
+            .params
+            .iter()
+            .map(|param| string::Handle::from_str(param.lexeme))
+            .collect();
         self.begin_scope();
 
         for token in &function.params {
             let constant = self.parse_variable(token)?;
             self.define_variable(constant);
+            self.current_mut().locals.last_mut().unwrap().is_parameter = true;
         }
 
-        for stmt in &function.body {
-            self.statement(stmt)?
-        }
-        self.current_line = function.brace.line;
-
-        let compiler = self.end_compiler();
-        let name = compiler.function.name.as_str().string;
-        let constant = self.make_constant(Value::Function(compiler.function), name)?;
-        self.emit_bytes(Op::Closure as u8, constant);
+        self.statements(&function.body)?;
+        self.locate(function.brace);
+        Ok(self.end_compiler())
+    }
 
-        for Upvalue { index, is_local } in compiler.upvalues {
-            self.emit_byte(is_local.into());
-            self.emit_byte(index);
+    // A function declared at the top of the script can't capture anything,
+    // so its body can wait until it's called.
+    fn lazy_function(&self, function: &stmt::Function<'a>) -> Option<Function> {
+        let context = self.lazy.as_ref()?;
+        if self.compilers.len() > 1 || self.current().scope_depth > 0 {
+            return None;
         }
-        Ok(())
+        // The parser's tokens are references into `tokens`, so their
+        // addresses give their indices. The declaration starts at `fun`.
+        let index = |token: &Token| {
+            (token as *const Token as usize - self.tokens.as_ptr() as usize)
+                / std::mem::size_of::<Token>()
+        };
+        let declaration = &self.tokens[index(function.name) - 1..=index(function.brace)];
+        let tokens = declaration
+            .iter()
+            .map(|token| (token.kind, token.line, token.column, token.span()))
+            .collect();
+        Some(Function {
+            arity: function.params.len(),
+            // Never run, but gives the function an identity of its own.
+            chunk: Arc::new(Chunk::new()),
+            name: string::Handle::from_str(function.name.lexeme),
+            params: function
+                .params
+                .iter()
+                .map(|param| string::Handle::from_str(param.lexeme))
+                .collect(),
+            upvalue_count: 0,
+            lazy: Some(Arc::new(Lazy {
+                source: Arc::clone(self.source),
+                tokens,
+                context: Arc::clone(context),
+                chunk: OnceLock::new(),
+            })),
+            identity: 0,
+        })
     }
 
     fn for_statement(&mut self, statement: &stmt::For<'a>) -> CompileResult<()> {
@@ -477,21 +1073,23 @@ impl<'a> CompilerWrapper<'a> {
         }
 
         let mut before_condition: Option<usize> = None;
-        let mut jump_after_cond: Option<usize> = None;
+        let mut jump_after_cond: Option<(usize, bool)> = None;
         let mut jump_to_body: Option<usize> = None;
 
         if let Some(cond) = &statement.condition {
             before_condition = Some(self.get_current_len());
-            self.expression(cond)?;
-            jump_after_cond = Some(self.emit_jump(Op::JumpIfFalse));
-            self.emit_op(Op::Pop);
+            let (jump, kept) = self.condition_jump(cond)?;
+            jump_after_cond = Some((jump, kept));
+            self.pop_condition(kept);
             jump_to_body = Some(self.emit_jump(Op::Jump));
         }
 
         let mut before_increment: Option<usize> = None;
 
         if let Some(incr) = &statement.increment {
-            jump_to_body.get_or_insert(self.emit_jump(Op::Jump));
+            if jump_to_body.is_none() {
+                jump_to_body = Some(self.emit_jump(Op::Jump));
+            }
             before_increment = Some(self.get_current_len());
             self.expression(incr)?;
             self.emit_op(Op::Pop);
@@ -506,33 +1104,81 @@ impl<'a> CompilerWrapper<'a> {
             self.patch_jump(jump)?;
         }
 
-        self.loop_depth += 1;
-        let enclosing_loop_start = self.loop_start;
-        self.loop_start = if let Some(incr) = before_increment {
+        let start = if let Some(incr) = before_increment {
             incr
         } else if let Some(cond) = before_condition {
             cond
         } else {
             before_body
         };
+        let scope_depth = self.current().scope_depth;
+
+        // Give each iteration its own copy of the loop variables so closures
+        // created in the body capture that iteration's values. The copies are
+        // written back before the increment runs.
+        let mut variables = Vec::new();
+        if let Some(Stmt::Var(var)) = statement.initializer {
+            let locals = &self.current().locals;
+            let outer: Vec<u16> = locals[locals.len() - var.declarators.len()..]
+                .iter()
+                .map(|local| local.slot)
+                .collect();
+            self.begin_scope();
+            for (declarator, outer) in var.declarators.iter().zip(outer) {
+                self.emit_variable_op(Op::GetLocal, outer);
+                self.add_local(declarator.name)?;
+                self.current_mut().locals.last_mut().unwrap().declaration = None;
+                self.mark_initialized();
+                let inner = self.current().locals.last().unwrap().slot;
+                variables.push((outer, inner));
+            }
+        }
 
-        self.statement(&statement.body)?;
+        self.loops.push(Loop {
+            label: statement.label.map(|label| label.lexeme),
+            start,
+            scope_depth,
+            variables: variables.clone(),
+            result: None,
+            breaks: Vec::new(),
+        });
+        self.statement(statement.body)?;
+
+        if !variables.is_empty() {
+            // What the body did with the copies counts for the variables.
+            let locals = &mut self.current_mut().locals;
+            let count = variables.len();
+            let split = locals.len() - count;
+            let (declared, copies) = locals.split_at_mut(split);
+            let start = declared.len() - count;
+            for (variable, copy) in declared[start..].iter_mut().zip(copies) {
+                variable.read |= copy.read;
+                variable.assignments.append(&mut copy.assignments);
+            }
+            self.copy_loop_variables(&variables);
+            self.end_scope();
+        }
 
-        self.emit_loop(self.loop_start)?;
+        self.emit_loop(start)?;
 
-        if let Some(jump) = jump_after_cond {
+        if let Some((jump, kept)) = jump_after_cond {
             self.patch_jump(jump)?;
-            self.emit_op(Op::Pop);
+            self.pop_condition(kept);
         }
 
-        self.patch_breaks()?;
-        self.loop_start = enclosing_loop_start;
-        self.loop_depth -= 1;
-
+        self.end_loop()?;
         self.end_scope();
         Ok(())
     }
 
+    fn copy_loop_variables(&mut self, variables: &[(u16, u16)]) {
+        for &(outer, inner) in variables {
+            self.emit_variable_op(Op::GetLocal, inner);
+            self.emit_variable_op(Op::SetLocal, outer);
+            self.emit_op(Op::Pop);
+        }
+    }
+
     fn fun_declaration(&mut self, function: &stmt::Function<'a>) -> CompileResult<()> {
         let global = self.parse_variable(function.name)?;
         self.mark_initialized();
@@ -541,33 +1187,70 @@ impl<'a> CompilerWrapper<'a> {
         Ok(())
     }
 
-    fn if_statement(&mut self, statement: &stmt::If<'a>) -> CompileResult<()> {
-        self.expression(&statement.condition)?;
+    // Compiles `condition` and a jump taken when it's false. A comparison
+    // compiles to a single instruction that compares, pops its operands and
+    // jumps, which leaves no bool behind. Returns the jump and whether the
+    // condition is left on the stack for each branch to pop.
+    fn condition_jump(&mut self, condition: &Expr<'a>) -> CompileResult<(usize, bool)> {
+        if let Expr::Binary(binary) = condition {
+            match comparison_jump(binary.operator.kind) {
+                Some(op) if !self.long_jumps && self.folded(binary).is_none() => {
+                    self.expression(binary.left)?;
+                    self.with_temporary(|c| c.expression(binary.right))?;
+                    self.locate(binary.operator);
+                    return Ok((self.emit_jump(op), false));
+                }
+                _ => (),
+            }
+        }
+        self.expression(condition)?;
+        Ok((self.emit_jump(Op::JumpIfFalse), true))
+    }
 
-        let jump_to_else = self.emit_jump(Op::JumpIfFalse);
-        self.emit_op(Op::Pop);
-        self.statement(&statement.then_branch)?;
+    fn pop_condition(&mut self, kept: bool) {
+        if kept {
+            self.emit_op(Op::Pop);
+        }
+    }
 
-        let jump_from_then = self.emit_jump(Op::Jump);
+    fn if_statement(&mut self, statement: &stmt::If<'a>) -> CompileResult<()> {
+        let (jump_to_else, kept) = self.condition_jump(&statement.condition)?;
+        self.pop_condition(kept);
+        self.statement(statement.then_branch)?;
+
+        // Nothing needs to jump over the else branch if the then branch
+        // never finishes.
+        let jump_from_then = if always_exits(statement.then_branch) {
+            None
+        } else {
+            Some(self.emit_jump(Op::Jump))
+        };
         self.patch_jump(jump_to_else)?;
-        self.emit_op(Op::Pop);
+        self.pop_condition(kept);
 
         if let Some(stmt) = &statement.else_branch {
             self.statement(stmt)?;
         }
-        self.patch_jump(jump_from_then)?;
+        if let Some(jump) = jump_from_then {
+            self.patch_jump(jump)?;
+        }
         Ok(())
     }
 
-    fn print_statement(&mut self, statement: &stmt::Print) -> CompileResult<()> {
-        self.current_line = statement.keyword.line;
-        self.expression(&statement.expression)?;
-        self.emit_op(Op::Print);
+    // The old print statement is a call to the `println` native. The native
+    // is a constant so redefining the global doesn't change the statement.
+    fn print_statement(&mut self, statement: &stmt::Print<'a>) -> CompileResult<()> {
+        self.locate(statement.keyword);
+        self.emit_constant(Value::Native(native::println), statement.keyword.lexeme)?;
+        self.with_temporary(|c| c.expression(&statement.expression))?;
+        self.locate(statement.keyword);
+        self.emit_bytes(Op::Call as u8, 1);
+        self.emit_op(Op::Pop);
         Ok(())
     }
 
-    fn return_statement(&mut self, statement: &stmt::Return) -> CompileResult<()> {
-        self.current_line = statement.keyword.line;
+    fn return_statement(&mut self, statement: &stmt::Return<'a>) -> CompileResult<()> {
+        self.locate(statement.keyword);
         if let Some(value) = &statement.value {
             self.expression(value)?
         } else {
@@ -578,48 +1261,113 @@ impl<'a> CompilerWrapper<'a> {
         Ok(())
     }
 
+    // The result starts out nil, in a slot under the body's locals, and
+    // `break value;` overwrites it.
+    fn loop_expression(&mut self, expression: &expr::Loop<'a>) -> CompileResult<()> {
+        self.locate(expression.keyword);
+        let result = self.current().next_slot() as u16;
+        self.emit_op(Op::Nil);
+
+        self.with_temporary(|c| {
+            let start = c.get_current_len();
+            c.loops.push(Loop {
+                label: None,
+                start,
+                scope_depth: c.current().scope_depth,
+                variables: Vec::new(),
+                result: Some(result),
+                breaks: Vec::new(),
+            });
+            c.statement(expression.body)?;
+            c.emit_loop(start)?;
+            c.end_loop()
+        })
+    }
+
+    fn loop_statement(&mut self, statement: &stmt::Loop<'a>) -> CompileResult<()> {
+        let start = self.get_current_len();
+
+        self.loops.push(Loop {
+            label: statement.label.map(|label| label.lexeme),
+            start,
+            scope_depth: self.current().scope_depth,
+            variables: Vec::new(),
+            result: None,
+            breaks: Vec::new(),
+        });
+        self.statement(statement.body)?;
+        self.emit_loop(start)?;
+
+        self.end_loop()?;
+        Ok(())
+    }
+
     fn while_statement(&mut self, statement: &stmt::While<'a>) -> CompileResult<()> {
-        let enclosing_loop_start = self.loop_start;
-        self.loop_start = self.get_current_len();
-        self.loop_depth += 1;
+        let start = self.get_current_len();
 
-        self.expression(&statement.condition)?;
-        let end_jump = self.emit_jump(Op::JumpIfFalse);
-        self.emit_op(Op::Pop);
+        let (end_jump, kept) = self.condition_jump(&statement.condition)?;
+        self.pop_condition(kept);
 
-        self.statement(&statement.body)?;
+        self.loops.push(Loop {
+            label: statement.label.map(|label| label.lexeme),
+            start,
+            scope_depth: self.current().scope_depth,
+            variables: Vec::new(),
+            result: None,
+            breaks: Vec::new(),
+        });
+        self.statement(statement.body)?;
 
-        self.emit_loop(self.loop_start)?;
+        self.emit_loop(start)?;
         self.patch_jump(end_jump)?;
-        self.emit_op(Op::Pop);
+        self.pop_condition(kept);
 
-        self.patch_breaks()?;
-        self.loop_start = enclosing_loop_start;
-        self.loop_depth -= 1;
+        self.end_loop()?;
         Ok(())
     }
 
     fn var_declaration(&mut self, statement: &stmt::Var<'a>) -> CompileResult<()> {
-        let global = self.parse_variable(statement.name)?;
+        for declarator in &statement.declarators {
+            let global = self.parse_variable(declarator.name)?;
 
-        if let Some(expr) = &statement.initializer {
-            self.expression(expr)?;
-        } else {
-            self.emit_op(Op::Nil);
-        }
+            if let Some(expr) = &declarator.initializer {
+                self.expression(expr)?;
+            } else {
+                self.emit_op(Op::Nil);
+            }
 
-        self.define_variable(global);
+            self.define_variable(global);
+        }
         Ok(())
     }
 
-    fn expression(&mut self, expression: &Expr) -> CompileResult<()> {
+    fn expression(&mut self, expression: &Expr<'a>) -> CompileResult<()> {
         match expression {
             Expr::Assign(expr) => self.assignment(expr),
             Expr::Binary(expr) => self.binary(expr),
-            Expr::Call(expr) => self.call(expr),
-            Expr::Grouping(expr) => self.expression(&expr.expr),
+            Expr::Call(expr) => {
+                self.check_void_call(expr);
+                self.call(expr)
+            }
+            Expr::Comma(expr) => {
+                self.discarded_expression(expr.left)?;
+                self.expression(expr.right)
+            }
+            Expr::ComparisonChain(expr) => self.comparison_chain(expr),
+            Expr::Get(expr) => self.get(expr),
+            Expr::Grouping(expr) => self.expression(expr.expr),
+            Expr::If(expr) => self.if_expression(expr),
+            Expr::Index(expr) => self.index(expr),
             Expr::Literal(expr) => self.literal(expr),
+            Expr::Loop(expr) => self.loop_expression(expr),
             Expr::Logical(expr) => self.logical(expr),
+            Expr::LogicalAssign(expr) => self.logical_assignment(expr),
+            Expr::LogicalSet(expr) => self.logical_set(expr),
+            Expr::Match(expr) => self.match_expression(expr),
+            Expr::Set(expr) => self.set(expr),
+            Expr::Object(expr) => self.object(expr),
+            Expr::SetLiteral(expr) => self.set_literal(expr),
+            Expr::Tuple(expr) => self.tuple(expr),
             Expr::Unary(expr) => self.unary(expr),
             Expr::Variable(expr) => self.variable(expr),
         }
@@ -631,76 +1379,226 @@ impl<'a> CompilerWrapper<'a> {
         local: Op,
         upvalue: Op,
         global: Op,
-    ) -> Result<(Op, u8), InterpretError> {
-        match self.with_current(|c| c.resolve_local(name)) {
-            Ok(Some(result)) => return Ok((local, result)),
+    ) -> Result<(Op, u16), InterpretError> {
+        let current = self.current_mut();
+        match current.resolve_local(name) {
+            Ok(Some(i)) => {
+                let local_var = &mut current.locals[i];
+                if matches!(local, Op::GetLocal) {
+                    local_var.read = true;
+                }
+                return Ok((local, local_var.slot));
+            }
             Err(message) => return self.error(Some(name), message),
             _ => (),
         };
 
-        match self.with_current_mut(|c| c.resolve_upvalue(name)) {
+        match resolve_upvalue(&mut self.compilers, name) {
             Ok(Some(result)) => return Ok((upvalue, result)),
             Err(message) => return self.error(Some(name), message),
             _ => (),
         }
 
-        Ok((global, self.identifier_constant(name)?))
+        Ok((global, self.name_index(name)?))
+    }
+
+    fn set_arg(&mut self, token: &'a Token<'a>) -> Result<(Op, u16), InterpretError> {
+        let name = token.lexeme;
+        let (op, arg) = self.get_arg(name, Op::SetLocal, Op::SetUpvalue, Op::SetGlobal)?;
+        if let Ok(Some(i)) = self.current().resolve_local(name) {
+            self.current_mut().locals[i].assignments.push(token);
+        }
+        if let (Op::SetGlobal, Some(declared)) = (&op, &self.declared_globals) {
+            if !declared.contains(name) && !vm::is_predefined_global(name) {
+                return self.error(Some(name), "Can't assign to an undeclared variable.");
+            }
+        }
+        Ok((op, arg))
     }
 
-    fn assignment(&mut self, assignment: &expr::Assign) -> CompileResult<()> {
-        self.expression(&assignment.value)?;
+    fn assignment(&mut self, assignment: &expr::Assign<'a>) -> CompileResult<()> {
+        self.expression(assignment.value)?;
 
-        let name = assignment.name.lexeme;
-        let (set_op, arg) = self.get_arg(name, Op::SetLocal, Op::SetUpvalue, Op::SetGlobal)?;
+        let (set_op, arg) = self.set_arg(assignment.name)?;
 
-        self.emit_bytes(set_op as u8, arg);
+        self.emit_variable_op(set_op, arg);
         Ok(())
     }
 
-    fn binary(&mut self, binary: &expr::Binary) -> CompileResult<()> {
-        self.expression(&binary.left)?;
-        self.expression(&binary.right)?;
+    fn folded(&self, binary: &expr::Binary) -> Option<Value> {
+        binary_constant(binary).filter(|_| self.fold)
+    }
+
+    fn binary(&mut self, binary: &expr::Binary<'a>) -> CompileResult<()> {
+        if let Some(value) = self.folded(binary) {
+            self.locate(binary.operator);
+            return self.emit_constant(value, binary.operator.lexeme);
+        }
+        self.expression(binary.left)?;
+        self.with_temporary(|c| c.expression(binary.right))?;
 
-        self.current_line = binary.operator.line;
+        self.locate(binary.operator);
         match binary.operator.kind {
             TokenKind::BangEqual => self.emit_ops(Op::Equal, Op::Not),
             TokenKind::EqualEqual => self.emit_op(Op::Equal),
-            TokenKind::Greater => self.emit_op(Op::Greater),
-            TokenKind::GreaterEqual => self.emit_ops(Op::Less, Op::Not),
-            TokenKind::Less => self.emit_op(Op::Less),
-            TokenKind::LessEqual => self.emit_ops(Op::Greater, Op::Not),
+            TokenKind::Is => self.emit_op(Op::Identical),
+            TokenKind::Greater
+            | TokenKind::GreaterEqual
+            | TokenKind::Less
+            | TokenKind::LessEqual => self.comparison_operator(binary.operator.kind),
             TokenKind::Plus => self.emit_op(Op::Add),
             TokenKind::Minus => self.emit_op(Op::Subtract),
             TokenKind::Slash => self.emit_op(Op::Divide),
             TokenKind::Star => self.emit_op(Op::Multiply),
+            TokenKind::Pipe => self.emit_op(Op::Union),
+            TokenKind::Ampersand => self.emit_op(Op::Intersection),
             _ => unreachable!(),
         };
         Ok(())
     }
 
-    fn call(&mut self, call: &expr::Call) -> CompileResult<()> {
-        self.expression(&call.callee)?;
-        for arg in &call.args {
-            self.expression(arg)?;
+    fn comparison_operator(&mut self, kind: TokenKind) {
+        match kind {
+            TokenKind::Greater => self.emit_op(Op::Greater),
+            TokenKind::GreaterEqual => self.emit_ops(Op::Less, Op::Not),
+            TokenKind::Less => self.emit_op(Op::Less),
+            TokenKind::LessEqual => self.emit_ops(Op::Greater, Op::Not),
+            _ => unreachable!(),
+        }
+    }
+
+    // Each inner operand is also stored in a hidden slot, so the next
+    // comparison can read it again without evaluating it twice. The first
+    // false comparison skips the rest, like `and`. The result ends up in the
+    // hidden slot.
+    fn comparison_chain(&mut self, chain: &expr::ComparisonChain<'a>) -> CompileResult<()> {
+        let slot = self.current().next_slot() as u16;
+        self.emit_op(Op::Nil);
+
+        self.with_temporary(|c| {
+            c.expression(&chain.operands[0])?;
+            let mut end_jumps = Vec::new();
+            for (i, (operator, operand)) in
+                chain.operators.iter().zip(&chain.operands[1..]).enumerate()
+            {
+                if i > 0 {
+                    end_jumps.push(c.emit_jump(Op::JumpIfFalse));
+                    c.emit_op(Op::Pop);
+                    c.emit_variable_op(Op::GetLocal, slot);
+                }
+                c.with_temporary(|c| c.expression(operand))?;
+                if i + 1 < chain.operators.len() {
+                    c.emit_variable_op(Op::SetLocal, slot);
+                }
+                c.locate(operator);
+                c.comparison_operator(operator.kind);
+            }
+
+            for jump in end_jumps {
+                c.patch_jump(jump)?;
+            }
+            c.emit_variable_op(Op::SetLocal, slot);
+            c.emit_op(Op::Pop);
+            Ok(())
+        })
+    }
+
+    fn check_void_call(&mut self, call: &expr::Call<'a>) {
+        if !self.lints {
+            return;
         }
-        self.emit_bytes(Op::Call as u8, call.args.len() as u8);
+        if let Expr::Variable(expr::Variable { name }) = call.callee {
+            if self.void_functions.contains(name.lexeme) {
+                self.warning(
+                    name,
+                    "Function never returns a value, so this call is always nil.",
+                );
+            }
+        }
+    }
+
+    fn call(&mut self, call: &expr::Call<'a>) -> CompileResult<()> {
+        let invoke = match call.callee {
+            Expr::Get(get) if call.keywords.is_empty() => {
+                self.expression(get.object)?;
+                Some(self.name_index(get.name.lexeme)?)
+            }
+            callee => {
+                self.expression(callee)?;
+                None
+            }
+        };
+        for (i, arg) in call.args.iter().enumerate() {
+            self.with_temporaries(i + 1, |c| c.expression(arg))?;
+        }
+        self.locate(call.paren);
+        if let Some(name) = invoke {
+            self.emit_variable_op(Op::Invoke, name);
+            self.emit_byte(call.args.len() as u8);
+            return Ok(());
+        }
+        if call.keywords.is_empty() {
+            self.emit_bytes(Op::Call as u8, call.args.len() as u8);
+            return Ok(());
+        }
+
+        let mut names = Vec::with_capacity(call.keywords.len());
+        for keyword in &call.keywords {
+            names.push(self.name_index(keyword.lexeme)?);
+        }
+        self.emit_bytes(Op::CallKeywords as u8, call.args.len() as u8);
+        self.emit_byte(names.len() as u8);
+        for name in names {
+            self.emit_bytes((name >> 8) as u8, name as u8);
+        }
+        Ok(())
+    }
+
+    fn get(&mut self, get: &expr::Get<'a>) -> CompileResult<()> {
+        self.expression(get.object)?;
+        self.locate(get.name);
+        let name = self.name_index(get.name.lexeme)?;
+        self.emit_variable_op(Op::GetProperty, name);
+        Ok(())
+    }
+
+    fn if_expression(&mut self, expression: &expr::If<'a>) -> CompileResult<()> {
+        let (jump_to_else, kept) = self.condition_jump(expression.condition)?;
+        self.locate(expression.keyword);
+        self.pop_condition(kept);
+        self.expression(expression.then_branch)?;
+
+        let jump_from_then = self.emit_jump(Op::Jump);
+        self.patch_jump(jump_to_else)?;
+        self.pop_condition(kept);
+        self.expression(expression.else_branch)?;
+        self.patch_jump(jump_from_then)?;
         Ok(())
     }
 
-    fn literal(&mut self, literal: &expr::Literal) -> CompileResult<()> {
-        self.current_line = literal.value.line;
+    fn index(&mut self, index: &expr::Index<'a>) -> CompileResult<()> {
+        self.expression(index.object)?;
+        self.with_temporary(|c| c.expression(index.index))?;
+        self.locate(index.bracket);
+        self.emit_op(Op::Index);
+        Ok(())
+    }
+
+    fn literal(&mut self, literal: &expr::Literal<'a>) -> CompileResult<()> {
+        self.locate(literal.value);
         match literal.value.kind {
             TokenKind::Nil => self.emit_op(Op::Nil),
             TokenKind::False => self.emit_op(Op::False),
             TokenKind::True => self.emit_op(Op::True),
             TokenKind::Number => self.number(literal.value.lexeme)?,
             TokenKind::String => self.string(literal.value.lexeme)?,
+            TokenKind::Symbol => self.symbol(literal.value.lexeme)?,
             _ => unreachable!(),
         };
         Ok(())
     }
 
-    fn logical(&mut self, logical: &expr::Logical) -> CompileResult<()> {
+    fn logical(&mut self, logical: &expr::Logical<'a>) -> CompileResult<()> {
         match logical.operator.kind {
             TokenKind::And => self.and(logical),
             TokenKind::Or => self.or(logical),
@@ -708,65 +1606,545 @@ impl<'a> CompilerWrapper<'a> {
         }
     }
 
-    fn unary(&mut self, unary: &expr::Unary) -> CompileResult<()> {
-        self.current_line = unary.operator.line;
-        self.expression(&unary.right)?;
+    // Reads the variable and only evaluates and stores the new value when the
+    // current one doesn't already decide the result, like `and` and `or`.
+    fn logical_assignment(&mut self, assignment: &expr::LogicalAssign<'a>) -> CompileResult<()> {
+        let name = assignment.name.lexeme;
+        self.locate(assignment.name);
+        let (get_op, get_arg) = self.get_arg(name, Op::GetLocal, Op::GetUpvalue, Op::GetGlobal)?;
+        self.emit_variable_op(get_op, get_arg);
+
+        let skip_jump = match assignment.operator.kind {
+            TokenKind::AndEqual => self.emit_jump(Op::JumpIfFalse),
+            TokenKind::OrEqual => self.emit_jump(Op::JumpIfTrue),
+            _ => unreachable!(),
+        };
+        self.emit_op(Op::Pop);
+
+        self.expression(assignment.value)?;
+        let (set_op, set_arg) = self.set_arg(assignment.name)?;
+        self.emit_variable_op(set_op, set_arg);
+
+        self.patch_jump(skip_jump)?;
+        Ok(())
+    }
+
+    // The object is evaluated once and duplicated for the get. Whichever way
+    // the operator goes, the copy under the result is dropped.
+    fn logical_set(&mut self, set: &expr::LogicalSet<'a>) -> CompileResult<()> {
+        self.expression(set.object)?;
+        self.locate(set.name);
+        let name = self.name_index(set.name.lexeme)?;
+        self.emit_op(Op::Dup);
+        self.emit_variable_op(Op::GetProperty, name);
+
+        let keep = |c: &mut Self| {
+            c.emit_op(Op::Swap);
+            c.emit_op(Op::Pop);
+        };
+        let assign = |c: &mut Self| -> CompileResult<()> {
+            c.emit_op(Op::Pop);
+            c.with_temporary(|c| c.expression(set.value))?;
+            c.locate(set.name);
+            c.emit_variable_op(Op::SetProperty, name);
+            Ok(())
+        };
+
+        let assign_if_truthy = set.operator.kind == TokenKind::AndEqual;
+        let else_jump = self.emit_jump(Op::JumpIfFalse);
+        if assign_if_truthy {
+            assign(self)?;
+        } else {
+            keep(self);
+        }
+        let end_jump = self.emit_jump(Op::Jump);
+        self.patch_jump(else_jump)?;
+        if assign_if_truthy {
+            keep(self);
+        } else {
+            assign(self)?;
+        }
+        self.patch_jump(end_jump)?;
+        Ok(())
+    }
+
+    // The matched value stays on the stack as a temporary while the arms are
+    // tested. Whichever arm runs overwrites it with its result.
+    fn match_expression(&mut self, expression: &expr::Match<'a>) -> CompileResult<()> {
+        let subject = self.current().next_slot() as u16;
+        self.expression(expression.subject)?;
+        let mut end_jumps: Vec<usize> = Vec::new();
+
+        self.with_temporary(|c| {
+            for arm in &expression.arms {
+                let mut next_arm: Option<usize> = None;
+
+                match &arm.pattern {
+                    expr::Pattern::Value(value) => {
+                        c.emit_variable_op(Op::GetLocal, subject);
+                        c.with_temporary(|c| c.expression(value))?;
+                        c.emit_op(Op::Equal);
+                        next_arm = Some(c.emit_jump(Op::JumpIfFalse));
+                        c.emit_op(Op::Pop);
+                        c.match_arm_body(&arm.body, subject)?;
+                    }
+                    expr::Pattern::Binding(name) => {
+                        c.begin_scope();
+                        c.emit_variable_op(Op::GetLocal, subject);
+                        c.declare_variable(name)?;
+                        c.mark_initialized();
+                        c.match_arm_body(&arm.body, subject)?;
+                        c.end_scope();
+                    }
+                    expr::Pattern::Else(keyword) => {
+                        c.locate(keyword);
+                        c.match_arm_body(&arm.body, subject)?;
+                    }
+                }
+
+                end_jumps.push(c.emit_jump(Op::Jump));
+                if let Some(jump) = next_arm {
+                    c.patch_jump(jump)?;
+                    c.emit_op(Op::Pop);
+                }
+            }
+            Ok(())
+        })?;
+
+        // No arm matched.
+        self.locate(expression.keyword);
+        self.emit_op(Op::Pop);
+        self.emit_op(Op::Nil);
+
+        for jump in end_jumps {
+            self.patch_jump(jump)?;
+        }
+        Ok(())
+    }
+
+    fn match_arm_body(&mut self, body: &Expr<'a>, subject: u16) -> CompileResult<()> {
+        self.expression(body)?;
+        self.emit_variable_op(Op::SetLocal, subject);
+        self.emit_op(Op::Pop);
+        Ok(())
+    }
+
+    fn set(&mut self, set: &expr::Set<'a>) -> CompileResult<()> {
+        self.expression(set.object)?;
+        self.with_temporary(|c| c.expression(set.value))?;
+        self.locate(set.name);
+        let name = self.name_index(set.name.lexeme)?;
+        self.emit_variable_op(Op::SetProperty, name);
+        Ok(())
+    }
+
+    // The field names go in a single tuple constant, in the same order as
+    // the values left on the stack.
+    fn object(&mut self, object: &expr::Object<'a>) -> CompileResult<()> {
+        self.elements(&object.values)?;
+        self.locate(object.brace);
+        let names: Arc<[Value]> = object
+            .names
+            .iter()
+            .map(|name| Value::String(self.names.strings.intern(name.lexeme)))
+            .collect();
+        let constant = self.make_constant(Value::Tuple(names), object.brace.lexeme)?;
+        self.emit_constant_op(Op::Object, Op::ObjectLong, constant);
+        Ok(())
+    }
+
+    fn set_literal(&mut self, set: &expr::SetLiteral<'a>) -> CompileResult<()> {
+        self.elements(&set.elements)?;
+        self.locate(set.brace);
+        self.emit_bytes(Op::Set as u8, set.elements.len() as u8);
+        Ok(())
+    }
+
+    fn tuple(&mut self, tuple: &expr::Tuple<'a>) -> CompileResult<()> {
+        self.elements(&tuple.elements)?;
+        self.locate(tuple.paren);
+        self.emit_bytes(Op::Tuple as u8, tuple.elements.len() as u8);
+        Ok(())
+    }
+
+    // Leaves each element on the stack for a collection instruction.
+    fn elements(&mut self, elements: &[Expr<'a>]) -> CompileResult<()> {
+        for (i, element) in elements.iter().enumerate() {
+            self.with_temporaries(i, |c| c.expression(element))?;
+        }
+        Ok(())
+    }
+
+    fn unary(&mut self, unary: &expr::Unary<'a>) -> CompileResult<()> {
+        self.locate(unary.operator);
+        if let Some(value) = unary_constant(unary).filter(|_| self.fold) {
+            return self.emit_constant(value, unary.operator.lexeme);
+        }
+        self.expression(unary.right)?;
         match unary.operator.kind {
             TokenKind::Bang => self.emit_op(Op::Not),
             TokenKind::Minus => self.emit_op(Op::Negate),
+            TokenKind::Type => self.emit_op(Op::Type),
             _ => unreachable!(),
         };
         Ok(())
     }
 
-    fn variable(&mut self, variable: &expr::Variable) -> CompileResult<()> {
+    fn variable(&mut self, variable: &expr::Variable<'a>) -> CompileResult<()> {
         let name = variable.name.lexeme;
-        self.current_line = variable.name.line;
+        self.locate(variable.name);
         let (get_op, arg) = self.get_arg(name, Op::GetLocal, Op::GetUpvalue, Op::GetGlobal)?;
-        self.emit_bytes(get_op as u8, arg);
+        self.emit_variable_op(get_op, arg);
         Ok(())
     }
 
-    fn and(&mut self, logical: &expr::Logical) -> CompileResult<()> {
-        self.expression(&logical.left)?;
+    fn and(&mut self, logical: &expr::Logical<'a>) -> CompileResult<()> {
+        self.expression(logical.left)?;
         let jump = self.emit_jump(Op::JumpIfFalse);
         self.emit_op(Op::Pop);
 
-        self.expression(&logical.right)?;
+        self.expression(logical.right)?;
         self.patch_jump(jump)?;
         Ok(())
     }
 
-    fn or(&mut self, logical: &expr::Logical) -> CompileResult<()> {
-        self.expression(&logical.left)?;
-        let else_jump = self.emit_jump(Op::JumpIfFalse);
-        let end_jump = self.emit_jump(Op::Jump);
+    fn or(&mut self, logical: &expr::Logical<'a>) -> CompileResult<()> {
+        self.expression(logical.left)?;
+        let end_jump = self.emit_jump(Op::JumpIfTrue);
 
-        self.patch_jump(else_jump)?;
         self.emit_op(Op::Pop);
-        self.expression(&logical.right)?;
+        self.expression(logical.right)?;
 
         self.patch_jump(end_jump)?;
         Ok(())
     }
 
     fn number(&mut self, lexeme: &str) -> CompileResult<()> {
-        let value: f64 = lexeme.parse().expect("Failed to parse string into float");
-        self.emit_constant(Value::Number(value), lexeme)?;
-        Ok(())
+        self.emit_constant(number_value(lexeme), lexeme)
     }
 
     fn string(&mut self, lexeme: &str) -> CompileResult<()> {
-        let handle = string::Handle::from_str(&lexeme[1..lexeme.len() - 1]);
-        self.emit_constant(Value::String(handle), lexeme)?;
-        Ok(())
+        let value = string_value(lexeme).or_else(|message| self.error(Some(lexeme), message))?;
+        self.emit_constant(value, lexeme)
+    }
+
+    fn symbol(&mut self, lexeme: &str) -> CompileResult<()> {
+        let handle = string::Handle::from_str(&lexeme[1..]);
+        self.emit_constant(Value::Symbol(handle), lexeme)
+    }
+}
+
+// Whether control never continues past `statement`.
+// The `return`, `break` or `continue` that makes `statement` always exit.
+fn exit_keyword<'a>(statement: &Stmt<'a>) -> Option<&'a Token<'a>> {
+    match statement {
+        Stmt::Return(statement) => Some(statement.keyword),
+        Stmt::Break(statement) => Some(statement.keyword),
+        Stmt::Continue(statement) => Some(statement.keyword),
+        Stmt::Block(block) => block
+            .statements
+            .iter()
+            .find(|statement| always_exits(statement))
+            .and_then(exit_keyword),
+        Stmt::If(statement) => exit_keyword(statement.then_branch),
+        _ => None,
+    }
+}
+
+fn always_exits(statement: &Stmt) -> bool {
+    match statement {
+        Stmt::Return(_) | Stmt::Break(_) | Stmt::Continue(_) => true,
+        Stmt::Block(block) => block.statements.iter().any(always_exits),
+        Stmt::If(statement) => {
+            always_exits(statement.then_branch) && statement.else_branch.is_some_and(always_exits)
+        }
+        _ => false,
+    }
+}
+
+fn number_value(lexeme: &str) -> Value {
+    let digits = lexeme.replace('_', "");
+    if let Some(digits) = digits.strip_suffix('n') {
+        return Value::BigInt(Arc::new(BigInt::parse(digits)));
+    }
+    // Integers too large for an int are floats.
+    match digits.parse() {
+        Ok(value) => Value::Int(value),
+        Err(_) => Value::Number(digits.parse().expect("Failed to parse string into float")),
+    }
+}
+
+fn string_value(lexeme: &str) -> Result<Value, &'static str> {
+    let contents = lexeme
+        .strip_prefix("\"\"\"")
+        .and_then(|lexeme| lexeme.strip_suffix("\"\"\""))
+        .unwrap_or(&lexeme[1..lexeme.len() - 1]);
+    Ok(Value::String(string::Handle::from_str(&unescape(
+        contents,
+    )?)))
+}
+
+// The value of an expression built only from literals and operators, or
+// `None` if it has to be computed at runtime. Operations that would fail are
+// left to the VM so it can report them.
+fn constant_value(expression: &Expr) -> Option<Value> {
+    match expression {
+        Expr::Literal(literal) => match literal.value.kind {
+            TokenKind::Nil => Some(Value::Nil),
+            TokenKind::False => Some(Value::Bool(false)),
+            TokenKind::True => Some(Value::Bool(true)),
+            TokenKind::Number => Some(number_value(literal.value.lexeme)),
+            TokenKind::String => string_value(literal.value.lexeme).ok(),
+            _ => None,
+        },
+        Expr::Grouping(grouping) => constant_value(grouping.expr),
+        Expr::Unary(unary) => unary_constant(unary),
+        Expr::Binary(binary) => binary_constant(binary),
+        _ => None,
+    }
+}
+
+fn unary_constant(unary: &expr::Unary) -> Option<Value> {
+    let value = constant_value(unary.right)?;
+    match unary.operator.kind {
+        TokenKind::Bang => Some(Value::Bool(value.is_falsy())),
+        TokenKind::Minus => vm::negate(&value),
+        _ => None,
+    }
+}
+
+// Mirrors what the VM does for each operator, including `>=` and `<=` being
+// the negations of `<` and `>`.
+// The instruction that jumps when a comparison is false.
+fn comparison_jump(kind: TokenKind) -> Option<Op> {
+    match kind {
+        TokenKind::Less => Some(Op::LessJumpIfFalse),
+        TokenKind::GreaterEqual => Some(Op::LessJumpIfTrue),
+        TokenKind::Greater => Some(Op::GreaterJumpIfFalse),
+        TokenKind::LessEqual => Some(Op::GreaterJumpIfTrue),
+        TokenKind::EqualEqual => Some(Op::EqualJumpIfFalse),
+        TokenKind::BangEqual => Some(Op::EqualJumpIfTrue),
+        _ => None,
+    }
+}
+
+fn binary_constant(binary: &expr::Binary) -> Option<Value> {
+    let a = constant_value(binary.left)?;
+    let b = constant_value(binary.right)?;
+    let compare = |int, float| vm::compare_numbers(&a, &b, int, float).map(Value::Bool);
+    let negated = |value: Option<Value>| Some(Value::Bool(value?.is_falsy()));
+    match binary.operator.kind {
+        TokenKind::Plus => match (&a, &b) {
+            (Value::String(a), Value::String(b)) => Some(Value::String(string::Handle::from_str(
+                &format!("{}{}", a, b),
+            ))),
+            _ => vm::arithmetic(&a, &b, i64::checked_add, |a, b| a + b),
+        },
+        TokenKind::Minus => vm::arithmetic(&a, &b, i64::checked_sub, |a, b| a - b),
+        TokenKind::Star => vm::arithmetic(&a, &b, i64::checked_mul, |a, b| a * b),
+        TokenKind::Slash => vm::arithmetic(&a, &b, vm::exact_division, |a, b| a / b),
+        TokenKind::EqualEqual => Some(Value::Bool(a == b)),
+        TokenKind::BangEqual => Some(Value::Bool(a != b)),
+        TokenKind::Greater => compare(i64::gt, f64::gt),
+        TokenKind::Less => compare(i64::lt, f64::lt),
+        TokenKind::GreaterEqual => negated(compare(i64::lt, f64::lt)),
+        TokenKind::LessEqual => negated(compare(i64::gt, f64::gt)),
+        _ => None,
+    }
+}
+
+// Decodes the backslash escapes in the contents of a string literal.
+fn unescape(contents: &str) -> Result<Cow<'_, str>, &'static str> {
+    if !contents.contains('\\') {
+        return Ok(Cow::Borrowed(contents));
+    }
+
+    let mut result = String::with_capacity(contents.len());
+    let mut chars = contents.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        let escaped = match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('"') => '"',
+            Some('\\') => '\\',
+            // `\u{1F600}`: one to six hex digits naming a Unicode scalar value.
+            Some('u') => {
+                let rest = chars.as_str();
+                let (digits, after) = rest
+                    .strip_prefix('{')
+                    .and_then(|rest| rest.split_once('}'))
+                    .ok_or("Invalid unicode escape.")?;
+                if digits.is_empty()
+                    || digits.len() > 6
+                    || !digits.chars().all(|c| c.is_ascii_hexdigit())
+                {
+                    return Err("Invalid unicode escape.");
+                }
+                chars = after.chars();
+                u32::from_str_radix(digits, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or("Invalid unicode escape.")?
+            }
+            _ => return Err("Invalid escape sequence."),
+        };
+        result.push(escaped);
+    }
+
+    Ok(Cow::Owned(result))
+}
+
+// Reports a compile error at `lexeme`, which is at `span` in `source`.
+pub fn report_error(source: &str, span: Range<usize>, lexeme: &str, message: &str) {
+    let (line, column) = snippet::line_column(source, span.start);
+    output::err(format_args!(
+        "[line {}:{}] Error at '{}': {}\n",
+        line, column, lexeme, message
+    ));
+    output::err(format_args!("{}", snippet::at(source, span.clone())));
+    output::diagnostic(span, message, true);
+}
+
+// The tokens are the ones scanned from `source`. The code gets a name table
+// of its own, so this is for checking a script rather than running it.
+pub fn compile(
+    source: &Arc<str>,
+    tokens: Vec<Token>,
+    options: &CompileOptions,
+) -> Result<Function, InterpretError> {
+    let defined: &dyn Fn(&str) -> bool = &vm::is_predefined_global;
+    compile_with(
+        source,
+        tokens,
+        options,
+        &mut Names::default(),
+        Some(defined),
+    )
+}
+
+// Compiles with a VM's names, so the code can run on that VM. `defined` says
+// which globals exist besides the ones the script declares; without it, any
+// global may be read.
+pub fn compile_with(
+    source: &Arc<str>,
+    tokens: Vec<Token>,
+    options: &CompileOptions,
+    names: &mut Names,
+    defined: Option<&dyn Fn(&str) -> bool>,
+) -> Result<Function, InterpretError> {
+    let nodes = parser::Nodes::default();
+    let statements = parser::parse_tokens(source, &tokens, &nodes, options.print_statement)
+        .ok_or(InterpretError::CompileError)?;
+    if options.dump_ast {
+        print!("{}", printer::print(&statements));
+    }
+    // `eval` can define any global, whether it's called by this code or by
+    // earlier code that ran on the same VM.
+    let uses_eval = names.contains("eval")
+        || tokens
+            .iter()
+            .any(|token| token.kind == TokenKind::Identifier && token.lexeme == "eval");
+    let defined = defined.filter(|_| !uses_eval);
+    if !resolver::resolve(source, &statements, defined) {
+        return Err(InterpretError::CompileError);
+    }
+    let declared_globals = if options.strict || uses_strict(&statements) {
+        Some(analysis::global_declarations(&statements))
+    } else {
+        None
+    };
+    let void_functions = analysis::void_functions(&statements);
+    let lazy = options.lazy.then(|| {
+        Arc::new(LazyContext {
+            options: options.clone(),
+            void_functions: void_functions.iter().map(|name| name.to_string()).collect(),
+            declared_globals: declared_globals
+                .as_ref()
+                .map(|globals| globals.iter().map(|name| name.to_string()).collect()),
+        })
+    });
+    let function = compile_twice(
+        names,
+        |long_jumps, quiet| {
+            let mut compiler = CompilerWrapper::new(
+                source,
+                &tokens,
+                void_functions.clone(),
+                declared_globals.clone(),
+                options,
+                long_jumps,
+                quiet,
+            );
+            compiler.lazy = lazy.clone();
+            compiler
+        },
+        |compiler| compiler.compile(&statements),
+    )?;
+    if options.disassemble {
+        print_disassembly(&function, names);
+    }
+    Ok(function)
+}
+
+// Prints the code of `function` and of each function it defines, except the
+// ones whose bodies are compiled lazily.
+fn print_disassembly(function: &Function, names: &Names) {
+    println!("== {} ==", function.get_name());
+    print!("{}", function.chunk.disassembly(names));
+    for constant in &function.chunk.constants {
+        match constant {
+            Value::Function(function) if function.lazy.is_none() => {
+                print_disassembly(function, names)
+            }
+            _ => (),
+        }
+    }
+}
+
+// The first attempt reports nothing. It's the result unless it had something
+// to report, or a jump needed more than 16 bits. Otherwise a second attempt
+// reports everything, using long jumps if they're needed.
+fn compile_twice<'a>(
+    names: &mut Names,
+    new_compiler: impl Fn(bool, bool) -> CompilerWrapper<'a>,
+    compile: impl Fn(&mut CompilerWrapper<'a>) -> Result<Function, InterpretError>,
+) -> Result<Function, InterpretError> {
+    let attempt = |compiler: &mut CompilerWrapper<'a>, names: &mut Names| {
+        compiler.names = std::mem::take(names);
+        let result = compile(compiler);
+        *names = std::mem::take(&mut compiler.names);
+        result
+    };
+    let mut quiet = new_compiler(false, true);
+    let result = attempt(&mut quiet, names);
+    if !quiet.suppressed && !quiet.jump_too_long {
+        return result;
+    }
+    let mut loud = new_compiler(quiet.jump_too_long, false);
+    if loud.dump_ir {
+        // The dump starts from the code before constants are folded, which
+        // a quiet compile that doesn't fold records.
+        let mut unfolded = new_compiler(quiet.jump_too_long, true);
+        unfolded.fold = false;
+        attempt(&mut unfolded, names).ok();
+        loud.raw_ir = unfolded.raw_ir;
     }
+    attempt(&mut loud, names)
 }
 
-pub fn compile<'a>(tokens: Vec<Token<'a>>) -> Result<Function, InterpretError> {
-    let statements = parser::parse_tokens(&tokens)
-        .ok_or(InterpretError::CompileError)?
-        .into_iter();
-    let mut compiler = CompilerWrapper::new();
-    compiler.compile(statements)
+// A `"use strict";` statement at the start of the script.
+fn uses_strict(statements: &[Stmt]) -> bool {
+    matches!(
+        statements.first(),
+        Some(Stmt::Expression(stmt::Expression {
+            expression: Expr::Literal(expr::Literal { value }),
+        })) if value.lexeme == "\"use strict\""
+    )
 }
@@ -0,0 +1,95 @@
+use crate::chunk::Chunk;
+use crate::value::{Function, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::sync::Arc;
+
+// What `--coverage` collects: how often execution entered each line of the
+// script. A line is entered when an instruction on it runs right after one
+// from another line of the same call, or first in a call, so a loop's lines
+// count once per iteration and a line with calls on it counts once however
+// many it makes. Only the script's own functions count, not the prelude's or
+// code run by `eval`. Under `--lazy`, a function's lines are only known once
+// it's first called.
+pub struct Coverage {
+    // Keyed by chunk.
+    chunks: HashSet<usize>,
+    // Every line with code, and how often it was entered.
+    lines: BTreeMap<i32, u64>,
+    // The chunk and line last run at each depth of calls.
+    last: Vec<(usize, i32)>,
+}
+
+impl Coverage {
+    pub fn new(script: &Function) -> Coverage {
+        let mut coverage = Coverage {
+            chunks: HashSet::new(),
+            lines: BTreeMap::new(),
+            last: Vec::new(),
+        };
+        coverage.add(script);
+        coverage
+    }
+
+    // Adds the lines of `function` and of each function it defines.
+    pub fn add(&mut self, function: &Function) {
+        if !self.chunks.insert(Arc::as_ptr(&function.chunk) as usize) {
+            return;
+        }
+        for position in function.chunk.lines.iter() {
+            self.lines.entry(position.line).or_insert(0);
+        }
+        for constant in &function.chunk.constants {
+            match constant {
+                Value::Function(function) if function.lazy.is_none() => self.add(function),
+                _ => (),
+            }
+        }
+    }
+
+    // `depth` is how many calls deep the instruction runs.
+    pub fn record(&mut self, chunk: &Arc<Chunk>, offset: usize, depth: usize) {
+        let key = Arc::as_ptr(chunk) as usize;
+        if !self.chunks.contains(&key) {
+            return;
+        }
+        let line = chunk.line_at(offset);
+        // Calls deeper than this one have returned, so the next one starts
+        // afresh.
+        self.last.resize(depth + 1, (0, 0));
+        if self.last[depth] != (key, line) {
+            self.last[depth] = (key, line);
+            *self.lines.entry(line).or_insert(0) += 1;
+        }
+    }
+
+    // The source with each line's count before it, `#####` for a line that
+    // never ran and `-` for one without code.
+    pub fn listing(&self, source: &str) -> String {
+        let mut out = String::new();
+        for (line, text) in (1..).zip(source.lines()) {
+            let count = match self.lines.get(&line) {
+                Some(0) => String::from("#####"),
+                Some(count) => count.to_string(),
+                None => String::from("-"),
+            };
+            writeln!(out, "{:>9}: {:>4}: {}", count, line, text).unwrap();
+        }
+        out
+    }
+
+    // The counts as an lcov tracefile, for coverage tools.
+    pub fn lcov(&self, source_path: &str) -> String {
+        let mut out = String::new();
+        writeln!(out, "TN:").unwrap();
+        writeln!(out, "SF:{}", source_path).unwrap();
+        for (line, count) in &self.lines {
+            writeln!(out, "DA:{},{}", line, count).unwrap();
+        }
+        writeln!(out, "LF:{}", self.lines.len()).unwrap();
+        let hit = self.lines.values().filter(|&&count| count > 0).count();
+        writeln!(out, "LH:{}", hit).unwrap();
+        writeln!(out, "end_of_record").unwrap();
+        out
+    }
+}
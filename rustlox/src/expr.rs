@@ -1,28 +1,66 @@
 use crate::scanner::Token;
+use crate::stmt::Stmt;
 
 #[derive(Debug)]
 pub struct Assign<'a> {
     pub name: &'a Token<'a>,
-    pub value: Box<Expr<'a>>,
+    pub value: &'a Expr<'a>,
 }
 
 #[derive(Debug)]
 pub struct Binary<'a> {
-    pub left: Box<Expr<'a>>,
+    pub left: &'a Expr<'a>,
     pub operator: &'a Token<'a>,
-    pub right: Box<Expr<'a>>,
+    pub right: &'a Expr<'a>,
 }
 
 #[derive(Debug)]
 pub struct Call<'a> {
-    pub callee: Box<Expr<'a>>,
+    pub callee: &'a Expr<'a>,
     pub paren: &'a Token<'a>,
     pub args: Vec<Expr<'a>>,
+    // Names of the trailing keyword arguments, in the order they were passed.
+    pub keywords: Vec<&'a Token<'a>>,
+}
+
+#[derive(Debug)]
+pub struct Comma<'a> {
+    pub left: &'a Expr<'a>,
+    pub right: &'a Expr<'a>,
+}
+
+// `a < b < c` and longer chains of comparisons, which mean `a < b and b < c`
+// with `b` evaluated once.
+#[derive(Debug)]
+pub struct ComparisonChain<'a> {
+    pub operands: Vec<Expr<'a>>,
+    pub operators: Vec<&'a Token<'a>>,
+}
+
+#[derive(Debug)]
+pub struct Get<'a> {
+    pub object: &'a Expr<'a>,
+    pub name: &'a Token<'a>,
 }
 
 #[derive(Debug)]
 pub struct Grouping<'a> {
-    pub expr: Box<Expr<'a>>,
+    pub expr: &'a Expr<'a>,
+}
+
+#[derive(Debug)]
+pub struct If<'a> {
+    pub keyword: &'a Token<'a>,
+    pub condition: &'a Expr<'a>,
+    pub then_branch: &'a Expr<'a>,
+    pub else_branch: &'a Expr<'a>,
+}
+
+#[derive(Debug)]
+pub struct Index<'a> {
+    pub object: &'a Expr<'a>,
+    pub bracket: &'a Token<'a>,
+    pub index: &'a Expr<'a>,
 }
 
 #[derive(Debug)]
@@ -30,17 +68,84 @@ pub struct Literal<'a> {
     pub value: &'a Token<'a>,
 }
 
+#[derive(Debug)]
+pub struct Loop<'a> {
+    pub keyword: &'a Token<'a>,
+    pub body: &'a Stmt<'a>,
+}
+
 #[derive(Debug)]
 pub struct Logical<'a> {
-    pub left: Box<Expr<'a>>,
+    pub left: &'a Expr<'a>,
+    pub operator: &'a Token<'a>,
+    pub right: &'a Expr<'a>,
+}
+
+#[derive(Debug)]
+pub struct LogicalAssign<'a> {
+    pub name: &'a Token<'a>,
+    pub operator: &'a Token<'a>,
+    pub value: &'a Expr<'a>,
+}
+
+#[derive(Debug)]
+pub struct LogicalSet<'a> {
+    pub object: &'a Expr<'a>,
+    pub name: &'a Token<'a>,
     pub operator: &'a Token<'a>,
-    pub right: Box<Expr<'a>>,
+    pub value: &'a Expr<'a>,
+}
+
+#[derive(Debug)]
+pub enum Pattern<'a> {
+    Binding(&'a Token<'a>),
+    Else(&'a Token<'a>),
+    Value(Expr<'a>),
+}
+
+#[derive(Debug)]
+pub struct MatchArm<'a> {
+    pub pattern: Pattern<'a>,
+    pub body: Expr<'a>,
+}
+
+#[derive(Debug)]
+pub struct Match<'a> {
+    pub keyword: &'a Token<'a>,
+    pub subject: &'a Expr<'a>,
+    pub arms: Vec<MatchArm<'a>>,
+}
+
+#[derive(Debug)]
+pub struct Object<'a> {
+    pub brace: &'a Token<'a>,
+    pub names: Vec<&'a Token<'a>>,
+    pub values: Vec<Expr<'a>>,
+}
+
+#[derive(Debug)]
+pub struct Set<'a> {
+    pub object: &'a Expr<'a>,
+    pub name: &'a Token<'a>,
+    pub value: &'a Expr<'a>,
+}
+
+#[derive(Debug)]
+pub struct SetLiteral<'a> {
+    pub brace: &'a Token<'a>,
+    pub elements: Vec<Expr<'a>>,
+}
+
+#[derive(Debug)]
+pub struct Tuple<'a> {
+    pub paren: &'a Token<'a>,
+    pub elements: Vec<Expr<'a>>,
 }
 
 #[derive(Debug)]
 pub struct Unary<'a> {
     pub operator: &'a Token<'a>,
-    pub right: Box<Expr<'a>>,
+    pub right: &'a Expr<'a>,
 }
 
 #[derive(Debug)]
@@ -53,9 +158,22 @@ pub enum Expr<'a> {
     Assign(Assign<'a>),
     Binary(Binary<'a>),
     Call(Call<'a>),
+    Comma(Comma<'a>),
+    ComparisonChain(ComparisonChain<'a>),
+    Get(Get<'a>),
     Grouping(Grouping<'a>),
+    If(If<'a>),
+    Index(Index<'a>),
     Literal(Literal<'a>),
+    Loop(Loop<'a>),
     Logical(Logical<'a>),
+    LogicalAssign(LogicalAssign<'a>),
+    LogicalSet(LogicalSet<'a>),
+    Match(Match<'a>),
+    Object(Object<'a>),
+    Set(Set<'a>),
+    SetLiteral(SetLiteral<'a>),
+    Tuple(Tuple<'a>),
     Unary(Unary<'a>),
     Variable(Variable<'a>),
 }
@@ -0,0 +1,570 @@
+use crate::expr::{Expr, Pattern};
+use crate::parser;
+use crate::scanner::{self, Comment, Token, TokenKind};
+use crate::stmt::{self, Stmt};
+use std::fmt::Write;
+
+// Prints a script back in one layout: two spaces per level of indentation,
+// one statement per line, the bodies of blocks on their own lines and
+// everything else of a statement on the line it starts. Comments are put
+// back before the statement, `else` or `}` that follows them, or at the end
+// of the line written for the code they trailed. If several lines of a
+// statement that's joined onto one line had comments, the last comment stays
+// at the end and the others go before the statement. At most one blank line
+// is kept between statements.
+
+struct Formatter<'a> {
+    tokens: &'a [Token<'a>],
+    comments: Vec<Comment<'a>>,
+    next_comment: usize,
+    out: String,
+    indent: usize,
+    // Where the text of the last `line` starts, for joining a `}` with an
+    // `else` after it.
+    line_start: usize,
+    // The source line of what was written last, to tell where blank lines
+    // were.
+    last_line: i32,
+    // Where the comment the last line ends with starts, if it has one.
+    trailing: Option<usize>,
+    // Whether the statement goes after a `for`, `while` or `if` on its line.
+    inline: bool,
+}
+
+impl<'a> Formatter<'a> {
+    fn line(&mut self, text: &str) {
+        self.line_start = self.out.len();
+        writeln!(self.out, "{:indent$}{}", "", text, indent = self.indent * 2).unwrap();
+        self.trailing = None;
+    }
+
+    // Takes back the text of the last `line`.
+    fn take_line(&mut self) -> String {
+        let text = self.out[self.line_start + self.indent * 2..]
+            .trim_end_matches('\n')
+            .to_string();
+        self.out.truncate(self.line_start);
+        self.trailing = None;
+        text
+    }
+
+    // Runs `write` against an empty output and returns what it wrote,
+    // without the indentation of the first line or the last newline.
+    fn capture(&mut self, write: impl FnOnce(&mut Self)) -> String {
+        let out = std::mem::take(&mut self.out);
+        let (line_start, trailing) = (self.line_start, self.trailing);
+        write(self);
+        let text = std::mem::replace(&mut self.out, out);
+        self.line_start = line_start;
+        self.trailing = trailing;
+        text[self.indent * 2..].trim_end_matches('\n').to_string()
+    }
+
+    fn blank_line(&mut self, line: i32) {
+        if line > self.last_line + 1 && !self.out.is_empty() && !self.out.ends_with("{\n") {
+            self.out.push('\n');
+        }
+    }
+
+    // Writes the comments that come before the token at `offset`, and
+    // returns whether there were any.
+    fn comments_before(&mut self, offset: usize) -> bool {
+        let first = self.next_comment;
+        while let Some(&comment) = self.comments.get(self.next_comment) {
+            if comment.offset >= offset {
+                break;
+            }
+            if comment.trailing && !self.out.is_empty() {
+                if let Some(start) = self.trailing {
+                    self.comment_above_line(start);
+                }
+                self.out.pop();
+                self.trailing = Some(self.out.len());
+                writeln!(self.out, " {}", comment.text).unwrap();
+            } else {
+                self.blank_line(comment.line);
+                self.line(comment.text);
+            }
+            // A comment trailing a line in the middle of a statement comes
+            // before where the statement ends.
+            self.last_line = self.last_line.max(comment.line);
+            self.next_comment += 1;
+        }
+        self.next_comment > first
+    }
+
+    // Moves the comment at the end of the last line, which trailed an
+    // earlier line of a statement written on one line, to a line of its own
+    // before it. The comment that trailed the statement's last line is the
+    // one that stays at the end.
+    fn comment_above_line(&mut self, start: usize) {
+        let comment = self.out[start + 1..].trim_end().to_string();
+        self.out.truncate(start);
+        self.out.push('\n');
+        let line = format!("{:indent$}{}\n", "", comment, indent = self.indent * 2);
+        self.out.insert_str(self.line_start, &line);
+        self.line_start += line.len();
+    }
+
+    fn index(&self, token: &Token) -> usize {
+        self.tokens
+            .binary_search_by_key(&token.offset, |token| token.offset)
+            .unwrap()
+    }
+
+    // The bracket that closes the one at `open`.
+    fn closing(&self, open: usize) -> usize {
+        let mut depth = 0;
+        for (index, token) in self.tokens.iter().enumerate().skip(open) {
+            match token.kind {
+                TokenKind::LeftParen
+                | TokenKind::LeftBrace
+                | TokenKind::LeftBracket
+                | TokenKind::HashBrace
+                | TokenKind::HashParen => depth += 1,
+                TokenKind::RightParen | TokenKind::RightBrace | TokenKind::RightBracket => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return index;
+                    }
+                }
+                _ => (),
+            }
+        }
+        self.tokens.len() - 1
+    }
+
+    // The bracket that opens the one at `close`.
+    fn opening(&self, close: usize) -> usize {
+        let mut depth = 0;
+        for index in (0..=close).rev() {
+            match self.tokens[index].kind {
+                TokenKind::RightParen | TokenKind::RightBrace | TokenKind::RightBracket => {
+                    depth += 1
+                }
+                TokenKind::LeftParen
+                | TokenKind::LeftBrace
+                | TokenKind::LeftBracket
+                | TokenKind::HashBrace
+                | TokenKind::HashParen => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return index;
+                    }
+                }
+                _ => (),
+            }
+        }
+        0
+    }
+
+    // The brace after the subject of a match.
+    fn match_brace(&self, keyword: &Token) -> usize {
+        let mut depth = 0;
+        for (index, token) in self.tokens.iter().enumerate().skip(self.index(keyword) + 1) {
+            match token.kind {
+                TokenKind::LeftBrace if depth == 0 => return index,
+                TokenKind::LeftParen
+                | TokenKind::LeftBrace
+                | TokenKind::LeftBracket
+                | TokenKind::HashBrace
+                | TokenKind::HashParen => depth += 1,
+                TokenKind::RightParen | TokenKind::RightBrace | TokenKind::RightBracket => {
+                    depth -= 1
+                }
+                _ => (),
+            }
+        }
+        self.tokens.len() - 1
+    }
+
+    // The last token the tree keeps of an expression. A grouping's `)` isn't
+    // kept, so it can come after.
+    fn last_of(&self, expr: &Expr) -> usize {
+        match expr {
+            Expr::Assign(expr) => self.last_of(expr.value),
+            Expr::Binary(expr) => self.last_of(expr.right),
+            Expr::Call(expr) => self.index(expr.paren),
+            Expr::Comma(expr) => self.last_of(expr.right),
+            Expr::ComparisonChain(expr) => self.last_of(expr.operands.last().unwrap()),
+            Expr::Get(expr) => self.index(expr.name),
+            Expr::Grouping(expr) => self.last_of(expr.expr),
+            Expr::If(expr) => self.last_of(expr.else_branch),
+            Expr::Index(expr) => self.closing(self.index(expr.bracket)),
+            Expr::Literal(expr) => self.index(expr.value),
+            Expr::Loop(expr) => self.last_of_statement(expr.body),
+            Expr::Logical(expr) => self.last_of(expr.right),
+            Expr::LogicalAssign(expr) => self.last_of(expr.value),
+            Expr::LogicalSet(expr) => self.last_of(expr.value),
+            Expr::Match(expr) => self.closing(self.match_brace(expr.keyword)),
+            Expr::Object(expr) => self.closing(self.index(expr.brace)),
+            Expr::Set(expr) => self.last_of(expr.value),
+            Expr::SetLiteral(expr) => self.closing(self.index(expr.brace)),
+            Expr::Tuple(expr) => self.closing(self.index(expr.paren)),
+            Expr::Unary(expr) => self.last_of(expr.right),
+            Expr::Variable(expr) => self.index(expr.name),
+        }
+    }
+
+    fn last_of_statement(&self, statement: &Stmt) -> usize {
+        match statement {
+            Stmt::Block(stmt) => self.index(stmt.brace),
+            Stmt::Break(stmt) => match (&stmt.value, stmt.label) {
+                (Some(value), _) => self.last_of(value),
+                (None, label) => self.index(label.unwrap_or(stmt.keyword)),
+            },
+            Stmt::Continue(stmt) => self.index(stmt.label.unwrap_or(stmt.keyword)),
+            Stmt::Expression(stmt) => self.last_of(&stmt.expression),
+            Stmt::For(stmt) => self.last_of_statement(stmt.body),
+            Stmt::Function(stmt) => self.index(stmt.brace),
+            Stmt::If(stmt) => self.last_of_statement(stmt.else_branch.unwrap_or(stmt.then_branch)),
+            Stmt::Loop(stmt) => self.last_of_statement(stmt.body),
+            Stmt::Print(stmt) => self.last_of(&stmt.expression),
+            Stmt::Return(stmt) => match &stmt.value {
+                Some(value) => self.last_of(value),
+                None => self.index(stmt.keyword),
+            },
+            Stmt::Var(stmt) => {
+                let declarator = stmt.declarators.last().unwrap();
+                match &declarator.initializer {
+                    Some(value) => self.last_of(value),
+                    None => self.index(declarator.name),
+                }
+            }
+            Stmt::While(stmt) => self.last_of_statement(stmt.body),
+        }
+    }
+
+    // Writes the statements that the tokens from `start` up to `end` make.
+    // Each one starts right after the one before it ends.
+    fn statements(&mut self, statements: &[Stmt], mut start: usize, end: usize) {
+        for statement in statements {
+            let mut last = self.last_of_statement(statement);
+            while last + 1 < end
+                && matches!(
+                    self.tokens[last + 1].kind,
+                    TokenKind::Semicolon | TokenKind::RightParen
+                )
+            {
+                last += 1;
+            }
+
+            self.comments_before(self.tokens[start].offset);
+            self.blank_line(self.tokens[start].line);
+            self.statement(statement);
+            self.last_line = self.tokens[last].line;
+            start = last + 1;
+        }
+    }
+
+    // `brace` is the one that ends the block.
+    fn braced(&mut self, head: String, brace: &Token, statements: &[Stmt]) {
+        let close = self.index(brace);
+        let open = self.opening(close);
+        let head = if head.is_empty() { head } else { head + " " };
+        let has_comments = matches!(
+            self.comments.get(self.next_comment),
+            Some(comment) if comment.offset < brace.offset
+        );
+        if statements.is_empty() && !has_comments {
+            self.line(&(head + "{}"));
+            return;
+        }
+
+        self.line(&(head + "{"));
+        self.last_line = self.tokens[open].line;
+        self.indent += 1;
+        let inline = std::mem::replace(&mut self.inline, false);
+        self.statements(statements, open + 1, close);
+        self.inline = inline;
+        self.comments_before(brace.offset);
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    // A block goes on the lines after `head`, anything else on its line.
+    fn body(&mut self, head: String, body: &Stmt) {
+        match body {
+            Stmt::Block(block) => self.braced(head, block.brace, &block.statements),
+            _ => {
+                let inline = std::mem::replace(&mut self.inline, true);
+                let body = self.capture(|formatter| formatter.statement(body));
+                self.inline = inline;
+                self.line(&format!("{} {}", head, body));
+            }
+        }
+    }
+
+    fn statement(&mut self, statement: &Stmt) {
+        match statement {
+            Stmt::Block(stmt) => self.braced(String::new(), stmt.brace, &stmt.statements),
+            Stmt::Break(stmt) => {
+                let mut text = String::from("break");
+                if let Some(label) = stmt.label {
+                    write!(text, " {}", label.lexeme).unwrap();
+                }
+                if let Some(value) = &stmt.value {
+                    write!(text, " {}", self.expression(value)).unwrap();
+                }
+                self.line(&(text + ";"));
+            }
+            Stmt::Continue(stmt) => match stmt.label {
+                Some(label) => self.line(&format!("continue {};", label.lexeme)),
+                None => self.line("continue;"),
+            },
+            Stmt::Expression(stmt) => {
+                let text = self.expression(&stmt.expression);
+                self.line(&(text + ";"));
+            }
+            Stmt::For(stmt) => {
+                let mut head = format!("{}for (", label(stmt.label));
+                match stmt.initializer {
+                    Some(initializer) => {
+                        let initializer =
+                            self.capture(|formatter| formatter.statement(initializer));
+                        head.push_str(&initializer);
+                    }
+                    None => head.push(';'),
+                }
+                if let Some(condition) = &stmt.condition {
+                    write!(head, " {}", self.expression(condition)).unwrap();
+                }
+                head.push(';');
+                if let Some(increment) = &stmt.increment {
+                    write!(head, " {}", self.expression(increment)).unwrap();
+                }
+                self.body(head + ")", stmt.body);
+            }
+            Stmt::Function(stmt) => {
+                let params: Vec<&str> = stmt.params.iter().map(|param| param.lexeme).collect();
+                let head = format!("fun {}({})", stmt.name.lexeme, params.join(", "));
+                self.braced(head, stmt.brace, &stmt.body);
+            }
+            Stmt::If(stmt) => {
+                let head = format!("if ({})", self.expression(&stmt.condition));
+                self.body(head, stmt.then_branch);
+                if let Some(else_branch) = stmt.else_branch {
+                    let keyword = self.tokens[self.last_of_statement(stmt.then_branch)..]
+                        .iter()
+                        .find(|token| token.kind == TokenKind::Else)
+                        .unwrap();
+                    let commented = self.comments_before(keyword.offset);
+                    // An `else` goes on the next line, unless it can follow
+                    // a `}` or the whole `if` is on one line and no comment
+                    // ends that line.
+                    let head = match stmt.then_branch {
+                        _ if commented => String::from("else"),
+                        Stmt::Block(_) => self.take_line() + " else",
+                        _ if self.inline => self.take_line() + " else",
+                        _ => String::from("else"),
+                    };
+                    match else_branch {
+                        // The rest of an `else if` chain keeps the layout.
+                        Stmt::If(_) if !self.inline => {
+                            let rest = self.capture(|formatter| formatter.statement(else_branch));
+                            self.line(&format!("{} {}", head, rest));
+                        }
+                        _ => self.body(head, else_branch),
+                    }
+                }
+            }
+            Stmt::Loop(stmt) => self.body(format!("{}loop", label(stmt.label)), stmt.body),
+            Stmt::Print(stmt) => {
+                let text = format!("print {};", self.expression(&stmt.expression));
+                self.line(&text);
+            }
+            Stmt::Return(stmt) => match &stmt.value {
+                Some(value) => {
+                    let text = format!("return {};", self.expression(value));
+                    self.line(&text);
+                }
+                None => self.line("return;"),
+            },
+            Stmt::Var(stmt) => {
+                let declarators: Vec<String> = stmt
+                    .declarators
+                    .iter()
+                    .map(|declarator| self.declarator(declarator))
+                    .collect();
+                self.line(&format!("var {};", declarators.join(", ")));
+            }
+            Stmt::While(stmt) => {
+                let head = format!(
+                    "{}while ({})",
+                    label(stmt.label),
+                    self.expression(&stmt.condition)
+                );
+                self.body(head, stmt.body);
+            }
+        }
+    }
+
+    fn declarator(&mut self, declarator: &stmt::Declarator) -> String {
+        match &declarator.initializer {
+            Some(value) => format!("{} = {}", declarator.name.lexeme, self.expression(value)),
+            None => declarator.name.lexeme.to_string(),
+        }
+    }
+
+    fn list(&mut self, exprs: &[Expr]) -> String {
+        let exprs: Vec<String> = exprs.iter().map(|expr| self.expression(expr)).collect();
+        exprs.join(", ")
+    }
+
+    // Each arm of a match goes on its own line, with the comments between
+    // them.
+    fn match_arms(&mut self, expr: &crate::expr::Match) -> String {
+        let close = self.closing(self.match_brace(expr.keyword));
+        self.capture(|formatter| {
+            let subject = formatter.expression(expr.subject);
+            formatter.line(&format!("match {} {{", subject));
+            formatter.indent += 1;
+            for arm in &expr.arms {
+                let (pattern, offset) = match &arm.pattern {
+                    Pattern::Binding(name) | Pattern::Else(name) => {
+                        (name.lexeme.to_string(), name.offset)
+                    }
+                    Pattern::Value(value) => {
+                        let offset = match value {
+                            Expr::Unary(unary) => unary.operator.offset,
+                            _ => formatter.tokens[formatter.last_of(value)].offset,
+                        };
+                        (formatter.expression(value), offset)
+                    }
+                };
+                formatter.comments_before(offset);
+                let body = formatter.expression(&arm.body);
+                formatter.line(&format!("{} => {},", pattern, body));
+                formatter.last_line = formatter.tokens[formatter.last_of(&arm.body)].line;
+            }
+            formatter.comments_before(formatter.tokens[close].offset);
+            formatter.indent -= 1;
+            formatter.line("}");
+        })
+    }
+
+    fn expression(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Assign(expr) => format!("{} = {}", expr.name.lexeme, self.expression(expr.value)),
+            Expr::Binary(expr) => format!(
+                "{} {} {}",
+                self.expression(expr.left),
+                expr.operator.lexeme,
+                self.expression(expr.right)
+            ),
+            Expr::Call(expr) => {
+                // Keyword arguments are the last ones, written `name: value`.
+                let positional = expr.args.len() - expr.keywords.len();
+                let mut args = vec![self.list(&expr.args[..positional])];
+                for (name, arg) in expr.keywords.iter().zip(&expr.args[positional..]) {
+                    args.push(format!("{}: {}", name.lexeme, self.expression(arg)));
+                }
+                args.retain(|arg| !arg.is_empty());
+                format!("{}({})", self.expression(expr.callee), args.join(", "))
+            }
+            Expr::Comma(expr) => format!(
+                "{}, {}",
+                self.expression(expr.left),
+                self.expression(expr.right)
+            ),
+            Expr::ComparisonChain(expr) => {
+                let mut text = self.expression(&expr.operands[0]);
+                for (operator, operand) in expr.operators.iter().zip(&expr.operands[1..]) {
+                    write!(text, " {} {}", operator.lexeme, self.expression(operand)).unwrap();
+                }
+                text
+            }
+            Expr::Get(expr) => format!("{}.{}", self.expression(expr.object), expr.name.lexeme),
+            Expr::Grouping(expr) => format!("({})", self.expression(expr.expr)),
+            Expr::If(expr) => format!(
+                "if ({}) {} else {}",
+                self.expression(expr.condition),
+                self.expression(expr.then_branch),
+                self.expression(expr.else_branch)
+            ),
+            Expr::Index(expr) => format!(
+                "{}[{}]",
+                self.expression(expr.object),
+                self.expression(expr.index)
+            ),
+            Expr::Literal(expr) => expr.value.lexeme.to_string(),
+            Expr::Loop(expr) => self.capture(|formatter| formatter.body("loop".into(), expr.body)),
+            Expr::Logical(expr) => format!(
+                "{} {} {}",
+                self.expression(expr.left),
+                expr.operator.lexeme,
+                self.expression(expr.right)
+            ),
+            Expr::LogicalAssign(expr) => format!(
+                "{} {} {}",
+                expr.name.lexeme,
+                expr.operator.lexeme,
+                self.expression(expr.value)
+            ),
+            Expr::LogicalSet(expr) => format!(
+                "{}.{} {} {}",
+                self.expression(expr.object),
+                expr.name.lexeme,
+                expr.operator.lexeme,
+                self.expression(expr.value)
+            ),
+            Expr::Match(expr) => self.match_arms(expr),
+            Expr::Object(expr) if expr.names.is_empty() => String::from("{}"),
+            Expr::Object(expr) => {
+                let fields: Vec<String> = expr
+                    .names
+                    .iter()
+                    .zip(&expr.values)
+                    .map(|(name, value)| format!("{}: {}", name.lexeme, self.expression(value)))
+                    .collect();
+                format!("{{ {} }}", fields.join(", "))
+            }
+            Expr::Set(expr) => format!(
+                "{}.{} = {}",
+                self.expression(expr.object),
+                expr.name.lexeme,
+                self.expression(expr.value)
+            ),
+            Expr::SetLiteral(expr) => format!("#{{{}}}", self.list(&expr.elements)),
+            Expr::Tuple(expr) => format!("#({})", self.list(&expr.elements)),
+            Expr::Unary(expr) => match expr.operator.kind {
+                TokenKind::Type => format!("type {}", self.expression(expr.right)),
+                _ => format!("{}{}", expr.operator.lexeme, self.expression(expr.right)),
+            },
+            Expr::Variable(expr) => expr.name.lexeme.to_string(),
+        }
+    }
+}
+
+fn label(label: Option<&Token>) -> String {
+    match label {
+        Some(label) => format!("{}: ", label.lexeme),
+        None => String::new(),
+    }
+}
+
+// Returns `None` if the script doesn't parse, after reporting why.
+pub fn format(source: &str) -> Option<String> {
+    let (tokens, comments) = scanner::scan_with_comments(source);
+    let nodes = parser::Nodes::default();
+    let statements = if tokens.is_empty() {
+        Vec::new()
+    } else {
+        parser::parse_tokens(source, &tokens, &nodes, true)?
+    };
+
+    let mut formatter = Formatter {
+        tokens: &tokens,
+        comments,
+        next_comment: 0,
+        out: String::new(),
+        indent: 0,
+        line_start: 0,
+        last_line: 0,
+        trailing: None,
+        inline: false,
+    };
+    formatter.statements(&statements, 0, tokens.len());
+    formatter.comments_before(usize::MAX);
+    Some(formatter.out)
+}
@@ -0,0 +1,260 @@
+use crate::lock::Lock;
+use crate::string;
+use crate::value::*;
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+
+// Values are reference counted, which frees everything except cycles. Every
+// cycle has to pass through something mutable, and instances and upvalues are
+// the only mutable objects that can hold arbitrary values, so the heap keeps
+// a weak reference to each of them. A collection marks everything reachable
+// from the VM's roots and empties the tracked objects it didn't reach, which
+// breaks their cycles and lets reference counting free them.
+//
+// An incremental collection spreads the marking out, following a few gray
+// values each time the VM allocates. Objects the marker has already scanned
+// can be written to in the meantime, so the VM marks every value it stores
+// in a global, a field or an upvalue while marking is under way. Once
+// nothing's left gray, the roots that change without a barrier, like the
+// stack, are marked again along with the objects made since marking began,
+// and only then is the heap swept. The marker holds on to what it's seen
+// until then, so a weak reference can outlive its object by a collection.
+
+pub const INITIAL_THRESHOLD: usize = 1024;
+
+// How many gray values an incremental collection follows at a time.
+pub const MARK_BUDGET: usize = 1000;
+
+// What an `Arc` holding a `T` takes up, counts included.
+pub fn arc_size<T>() -> usize {
+    2 * std::mem::size_of::<usize>() + std::mem::size_of::<T>()
+}
+
+enum Object {
+    Instance(Weak<Lock<Instance>>),
+    Upvalue(Weak<Lock<Upvalue>>),
+}
+
+pub struct Heap {
+    objects: Vec<Object>,
+    // Collect once this many objects are tracked.
+    next_gc: usize,
+    // The least `next_gc` can be.
+    threshold: usize,
+    // Bytes allocated for objects, counted where the VM makes them. It's an
+    // estimate, and leaves out growth like an instance gaining fields.
+    allocated: usize,
+}
+
+impl Heap {
+    pub fn new(threshold: usize) -> Heap {
+        Heap {
+            objects: Vec::new(),
+            next_gc: threshold,
+            threshold,
+            allocated: 0,
+        }
+    }
+
+    pub fn track_instance(&mut self, instance: &Arc<Lock<Instance>>) {
+        let fields = instance.borrow().fields.capacity();
+        self.count_allocation(
+            arc_size::<Lock<Instance>>() + fields * std::mem::size_of::<(string::Handle, Value)>(),
+        );
+        self.objects
+            .push(Object::Instance(Arc::downgrade(instance)));
+    }
+
+    pub fn track_upvalue(&mut self, upvalue: &Arc<Lock<Upvalue>>) {
+        self.count_allocation(arc_size::<Lock<Upvalue>>());
+        self.objects.push(Object::Upvalue(Arc::downgrade(upvalue)));
+    }
+
+    pub fn count_allocation(&mut self, bytes: usize) {
+        self.allocated += bytes;
+    }
+
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    pub fn should_collect(&self) -> bool {
+        cfg!(feature = "stress-gc") || self.objects.len() >= self.next_gc
+    }
+
+    pub fn tracked(&self) -> usize {
+        self.objects.len()
+    }
+
+    // Marks the objects tracked since `marker` started, which it can't have
+    // seen if they were made while marking was spread out.
+    pub fn mark_new(&self, marker: &mut Marker) {
+        for object in &self.objects[marker.tracked..] {
+            match object {
+                Object::Instance(weak) => {
+                    if let Some(instance) = weak.upgrade() {
+                        marker.mark_value(&Value::Instance(instance));
+                    }
+                }
+                Object::Upvalue(weak) => {
+                    if let Some(upvalue) = weak.upgrade() {
+                        marker.mark_upvalue(&upvalue);
+                    }
+                }
+            }
+        }
+    }
+
+    // Empties every object tracked when `marker` started that it didn't
+    // reach, and forgets the ones that were already freed. Returns how many
+    // objects were emptied.
+    pub fn sweep(&mut self, marker: &Marker) -> usize {
+        let mut garbage = Vec::new();
+        let mut objects = std::mem::take(&mut self.objects);
+        let newer = objects.split_off(marker.tracked);
+        objects.retain(|object| match object {
+            Object::Instance(weak) => match weak.upgrade() {
+                Some(instance) if !marker.is_marked(&instance) => {
+                    let mut instance = instance.borrow_mut();
+                    garbage.extend(instance.fields.drain().map(|(_, value)| value));
+                    false
+                }
+                Some(_) => true,
+                None => false,
+            },
+            Object::Upvalue(weak) => match weak.upgrade() {
+                // An open upvalue points into a fiber's stack, and the fiber
+                // closes it when it's dropped.
+                Some(upvalue)
+                    if !marker.is_marked(&upvalue) && upvalue.borrow().closed.is_some() =>
+                {
+                    garbage.extend(upvalue.borrow_mut().closed.take());
+                    false
+                }
+                Some(_) => true,
+                None => false,
+            },
+        });
+        objects.extend(newer);
+        self.objects = objects;
+        let emptied = garbage.len();
+        // Dropping the values frees whatever only the emptied objects held.
+        drop(garbage);
+        self.next_gc = self.threshold.max(self.objects.len() * 2);
+        emptied
+    }
+}
+
+pub struct Marker {
+    // Holds on to each marked object, so that while marking is spread out a
+    // freed object's address can't be reused by a new one that would then
+    // look marked.
+    marked: HashMap<usize, Arc<dyn Any + Send + Sync>>,
+    // Values that are reachable but whose references haven't been followed.
+    gray: Vec<Value>,
+    // How many objects the heap tracked when marking began.
+    tracked: usize,
+}
+
+impl Marker {
+    pub fn new(heap: &Heap) -> Marker {
+        Marker {
+            marked: HashMap::new(),
+            gray: Vec::new(),
+            tracked: heap.tracked(),
+        }
+    }
+
+    pub fn mark_value(&mut self, value: &Value) {
+        self.gray.push(value.clone());
+    }
+
+    pub fn mark_upvalue(&mut self, upvalue: &Arc<Lock<Upvalue>>) {
+        if self.first_visit(upvalue) {
+            let upvalue = upvalue.borrow();
+            match &upvalue.closed {
+                Some(value) => self.gray.push(value.clone()),
+                // An open upvalue's value is on a stack, which is marked along
+                // with its fiber.
+                None => {
+                    if let Some(fiber) = upvalue.fiber.as_ref().and_then(Weak::upgrade) {
+                        self.gray.push(Value::Fiber(fiber));
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn mark_open_upvalues(&mut self, open_upvalues: &Option<Arc<Lock<Upvalue>>>) {
+        let mut next = open_upvalues.clone();
+        while let Some(upvalue) = next {
+            self.mark_upvalue(&upvalue);
+            next = upvalue.borrow().next.clone();
+        }
+    }
+
+    // Follows references out of the gray values until everything reachable
+    // has been marked. Uses a worklist rather than recursion so long chains
+    // of objects can't overflow the native stack.
+    pub fn trace(&mut self) {
+        while self.step() {}
+    }
+
+    // Follows the references out of up to `budget` gray values. Returns
+    // whether any are left.
+    pub fn trace_some(&mut self, budget: usize) -> bool {
+        for _ in 0..budget {
+            if !self.step() {
+                return false;
+            }
+        }
+        !self.gray.is_empty()
+    }
+
+    fn step(&mut self) -> bool {
+        let value = match self.gray.pop() {
+            Some(value) => value,
+            None => return false,
+        };
+        match &value {
+            Value::Instance(instance) if self.first_visit(instance) => {
+                self.gray.extend(instance.borrow().fields.values().cloned());
+            }
+            Value::Closure(closure) if self.first_visit(closure) => {
+                for upvalue in closure.upvalues.iter() {
+                    self.mark_upvalue(upvalue);
+                }
+            }
+            Value::Fiber(fiber) if self.first_visit(fiber) => fiber.borrow().mark_roots(self),
+            Value::Builtin(Builtin::FiberResume(fiber)) => {
+                self.gray.push(Value::Fiber(Arc::clone(fiber)));
+            }
+            Value::Tuple(elements) => self.gray.extend(elements.iter().cloned()),
+            _ => (),
+        }
+        true
+    }
+
+    fn first_visit<T: Any + Send + Sync>(&mut self, object: &Arc<T>) -> bool {
+        let key = Arc::as_ptr(object) as usize;
+        if self.marked.contains_key(&key) {
+            return false;
+        }
+        self.marked
+            .insert(key, Arc::clone(object) as Arc<dyn Any + Send + Sync>);
+        true
+    }
+
+    // How many of the objects marked so far are `T`s.
+    pub fn count<T: Any>(&self) -> usize {
+        self.marked
+            .values()
+            .filter(|object| object.is::<T>())
+            .count()
+    }
+
+    fn is_marked<T>(&self, object: &Arc<T>) -> bool {
+        self.marked.contains_key(&(Arc::as_ptr(object) as usize))
+    }
+}
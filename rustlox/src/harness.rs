@@ -0,0 +1,244 @@
+use crate::compiler::CompileOptions;
+use crate::output;
+use crate::value::Value;
+use crate::vm::{InterpretError, VM};
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+// What `rustlox test <path>` checks. Each script runs in a VM of its own, with
+// its output captured, and is checked against the comments it contains, the
+// same way test/main.test.ts does:
+// - `// expect runtime error: <message>` wants the script to stop with that
+//   error.
+// - `// Error at '<lexeme>': <message>` wants that compile error first, on
+//   the line of the comment.
+// - `// [line <n>:<column>] Error ...` lines want exactly those compile
+//   errors. Lines for one implementation, like `// [c line <n>] ...`, follow
+//   how that implementation recovers from an earlier error, so they're left
+//   out.
+// - Otherwise, `// expect: <line>` comments are what the script prints.
+// - `// Warning at '<lexeme>': <message>` wants that warning, on the line of
+//   the comment, and turns warnings on for the script.
+
+enum Expectation {
+    // The status and the first line of stderr.
+    Error(i32, String),
+    // The line and the first line of stderr, after its `[line L:C] `.
+    CompileError(usize, String),
+    CompileErrors(Vec<String>),
+    Output(Vec<String>),
+}
+
+// The rest of each line that has `marker` in it.
+fn comments<'a>(source: &'a str, marker: &str) -> Vec<&'a str> {
+    source
+        .lines()
+        .filter_map(|line| Some(&line[line.find(marker)? + marker.len()..]))
+        .filter(|rest| !rest.is_empty())
+        .collect()
+}
+
+fn expectation(source: &str) -> Expectation {
+    if let Some(message) = comments(source, "// expect runtime error: ").first() {
+        return Expectation::Error(70, message.to_string());
+    }
+    let compile_error = source.lines().enumerate().find_map(|(i, line)| {
+        let marker = "// Error at '";
+        Some((i + 1, &line[line.find(marker)? + 3..]))
+    });
+    if let Some((line, error)) = compile_error {
+        return Expectation::CompileError(line, error.to_string());
+    }
+    let errors = comments(source, "// [");
+    if !errors.is_empty() {
+        return Expectation::CompileErrors(
+            errors
+                .iter()
+                .filter_map(|error| expected_error(error))
+                .collect(),
+        );
+    }
+    Expectation::Output(
+        comments(source, "// expect: ")
+            .iter()
+            .map(|line| line.to_string())
+            .collect(),
+    )
+}
+
+// The warnings the script's comments want, in order, without columns.
+fn expected_warnings(source: &str) -> Vec<String> {
+    source
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let marker = "// Warning at '";
+            Some(format!(
+                "[line {}] {}",
+                i + 1,
+                &line[line.find(marker)? + 3..]
+            ))
+        })
+        .collect()
+}
+
+// The rest of a `// [line L:C] ...` comment as `[line L:C] ...`, if it's
+// for every implementation.
+fn expected_error(comment: &str) -> Option<String> {
+    comment
+        .strip_prefix("line ")
+        .map(|rest| format!("[line {}", rest))
+}
+
+// The status `rustlox <path>` would exit with.
+fn status(result: &Result<Value, InterpretError>) -> i32 {
+    match result {
+        Err(InterpretError::CompileError) => 65,
+        Err(InterpretError::RuntimeError) => 70,
+        Err(InterpretError::InternalError(_)) => 1,
+        Ok(Value::Number(status)) => *status as i32,
+        Ok(Value::Int(status)) => *status as i32,
+        Ok(_) => 0,
+    }
+}
+
+// `[line L:C] ...` as `[line L] ...`.
+fn without_column(line: &str) -> String {
+    let position = line
+        .strip_prefix("[line ")
+        .and_then(|rest| rest.split_once("] "))
+        .and_then(|(position, rest)| Some((position.split_once(':')?.0, rest)));
+    match position {
+        Some((number, rest)) => format!("[line {}] {}", number, rest),
+        None => line.to_string(),
+    }
+}
+
+// Runs one script, and describes how it failed if it did.
+fn run_test(path: &Path, options: &CompileOptions, vm: &impl Fn() -> VM) -> Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|error| error.to_string())?;
+    let warnings = expected_warnings(&source);
+    let options = CompileOptions {
+        warnings: options.warnings || !warnings.is_empty(),
+        ..options.clone()
+    };
+    let (result, captured) = output::capture(|| {
+        panic::catch_unwind(AssertUnwindSafe(|| vm().interpret(&source, &options)))
+    });
+    let result = result.map_err(|_| String::from("The VM panicked."))?;
+    let status = status(&result);
+    let stdout: Vec<&str> = captured.stdout.trim_end().lines().collect();
+    let stderr: Vec<&str> = captured.stderr.trim_end().lines().collect();
+
+    let expectation = expectation(&source);
+    let (expected_status, expected, actual): (i32, Vec<String>, Vec<String>) = match &expectation {
+        Expectation::Error(expected_status, message) => (
+            *expected_status,
+            vec![message.clone()],
+            stderr
+                .first()
+                .map(|line| line.to_string())
+                .into_iter()
+                .collect(),
+        ),
+        // The column isn't in the comment, so it isn't compared.
+        Expectation::CompileError(line, message) => (
+            65,
+            vec![format!("[line {}] {}", line, message)],
+            stderr
+                .first()
+                .map(|first| without_column(first))
+                .into_iter()
+                .collect(),
+        ),
+        // Leaves out the source lines shown under each error.
+        Expectation::CompileErrors(errors) => (
+            65,
+            errors.clone(),
+            stderr
+                .iter()
+                .filter(|line| line.starts_with('['))
+                .map(|line| line.to_string())
+                .collect(),
+        ),
+        Expectation::Output(lines) => (
+            0,
+            lines.clone(),
+            stdout.iter().map(|line| line.to_string()).collect(),
+        ),
+    };
+    if status != expected_status {
+        return Err(format!(
+            "Expected status {} but got {}.\n{}",
+            expected_status,
+            status,
+            stderr.join("\n")
+        ));
+    }
+    if expected != actual {
+        return Err(format!(
+            "Expected:\n{}\nGot:\n{}",
+            expected.join("\n"),
+            actual.join("\n")
+        ));
+    }
+    if !warnings.is_empty() {
+        let actual: Vec<String> = stderr
+            .iter()
+            .filter(|line| line.starts_with('[') && line.contains("] Warning at '"))
+            .map(|line| without_column(line))
+            .collect();
+        if warnings != actual {
+            return Err(format!(
+                "Expected warnings:\n{}\nGot:\n{}",
+                warnings.join("\n"),
+                actual.join("\n")
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Every `.lox` file under `path`, in order, or `path` itself if it's a file.
+fn test_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(path)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            test_files(&entry, files)?;
+        } else if entry
+            .extension()
+            .is_some_and(|extension| extension == "lox")
+        {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
+
+// Prints each failure and a summary. True if every script passed. Each
+// script gets a VM of its own from `vm`.
+pub fn run(path: &Path, options: &CompileOptions, vm: impl Fn() -> VM) -> io::Result<bool> {
+    let mut files = Vec::new();
+    test_files(path, &mut files)?;
+    let mut failed = 0;
+    for file in &files {
+        if let Err(failure) = run_test(file, options, &vm) {
+            failed += 1;
+            println!("FAIL {}", file.display());
+            for line in failure.lines() {
+                println!("    {}", line);
+            }
+        }
+    }
+    println!("{} passed, {} failed", files.len() - failed, failed);
+    Ok(failed == 0)
+}
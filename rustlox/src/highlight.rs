@@ -0,0 +1,141 @@
+use crate::scanner::{self, TokenKind};
+use std::fmt::Write;
+
+// Prints a script with its tokens colored, for reading in a terminal or
+// putting in documentation. Everything between tokens, like whitespace and
+// what the scanner couldn't make sense of, is copied as it is.
+
+enum Piece<'a> {
+    Token(TokenKind, &'a str),
+    Comment(&'a str),
+    Text(&'a str),
+}
+
+// The script cut into tokens, comments and the text between them.
+fn pieces(source: &str) -> Vec<Piece<'_>> {
+    let (tokens, comments) = scanner::scan_with_comments(source);
+    let mut marks: Vec<(usize, usize, Option<TokenKind>)> = tokens
+        .iter()
+        .map(|token| (token.span().start, token.span().end, Some(token.kind)))
+        .chain(
+            comments
+                .iter()
+                .map(|comment| (comment.offset, comment.offset + comment.text.len(), None)),
+        )
+        .collect();
+    marks.sort_by_key(|&(start, _, _)| start);
+
+    let mut pieces = Vec::new();
+    let mut end = 0;
+    for (start, stop, kind) in marks {
+        if start < end || start == stop {
+            continue;
+        }
+        if start > end {
+            pieces.push(Piece::Text(&source[end..start]));
+        }
+        pieces.push(match kind {
+            Some(kind) => Piece::Token(kind, &source[start..stop]),
+            None => Piece::Comment(&source[start..stop]),
+        });
+        end = stop;
+    }
+    if end < source.len() {
+        pieces.push(Piece::Text(&source[end..]));
+    }
+    pieces
+}
+
+fn is_keyword(kind: TokenKind) -> bool {
+    matches!(
+        kind,
+        TokenKind::And
+            | TokenKind::Break
+            | TokenKind::Class
+            | TokenKind::Continue
+            | TokenKind::Else
+            | TokenKind::For
+            | TokenKind::Fun
+            | TokenKind::If
+            | TokenKind::Is
+            | TokenKind::Loop
+            | TokenKind::Match
+            | TokenKind::Or
+            | TokenKind::Return
+            | TokenKind::Super
+            | TokenKind::This
+            | TokenKind::Type
+            | TokenKind::Var
+            | TokenKind::While
+    )
+}
+
+// The escape sequence that starts the color of a kind of token, if it has
+// one.
+fn color(kind: TokenKind) -> Option<&'static str> {
+    match kind {
+        TokenKind::String => Some("\x1b[32m"),
+        TokenKind::Number | TokenKind::True | TokenKind::False | TokenKind::Nil => Some("\x1b[33m"),
+        TokenKind::Symbol => Some("\x1b[36m"),
+        TokenKind::AndEqual | TokenKind::OrEqual => Some("\x1b[35m"),
+        kind if is_keyword(kind) => Some("\x1b[35m"),
+        _ => None,
+    }
+}
+
+pub fn ansi(source: &str) -> String {
+    let mut out = String::new();
+    for piece in pieces(source) {
+        match piece {
+            Piece::Token(kind, text) => match color(kind) {
+                Some(color) => write!(out, "{}{}\x1b[0m", color, text).unwrap(),
+                None => out.push_str(text),
+            },
+            Piece::Comment(text) => write!(out, "\x1b[90m{}\x1b[0m", text).unwrap(),
+            Piece::Text(text) => out.push_str(text),
+        }
+    }
+    out
+}
+
+// `LeftParen` becomes `left-paren`.
+fn class(kind: TokenKind) -> String {
+    let mut class = String::new();
+    for c in format!("{:?}", kind).chars() {
+        if c.is_uppercase() && !class.is_empty() {
+            class.push('-');
+        }
+        class.push(c.to_ascii_lowercase());
+    }
+    class
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Each token is a `span` with its kind as its class, and keywords also
+// have the `keyword` class. Comments have the `comment` class.
+pub fn html(source: &str) -> String {
+    let mut out = String::from("<pre class=\"lox\"><code>");
+    for piece in pieces(source) {
+        match piece {
+            Piece::Token(kind, text) => {
+                let mut classes = class(kind);
+                if is_keyword(kind) {
+                    classes.push_str(" keyword");
+                }
+                write!(out, "<span class=\"{}\">{}</span>", classes, escape(text)).unwrap();
+            }
+            Piece::Comment(text) => {
+                write!(out, "<span class=\"comment\">{}</span>", escape(text)).unwrap()
+            }
+            Piece::Text(text) => out.push_str(&escape(text)),
+        }
+    }
+    out.push_str("</code></pre>\n");
+    out
+}
@@ -0,0 +1,32 @@
+mod analysis;
+mod arena;
+mod bigint;
+mod chunk;
+pub mod compiler;
+mod coverage;
+mod expr;
+pub mod formatter;
+mod gc;
+pub mod harness;
+pub mod highlight;
+pub mod lint;
+mod lock;
+pub mod lsp;
+mod names;
+mod native;
+mod output;
+mod parser;
+mod passes;
+mod prelude;
+mod printer;
+mod profile;
+mod resolver;
+mod sampler;
+pub mod scanner;
+mod set;
+mod snippet;
+mod stmt;
+mod string;
+mod tracer;
+pub mod value;
+pub mod vm;
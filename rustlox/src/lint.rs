@@ -0,0 +1,449 @@
+use crate::expr::{Expr, Pattern};
+use crate::output;
+use crate::parser;
+use crate::scanner::{self, Comment, Token, TokenKind};
+use crate::snippet;
+use crate::stmt::Stmt;
+use std::collections::HashMap;
+
+// Checks a script for code that runs but is probably a mistake. Each rule
+// can be turned off or made an error by a config file, and a comment
+// starting with `// lint-allow` turns rules off for one line: the line it
+// ends, or the line after it when it's on a line of its own. It can name
+// the rules, as in `// lint-allow: empty-block, self-assignment`.
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Rule {
+    ShadowedVariable,
+    EmptyBlock,
+    ConstantCondition,
+    SelfAssignment,
+}
+
+const RULES: [Rule; 4] = [
+    Rule::ShadowedVariable,
+    Rule::EmptyBlock,
+    Rule::ConstantCondition,
+    Rule::SelfAssignment,
+];
+
+impl Rule {
+    pub fn name(self) -> &'static str {
+        match self {
+            Rule::ShadowedVariable => "shadowed-variable",
+            Rule::EmptyBlock => "empty-block",
+            Rule::ConstantCondition => "constant-condition",
+            Rule::SelfAssignment => "self-assignment",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Rule> {
+        RULES.iter().copied().find(|rule| rule.name() == name)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Level {
+    Off,
+    Warn,
+    Error,
+}
+
+// How much each rule matters. Every rule warns unless the config says
+// otherwise.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    levels: HashMap<&'static str, Level>,
+}
+
+impl Config {
+    // Reads lines like `empty-block = off`, with `off`, `warn` or `error`
+    // after the `=`. A `#` starts a comment.
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (name, level) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: Expect '=' after rule name.", number + 1))?;
+            let rule = Rule::from_name(name.trim())
+                .ok_or_else(|| format!("line {}: Unknown rule '{}'.", number + 1, name.trim()))?;
+            let level = match level.trim() {
+                "off" => Level::Off,
+                "warn" => Level::Warn,
+                "error" => Level::Error,
+                _ => {
+                    return Err(format!(
+                        "line {}: Expect 'off', 'warn' or 'error' after '='.",
+                        number + 1
+                    ))
+                }
+            };
+            config.levels.insert(rule.name(), level);
+        }
+        Ok(config)
+    }
+
+    pub fn level(&self, rule: Rule) -> Level {
+        self.levels.get(rule.name()).copied().unwrap_or(Level::Warn)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub warnings: usize,
+    pub errors: usize,
+}
+
+struct Linter<'a, 'c> {
+    source: &'a str,
+    tokens: &'a [Token<'a>],
+    comments: &'a [Comment<'a>],
+    config: &'c Config,
+    // The rules turned off on each line, or `None` for all of them.
+    allowed: HashMap<i32, Option<Vec<Rule>>>,
+    // Printed before the first diagnostic.
+    header: Option<&'c str>,
+    // The locals declared so far in each enclosing scope, innermost last.
+    scopes: Vec<Vec<&'a str>>,
+    report: Report,
+}
+
+impl<'a> Linter<'a, '_> {
+    fn report(&mut self, rule: Rule, token: &Token, message: &str) {
+        let allowed = match self.allowed.get(&token.line) {
+            Some(None) => true,
+            Some(Some(rules)) => rules.contains(&rule),
+            None => false,
+        };
+        let kind = match self.config.level(rule) {
+            _ if allowed => return,
+            Level::Off => return,
+            Level::Warn => {
+                self.report.warnings += 1;
+                "Warning"
+            }
+            Level::Error => {
+                self.report.errors += 1;
+                "Error"
+            }
+        };
+        if let Some(header) = self.header.take() {
+            output::err(format_args!("{}\n", header));
+        }
+        output::err(format_args!(
+            "[line {}:{}] {} at '{}': {} ({})\n",
+            token.line,
+            token.column,
+            kind,
+            token.lexeme,
+            message,
+            rule.name()
+        ));
+        output::err(format_args!("{}", snippet::at(self.source, token.span())));
+    }
+
+    fn allow_comments(&mut self) -> Result<(), String> {
+        for comment in self.comments {
+            let rest = match comment.text["//".len()..].trim().strip_prefix("lint-allow") {
+                Some(rest) => rest.trim(),
+                None => continue,
+            };
+            let line = if comment.trailing {
+                comment.line
+            } else {
+                comment.line + 1
+            };
+            let rules = match rest.strip_prefix(':') {
+                Some(names) => names
+                    .split(',')
+                    .map(|name| {
+                        Rule::from_name(name.trim()).ok_or_else(|| {
+                            format!("line {}: Unknown rule '{}'.", comment.line, name.trim())
+                        })
+                    })
+                    .collect::<Result<Vec<Rule>, String>>()
+                    .map(Some)?,
+                None if rest.is_empty() => None,
+                None => continue,
+            };
+            self.allowed.insert(line, rules);
+        }
+        Ok(())
+    }
+
+    fn declare(&mut self, name: &'a Token<'a>) {
+        let (scope, enclosing) = match self.scopes.split_last_mut() {
+            Some(scopes) => scopes,
+            None => return,
+        };
+        let shadows = enclosing.iter().any(|scope| scope.contains(&name.lexeme));
+        scope.push(name.lexeme);
+        if shadows {
+            self.report(
+                Rule::ShadowedVariable,
+                name,
+                "Shadows a variable in an enclosing scope.",
+            );
+        }
+    }
+
+    // A comment between the braces counts as the block's body.
+    fn empty_block(&mut self, brace: &Token) {
+        let close = self
+            .tokens
+            .binary_search_by_key(&brace.offset, |token| token.offset)
+            .unwrap();
+        let open = &self.tokens[close - 1];
+        if self
+            .comments
+            .iter()
+            .any(|comment| comment.line >= open.line && comment.line < brace.line)
+        {
+            return;
+        }
+        self.report(Rule::EmptyBlock, open, "Block is empty.");
+    }
+
+    fn condition(&mut self, condition: &Expr) {
+        if let Some((token, value)) = constant(condition) {
+            let message = if value {
+                "Condition is always true."
+            } else {
+                "Condition is always false."
+            };
+            self.report(Rule::ConstantCondition, token, message);
+        }
+    }
+
+    fn statements(&mut self, statements: &[Stmt<'a>]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Stmt<'a>) {
+        match statement {
+            Stmt::Block(block) => {
+                if block.statements.is_empty() {
+                    self.empty_block(block.brace);
+                }
+                self.scopes.push(Vec::new());
+                self.statements(&block.statements);
+                self.scopes.pop();
+            }
+            Stmt::Break(stmt) => {
+                if let Some(value) = &stmt.value {
+                    self.expression(value);
+                }
+            }
+            Stmt::Continue(_) => (),
+            Stmt::Expression(stmt) => self.expression(&stmt.expression),
+            Stmt::For(stmt) => {
+                self.scopes.push(Vec::new());
+                if let Some(initializer) = stmt.initializer {
+                    self.statement(initializer);
+                }
+                if let Some(condition) = &stmt.condition {
+                    self.condition(condition);
+                    self.expression(condition);
+                }
+                if let Some(increment) = &stmt.increment {
+                    self.expression(increment);
+                }
+                self.statement(stmt.body);
+                self.scopes.pop();
+            }
+            Stmt::Function(function) => {
+                self.declare(function.name);
+                self.scopes.push(Vec::new());
+                for param in &function.params {
+                    self.declare(param);
+                }
+                self.statements(&function.body);
+                self.scopes.pop();
+            }
+            Stmt::If(stmt) => {
+                self.condition(&stmt.condition);
+                self.expression(&stmt.condition);
+                self.statement(stmt.then_branch);
+                if let Some(else_branch) = stmt.else_branch {
+                    self.statement(else_branch);
+                }
+            }
+            Stmt::Loop(stmt) => self.statement(stmt.body),
+            Stmt::Print(stmt) => self.expression(&stmt.expression),
+            Stmt::Return(stmt) => {
+                if let Some(value) = &stmt.value {
+                    self.expression(value);
+                }
+            }
+            Stmt::Var(stmt) => {
+                for declarator in &stmt.declarators {
+                    if let Some(initializer) = &declarator.initializer {
+                        self.expression(initializer);
+                    }
+                    self.declare(declarator.name);
+                }
+            }
+            Stmt::While(stmt) => {
+                self.condition(&stmt.condition);
+                self.expression(&stmt.condition);
+                self.statement(stmt.body);
+            }
+        }
+    }
+
+    fn expressions(&mut self, expressions: &[Expr<'a>]) {
+        for expression in expressions {
+            self.expression(expression);
+        }
+    }
+
+    fn expression(&mut self, expression: &Expr<'a>) {
+        match expression {
+            Expr::Assign(expr) => {
+                if let Expr::Variable(value) = expr.value {
+                    if value.name.lexeme == expr.name.lexeme {
+                        self.report(
+                            Rule::SelfAssignment,
+                            expr.name,
+                            "Variable is assigned to itself.",
+                        );
+                    }
+                }
+                self.expression(expr.value);
+            }
+            Expr::Binary(expr) => {
+                self.expression(expr.left);
+                self.expression(expr.right);
+            }
+            Expr::Call(expr) => {
+                self.expression(expr.callee);
+                self.expressions(&expr.args);
+            }
+            Expr::Comma(expr) => {
+                self.expression(expr.left);
+                self.expression(expr.right);
+            }
+            Expr::ComparisonChain(expr) => self.expressions(&expr.operands),
+            Expr::Get(expr) => self.expression(expr.object),
+            Expr::Grouping(expr) => self.expression(expr.expr),
+            Expr::If(expr) => {
+                self.condition(expr.condition);
+                self.expression(expr.condition);
+                self.expression(expr.then_branch);
+                self.expression(expr.else_branch);
+            }
+            Expr::Index(expr) => {
+                self.expression(expr.object);
+                self.expression(expr.index);
+            }
+            Expr::Literal(_) => (),
+            Expr::Loop(expr) => self.statement(expr.body),
+            Expr::Logical(expr) => {
+                self.expression(expr.left);
+                self.expression(expr.right);
+            }
+            Expr::LogicalAssign(expr) => self.expression(expr.value),
+            Expr::LogicalSet(expr) => {
+                self.expression(expr.object);
+                self.expression(expr.value);
+            }
+            Expr::Match(expr) => {
+                self.expression(expr.subject);
+                for arm in &expr.arms {
+                    match &arm.pattern {
+                        Pattern::Binding(name) => {
+                            self.scopes.push(Vec::new());
+                            self.declare(name);
+                            self.expression(&arm.body);
+                            self.scopes.pop();
+                        }
+                        Pattern::Value(_) | Pattern::Else(_) => self.expression(&arm.body),
+                    }
+                }
+            }
+            Expr::Object(expr) => self.expressions(&expr.values),
+            Expr::Set(expr) => {
+                if let Expr::Get(value) = expr.value {
+                    if value.name.lexeme == expr.name.lexeme
+                        && same_place(value.object, expr.object)
+                    {
+                        self.report(
+                            Rule::SelfAssignment,
+                            expr.name,
+                            "Field is assigned to itself.",
+                        );
+                    }
+                }
+                self.expression(expr.object);
+                self.expression(expr.value);
+            }
+            Expr::SetLiteral(expr) => self.expressions(&expr.elements),
+            Expr::Tuple(expr) => self.expressions(&expr.elements),
+            Expr::Unary(expr) => self.expression(expr.right),
+            Expr::Variable(_) => (),
+        }
+    }
+}
+
+// Whether two expressions name the same variable or the same field of one,
+// like `a.b` and `a.b`.
+fn same_place(a: &Expr, b: &Expr) -> bool {
+    match (a, b) {
+        (Expr::Variable(a), Expr::Variable(b)) => a.name.lexeme == b.name.lexeme,
+        (Expr::Get(a), Expr::Get(b)) => {
+            a.name.lexeme == b.name.lexeme && same_place(a.object, b.object)
+        }
+        _ => false,
+    }
+}
+
+// The value of a condition that's the same every time, with the token it's
+// written with.
+fn constant<'e>(expr: &'e Expr) -> Option<(&'e Token<'e>, bool)> {
+    match expr {
+        Expr::Literal(literal) => {
+            let value = !matches!(literal.value.kind, TokenKind::False | TokenKind::Nil);
+            Some((literal.value, value))
+        }
+        Expr::Grouping(grouping) => constant(grouping.expr),
+        Expr::Unary(unary) if unary.operator.kind == TokenKind::Bang => {
+            constant(unary.right).map(|(_, value)| (unary.operator, !value))
+        }
+        _ => None,
+    }
+}
+
+// Checks a script, printing what it finds after `header`. Returns `None` if
+// the script doesn't parse or a `lint-allow` comment names a rule that
+// doesn't exist, after reporting why.
+pub fn lint(source: &str, config: &Config, header: &str) -> Option<Report> {
+    let (tokens, comments) = scanner::scan_with_comments(source);
+    if tokens.is_empty() {
+        return Some(Report::default());
+    }
+    let nodes = parser::Nodes::default();
+    let statements = parser::parse_tokens(source, &tokens, &nodes, true)?;
+
+    let mut linter = Linter {
+        source,
+        tokens: &tokens,
+        comments: &comments,
+        config,
+        allowed: HashMap::new(),
+        header: Some(header),
+        scopes: Vec::new(),
+        report: Report::default(),
+    };
+    if let Err(message) = linter.allow_comments() {
+        output::err(format_args!("{}\n{}\n", header, message));
+        return None;
+    }
+    linter.statements(&statements);
+    Some(linter.report)
+}
@@ -0,0 +1,22 @@
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// A `RefCell` that can be shared between threads, so values can be sent to
+// another thread along with the VM that owns them. Objects only ever belong
+// to one running VM, so a conflicting borrow is a bug and panics the way
+// `RefCell` does instead of waiting for the lock.
+#[derive(Debug, Default)]
+pub struct Lock<T>(RwLock<T>);
+
+impl<T> Lock<T> {
+    pub fn new(value: T) -> Lock<T> {
+        Lock(RwLock::new(value))
+    }
+
+    pub fn borrow(&self) -> RwLockReadGuard<'_, T> {
+        self.0.try_read().expect("already mutably borrowed")
+    }
+
+    pub fn borrow_mut(&self) -> RwLockWriteGuard<'_, T> {
+        self.0.try_write().expect("already borrowed")
+    }
+}
@@ -0,0 +1,292 @@
+use crate::compiler::{self, CompileOptions};
+use crate::output::{self, Diagnostic};
+use crate::parser;
+use crate::resolver::{self, Declaration};
+use crate::scanner;
+use crate::vm;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::sync::Arc;
+
+// `rustlox lsp` speaks the Language Server Protocol over stdin and stdout.
+// Editors send the whole text of a script whenever it changes, and get back
+// the errors and warnings compiling it reports. Go-to-definition and hover
+// work from the names the resolver finds.
+
+// Reads the body of one message, or `None` at the end of the input.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Expect a Content-Length header.",
+        )
+    })?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+fn send(message: Value) -> io::Result<()> {
+    let body = message.to_string();
+    let mut stdout = io::stdout().lock();
+    write!(stdout, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    stdout.flush()
+}
+
+// Positions count lines from 0 and characters in UTF-16 code units.
+fn position(text: &str, offset: usize) -> Value {
+    let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    json!({
+        "line": text[..offset].matches('\n').count(),
+        "character": text[line_start..offset].encode_utf16().count(),
+    })
+}
+
+fn range(text: &str, span: Range<usize>) -> Value {
+    json!({ "start": position(text, span.start), "end": position(text, span.end) })
+}
+
+fn offset(text: &str, position: &Value) -> Option<usize> {
+    let line = position["line"].as_u64()? as usize;
+    let character = position["character"].as_u64()? as usize;
+    let mut line_start = 0;
+    for _ in 0..line {
+        line_start += text[line_start..].find('\n')? + 1;
+    }
+    let mut units = 0;
+    for (index, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return Some(line_start + index);
+        }
+        units += c.len_utf16();
+    }
+    Some(text.len())
+}
+
+// What compiling the script reports, without running it.
+fn diagnostics(text: &str) -> Vec<Value> {
+    let source: Arc<str> = Arc::from(text);
+    let options = CompileOptions {
+        warnings: true,
+        ..CompileOptions::default()
+    };
+    let (_, captured) = output::capture(|| {
+        let tokens = scanner::scan_tokens(&source);
+        if !tokens.is_empty() {
+            compiler::compile(&source, tokens, &options).ok();
+        }
+    });
+    captured
+        .diagnostics
+        .iter()
+        .map(|diagnostic: &Diagnostic| {
+            json!({
+                "range": range(text, diagnostic.span.clone()),
+                "severity": if diagnostic.is_error { 1 } else { 2 },
+                "source": "rustlox",
+                "message": diagnostic.message,
+            })
+        })
+        .collect()
+}
+
+// A name in the script, with where it's declared and how it's described
+// when hovered, in markdown.
+struct Symbol {
+    span: Range<usize>,
+    definition: Option<Range<usize>>,
+    description: String,
+}
+
+fn symbol_at(text: &str, offset: usize) -> Option<Symbol> {
+    let tokens = scanner::scan_tokens(text);
+    if tokens.is_empty() {
+        return None;
+    }
+    let nodes = parser::Nodes::default();
+    let (symbols, _) = output::capture(|| {
+        parser::parse_tokens(text, &tokens, &nodes, true)
+            .map(|statements| resolver::symbols(text, &statements))
+    });
+    let symbols = symbols?;
+
+    // The cursor can be just past the end of the name.
+    let (name, definition) = symbols
+        .uses
+        .iter()
+        .find(|(name, _)| name.span().contains(&offset) || name.span().end == offset)?;
+    let definition = definition.map(|index| &symbols.definitions[index]);
+    let signature = match definition {
+        Some(definition) => {
+            let scope = if definition.global { "global" } else { "local" };
+            match &definition.declaration {
+                Declaration::Variable => format!("({}) var {}", scope, name.lexeme),
+                Declaration::Parameter => format!("(parameter) {}", name.lexeme),
+                Declaration::Function(signature) => format!("({}) {}", scope, signature),
+                Declaration::Binding => format!("(match binding) {}", name.lexeme),
+            }
+        }
+        None if vm::is_predefined_global(name.lexeme) => format!("(built-in) {}", name.lexeme),
+        None => return None,
+    };
+    Some(Symbol {
+        span: name.span(),
+        definition: definition.map(|definition| definition.name.span()),
+        description: format!("```lox\n{}\n```", signature),
+    })
+}
+
+struct Server {
+    documents: HashMap<String, String>,
+    shut_down: bool,
+}
+
+impl Server {
+    fn publish(&self, uri: &str) -> io::Result<()> {
+        let diagnostics = match self.documents.get(uri) {
+            Some(text) => diagnostics(text),
+            None => Vec::new(),
+        };
+        send(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        }))
+    }
+
+    // The document a request is about and the offset of its position.
+    fn locate<'s>(&'s self, params: &Value) -> Option<(&'s str, &'s str, usize)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let (uri, text) = self.documents.get_key_value(uri)?;
+        Some((uri, text, offset(text, &params["position"])?))
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        let definition = self.locate(params).and_then(|(uri, text, offset)| {
+            let span = symbol_at(text, offset)?.definition?;
+            Some(json!({ "uri": uri, "range": range(text, span) }))
+        });
+        definition.unwrap_or(Value::Null)
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let hover = self.locate(params).and_then(|(_, text, offset)| {
+            let symbol = symbol_at(text, offset)?;
+            Some(json!({
+                "contents": { "kind": "markdown", "value": symbol.description },
+                "range": range(text, symbol.span),
+            }))
+        });
+        hover.unwrap_or(Value::Null)
+    }
+
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, (i32, String)> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    // The whole text is sent on every change.
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                },
+                "serverInfo": { "name": "rustlox" },
+            })),
+            "shutdown" => {
+                self.shut_down = true;
+                Ok(Value::Null)
+            }
+            "textDocument/definition" => Ok(self.definition(params)),
+            "textDocument/hover" => Ok(self.hover(params)),
+            _ => Err((-32601, format!("Unknown method '{}'.", method))),
+        }
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) -> io::Result<()> {
+        let uri = match params["textDocument"]["uri"].as_str() {
+            Some(uri) => uri.to_string(),
+            None => return Ok(()),
+        };
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.documents.insert(uri.clone(), text.to_string());
+            }
+            "textDocument/didChange" => {
+                let changes = params["contentChanges"].as_array();
+                match changes.and_then(|changes| changes.last()?["text"].as_str()) {
+                    Some(text) => self.documents.insert(uri.clone(), text.to_string()),
+                    None => return Ok(()),
+                };
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+            }
+            _ => return Ok(()),
+        }
+        self.publish(&uri)
+    }
+}
+
+// Serves one editor until it says to exit. Returns the status to exit
+// with, which is 1 if the editor didn't ask to shut down first.
+pub fn run() -> io::Result<i32> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut server = Server {
+        documents: HashMap::new(),
+        shut_down: false,
+    };
+    while let Some(body) = read_message(&mut input)? {
+        let message: Value = match serde_json::from_slice(&body) {
+            Ok(message) => message,
+            // There's no id to answer, so the error goes back with a null
+            // one, and the server carries on with the next message.
+            Err(error) => {
+                send(json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": { "code": -32700, "message": format!("Parse error: {}", error) },
+                }))?;
+                continue;
+            }
+        };
+        let method = message["method"].as_str().unwrap_or_default();
+        if method == "exit" {
+            return Ok(if server.shut_down { 0 } else { 1 });
+        }
+        let params = &message["params"];
+        match message.get("id") {
+            Some(id) => {
+                let response = match server.request(method, params) {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err((code, message)) => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": code, "message": message },
+                    }),
+                };
+                send(response)?;
+            }
+            None => server.notification(method, params)?,
+        }
+    }
+    Ok(1)
+}
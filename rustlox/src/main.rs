@@ -1,58 +1,492 @@
-mod chunk;
-mod compiler;
-mod expr;
-mod native;
-mod parser;
-mod scanner;
-mod stmt;
-mod string;
-mod value;
-mod vm;
-
-use vm::*;
-
-fn repl() {
-    use std::io::{self, BufRead, Write};
-
-    let stdin = io::stdin();
-    let mut lines = stdin.lock().lines();
+use rustlox::compiler::{self, CompileOptions};
+use rustlox::formatter;
+use rustlox::harness;
+use rustlox::highlight;
+use rustlox::lint;
+use rustlox::lsp;
+use rustlox::scanner;
+use rustlox::value::Value;
+use rustlox::vm::*;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::path::Path;
+use std::sync::Arc;
+
+const USAGE: &str = "Usage: rustlox [--dump-ast] [--dump-ir] [--disassemble] \
+                     [--no-print-statement] [--strict-concat] [--strict] \
+                     [--warnings] [--deny-warnings] \
+                     [--max-call-depth=<n>] [--stack-size=<n>] [--max-interned-bytes=<n>] \
+                     [--trace[=<path>]] [--trace-function=<name>]... [--trace-depth=<n>] \
+                     [--debug] [--profile] \
+                     [--sample=<path>] [--coverage[=<path>]] [--heap-stats] [--lazy] \
+                     [--incremental-gc] [path]\n       rustlox test [--lazy] [--incremental-gc] <path>\n       \
+                     rustlox fmt [--check] <path>...\n       \
+                     rustlox lint [--config=<path>] <path>...\n       \
+                     rustlox lsp\n       \
+                     rustlox highlight [--html] <path>";
+
+fn report_profiles(vm: &mut VM, options: &CompileOptions) {
+    if options.profile {
+        vm.print_profile();
+    }
+    if options.heap_stats {
+        eprint!("{}", vm.heap_stats());
+    }
+    if let Err(error) = vm.finish_trace() {
+        eprintln!("Could not write the trace: {}", error);
+    }
+    if let Some(path) = &options.sample {
+        if let Err(error) = vm.write_samples() {
+            eprintln!("Could not write samples to '{}': {}", path, error);
+        }
+    }
+}
+
+// Completes the identifier before the cursor with the names of the globals
+// the session can use.
+struct Completions {
+    names: Vec<String>,
+}
+
+impl Completer for Completions {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let names: Vec<&str> = self.names.iter().map(String::as_str).collect();
+        let (start, candidates) = completions(&names, line, pos);
+        Ok((start, candidates.into_iter().map(String::from).collect()))
+    }
+}
+
+impl Hinter for Completions {
+    type Hint = String;
+}
+
+impl Highlighter for Completions {}
+
+impl Validator for Completions {}
+
+impl Helper for Completions {}
+
+// Lines starting with `:` are commands:
+// - `:disassemble` toggles printing each line's code before it runs.
+// - `:save <path>` writes every line that has run without an error so far to
+//   a file, which makes a script that recreates the session.
+// - `:load <path>` runs a file in the session, as if it were one line.
+fn repl(vm: &mut VM, options: &CompileOptions) {
+    use std::fs;
+
+    let mut options = options.clone();
+    let mut session = String::new();
+    let mut editor: Editor<Completions, DefaultHistory> =
+        Editor::new().expect("Couldn't start the line editor");
     loop {
-        print!("> ");
-        io::stdout().flush().expect("Couldn't flush stdout");
-        let result = match lines.next() {
-            Some(Ok(line)) => vm::interpret(&line),
-            _ => break,
+        editor.set_helper(Some(Completions {
+            names: vm.global_names().into_iter().map(String::from).collect(),
+        }));
+        let line = match editor.readline("> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(_) => break,
+        };
+        editor.add_history_entry(line.as_str()).ok();
+
+        let (command, path) = match line.trim().split_once(char::is_whitespace) {
+            Some((command, path)) => (command, path.trim()),
+            None => (line.trim(), ""),
+        };
+        let source = match command {
+            ":disassemble" => {
+                options.disassemble = !options.disassemble;
+                continue;
+            }
+            ":save" if !path.is_empty() => {
+                if let Err(error) = fs::write(path, &session) {
+                    eprintln!("Could not save the session to '{}': {}", path, error);
+                }
+                continue;
+            }
+            ":load" if !path.is_empty() => match fs::read_to_string(path) {
+                Ok(source) => source,
+                Err(error) => {
+                    eprintln!("Could not load '{}': {}", path, error);
+                    continue;
+                }
+            },
+            ":save" | ":load" => {
+                eprintln!("Usage: {} <path>", command);
+                continue;
+            }
+            _ => line.clone(),
         };
 
-        if let Err(InterpretError::InternalError(message)) = result {
-            eprintln!("{}", message);
+        let result = vm.interpret(&source, &options);
+        if result.is_ok() {
+            session.push_str(&source);
+            if !session.ends_with('\n') {
+                session.push('\n');
+            }
+        }
+        match result {
+            Ok(Value::Nil) => (),
+            Ok(value) => println!("{}", value),
+            Err(InterpretError::InternalError(message)) => eprintln!("{}", message),
+            Err(_) => (),
+        }
+    }
+    report_profiles(vm, &options);
+}
+
+// With `--coverage`, prints the source annotated with how often each line
+// ran, or writes the counts to `lcov` as an lcov tracefile.
+fn report_coverage(vm: &VM, path: &str, source: &str, lcov: Option<&str>) {
+    use std::fs;
+
+    match lcov {
+        Some(lcov) => {
+            if let Some(tracefile) = vm.coverage_lcov(path) {
+                if let Err(error) = fs::write(lcov, tracefile) {
+                    eprintln!("Could not write coverage to '{}': {}", lcov, error);
+                }
+            }
+        }
+        None => {
+            if let Some(listing) = vm.coverage_listing(source) {
+                eprint!("{}", listing);
+            }
         }
     }
 }
 
-fn run_file(path: &String) {
+fn run_file(vm: &mut VM, path: &String, options: &CompileOptions, lcov: Option<&str>) {
     use std::fs;
 
     let source = fs::read_to_string(path).expect("Failed to read filed");
 
-    match vm::interpret(&source) {
+    let result = vm.interpret(&source, options);
+    report_profiles(vm, options);
+    report_coverage(vm, path, &source, lcov);
+    match result {
         Err(InterpretError::CompileError) => std::process::exit(65),
         Err(InterpretError::RuntimeError) => std::process::exit(70),
         Err(InterpretError::InternalError(message)) => {
             eprintln!("Fatal error: {}", message);
             std::process::exit(1)
         }
-        Ok(()) => (),
+        // A script that ends in a number exits with it as its status.
+        Ok(Value::Number(status)) => std::process::exit(status as i32),
+        Ok(Value::Int(status)) => std::process::exit(status as i32),
+        Ok(_) => (),
+    }
+}
+
+// Compiles the file without running it, for `--dump-ast`,
+// `--dump-ir` and `--disassemble`.
+fn compile_file(path: &String, options: &CompileOptions) {
+    use std::fs;
+
+    let source = Arc::from(fs::read_to_string(path).expect("Failed to read filed"));
+    let tokens = scanner::scan_tokens(&source);
+    if tokens.is_empty() {
+        return;
+    }
+
+    if compiler::compile(&source, tokens, options).is_err() {
+        std::process::exit(65);
+    }
+}
+
+// `rustlox test <path>` runs every script under the path and checks its
+// output against its `// expect:` comments. `--lazy` and `--incremental-gc`
+// run them the way they would with those flags.
+fn run_tests(args: &[String]) {
+    let mut options = CompileOptions::default();
+    let mut incremental_gc = false;
+    let mut path = None;
+    for arg in args {
+        match arg.as_str() {
+            "--lazy" => options.lazy = true,
+            "--incremental-gc" => incremental_gc = true,
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => {
+                eprintln!("{}", USAGE);
+                std::process::exit(64);
+            }
+        }
+    }
+    let path = match path {
+        Some(path) => path,
+        None => {
+            eprintln!("{}", USAGE);
+            std::process::exit(64);
+        }
+    };
+
+    let vm = || VM::builder().incremental_gc(incremental_gc).build();
+    match harness::run(Path::new(path), &options, vm) {
+        Ok(true) => (),
+        Ok(false) => std::process::exit(1),
+        Err(error) => {
+            eprintln!("Could not read '{}': {}", path, error);
+            std::process::exit(1)
+        }
+    }
+}
+
+// `rustlox fmt <path>...` rewrites each file in the formatter's layout. With
+// `--check` it only lists the files that aren't formatted yet.
+fn format_files(args: &[String]) {
+    use std::fs;
+
+    let check = args.iter().any(|arg| arg == "--check");
+    let paths: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
+    if paths.is_empty() || paths.iter().any(|path| path.starts_with("--")) {
+        eprintln!("{}", USAGE);
+        std::process::exit(64);
+    }
+
+    let mut had_error = false;
+    let mut unformatted = false;
+    for path in paths {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(error) => {
+                eprintln!("Could not read '{}': {}", path, error);
+                had_error = true;
+                continue;
+            }
+        };
+        let formatted = match formatter::format(&source) {
+            Some(formatted) => formatted,
+            None => {
+                had_error = true;
+                continue;
+            }
+        };
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{}", path);
+            unformatted = true;
+        } else if let Err(error) = fs::write(path, formatted) {
+            eprintln!("Could not write '{}': {}", path, error);
+            had_error = true;
+        }
+    }
+
+    if had_error {
+        std::process::exit(65);
+    }
+    if unformatted {
+        std::process::exit(1);
+    }
+}
+
+// `rustlox lint <path>...` reports likely mistakes in each file. The rules
+// are set by `--config`, or by `.loxlint` in the working directory if
+// there is one. Rules set to `error` make the command fail.
+fn lint_files(args: &[String]) {
+    use std::fs;
+
+    let mut config_path = None;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.strip_prefix("--config=") {
+            Some(path) => config_path = Some(path.to_string()),
+            None if !arg.starts_with("--") => paths.push(arg),
+            None => {
+                eprintln!("{}", USAGE);
+                std::process::exit(64);
+            }
+        }
+    }
+    if paths.is_empty() {
+        eprintln!("{}", USAGE);
+        std::process::exit(64);
+    }
+
+    let config_path = config_path.or_else(|| {
+        Path::new(".loxlint")
+            .exists()
+            .then(|| String::from(".loxlint"))
+    });
+    let config = match config_path {
+        Some(path) => match fs::read_to_string(&path)
+            .map_err(|error| error.to_string())
+            .and_then(|text| lint::Config::parse(&text))
+        {
+            Ok(config) => config,
+            Err(error) => {
+                eprintln!("Could not read '{}': {}", path, error);
+                std::process::exit(1)
+            }
+        },
+        None => lint::Config::default(),
+    };
+
+    let mut had_error = false;
+    let mut denied = false;
+    for path in paths {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(error) => {
+                eprintln!("Could not read '{}': {}", path, error);
+                had_error = true;
+                continue;
+            }
+        };
+        match lint::lint(&source, &config, path) {
+            Some(report) => denied |= report.errors > 0,
+            None => had_error = true,
+        }
+    }
+
+    if had_error {
+        std::process::exit(65);
+    }
+    if denied {
+        std::process::exit(1);
+    }
+}
+
+// `rustlox highlight <path>` prints the file with its tokens colored for
+// the terminal, or as HTML with `--html`.
+fn highlight_file(args: &[String]) {
+    use std::fs;
+
+    let (html, path) = match args {
+        [path] if !path.starts_with("--") => (false, path),
+        [flag, path] if flag == "--html" => (true, path),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(64);
+        }
+    };
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("Could not read '{}': {}", path, error);
+            std::process::exit(1)
+        }
+    };
+    if html {
+        print!("{}", highlight::html(&source));
+    } else {
+        print!("{}", highlight::ansi(&source));
     }
 }
 
 fn main() {
     use std::env;
+    use std::fs::File;
+    use std::io::BufWriter;
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("fmt") {
+        return format_files(&args[1..]);
+    }
+    if args.first().map(String::as_str) == Some("lint") {
+        return lint_files(&args[1..]);
+    }
+    if args.first().map(String::as_str) == Some("highlight") {
+        return highlight_file(&args[1..]);
+    }
+    if let [command] = args.as_slice() {
+        if command == "lsp" {
+            match lsp::run() {
+                Ok(status) => std::process::exit(status),
+                Err(error) => {
+                    eprintln!("Language server stopped: {}", error);
+                    std::process::exit(1)
+                }
+            }
+        }
+    }
+    if args.first().map(String::as_str) == Some("test") {
+        return run_tests(&args[1..]);
+    }
+
+    let mut options = CompileOptions::default();
+    let mut builder = VM::builder();
+    let mut path: Option<String> = None;
+    let mut lcov: Option<String> = None;
+    for arg in args {
+        match arg.as_str() {
+            "--dump-ast" => options.dump_ast = true,
+            "--dump-ir" => options.dump_ir = true,
+            "--disassemble" => options.disassemble = true,
+            "--no-print-statement" => options.print_statement = false,
+            "--strict-concat" => options.strict_concat = true,
+            "--strict" => options.strict = true,
+            "--warnings" => options.warnings = true,
+            "--deny-warnings" => options.deny_warnings = true,
+            "--trace" => builder = builder.trace(true),
+            "--debug" => builder = builder.debug(true),
+            "--incremental-gc" => builder = builder.incremental_gc(true),
+            "--profile" => options.profile = true,
+            "--heap-stats" => options.heap_stats = true,
+            "--lazy" => options.lazy = true,
+            "--coverage" => options.coverage = true,
+            _ if arg.starts_with("--coverage=") => {
+                options.coverage = true;
+                lcov = Some(arg["--coverage=".len()..].to_string());
+            }
+            _ if arg.starts_with("--trace=") => {
+                let path = &arg["--trace=".len()..];
+                match File::create(path) {
+                    Ok(file) => builder = builder.trace_to(BufWriter::new(file)),
+                    Err(error) => return eprintln!("Could not open '{}': {}", path, error),
+                }
+            }
+            _ if arg.starts_with("--trace-function=") => {
+                builder = builder.trace_function(&arg["--trace-function=".len()..])
+            }
+            _ if arg.starts_with("--sample=") => {
+                options.sample = Some(arg["--sample=".len()..].to_string())
+            }
+            _ if arg.starts_with("--max-call-depth=") => {
+                match arg["--max-call-depth=".len()..].parse() {
+                    Ok(depth) if depth > 0 => builder = builder.max_call_depth(depth),
+                    _ => return eprintln!("{}", USAGE),
+                }
+            }
+            _ if arg.starts_with("--max-interned-bytes=") => {
+                match arg["--max-interned-bytes=".len()..].parse() {
+                    Ok(bytes) => builder = builder.max_interned_bytes(bytes),
+                    _ => return eprintln!("{}", USAGE),
+                }
+            }
+            _ if arg.starts_with("--trace-depth=") => match arg["--trace-depth=".len()..].parse() {
+                Ok(frames) => builder = builder.trace_depth(frames),
+                _ => return eprintln!("{}", USAGE),
+            },
+            _ if arg.starts_with("--stack-size=") => match arg["--stack-size=".len()..].parse() {
+                Ok(slots) => builder = builder.stack_size(slots),
+                _ => return eprintln!("{}", USAGE),
+            },
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => return eprintln!("{}", USAGE),
+        }
+    }
 
-    let args: Vec<String> = env::args().collect();
-    match args.len() {
-        1 => repl(),
-        2 => run_file(&args[1]),
-        _ => eprintln!("Usage: rustlox [path]"),
+    let mut vm = builder.build();
+    match path {
+        Some(path) if options.dump_ast || options.dump_ir || options.disassemble => {
+            compile_file(&path, &options)
+        }
+        Some(path) => run_file(&mut vm, &path, &options, lcov.as_deref()),
+        None if options.dump_ast || options.dump_ir || options.coverage => eprintln!("{}", USAGE),
+        None => repl(&mut vm, &options),
     }
 }
@@ -0,0 +1,45 @@
+use crate::string::{Handle, Interner};
+use std::collections::HashMap;
+
+// Each identifier gets an index the first time a VM compiles it, whether it
+// names a global, a property or a keyword argument. Chunks refer to names by
+// index instead of each keeping its own string constants, and the VM keeps
+// globals in a vector indexed the same way. Every chunk a VM runs was
+// compiled with its table, so REPL lines, eval'd code and lazily compiled
+// bodies agree on what each index means. An index only says where a global's
+// value goes; whether the global is defined is still decided when the code
+// runs.
+//
+// The table also holds the VM's interner, since the compiler makes the
+// string constants and names of the code it compiles.
+#[derive(Default)]
+pub struct Names {
+    indices: HashMap<Handle, usize>,
+    handles: Vec<Handle>,
+    pub strings: Interner,
+}
+
+impl Names {
+    pub fn index(&mut self, name: &str) -> usize {
+        if let Some(&index) = self.indices.get(name) {
+            return index;
+        }
+        let handle = self.strings.intern(name);
+        self.indices.insert(handle.clone(), self.handles.len());
+        self.handles.push(handle);
+        self.handles.len() - 1
+    }
+
+    // Whether code compiled with the table has used `name`.
+    pub fn contains(&self, name: &str) -> bool {
+        self.indices.contains_key(name)
+    }
+
+    pub fn handle(&self, index: usize) -> &Handle {
+        &self.handles[index]
+    }
+
+    pub fn name(&self, index: usize) -> &str {
+        self.handles[index].as_str()
+    }
+}
@@ -1,8 +1,24 @@
+use crate::output;
 use crate::value::*;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 pub type Function = fn(args: &[Value]) -> Value;
 
+// Natives are only copied into the VM's globals the first time a script looks
+// one up, so starting a VM doesn't pay for the ones a script never uses.
+const NATIVES: &[(&str, Function)] = &[("clock", clock), ("print", print), ("println", println)];
+
+pub fn names() -> impl Iterator<Item = &'static str> {
+    NATIVES.iter().map(|(name, _)| *name)
+}
+
+pub fn lookup(name: &str) -> Option<Function> {
+    NATIVES
+        .iter()
+        .find(|(native, _)| *native == name)
+        .map(|(_, function)| *function)
+}
+
 pub fn clock(_args: &[Value]) -> Value {
     let timestamp = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -10,3 +26,26 @@ pub fn clock(_args: &[Value]) -> Value {
         .as_secs_f64();
     Value::Number(timestamp)
 }
+
+fn write_args(args: &[Value]) {
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            output::out(format_args!(" "));
+        }
+        output::out(format_args!("{}", arg));
+    }
+}
+
+// Prints its arguments separated by spaces. The output is flushed so it isn't
+// lost if the script then exits on an error.
+pub fn print(args: &[Value]) -> Value {
+    write_args(args);
+    output::flush();
+    Value::Nil
+}
+
+pub fn println(args: &[Value]) -> Value {
+    write_args(args);
+    output::out(format_args!("\n"));
+    Value::Nil
+}
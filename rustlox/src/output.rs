@@ -0,0 +1,68 @@
+use std::cell::RefCell;
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+use std::ops::Range;
+
+// Where scripts' output goes: what they print, and their compile and runtime
+// errors. It's normally stdout and stderr, but a thread can capture it
+// instead, which lets the test runner check a script's output without
+// starting a process for it.
+
+#[derive(Default)]
+pub struct Captured {
+    pub stdout: String,
+    pub stderr: String,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+// A compile error or warning as data, for tools that show it somewhere
+// other than stderr. It's only kept while capturing.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    // The bytes of the source it's about.
+    pub span: Range<usize>,
+    pub message: String,
+    pub is_error: bool,
+}
+
+thread_local! {
+    static CAPTURED: RefCell<Option<Captured>> = const { RefCell::new(None) };
+}
+
+// Runs `f`, keeping everything it prints on this thread.
+pub fn capture<T>(f: impl FnOnce() -> T) -> (T, Captured) {
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(Captured::default()));
+    let result = f();
+    let captured = CAPTURED.with(|captured| captured.borrow_mut().take());
+    (result, captured.unwrap_or_default())
+}
+
+pub fn out(args: fmt::Arguments) {
+    CAPTURED.with(|captured| match &mut *captured.borrow_mut() {
+        Some(captured) => captured.stdout.write_fmt(args).unwrap(),
+        None => io::stdout().write_fmt(args).unwrap(),
+    })
+}
+
+pub fn err(args: fmt::Arguments) {
+    CAPTURED.with(|captured| match &mut *captured.borrow_mut() {
+        Some(captured) => captured.stderr.write_fmt(args).unwrap(),
+        None => io::stderr().write_fmt(args).unwrap(),
+    })
+}
+
+pub fn diagnostic(span: Range<usize>, message: &str, is_error: bool) {
+    CAPTURED.with(|captured| {
+        if let Some(captured) = &mut *captured.borrow_mut() {
+            captured.diagnostics.push(Diagnostic {
+                span,
+                message: message.to_string(),
+                is_error,
+            })
+        }
+    })
+}
+
+pub fn flush() {
+    io::stdout().flush().unwrap();
+}
@@ -1,37 +1,70 @@
+use crate::arena::Arena;
 use crate::expr::{self, Expr};
+use crate::output;
 use crate::scanner::*;
+use crate::snippet;
 use crate::stmt::{self, FunctionKind, Stmt};
 
-#[derive(Copy, Clone, PartialEq)]
-enum Loop {
-    None,
-    While,
-    For,
+// Where the parser allocates the syntax tree's nodes, which can then point
+// at each other for as long as the tokens live.
+#[derive(Default)]
+pub struct Nodes<'a> {
+    exprs: Arena<Expr<'a>>,
+    stmts: Arena<Stmt<'a>>,
+}
+
+impl<'a> Nodes<'a> {
+    fn expr(&'a self, expr: Expr<'a>) -> &'a Expr<'a> {
+        self.exprs.alloc(expr)
+    }
+
+    fn stmt(&'a self, stmt: Stmt<'a>) -> &'a Stmt<'a> {
+        self.stmts.alloc(stmt)
+    }
 }
 
 struct Parser<'a> {
+    source: &'a str,
     tokens: &'a Vec<Token<'a>>,
+    nodes: &'a Nodes<'a>,
     current: usize,
-    last_line: i32,
+    // Errors at the end of the script are reported just past it.
+    last: &'a Token<'a>,
     had_error: bool,
     panic_mode: bool,
 
     function_kind: FunctionKind,
-    loop_kind: Loop,
+    allow_print_statement: bool,
+    // The loops enclosing the current statement, innermost last.
+    loops: Vec<EnclosingLoop<'a>>,
+}
+
+struct EnclosingLoop<'a> {
+    label: Option<&'a str>,
+    // A loop in expression position, which `break value;` leaves with a result.
+    is_expression: bool,
 }
 
 type ParseResult<T> = std::result::Result<T, ()>;
 
 impl<'a> Parser<'a> {
-    fn new(tokens: &'a Vec<Token<'a>>) -> Parser<'a> {
+    fn new(
+        source: &'a str,
+        tokens: &'a Vec<Token<'a>>,
+        nodes: &'a Nodes<'a>,
+        allow_print_statement: bool,
+    ) -> Parser<'a> {
         Parser {
-            tokens: tokens,
+            source,
+            tokens,
+            nodes,
             current: 0,
-            last_line: tokens.last().unwrap().line,
+            last: tokens.last().unwrap(),
             had_error: false,
             panic_mode: false,
             function_kind: FunctionKind::Script,
-            loop_kind: Loop::None,
+            allow_print_statement,
+            loops: Vec::new(),
         }
     }
 
@@ -55,10 +88,11 @@ impl<'a> Parser<'a> {
     }
 
     fn check(&self, desired: TokenKind) -> bool {
-        match self.peek() {
-            Some(Token { kind, .. }) if *kind == desired => true,
-            _ => false,
-        }
+        matches!(self.peek(), Some(Token { kind, .. }) if *kind == desired)
+    }
+
+    fn check_next(&self, desired: TokenKind) -> bool {
+        matches!(self.tokens.get(self.current + 1), Some(Token { kind, .. }) if *kind == desired)
     }
 
     fn match_current(&mut self, kind: TokenKind) -> bool {
@@ -75,21 +109,34 @@ impl<'a> Parser<'a> {
             return;
         }
 
-        let line = if let Some(t) = token {
-            t.line
-        } else {
-            self.last_line
+        let (line, column, span) = match token {
+            Some(t) => (t.line, t.column, t.span()),
+            None => {
+                let span = self.last.span();
+                let width = self.source[span.clone()].chars().count() as i32;
+                (self.last.line, self.last.column + width, span.end..span.end)
+            }
         };
 
-        eprint!("[line {}] Error", line);
+        output::err(format_args!("[line {}:{}] Error", line, column));
 
-        if token.is_none() {
-            eprint!(" at end");
-        } else if token.unwrap().kind != TokenKind::Error {
-            eprint!(" at '{}'", token.unwrap().lexeme);
-        }
+        // An error token carries the scanner's message, which says more than
+        // the parser's.
+        let message = match token {
+            None => {
+                output::err(format_args!(" at end"));
+                message
+            }
+            Some(t) if t.kind == TokenKind::Error => t.lexeme,
+            Some(t) => {
+                output::err(format_args!(" at '{}'", t.lexeme));
+                message
+            }
+        };
 
-        eprintln!(": {}", message);
+        output::err(format_args!(": {}\n", message));
+        output::err(format_args!("{}", snippet::at(self.source, span.clone())));
+        output::diagnostic(span, message, true);
         self.panic_mode = true;
         self.had_error = true;
     }
@@ -119,7 +166,18 @@ impl<'a> Parser<'a> {
     fn function(&mut self, kind: FunctionKind) -> ParseResult<Stmt<'a>> {
         let enclosing_kind = self.function_kind;
         self.function_kind = kind;
+        let enclosing_loops = std::mem::take(&mut self.loops);
 
+        // Restore the enclosing context even when the body fails to parse, so
+        // synchronizing afterwards sees the right loops.
+        let function = self.function_declaration();
+
+        self.function_kind = enclosing_kind;
+        self.loops = enclosing_loops;
+        function
+    }
+
+    fn function_declaration(&mut self) -> ParseResult<Stmt<'a>> {
         let name = self.consume(TokenKind::Identifier, "Expect function name.")?;
 
         self.consume(TokenKind::LeftParen, "Expect '(' after function name")?;
@@ -145,32 +203,36 @@ impl<'a> Parser<'a> {
 
         let body = self.block()?;
 
-        self.function_kind = enclosing_kind;
-
         Ok(Stmt::Function(stmt::Function {
             name,
             params,
             body,
-            kind,
             brace: self.previous().unwrap(),
         }))
     }
 
     fn statement(&mut self) -> ParseResult<Stmt<'a>> {
+        if self.check(TokenKind::Identifier) && self.check_next(TokenKind::Colon) {
+            return self.labeled_statement();
+        }
         if self.match_current(TokenKind::For) {
-            return self.for_statement();
+            return self.for_statement(None);
         }
         if self.match_current(TokenKind::If) {
             return self.if_statement();
         }
-        if self.match_current(TokenKind::Print) {
+        if self.match_current(TokenKind::Loop) {
+            return self.loop_statement(None);
+        }
+        if self.is_print_statement() {
+            self.advance();
             return self.print_statement();
         }
         if self.match_current(TokenKind::Return) {
             return self.return_statement();
         }
         if self.match_current(TokenKind::While) {
-            return self.while_statement();
+            return self.while_statement(None);
         }
         if self.match_current(TokenKind::LeftBrace) {
             return self.block_statement();
@@ -184,23 +246,78 @@ impl<'a> Parser<'a> {
         self.expression_statement()
     }
 
+    fn labeled_statement(&mut self) -> ParseResult<Stmt<'a>> {
+        let label = self.advance();
+        self.advance();
+
+        if self.loop_with_label(label.lexeme).is_some() {
+            self.error(Some(label), "Label already used by an enclosing loop.");
+        }
+
+        if self.match_current(TokenKind::For) {
+            return self.for_statement(Some(label));
+        }
+        if self.match_current(TokenKind::While) {
+            return self.while_statement(Some(label));
+        }
+        if self.match_current(TokenKind::Loop) {
+            return self.loop_statement(Some(label));
+        }
+
+        self.error(self.peek(), "Expect loop after label.");
+        Err(())
+    }
+
+    fn loop_body(&mut self, label: Option<&'a Token<'a>>) -> ParseResult<&'a Stmt<'a>> {
+        self.loops.push(EnclosingLoop {
+            label: label.map(|label| label.lexeme),
+            is_expression: false,
+        });
+        let body = self.statement();
+        self.loops.pop();
+        Ok(self.nodes.stmt(body?))
+    }
+
+    // `print` is a native, but with the compatibility flag on, a statement
+    // starting with `print` is still the print statement, parentheses or
+    // not. Only `--no-print-statement` makes `print(...);` call the native.
+    fn is_print_statement(&self) -> bool {
+        self.allow_print_statement
+            && matches!(
+                self.peek(),
+                Some(Token {
+                    kind: TokenKind::Identifier,
+                    lexeme: "print",
+                    ..
+                })
+            )
+    }
+
     fn var_declaration(&mut self) -> ParseResult<Stmt<'a>> {
-        let name = self.consume(TokenKind::Identifier, "Expect variable name.")?;
+        let mut declarators = Vec::new();
+        loop {
+            let name = self.consume(TokenKind::Identifier, "Expect variable name.")?;
 
-        let initializer = if self.match_current(TokenKind::Equal) {
-            Some(self.expression()?)
-        } else {
-            None
-        };
+            let initializer = if self.match_current(TokenKind::Equal) {
+                Some(self.assignment()?)
+            } else {
+                None
+            };
+
+            declarators.push(stmt::Declarator { name, initializer });
+            if !self.match_current(TokenKind::Comma) {
+                break;
+            }
+        }
 
         self.consume(
             TokenKind::Semicolon,
             "Expect ';' after variable declaration.",
         )?;
-        Ok(Stmt::Var(stmt::Var { name, initializer }))
+        Ok(Stmt::Var(stmt::Var { declarators }))
     }
 
-    fn for_statement(&mut self) -> ParseResult<Stmt<'a>> {
+    fn for_statement(&mut self, label: Option<&'a Token<'a>>) -> ParseResult<Stmt<'a>> {
         self.consume(TokenKind::LeftParen, "Expect '(' after 'for'.")?;
 
         let initializer = if self.match_current(TokenKind::Semicolon) {
@@ -225,13 +342,11 @@ impl<'a> Parser<'a> {
         };
         self.consume(TokenKind::RightParen, "Expect ')' after for clauses")?;
 
-        let enclosing_loop = self.loop_kind;
-        self.loop_kind = Loop::For;
-        let body = Box::from(self.statement()?);
-        self.loop_kind = enclosing_loop;
+        let body = self.loop_body(label)?;
 
         Ok(Stmt::For(stmt::For {
-            initializer: initializer.map(|stmt| Box::from(stmt)),
+            label,
+            initializer: initializer.map(|initializer| self.nodes.stmt(initializer)),
             condition,
             increment,
             body,
@@ -243,9 +358,9 @@ impl<'a> Parser<'a> {
         let condition = self.expression()?;
         self.consume(TokenKind::RightParen, "Expect ')' after condition.")?;
 
-        let then_branch = Box::from(self.statement()?);
+        let then_branch = self.nodes.stmt(self.statement()?);
         let else_branch = if self.match_current(TokenKind::Else) {
-            Some(Box::from(self.statement()?))
+            Some(self.nodes.stmt(self.statement()?))
         } else {
             None
         };
@@ -281,17 +396,28 @@ impl<'a> Parser<'a> {
         Ok(Stmt::Return(stmt::Return { keyword, value }))
     }
 
-    fn while_statement(&mut self) -> ParseResult<Stmt<'a>> {
+    fn loop_statement(&mut self, label: Option<&'a Token<'a>>) -> ParseResult<Stmt<'a>> {
+        if !self.check(TokenKind::LeftBrace) {
+            self.error(self.peek(), "Expect '{' after 'loop'.");
+            return Err(());
+        }
+        let body = self.loop_body(label)?;
+
+        Ok(Stmt::Loop(stmt::Loop { label, body }))
+    }
+
+    fn while_statement(&mut self, label: Option<&'a Token<'a>>) -> ParseResult<Stmt<'a>> {
         self.consume(TokenKind::LeftParen, "Expect '(' after 'while'.")?;
         let condition = self.expression()?;
         self.consume(TokenKind::RightParen, "Expect ')' after condition.")?;
 
-        let enclosing_loop = self.loop_kind;
-        self.loop_kind = Loop::While;
-        let body = Box::from(self.statement()?);
-        self.loop_kind = enclosing_loop;
+        let body = self.loop_body(label)?;
 
-        Ok(Stmt::While(stmt::While { condition, body }))
+        Ok(Stmt::While(stmt::While {
+            label,
+            condition,
+            body,
+        }))
     }
 
     fn block(&mut self) -> ParseResult<Vec<Stmt<'a>>> {
@@ -313,23 +439,77 @@ impl<'a> Parser<'a> {
     }
 
     fn break_statement(&mut self) -> ParseResult<Stmt<'a>> {
-        if self.loop_kind == Loop::None {
-            self.error(self.previous(), "Unexpected 'break' statement.");
+        let keyword = self.previous().unwrap();
+        if self.loops.is_empty() {
+            self.error(Some(keyword), "Unexpected 'break' statement.");
+        }
+        // `break name;` names a loop when one has that label, or when the loop
+        // it would otherwise leave can't take a value.
+        let label = match self.peek() {
+            Some(token)
+                if token.kind == TokenKind::Identifier
+                    && (self.loop_with_label(token.lexeme).is_some()
+                        || !self.loops.last().is_some_and(|target| target.is_expression)) =>
+            {
+                self.loop_label()?
+            }
+            _ => None,
+        };
+        let value = if self.check(TokenKind::Semicolon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        let target = match label {
+            Some(label) => self.loop_with_label(label.lexeme),
+            None => self.loops.len().checked_sub(1),
+        };
+        if value.is_some() && target.is_some_and(|target| !self.loops[target].is_expression) {
+            self.error(
+                Some(keyword),
+                "Can't break with a value out of a loop statement.",
+            );
         }
         self.consume(TokenKind::Semicolon, "Expect ';' after 'break'.")?;
         Ok(Stmt::Break(stmt::Break {
-            keyword: self.previous().unwrap(),
+            keyword,
+            label,
+            value,
         }))
     }
 
     fn continue_statement(&mut self) -> ParseResult<Stmt<'a>> {
-        if self.loop_kind == Loop::None {
-            self.error(self.previous(), "Unexpected 'continue' statement.");
+        let keyword = self.previous().unwrap();
+        if self.loops.is_empty() {
+            self.error(Some(keyword), "Unexpected 'continue' statement.");
         }
+        let label = self.loop_label()?;
         self.consume(TokenKind::Semicolon, "Expect ';' after 'continue'.")?;
-        Ok(Stmt::Continue(stmt::Continue {
-            keyword: self.previous().unwrap(),
-        }))
+        Ok(Stmt::Continue(stmt::Continue { keyword, label }))
+    }
+
+    fn loop_label(&mut self) -> ParseResult<Option<&'a Token<'a>>> {
+        if !self.match_current(TokenKind::Identifier) {
+            return Ok(None);
+        }
+
+        let label = self.previous().unwrap();
+        match self.loop_with_label(label.lexeme) {
+            None if !self.loops.is_empty() => self.error(Some(label), "Undefined loop label."),
+            // Jumping past a loop expression would leave its operands on the
+            // stack.
+            Some(target) if self.loops[target + 1..].iter().any(|l| l.is_expression) => {
+                self.error(Some(label), "Can't jump out of a loop expression.")
+            }
+            _ => (),
+        }
+        Ok(Some(label))
+    }
+
+    fn loop_with_label(&self, label: &str) -> Option<usize> {
+        self.loops
+            .iter()
+            .rposition(|enclosing| enclosing.label == Some(label))
     }
 
     fn expression_statement(&mut self) -> ParseResult<Stmt<'a>> {
@@ -339,7 +519,23 @@ impl<'a> Parser<'a> {
     }
 
     fn expression(&mut self) -> ParseResult<Expr<'a>> {
-        self.assignment()
+        self.comma()
+    }
+
+    // Binds looser than assignment. Lists that are separated by commas, like
+    // arguments and match arms, parse their items with `assignment`.
+    fn comma(&mut self) -> ParseResult<Expr<'a>> {
+        let mut expr = self.assignment()?;
+
+        while self.match_current(TokenKind::Comma) {
+            let right = self.assignment()?;
+            expr = Expr::Comma(expr::Comma {
+                left: self.nodes.expr(expr),
+                right: self.nodes.expr(right),
+            });
+        }
+
+        Ok(expr)
     }
 
     fn assignment(&mut self) -> ParseResult<Expr<'a>> {
@@ -349,14 +545,49 @@ impl<'a> Parser<'a> {
             let equals = self.previous().unwrap();
             let value = self.assignment()?;
 
-            if let Expr::Variable(expr::Variable { name, .. }) = expr {
-                return Ok(Expr::Assign(expr::Assign {
-                    name: name,
-                    value: Box::from(value),
-                }));
+            match expr {
+                Expr::Variable(expr::Variable { name, .. }) => {
+                    return Ok(Expr::Assign(expr::Assign {
+                        name,
+                        value: self.nodes.expr(value),
+                    }));
+                }
+                Expr::Get(expr::Get { object, name }) => {
+                    return Ok(Expr::Set(expr::Set {
+                        object,
+                        name,
+                        value: self.nodes.expr(value),
+                    }));
+                }
+                _ => (),
             }
 
             self.error(Some(equals), "Invalid assignment target.");
+        } else if self.match_current(TokenKind::AndEqual) || self.match_current(TokenKind::OrEqual)
+        {
+            let operator = self.previous().unwrap();
+            let value = self.assignment()?;
+
+            match expr {
+                Expr::Variable(expr::Variable { name, .. }) => {
+                    return Ok(Expr::LogicalAssign(expr::LogicalAssign {
+                        name,
+                        operator,
+                        value: self.nodes.expr(value),
+                    }));
+                }
+                Expr::Get(expr::Get { object, name }) => {
+                    return Ok(Expr::LogicalSet(expr::LogicalSet {
+                        object,
+                        name,
+                        operator,
+                        value: self.nodes.expr(value),
+                    }));
+                }
+                _ => (),
+            }
+
+            self.error(Some(operator), "Invalid assignment target.");
         }
 
         Ok(expr)
@@ -369,9 +600,9 @@ impl<'a> Parser<'a> {
             let operator = self.previous().unwrap();
             let right = self.and()?;
             expr = Expr::Logical(expr::Logical {
-                left: Box::from(expr),
+                left: self.nodes.expr(expr),
                 operator,
-                right: Box::from(right),
+                right: self.nodes.expr(right),
             })
         }
 
@@ -385,9 +616,9 @@ impl<'a> Parser<'a> {
             let operator = self.previous().unwrap();
             let right = self.and()?;
             expr = Expr::Logical(expr::Logical {
-                left: Box::from(expr),
+                left: self.nodes.expr(expr),
                 operator,
-                right: Box::from(right),
+                right: self.nodes.expr(right),
             })
         }
 
@@ -397,12 +628,14 @@ impl<'a> Parser<'a> {
     fn equality(&mut self) -> ParseResult<Expr<'a>> {
         let mut expr = self.comparison()?;
 
-        while self.match_current(TokenKind::EqualEqual) || self.match_current(TokenKind::BangEqual)
+        while self.match_current(TokenKind::EqualEqual)
+            || self.match_current(TokenKind::BangEqual)
+            || self.match_current(TokenKind::Is)
         {
             let operator = self.previous().unwrap();
-            let right = Box::from(self.equality()?);
+            let right = self.nodes.expr(self.equality()?);
             expr = Expr::Binary(expr::Binary {
-                left: Box::from(expr),
+                left: self.nodes.expr(expr),
                 operator,
                 right,
             })
@@ -412,17 +645,61 @@ impl<'a> Parser<'a> {
     }
 
     fn comparison(&mut self) -> ParseResult<Expr<'a>> {
-        let mut expr = self.term()?;
+        let mut operands = vec![self.union()?];
+        let mut operators = Vec::new();
 
         while self.match_current(TokenKind::Greater)
             || self.match_current(TokenKind::GreaterEqual)
             || self.match_current(TokenKind::Less)
             || self.match_current(TokenKind::LessEqual)
         {
+            operators.push(self.previous().unwrap());
+            operands.push(self.union()?);
+        }
+
+        if operators.len() > 1 {
+            return Ok(Expr::ComparisonChain(expr::ComparisonChain {
+                operands,
+                operators,
+            }));
+        }
+
+        let mut operands = operands.into_iter();
+        let left = operands.next().unwrap();
+        Ok(match (operators.pop(), operands.next()) {
+            (Some(operator), Some(right)) => Expr::Binary(expr::Binary {
+                left: self.nodes.expr(left),
+                operator,
+                right: self.nodes.expr(right),
+            }),
+            _ => left,
+        })
+    }
+
+    fn union(&mut self) -> ParseResult<Expr<'a>> {
+        let mut expr = self.intersection()?;
+
+        while self.match_current(TokenKind::Pipe) {
             let operator = self.previous().unwrap();
-            let right = Box::from(self.term()?);
+            let right = self.nodes.expr(self.intersection()?);
             expr = Expr::Binary(expr::Binary {
-                left: Box::from(expr),
+                left: self.nodes.expr(expr),
+                operator,
+                right,
+            })
+        }
+
+        Ok(expr)
+    }
+
+    fn intersection(&mut self) -> ParseResult<Expr<'a>> {
+        let mut expr = self.term()?;
+
+        while self.match_current(TokenKind::Ampersand) {
+            let operator = self.previous().unwrap();
+            let right = self.nodes.expr(self.term()?);
+            expr = Expr::Binary(expr::Binary {
+                left: self.nodes.expr(expr),
                 operator,
                 right,
             })
@@ -438,9 +715,9 @@ impl<'a> Parser<'a> {
             let operator = self.previous().unwrap();
             let right = self.factor()?;
             expr = Expr::Binary(expr::Binary {
-                left: Box::from(expr),
+                left: self.nodes.expr(expr),
                 operator,
-                right: Box::from(right),
+                right: self.nodes.expr(right),
             })
         }
 
@@ -454,9 +731,9 @@ impl<'a> Parser<'a> {
             let operator = self.previous().unwrap();
             let right = self.unary()?;
             expr = Expr::Binary(expr::Binary {
-                left: Box::from(expr),
+                left: self.nodes.expr(expr),
                 operator,
-                right: Box::from(right),
+                right: self.nodes.expr(right),
             })
         }
 
@@ -464,12 +741,15 @@ impl<'a> Parser<'a> {
     }
 
     fn unary(&mut self) -> ParseResult<Expr<'a>> {
-        if self.match_current(TokenKind::Bang) || self.match_current(TokenKind::Minus) {
+        if self.match_current(TokenKind::Bang)
+            || self.match_current(TokenKind::Minus)
+            || self.match_current(TokenKind::Type)
+        {
             let operator = self.previous().unwrap();
             let right = self.unary()?;
             return Ok(Expr::Unary(expr::Unary {
                 operator,
-                right: Box::from(right),
+                right: self.nodes.expr(right),
             }));
         }
 
@@ -478,6 +758,7 @@ impl<'a> Parser<'a> {
 
     fn finish_call(&mut self, callee: Expr<'a>) -> ParseResult<Expr<'a>> {
         let mut args: Vec<Expr<'a>> = Vec::new();
+        let mut keywords: Vec<&'a Token<'a>> = Vec::new();
 
         if !self.check(TokenKind::RightParen) {
             loop {
@@ -485,7 +766,20 @@ impl<'a> Parser<'a> {
                     self.error(self.peek(), "Can't have more than 255 arguments.")
                 }
 
-                args.push(self.expression()?);
+                if self.check(TokenKind::Identifier) && self.check_next(TokenKind::Colon) {
+                    let name = self.advance();
+                    self.advance();
+                    if keywords.iter().any(|keyword| keyword.lexeme == name.lexeme) {
+                        self.error(Some(name), "Duplicate keyword argument.");
+                    }
+                    keywords.push(name);
+                } else if !keywords.is_empty() {
+                    self.error(
+                        self.peek(),
+                        "Positional argument can't follow a keyword argument.",
+                    );
+                }
+                args.push(self.assignment()?);
                 if !self.match_current(TokenKind::Comma) {
                     break;
                 }
@@ -495,9 +789,10 @@ impl<'a> Parser<'a> {
         let paren = self.consume(TokenKind::RightParen, "Expect ')' after args.")?;
 
         Ok(Expr::Call(expr::Call {
-            callee: Box::from(callee),
+            callee: self.nodes.expr(callee),
             paren,
             args,
+            keywords,
         }))
     }
 
@@ -507,6 +802,22 @@ impl<'a> Parser<'a> {
         loop {
             if self.match_current(TokenKind::LeftParen) {
                 expr = self.finish_call(expr)?;
+            } else if self.match_current(TokenKind::Dot) {
+                let name =
+                    self.consume(TokenKind::Identifier, "Expect property name after '.'.")?;
+                expr = Expr::Get(expr::Get {
+                    object: self.nodes.expr(expr),
+                    name,
+                });
+            } else if self.match_current(TokenKind::LeftBracket) {
+                let bracket = self.previous().unwrap();
+                let index = self.expression()?;
+                self.consume(TokenKind::RightBracket, "Expect ']' after index.")?;
+                expr = Expr::Index(expr::Index {
+                    object: self.nodes.expr(expr),
+                    bracket,
+                    index: self.nodes.expr(index),
+                });
             } else {
                 break;
             }
@@ -522,7 +833,8 @@ impl<'a> Parser<'a> {
                 | TokenKind::True
                 | TokenKind::Nil
                 | TokenKind::Number
-                | TokenKind::String => {
+                | TokenKind::String
+                | TokenKind::Symbol => {
                     self.advance();
                     return Ok(Expr::Literal(expr::Literal { value: token }));
                 }
@@ -530,23 +842,236 @@ impl<'a> Parser<'a> {
             }
         }
 
-        if self.match_current(TokenKind::Identifier) {
+        // `this` and `super` parse as variables so the resolver can say why
+        // they can't be used.
+        if self.match_current(TokenKind::Identifier)
+            || self.match_current(TokenKind::This)
+            || self.match_current(TokenKind::Super)
+        {
             return Ok(Expr::Variable(expr::Variable {
                 name: self.previous().unwrap(),
             }));
         }
 
+        if self.match_current(TokenKind::Match) {
+            return self.match_expression();
+        }
+
+        if self.match_current(TokenKind::If) {
+            return self.if_expression();
+        }
+
         if self.match_current(TokenKind::LeftParen) {
-            let expr = Box::from(self.expression()?);
+            let expr = self.expression()?;
             self.consume(TokenKind::RightParen, "Expect ')' after expression")?;
-            return Ok(Expr::Grouping(expr::Grouping { expr }));
+            return Ok(Expr::Grouping(expr::Grouping {
+                expr: self.nodes.expr(expr),
+            }));
+        }
+
+        if self.match_current(TokenKind::HashBrace) {
+            return self.set_literal();
         }
 
-        self.error(self.peek(), "Expected expression.");
+        if self.match_current(TokenKind::HashParen) {
+            return self.tuple_literal();
+        }
+
+        // An object literal is `{}` or starts with a field, so blocks in
+        // expression position stay errors. A statement that starts with `{`
+        // is still a block.
+        if self.check(TokenKind::LeftBrace) && self.check_next(TokenKind::RightBrace) {
+            self.advance();
+            return self.object_literal();
+        }
+        if self.check(TokenKind::LeftBrace)
+            && self.check_next(TokenKind::Identifier)
+            && matches!(
+                self.tokens.get(self.current + 2),
+                Some(Token {
+                    kind: TokenKind::Colon,
+                    ..
+                })
+            )
+        {
+            self.advance();
+            return self.object_literal();
+        }
+
+        if self.match_current(TokenKind::Loop) {
+            return self.loop_expression();
+        }
+
+        self.error(self.peek(), "Expect expression.");
+        Err(())
+    }
+
+    // `#(a, b)`. The `#` keeps `(a, b)` the comma operator.
+    fn tuple_literal(&mut self) -> ParseResult<Expr<'a>> {
+        let paren = self.previous().unwrap();
+        let mut elements: Vec<Expr<'a>> = Vec::new();
+        while !self.check(TokenKind::RightParen) {
+            if elements.len() >= 255 {
+                self.error(self.peek(), "Can't have more than 255 elements in a tuple.");
+            }
+            elements.push(self.assignment()?);
+            if !self.match_current(TokenKind::Comma) {
+                break;
+            }
+        }
+
+        self.consume(TokenKind::RightParen, "Expect ')' after tuple elements.")?;
+        Ok(Expr::Tuple(expr::Tuple { paren, elements }))
+    }
+
+    fn loop_expression(&mut self) -> ParseResult<Expr<'a>> {
+        let keyword = self.previous().unwrap();
+        if !self.check(TokenKind::LeftBrace) {
+            self.error(self.peek(), "Expect '{' after 'loop'.");
+            return Err(());
+        }
+        self.loops.push(EnclosingLoop {
+            label: None,
+            is_expression: true,
+        });
+        let body = self.statement();
+        self.loops.pop();
+
+        Ok(Expr::Loop(expr::Loop {
+            keyword,
+            body: self.nodes.stmt(body?),
+        }))
+    }
+
+    fn object_literal(&mut self) -> ParseResult<Expr<'a>> {
+        let brace = self.previous().unwrap();
+        let mut names: Vec<&'a Token<'a>> = Vec::new();
+        let mut values: Vec<Expr<'a>> = Vec::new();
+        while !self.check(TokenKind::RightBrace) {
+            if names.len() >= 255 {
+                self.error(self.peek(), "Can't have more than 255 fields in an object.");
+            }
+            let name = self.consume(TokenKind::Identifier, "Expect field name.")?;
+            if names.iter().any(|other| other.lexeme == name.lexeme) {
+                self.error(Some(name), "Duplicate field in object literal.");
+            }
+            self.consume(TokenKind::Colon, "Expect ':' after field name.")?;
+            names.push(name);
+            values.push(self.assignment()?);
+            if !self.match_current(TokenKind::Comma) || self.check(TokenKind::RightBrace) {
+                break;
+            }
+        }
+
+        self.consume(TokenKind::RightBrace, "Expect '}' after object fields.")?;
+        Ok(Expr::Object(expr::Object {
+            brace,
+            names,
+            values,
+        }))
+    }
+
+    fn set_literal(&mut self) -> ParseResult<Expr<'a>> {
+        let brace = self.previous().unwrap();
+        let mut elements: Vec<Expr<'a>> = Vec::new();
+        while !self.check(TokenKind::RightBrace) {
+            if elements.len() >= 255 {
+                self.error(self.peek(), "Can't have more than 255 elements in a set.");
+            }
+            elements.push(self.assignment()?);
+            if !self.match_current(TokenKind::Comma) {
+                break;
+            }
+        }
+
+        self.consume(TokenKind::RightBrace, "Expect '}' after set elements.")?;
+        Ok(Expr::SetLiteral(expr::SetLiteral { brace, elements }))
+    }
+
+    fn match_expression(&mut self) -> ParseResult<Expr<'a>> {
+        let keyword = self.previous().unwrap();
+        let subject = self.nodes.expr(self.expression()?);
+        self.consume(TokenKind::LeftBrace, "Expect '{' after match value.")?;
+
+        let mut arms: Vec<expr::MatchArm<'a>> = Vec::new();
+        while !self.is_at_end() && !self.check(TokenKind::RightBrace) {
+            let pattern = self.pattern()?;
+            self.consume(TokenKind::Arrow, "Expect '=>' after pattern.")?;
+            let body = self.assignment()?;
+            arms.push(expr::MatchArm { pattern, body });
+
+            if !self.match_current(TokenKind::Comma) {
+                break;
+            }
+        }
+
+        self.consume(TokenKind::RightBrace, "Expect '}' after match arms.")?;
+        Ok(Expr::Match(expr::Match {
+            keyword,
+            subject,
+            arms,
+        }))
+    }
+
+    fn if_expression(&mut self) -> ParseResult<Expr<'a>> {
+        let keyword = self.previous().unwrap();
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'if'.")?;
+        let condition = self.nodes.expr(self.expression()?);
+        self.consume(TokenKind::RightParen, "Expect ')' after condition.")?;
+
+        let then_branch = self.nodes.expr(self.assignment()?);
+        self.consume(TokenKind::Else, "Expect 'else' after if expression branch.")?;
+        let else_branch = self.nodes.expr(self.assignment()?);
+
+        Ok(Expr::If(expr::If {
+            keyword,
+            condition,
+            then_branch,
+            else_branch,
+        }))
+    }
+
+    fn pattern(&mut self) -> ParseResult<expr::Pattern<'a>> {
+        if self.match_current(TokenKind::Else) {
+            return Ok(expr::Pattern::Else(self.previous().unwrap()));
+        }
+
+        if self.match_current(TokenKind::Identifier) {
+            return Ok(expr::Pattern::Binding(self.previous().unwrap()));
+        }
+
+        if self.match_current(TokenKind::Minus) {
+            let operator = self.previous().unwrap();
+            let value = self.consume(TokenKind::Number, "Expect number after '-' in pattern.")?;
+            return Ok(expr::Pattern::Value(Expr::Unary(expr::Unary {
+                operator,
+                right: self.nodes.expr(Expr::Literal(expr::Literal { value })),
+            })));
+        }
+
+        if let Some(token) = self.peek() {
+            match token.kind {
+                TokenKind::False
+                | TokenKind::True
+                | TokenKind::Nil
+                | TokenKind::Number
+                | TokenKind::String
+                | TokenKind::Symbol => {
+                    self.advance();
+                    return Ok(expr::Pattern::Value(Expr::Literal(expr::Literal {
+                        value: token,
+                    })));
+                }
+                _ => (),
+            }
+        }
+
+        self.error(self.peek(), "Expect pattern.");
         Err(())
     }
 
     fn synchronize(&mut self) {
+        self.panic_mode = false;
         self.advance();
 
         while !self.is_at_end() {
@@ -561,8 +1086,9 @@ impl<'a> Parser<'a> {
                 | TokenKind::For
                 | TokenKind::If
                 | TokenKind::While
-                | TokenKind::Print
+                | TokenKind::Loop
                 | TokenKind::Return => return,
+                _ if self.is_print_statement() => return,
                 _ => (),
             }
 
@@ -571,8 +1097,13 @@ impl<'a> Parser<'a> {
     }
 }
 
-pub fn parse_tokens<'a>(tokens: &'a Vec<Token<'a>>) -> Option<Vec<Stmt<'a>>> {
-    let mut parser = Parser::new(tokens);
+pub fn parse_tokens<'a>(
+    source: &'a str,
+    tokens: &'a Vec<Token<'a>>,
+    nodes: &'a Nodes<'a>,
+    allow_print_statement: bool,
+) -> Option<Vec<Stmt<'a>>> {
+    let mut parser = Parser::new(source, tokens, nodes, allow_print_statement);
     let mut statements: Vec<Stmt<'a>> = Default::default();
     while !parser.is_at_end() {
         match parser.declaration() {
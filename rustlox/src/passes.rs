@@ -0,0 +1,292 @@
+use crate::chunk::{Chunk, Lines, Op, Position};
+use crate::names::Names;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+
+pub struct Pass {
+    pub name: &'static str,
+    pub run: fn(&mut Chunk),
+}
+
+// Bytecode passes run in order over every chunk once its function has been
+// compiled.
+const PASSES: &[Pass] = &[
+    Pass {
+        name: "thread-jumps",
+        run: thread_jumps,
+    },
+    Pass {
+        name: "fuse",
+        run: fuse,
+    },
+];
+
+// With `dump_ir`, prints the code at each stage: `raw`, as compiled without
+// folding constants, then folded, then after each pass.
+pub fn run(chunk: &mut Chunk, name: &str, dump_ir: bool, raw: Option<&str>, names: &Names) {
+    let mut previous = String::new();
+    if dump_ir {
+        previous = chunk.disassembly(names);
+        let raw = raw.unwrap_or(&previous);
+        println!("== {} [raw] ==", name);
+        print!("{}", raw);
+        println!("== {} [fold] ==", name);
+        print_diff(raw, &previous);
+    }
+
+    for pass in PASSES {
+        (pass.run)(chunk);
+
+        if dump_ir {
+            let current = chunk.disassembly(names);
+            println!("== {} [{}] ==", name, pass.name);
+            print_diff(&previous, &current);
+            previous = current;
+        }
+    }
+}
+
+// A superinstruction's operands are the operands of the sequence it replaces,
+// in order. If the sequence ends in a jump, so does the superinstruction.
+struct Fusion {
+    sequence: &'static [u8],
+    fused: u8,
+    // Operands appended after the sequence's own, for members like `One`
+    // that carry their operand in the opcode.
+    operands: &'static [u8],
+}
+
+const FUSIONS: &[Fusion] = &[
+    Fusion {
+        sequence: &[Op::GetLocal as u8, Op::Constant as u8, Op::Add as u8],
+        fused: Op::AddLocalConstant as u8,
+        operands: &[],
+    },
+    Fusion {
+        sequence: &[Op::GetLocal as u8, Op::SmallInt as u8, Op::Add as u8],
+        fused: Op::AddLocalSmallInt as u8,
+        operands: &[],
+    },
+    Fusion {
+        sequence: &[Op::GetLocal as u8, Op::One as u8, Op::Add as u8],
+        fused: Op::AddLocalSmallInt as u8,
+        operands: &[1],
+    },
+    Fusion {
+        sequence: &[Op::GetLocal as u8, Op::MinusOne as u8, Op::Add as u8],
+        fused: Op::AddLocalSmallInt as u8,
+        operands: &[-1i8 as u8],
+    },
+    Fusion {
+        sequence: &[
+            Op::GetLocal as u8,
+            Op::GetLocal as u8,
+            Op::LessJumpIfFalse as u8,
+        ],
+        fused: Op::LessLocalsJumpIfFalse as u8,
+        operands: &[],
+    },
+];
+
+// Where a jump lands, and how wide its offset is, if `offset` holds one.
+fn jump(chunk: &Chunk, offset: usize) -> Option<(usize, usize)> {
+    let code = &chunk.code;
+    let short = || u16::from_be_bytes([code[offset + 1], code[offset + 2]]) as usize;
+    let long = || u32::from_be_bytes(code[offset + 1..offset + 5].try_into().unwrap()) as usize;
+    match code[offset].try_into() {
+        Ok(
+            Op::Jump
+            | Op::JumpIfFalse
+            | Op::JumpIfTrue
+            | Op::LessJumpIfFalse
+            | Op::LessJumpIfTrue
+            | Op::GreaterJumpIfFalse
+            | Op::GreaterJumpIfTrue
+            | Op::EqualJumpIfFalse
+            | Op::EqualJumpIfTrue,
+        ) => Some((offset + 3 + short(), 2)),
+        Ok(Op::Loop) => Some((offset + 3 - short(), 2)),
+        Ok(Op::JumpLong | Op::JumpIfFalseLong | Op::JumpIfTrueLong) => {
+            Some((offset + 5 + long(), 4))
+        }
+        Ok(Op::LoopLong) => Some((offset + 5 - long(), 4)),
+        _ => None,
+    }
+}
+
+// Fusing only shrinks the code, so every jump offset still fits in its
+// original width once it's recomputed.
+fn instruction_starts(chunk: &Chunk) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        starts.push(offset);
+        offset = chunk.next_instruction(offset);
+    }
+    starts
+}
+
+// Points a forward jump that lands on another jump at wherever that one goes.
+// A conditional jump leaves its condition on the stack, so one landing on
+// another jump on the same condition is known to take it too. The rest of the
+// code stays the same, so only jumps whose new offset still fits are
+// rewritten. A comparison jump pops its operands, so it can only be threaded
+// through unconditional jumps.
+fn thread_jumps(chunk: &mut Chunk) {
+    const UNCONDITIONAL: &[u8] = &[Op::Jump as u8, Op::JumpLong as u8];
+    const IF_FALSE: &[u8] = &[Op::JumpIfFalse as u8, Op::JumpIfFalseLong as u8];
+    const IF_TRUE: &[u8] = &[Op::JumpIfTrue as u8, Op::JumpIfTrueLong as u8];
+    const COMPARISONS: &[u8] = &[
+        Op::LessJumpIfFalse as u8,
+        Op::LessJumpIfTrue as u8,
+        Op::GreaterJumpIfFalse as u8,
+        Op::GreaterJumpIfTrue as u8,
+        Op::EqualJumpIfFalse as u8,
+        Op::EqualJumpIfTrue as u8,
+    ];
+
+    for start in instruction_starts(chunk) {
+        let conditional = [IF_FALSE, IF_TRUE]
+            .iter()
+            .find(|jumps| jumps.contains(&chunk.code[start]));
+        if conditional.is_none()
+            && !UNCONDITIONAL.contains(&chunk.code[start])
+            && !COMPARISONS.contains(&chunk.code[start])
+        {
+            continue;
+        }
+        let (target, width) = jump(chunk, start).unwrap();
+        let end = start + 1 + width;
+
+        let mut destination = target;
+        while destination < chunk.code.len()
+            && (UNCONDITIONAL.contains(&chunk.code[destination])
+                || conditional.is_some_and(|jumps| jumps.contains(&chunk.code[destination])))
+        {
+            match jump(chunk, destination) {
+                // Forward only, which also rules out cycles.
+                Some((next, _)) if next > destination => destination = next,
+                _ => break,
+            }
+        }
+
+        let bytes = ((destination - end) as u32).to_be_bytes();
+        if destination != target && bytes[..4 - width].iter().all(|&byte| byte == 0) {
+            chunk.code[start + 1..end].copy_from_slice(&bytes[4 - width..]);
+        }
+    }
+}
+
+fn fuse(chunk: &mut Chunk) {
+    let starts = instruction_starts(chunk);
+    // A sequence can't be fused if something jumps into the middle of it.
+    let targets: HashSet<usize> = starts
+        .iter()
+        .filter_map(|&start| Some(jump(chunk, start)?.0))
+        .collect();
+
+    let old_lines: Vec<Position> = chunk.lines.iter().collect();
+    let mut code = Vec::with_capacity(chunk.code.len());
+    let mut lines = Lines::default();
+    // The new offset of every instruction, and of the end of the code.
+    let mut moved = HashMap::new();
+    // The new offset of each jump, the end of its instruction, and where it
+    // used to land.
+    let mut jumps = Vec::new();
+
+    let mut i = 0;
+    while i < starts.len() {
+        let fusion = FUSIONS.iter().find(|fusion| {
+            let length = fusion.sequence.len();
+            i + length <= starts.len()
+                && fusion
+                    .sequence
+                    .iter()
+                    .zip(&starts[i..])
+                    .all(|(&op, &start)| chunk.code[start] == op)
+                && starts[i + 1..i + length]
+                    .iter()
+                    .all(|start| !targets.contains(start))
+        });
+        let length = fusion.map_or(1, |fusion| fusion.sequence.len());
+        let start = starts[i];
+        let last = starts[i + length - 1];
+        let end = chunk.next_instruction(last);
+        moved.insert(start, code.len());
+
+        match fusion {
+            Some(fusion) => {
+                code.push(fusion.fused);
+                for &member in &starts[i..i + length] {
+                    code.extend_from_slice(&chunk.code[member + 1..chunk.next_instruction(member)]);
+                }
+                code.extend_from_slice(fusion.operands);
+                // Errors are reported at the position of the sequence's last
+                // instruction, which is the one that can fail.
+                for _ in 0..code.len() - moved[&start] {
+                    lines.push(old_lines[last]);
+                }
+            }
+            None => {
+                code.extend_from_slice(&chunk.code[start..end]);
+                for &position in &old_lines[start..end] {
+                    lines.push(position);
+                }
+            }
+        }
+        if let Some((target, width)) = jump(chunk, last) {
+            jumps.push((code.len() - width, width, target));
+        }
+        i += length;
+    }
+    moved.insert(chunk.code.len(), code.len());
+
+    for (operand, width, target) in jumps {
+        let end = operand + width;
+        let distance = moved[&target].max(end) - moved[&target].min(end);
+        let bytes = (distance as u32).to_be_bytes();
+        code[operand..end].copy_from_slice(&bytes[4 - width..]);
+    }
+    chunk.code = code;
+    chunk.lines = lines;
+}
+
+fn print_diff(before: &str, after: &str) {
+    if before == after {
+        println!("(no changes)");
+        return;
+    }
+
+    let before: Vec<&str> = before.lines().collect();
+    let after: Vec<&str> = after.lines().collect();
+    // Compare instructions without their offset and line columns, which shift
+    // whenever a pass changes the length of the code before them.
+    let same = |a: &str, b: &str| a.get(10..) == b.get(10..);
+
+    // lengths[i][j] is the longest common subsequence of before[i..] and after[j..].
+    let mut lengths = vec![vec![0usize; after.len() + 1]; before.len() + 1];
+    for i in (0..before.len()).rev() {
+        for j in (0..after.len()).rev() {
+            lengths[i][j] = if same(before[i], after[j]) {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < before.len() || j < after.len() {
+        if i < before.len() && j < after.len() && same(before[i], after[j]) {
+            println!("  {}", after[j]);
+            i += 1;
+            j += 1;
+        } else if j < after.len() && (i == before.len() || lengths[i][j + 1] >= lengths[i + 1][j]) {
+            println!("+ {}", after[j]);
+            j += 1;
+        } else {
+            println!("- {}", before[i]);
+            i += 1;
+        }
+    }
+}
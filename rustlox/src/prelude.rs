@@ -0,0 +1,73 @@
+use crate::compiler::{self, CompileOptions};
+use crate::names::Names;
+use crate::scanner::{self, TokenKind};
+use crate::value::{Function, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+
+const SOURCE: &str = include_str!("prelude.lox");
+
+// Shared by every compiled prelude, so its chunks can be told apart from a
+// script's.
+static SHARED_SOURCE: LazyLock<Arc<str>> = LazyLock::new(|| Arc::from(SOURCE));
+
+// What the prelude calls its own functions, so a script that declares a
+// global with the same name doesn't change what they call. No identifier
+// can be written like this.
+const PRIVATE_PREFIX: &str = "prelude.";
+
+// Functions written in Lox that every script starts with. Like the natives,
+// they're looked up the first time a script uses their name. The prelude is
+// compiled then, by the VM that needs it and with its names, and the VM
+// keeps the compiled functions.
+pub fn compile(table: &mut Names) -> HashMap<String, Arc<Function>> {
+    static PRIVATE_NAMES: LazyLock<Vec<String>> = LazyLock::new(|| {
+        names()
+            .map(|name| format!("{}{}", PRIVATE_PREFIX, name))
+            .collect()
+    });
+    let source = SHARED_SOURCE.clone();
+    let mut tokens = scanner::scan_tokens(&source);
+    for i in 1..tokens.len() {
+        if tokens[i].kind != TokenKind::Identifier || tokens[i - 1].kind == TokenKind::Fun {
+            continue;
+        }
+        if let Some(index) = names().position(|name| name == tokens[i].lexeme) {
+            tokens[i].lexeme = &PRIVATE_NAMES[index];
+        }
+    }
+    let script = compiler::compile_with(&source, tokens, &CompileOptions::default(), table, None)
+        .expect("The prelude should compile.");
+    script
+        .chunk
+        .constants
+        .iter()
+        .filter_map(|constant| match constant {
+            Value::Function(function) => Some((function.get_name().to_string(), function.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+// The names of the functions the prelude declares, found without compiling
+// it.
+pub fn names() -> impl Iterator<Item = &'static str> {
+    static NAMES: LazyLock<Vec<&'static str>> = LazyLock::new(|| {
+        let tokens = scanner::scan_tokens(SOURCE);
+        tokens
+            .windows(2)
+            .filter(|pair| pair[0].kind == TokenKind::Fun && pair[1].kind == TokenKind::Identifier)
+            .map(|pair| pair[1].lexeme)
+            .collect()
+    });
+    NAMES.iter().copied()
+}
+
+// The prelude function a private name refers to.
+pub fn private_name(name: &str) -> Option<&str> {
+    name.strip_prefix(PRIVATE_PREFIX)
+}
+
+pub fn is_prelude(source: &Arc<str>) -> bool {
+    Arc::ptr_eq(source, &SHARED_SOURCE)
+}
@@ -0,0 +1,250 @@
+use crate::expr::{Expr, Pattern};
+use crate::scanner::Token;
+use crate::stmt::Stmt;
+use std::fmt::Write;
+
+// Writes the tree `--dump-ast` prints: one S-expression per statement, with
+// the statements of blocks and function bodies on their own indented lines.
+// Expressions stay on one line. A missing clause of a `for` is written `_`.
+
+#[derive(Default)]
+struct Printer {
+    out: String,
+    indent: usize,
+}
+
+impl Printer {
+    fn line(&mut self, text: &str) {
+        writeln!(self.out, "{:indent$}{}", "", text, indent = self.indent * 2).unwrap();
+    }
+
+    // Closes the list on the last line written.
+    fn close(&mut self) {
+        self.out.pop();
+        self.out.push_str(")\n");
+    }
+
+    fn statements(&mut self, statements: &[Stmt]) {
+        self.indent += 1;
+        for statement in statements {
+            self.statement(statement);
+        }
+        self.indent -= 1;
+        self.close();
+    }
+
+    // Nested statements go on their own lines after `head`.
+    fn nested(&mut self, head: String, statements: &[&Stmt]) {
+        self.line(&head);
+        self.indent += 1;
+        for statement in statements {
+            self.statement(statement);
+        }
+        self.indent -= 1;
+        self.close();
+    }
+
+    fn statement(&mut self, statement: &Stmt) {
+        match statement {
+            Stmt::Block(stmt) => {
+                self.line("(block");
+                self.statements(&stmt.statements);
+            }
+            Stmt::Break(stmt) => {
+                let mut text = String::from("(break");
+                if let Some(label) = stmt.label {
+                    write!(text, " {}", label.lexeme).unwrap();
+                }
+                if let Some(value) = &stmt.value {
+                    write!(text, " {}", expression(value)).unwrap();
+                }
+                self.line(&(text + ")"));
+            }
+            Stmt::Continue(stmt) => match stmt.label {
+                Some(label) => self.line(&format!("(continue {})", label.lexeme)),
+                None => self.line("(continue)"),
+            },
+            Stmt::Expression(stmt) => self.line(&format!("(; {})", expression(&stmt.expression))),
+            Stmt::For(stmt) => {
+                self.line(&format!("({}for", label(stmt.label)));
+                self.indent += 1;
+                match stmt.initializer {
+                    Some(initializer) => self.statement(initializer),
+                    None => self.line("_"),
+                }
+                for clause in [&stmt.condition, &stmt.increment] {
+                    match clause {
+                        Some(clause) => self.line(&expression(clause)),
+                        None => self.line("_"),
+                    }
+                }
+                self.statement(stmt.body);
+                self.indent -= 1;
+                self.close();
+            }
+            Stmt::Function(stmt) => {
+                let params: Vec<&str> = stmt.params.iter().map(|param| param.lexeme).collect();
+                self.line(&format!("(fun {} ({})", stmt.name.lexeme, params.join(" ")));
+                self.statements(&stmt.body);
+            }
+            Stmt::If(stmt) => {
+                let head = format!("(if {}", expression(&stmt.condition));
+                match stmt.else_branch {
+                    Some(else_branch) => self.nested(head, &[stmt.then_branch, else_branch]),
+                    None => self.nested(head, &[stmt.then_branch]),
+                }
+            }
+            Stmt::Loop(stmt) => self.nested(format!("({}loop", label(stmt.label)), &[stmt.body]),
+            Stmt::Print(stmt) => self.line(&format!("(print {})", expression(&stmt.expression))),
+            Stmt::Return(stmt) => match &stmt.value {
+                Some(value) => self.line(&format!("(return {})", expression(value))),
+                None => self.line("(return)"),
+            },
+            Stmt::Var(stmt) => {
+                let declarators: Vec<String> = stmt
+                    .declarators
+                    .iter()
+                    .map(|declarator| match &declarator.initializer {
+                        Some(value) => {
+                            format!("({} {})", declarator.name.lexeme, expression(value))
+                        }
+                        None => format!("({})", declarator.name.lexeme),
+                    })
+                    .collect();
+                self.line(&format!("(var {})", declarators.join(" ")));
+            }
+            Stmt::While(stmt) => {
+                let head = format!(
+                    "({}while {}",
+                    label(stmt.label),
+                    expression(&stmt.condition)
+                );
+                self.nested(head, &[stmt.body]);
+            }
+        }
+    }
+}
+
+// A loop's label is written before the loop, as in the source.
+fn label(label: Option<&Token>) -> String {
+    match label {
+        Some(label) => format!("{}: ", label.lexeme),
+        None => String::new(),
+    }
+}
+
+fn list(head: &str, exprs: &[Expr]) -> String {
+    let mut text = format!("({}", head);
+    for expr in exprs {
+        write!(text, " {}", expression(expr)).unwrap();
+    }
+    text + ")"
+}
+
+fn expression(expr: &Expr) -> String {
+    match expr {
+        Expr::Assign(expr) => format!("(= {} {})", expr.name.lexeme, expression(expr.value)),
+        Expr::Binary(expr) => format!(
+            "({} {} {})",
+            expr.operator.lexeme,
+            expression(expr.left),
+            expression(expr.right)
+        ),
+        Expr::Call(expr) => {
+            // Keyword arguments are the last ones, written `name: value`.
+            let positional = expr.args.len() - expr.keywords.len();
+            let mut text = format!("(call {}", expression(expr.callee));
+            for arg in &expr.args[..positional] {
+                write!(text, " {}", expression(arg)).unwrap();
+            }
+            for (name, arg) in expr.keywords.iter().zip(&expr.args[positional..]) {
+                write!(text, " {}: {}", name.lexeme, expression(arg)).unwrap();
+            }
+            text + ")"
+        }
+        Expr::Comma(expr) => format!("(, {} {})", expression(expr.left), expression(expr.right)),
+        Expr::ComparisonChain(expr) => {
+            let mut text = format!("(chain {}", expression(&expr.operands[0]));
+            for (operator, operand) in expr.operators.iter().zip(&expr.operands[1..]) {
+                write!(text, " {} {}", operator.lexeme, expression(operand)).unwrap();
+            }
+            text + ")"
+        }
+        Expr::Get(expr) => format!("(. {} {})", expression(expr.object), expr.name.lexeme),
+        Expr::Grouping(expr) => format!("(group {})", expression(expr.expr)),
+        Expr::If(expr) => format!(
+            "(if {} {} {})",
+            expression(expr.condition),
+            expression(expr.then_branch),
+            expression(expr.else_branch)
+        ),
+        Expr::Index(expr) => format!(
+            "([] {} {})",
+            expression(expr.object),
+            expression(expr.index)
+        ),
+        Expr::Literal(expr) => expr.value.lexeme.to_string(),
+        Expr::Loop(expr) => {
+            // The body goes on the same line as the rest of the expression.
+            let mut printer = Printer::default();
+            printer.statement(expr.body);
+            let body: Vec<&str> = printer.out.lines().map(str::trim).collect();
+            format!("(loop {})", body.join(" "))
+        }
+        Expr::Logical(expr) => format!(
+            "({} {} {})",
+            expr.operator.lexeme,
+            expression(expr.left),
+            expression(expr.right)
+        ),
+        Expr::LogicalAssign(expr) => format!(
+            "({} {} {})",
+            expr.operator.lexeme,
+            expr.name.lexeme,
+            expression(expr.value)
+        ),
+        Expr::LogicalSet(expr) => format!(
+            "({} (. {} {}) {})",
+            expr.operator.lexeme,
+            expression(expr.object),
+            expr.name.lexeme,
+            expression(expr.value)
+        ),
+        Expr::Match(expr) => {
+            let mut text = format!("(match {}", expression(expr.subject));
+            for arm in &expr.arms {
+                let pattern = match &arm.pattern {
+                    Pattern::Binding(name) | Pattern::Else(name) => name.lexeme.to_string(),
+                    Pattern::Value(value) => expression(value),
+                };
+                write!(text, " ({} {})", pattern, expression(&arm.body)).unwrap();
+            }
+            text + ")"
+        }
+        Expr::Object(expr) => {
+            let mut text = String::from("(object");
+            for (name, value) in expr.names.iter().zip(&expr.values) {
+                write!(text, " ({} {})", name.lexeme, expression(value)).unwrap();
+            }
+            text + ")"
+        }
+        Expr::Set(expr) => format!(
+            "(= (. {} {}) {})",
+            expression(expr.object),
+            expr.name.lexeme,
+            expression(expr.value)
+        ),
+        Expr::SetLiteral(expr) => list("set", &expr.elements),
+        Expr::Tuple(expr) => list("tuple", &expr.elements),
+        Expr::Unary(expr) => format!("({} {})", expr.operator.lexeme, expression(expr.right)),
+        Expr::Variable(expr) => expr.name.lexeme.to_string(),
+    }
+}
+
+pub fn print(statements: &[Stmt]) -> String {
+    let mut printer = Printer::default();
+    for statement in statements {
+        printer.statement(statement);
+    }
+    printer.out
+}
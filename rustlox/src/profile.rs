@@ -0,0 +1,109 @@
+use crate::chunk::Op;
+use crate::value::Function;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+// What `--profile` collects: how often each instruction ran and how long it
+// took, and the same for each function. A function's time only counts its
+// own instructions, not those of the functions it calls.
+
+#[derive(Default, Copy, Clone)]
+struct Stats {
+    count: u64,
+    time: Duration,
+}
+
+impl Stats {
+    fn add(&mut self, time: Duration) {
+        self.count += 1;
+        self.time += time;
+    }
+}
+
+#[derive(Default)]
+struct FunctionStats {
+    name: String,
+    calls: u64,
+    instructions: Stats,
+}
+
+pub struct Profile {
+    ops: [Stats; 256],
+    // Keyed by chunk, since different functions can share a name.
+    functions: HashMap<usize, FunctionStats>,
+}
+
+impl Default for Profile {
+    fn default() -> Profile {
+        Profile {
+            ops: [Stats::default(); 256],
+            functions: HashMap::new(),
+        }
+    }
+}
+
+impl Profile {
+    fn function(&mut self, function: &Function) -> &mut FunctionStats {
+        let key = Arc::as_ptr(&function.chunk) as usize;
+        self.functions.entry(key).or_insert_with(|| FunctionStats {
+            name: function.get_name().to_string(),
+            ..Default::default()
+        })
+    }
+
+    pub fn record_call(&mut self, function: &Function) {
+        self.function(function).calls += 1;
+    }
+
+    pub fn record(&mut self, instruction: u8, function: &Function, time: Duration) {
+        self.ops[instruction as usize].add(time);
+        self.function(function).instructions.add(time);
+    }
+
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        let mut ops: Vec<(u8, &Stats)> = (0..=u8::MAX)
+            .zip(&self.ops)
+            .filter(|(_, stats)| stats.count > 0)
+            .collect();
+        ops.sort_by_key(|(_, stats)| Reverse(stats.time));
+        writeln!(out, "{:<24} {:>12} {:>12}", "instruction", "count", "time").unwrap();
+        for (instruction, stats) in ops {
+            let name = match Op::try_from(instruction) {
+                Ok(op) => format!("{:?}", op),
+                Err(byte) => format!("<{}>", byte),
+            };
+            writeln!(
+                out,
+                "{:<24} {:>12} {:>12.3?}",
+                name, stats.count, stats.time
+            )
+            .unwrap();
+        }
+
+        let mut functions: Vec<&FunctionStats> = self.functions.values().collect();
+        functions.sort_by_key(|function| Reverse(function.instructions.time));
+        writeln!(
+            out,
+            "\n{:<24} {:>12} {:>12} {:>12}",
+            "function", "calls", "instructions", "time"
+        )
+        .unwrap();
+        for function in functions {
+            writeln!(
+                out,
+                "{:<24} {:>12} {:>12} {:>12.3?}",
+                function.name,
+                function.calls,
+                function.instructions.count,
+                function.instructions.time
+            )
+            .unwrap();
+        }
+        out
+    }
+}
@@ -0,0 +1,362 @@
+use crate::compiler;
+use crate::expr::{Expr, Pattern};
+use crate::scanner::{Token, TokenKind};
+use crate::stmt::Stmt;
+use std::collections::HashMap;
+
+// Finds mistakes that don't need the script to run before any code is
+// generated: using `this` or `super`, declaring a local twice in one scope,
+// and reading a global that can't be defined when the read runs. Scopes are
+// tracked the way the compiler tracks them, and errors look like the
+// compiler's.
+
+// How a name was declared, for editors to show.
+#[derive(Clone, Debug)]
+pub enum Declaration {
+    Variable,
+    Parameter,
+    // With how it's called, like `fun add(a, b)`.
+    Function(String),
+    Binding,
+}
+
+#[derive(Debug)]
+pub struct Definition<'a> {
+    pub name: &'a Token<'a>,
+    pub declaration: Declaration,
+    pub global: bool,
+}
+
+// Every name the script declares, and what each use of a name refers to.
+#[derive(Debug, Default)]
+pub struct Symbols<'a> {
+    pub definitions: Vec<Definition<'a>>,
+    // Declarations count as uses of what they declare. A name the script
+    // doesn't declare refers to nothing.
+    pub uses: Vec<(&'a Token<'a>, Option<usize>)>,
+}
+
+struct Resolver<'a> {
+    source: &'a str,
+    // The locals declared so far in each enclosing scope, innermost last,
+    // with where they are in `symbols.definitions`.
+    scopes: Vec<Vec<(&'a str, usize)>>,
+    had_error: bool,
+    symbols: Symbols<'a>,
+    // The first declaration of each global. Functions can use globals
+    // declared after them, so uses of globals are looked up at the end.
+    global_definitions: HashMap<&'a str, usize>,
+    global_uses: Vec<usize>,
+    // How many functions, loops and branches the resolver is inside. At 0,
+    // code runs whenever the script does.
+    branches: usize,
+    // Reads of globals in that code, which are checked once every global
+    // the script declares is known.
+    straight_line_reads: Vec<&'a Token<'a>>,
+}
+
+impl<'a> Resolver<'a> {
+    fn new(source: &'a str) -> Resolver<'a> {
+        Resolver {
+            source,
+            scopes: Vec::new(),
+            had_error: false,
+            symbols: Symbols::default(),
+            global_definitions: HashMap::new(),
+            global_uses: Vec::new(),
+            branches: 0,
+            straight_line_reads: Vec::new(),
+        }
+    }
+
+    // Resolves code that may not run.
+    fn branch(&mut self, resolve: impl FnOnce(&mut Self)) {
+        self.branches += 1;
+        resolve(self);
+        self.branches -= 1;
+    }
+
+    fn error(&mut self, token: &Token, message: &str) {
+        compiler::report_error(self.source, token.span(), token.lexeme, message);
+        self.had_error = true;
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    fn end_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    // Outside any scope, a declaration is a global's.
+    fn declare(&mut self, name: &'a Token<'a>, declaration: Declaration) {
+        let index = self.symbols.definitions.len();
+        self.symbols.definitions.push(Definition {
+            name,
+            declaration,
+            global: self.scopes.is_empty(),
+        });
+        self.symbols.uses.push((name, Some(index)));
+        let scope = match self.scopes.last_mut() {
+            Some(scope) => scope,
+            None => {
+                self.global_definitions.entry(name.lexeme).or_insert(index);
+                return;
+            }
+        };
+        if scope.iter().any(|(local, _)| *local == name.lexeme) {
+            self.error(name, "Already variable with this name in this scope.");
+        } else {
+            scope.push((name.lexeme, index));
+        }
+    }
+
+    // Records what a use of a name refers to, and returns whether it's a
+    // local.
+    fn use_name(&mut self, name: &'a Token<'a>) -> bool {
+        let local = self
+            .scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|(local, _)| *local == name.lexeme)
+            .map(|&(_, index)| index);
+        if local.is_none() {
+            self.global_uses.push(self.symbols.uses.len());
+        }
+        self.symbols.uses.push((name, local));
+        local.is_some()
+    }
+
+    // There are no classes, so `this` and `super` never mean anything.
+    fn keyword(&mut self, name: &Token) -> bool {
+        let message = match name.kind {
+            TokenKind::This => "Can't use 'this' outside of a class.",
+            TokenKind::Super => "Can't use 'super' outside of a class.",
+            _ => return false,
+        };
+        self.error(name, message);
+        true
+    }
+
+    // Only reads that run whenever the script does are checked: a later
+    // REPL line or a branch that never runs can make the others fine.
+    fn read(&mut self, name: &'a Token<'a>) {
+        if !self.keyword(name) && !self.use_name(name) && self.branches == 0 {
+            self.straight_line_reads.push(name);
+        }
+    }
+
+    // `defined` says which globals exist besides the ones the script
+    // declares.
+    fn check_reads(&mut self, defined: &dyn Fn(&str) -> bool) {
+        for name in std::mem::take(&mut self.straight_line_reads) {
+            if !self.global_definitions.contains_key(name.lexeme) && !defined(name.lexeme) {
+                self.error(name, &format!("Undefined variable '{}'.", name.lexeme));
+            }
+        }
+    }
+
+    fn statements(&mut self, statements: &[Stmt<'a>]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Stmt<'a>) {
+        match statement {
+            Stmt::Block(block) => {
+                self.begin_scope();
+                self.statements(&block.statements);
+                self.end_scope();
+            }
+            Stmt::Break(stmt) => {
+                if let Some(value) = &stmt.value {
+                    self.expression(value);
+                }
+            }
+            Stmt::Continue(_) => (),
+            Stmt::Expression(stmt) => self.expression(&stmt.expression),
+            Stmt::For(stmt) => {
+                self.begin_scope();
+                if let Some(initializer) = stmt.initializer {
+                    self.statement(initializer);
+                }
+                if let Some(condition) = &stmt.condition {
+                    self.expression(condition);
+                }
+                self.branch(|resolver| {
+                    if let Some(increment) = &stmt.increment {
+                        resolver.expression(increment);
+                    }
+                    resolver.statement(stmt.body);
+                });
+                self.end_scope();
+            }
+            Stmt::Function(function) => {
+                let params: Vec<&str> = function.params.iter().map(|param| param.lexeme).collect();
+                let signature = format!("fun {}({})", function.name.lexeme, params.join(", "));
+                self.declare(function.name, Declaration::Function(signature));
+                self.begin_scope();
+                for param in &function.params {
+                    self.declare(param, Declaration::Parameter);
+                }
+                self.branch(|resolver| resolver.statements(&function.body));
+                self.end_scope();
+            }
+            Stmt::If(stmt) => {
+                self.expression(&stmt.condition);
+                self.branch(|resolver| {
+                    resolver.statement(stmt.then_branch);
+                    if let Some(else_branch) = stmt.else_branch {
+                        resolver.statement(else_branch);
+                    }
+                });
+            }
+            Stmt::Loop(stmt) => self.branch(|resolver| resolver.statement(stmt.body)),
+            Stmt::Print(stmt) => self.expression(&stmt.expression),
+            Stmt::Return(stmt) => {
+                if let Some(value) = &stmt.value {
+                    self.expression(value);
+                }
+            }
+            Stmt::Var(stmt) => {
+                for declarator in &stmt.declarators {
+                    self.declare(declarator.name, Declaration::Variable);
+                    if let Some(initializer) = &declarator.initializer {
+                        self.expression(initializer);
+                    }
+                }
+            }
+            Stmt::While(stmt) => {
+                self.expression(&stmt.condition);
+                self.branch(|resolver| resolver.statement(stmt.body));
+            }
+        }
+    }
+
+    fn expressions(&mut self, expressions: &[Expr<'a>]) {
+        for expression in expressions {
+            self.expression(expression);
+        }
+    }
+
+    fn expression(&mut self, expression: &Expr<'a>) {
+        match expression {
+            // Assigning to an undefined global is still a runtime error, even
+            // with `and=` and `or=`, which read it first.
+            Expr::Assign(expr) => {
+                if !self.keyword(expr.name) {
+                    self.use_name(expr.name);
+                }
+                self.expression(expr.value);
+            }
+            Expr::Binary(expr) => {
+                self.expression(expr.left);
+                self.expression(expr.right);
+            }
+            Expr::Call(expr) => {
+                self.expression(expr.callee);
+                self.expressions(&expr.args);
+            }
+            Expr::Comma(expr) => {
+                self.expression(expr.left);
+                self.expression(expr.right);
+            }
+            // Each comparison after the first only runs if the ones before
+            // it held.
+            Expr::ComparisonChain(expr) => {
+                let (first, rest) = expr.operands.split_at(2.min(expr.operands.len()));
+                self.expressions(first);
+                self.branch(|resolver| resolver.expressions(rest));
+            }
+            Expr::Get(expr) => self.expression(expr.object),
+            Expr::Grouping(expr) => self.expression(expr.expr),
+            Expr::If(expr) => {
+                self.expression(expr.condition);
+                self.branch(|resolver| {
+                    resolver.expression(expr.then_branch);
+                    resolver.expression(expr.else_branch);
+                });
+            }
+            Expr::Index(expr) => {
+                self.expression(expr.object);
+                self.expression(expr.index);
+            }
+            Expr::Literal(_) => (),
+            Expr::Loop(expr) => self.branch(|resolver| resolver.statement(expr.body)),
+            Expr::Logical(expr) => {
+                self.expression(expr.left);
+                self.branch(|resolver| resolver.expression(expr.right));
+            }
+            Expr::LogicalAssign(expr) => {
+                if !self.keyword(expr.name) {
+                    self.use_name(expr.name);
+                }
+                self.branch(|resolver| resolver.expression(expr.value));
+            }
+            Expr::LogicalSet(expr) => {
+                self.expression(expr.object);
+                self.branch(|resolver| resolver.expression(expr.value));
+            }
+            Expr::Match(expr) => {
+                self.expression(expr.subject);
+                self.branch(|resolver| {
+                    for arm in &expr.arms {
+                        match &arm.pattern {
+                            Pattern::Value(value) => {
+                                resolver.expression(value);
+                                resolver.expression(&arm.body);
+                            }
+                            Pattern::Binding(name) => {
+                                resolver.begin_scope();
+                                resolver.declare(name, Declaration::Binding);
+                                resolver.expression(&arm.body);
+                                resolver.end_scope();
+                            }
+                            Pattern::Else(_) => resolver.expression(&arm.body),
+                        }
+                    }
+                });
+            }
+            Expr::Object(expr) => self.expressions(&expr.values),
+            Expr::Set(expr) => {
+                self.expression(expr.object);
+                self.expression(expr.value);
+            }
+            Expr::SetLiteral(expr) => self.expressions(&expr.elements),
+            Expr::Tuple(expr) => self.expressions(&expr.elements),
+            Expr::Unary(expr) => self.expression(expr.right),
+            Expr::Variable(expr) => self.read(expr.name),
+        }
+    }
+}
+
+// Reports every mistake it finds, and returns whether there were none.
+// `defined` says which globals exist besides the ones the script declares;
+// without it, any global may be read.
+pub fn resolve<'a>(
+    source: &'a str,
+    statements: &[Stmt<'a>],
+    defined: Option<&dyn Fn(&str) -> bool>,
+) -> bool {
+    let mut resolver = Resolver::new(source);
+    resolver.statements(statements);
+    if let Some(defined) = defined {
+        resolver.check_reads(defined);
+    }
+    !resolver.had_error
+}
+
+// The names the script declares and uses, after reporting the same
+// mistakes `resolve` does, besides reads of undefined globals.
+pub fn symbols<'a>(source: &'a str, statements: &[Stmt<'a>]) -> Symbols<'a> {
+    let mut resolver = Resolver::new(source);
+    resolver.statements(statements);
+    for &index in &resolver.global_uses {
+        let (name, definition) = &mut resolver.symbols.uses[index];
+        *definition = resolver.global_definitions.get(name.lexeme).copied();
+    }
+    resolver.symbols
+}
@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const INTERVAL: Duration = Duration::from_millis(1);
+
+// What `--sample=<path>` collects. The VM can't be read from another thread,
+// so a background thread only marks when a sample is due and the VM takes it
+// before its next instruction. Samples are kept as collapsed stacks, the
+// input format of flamegraph tools: one line per distinct call chain, outer
+// functions first, followed by how often it was seen.
+pub struct Sampler {
+    path: String,
+    due: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+    stacks: HashMap<String, u64>,
+}
+
+impl Sampler {
+    pub fn start(path: String) -> Sampler {
+        let due = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::new(AtomicBool::new(false));
+        {
+            let due = Arc::clone(&due);
+            let stopped = Arc::clone(&stopped);
+            thread::spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    thread::sleep(INTERVAL);
+                    due.store(true, Ordering::Relaxed);
+                }
+            });
+        }
+        Sampler {
+            path,
+            due,
+            stopped,
+            stacks: HashMap::new(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_due(&self) -> bool {
+        self.due.load(Ordering::Relaxed)
+    }
+
+    pub fn record<'a>(&mut self, frames: impl Iterator<Item = &'a str>) {
+        self.due.store(false, Ordering::Relaxed);
+        let stack = frames.collect::<Vec<_>>().join(";");
+        *self.stacks.entry(stack).or_insert(0) += 1;
+    }
+
+    pub fn write(&self) -> io::Result<()> {
+        let mut stacks: Vec<(&String, &u64)> = self.stacks.iter().collect();
+        stacks.sort_unstable();
+        let mut out = BufWriter::new(File::create(&self.path)?);
+        for (stack, count) in stacks {
+            writeln!(out, "{} {}", stack, count)?;
+        }
+        out.flush()
+    }
+}
+
+impl Drop for Sampler {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
@@ -1,4 +1,5 @@
 use std::iter::Peekable;
+use std::ops::Range;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 #[repr(u8)]
@@ -8,6 +9,11 @@ pub enum TokenKind {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Ampersand,
+    Pipe,
+    Colon,
     Comma,
     Dot,
     Minus,
@@ -20,6 +26,11 @@ pub enum TokenKind {
     BangEqual,
     Equal,
     EqualEqual,
+    Arrow,
+    HashBrace,
+    HashParen,
+    AndEqual,
+    OrEqual,
     Greater,
     GreaterEqual,
     Less,
@@ -28,6 +39,7 @@ pub enum TokenKind {
     Identifier,
     String,
     Number,
+    Symbol,
     // Keywords.
     And,
     Break,
@@ -38,13 +50,16 @@ pub enum TokenKind {
     For,
     Fun,
     If,
+    Is,
+    Loop,
+    Match,
     Nil,
     Or,
-    Print,
     Return,
     Super,
     This,
     True,
+    Type,
     Var,
     While,
 
@@ -55,23 +70,54 @@ pub enum TokenKind {
 pub struct Token<'a> {
     pub kind: TokenKind,
     pub line: i32,
+    // Counted in characters from 1, like editors do.
+    pub column: i32,
+    // Where the lexeme starts in the source, in bytes. An error token's
+    // lexeme is its message instead, but its offset is still where the
+    // token that failed starts.
+    pub offset: usize,
     pub lexeme: &'a str,
 }
 
+impl Token<'_> {
+    // The bytes of the source the token covers.
+    pub fn span(&self) -> Range<usize> {
+        let len = if self.kind == TokenKind::Error {
+            0
+        } else {
+            self.lexeme.len()
+        };
+        self.offset..self.offset + len
+    }
+}
+
+// A `//` comment, which the parser never sees but the formatter keeps.
+#[derive(Copy, Clone, Debug)]
+pub struct Comment<'a> {
+    pub line: i32,
+    pub offset: usize,
+    // Starts with the `//`, without the spaces at the end of the line.
+    pub text: &'a str,
+    // Whether code comes before the comment on its line.
+    pub trailing: bool,
+}
+
 struct Scanner<'a> {
-    source: &'a String,
+    source: &'a str,
     pub lines: i32,
     start: usize,
     iter: Peekable<std::str::CharIndices<'a>>,
+    comments: Vec<Comment<'a>>,
 }
 
 impl<'a> Scanner<'a> {
-    fn new(source: &'a String) -> Scanner<'a> {
+    fn new(source: &'a str) -> Scanner<'a> {
         Scanner {
             source,
             lines: 1,
             start: 0,
             iter: source.char_indices().peekable(),
+            comments: Vec::new(),
         }
     }
 
@@ -99,7 +145,7 @@ impl<'a> Scanner<'a> {
                 }
                 '/' => {
                     if let Some((_, '/')) = self.peek_next() {
-                        self.consume_while(|c| c != '\n');
+                        self.comment();
                     } else {
                         return;
                     }
@@ -109,6 +155,19 @@ impl<'a> Scanner<'a> {
         }
     }
 
+    fn comment(&mut self) {
+        let start = self.iter.peek().unwrap().0;
+        self.consume_while(|c| c != '\n');
+        let end = self.iter.peek().map_or(self.source.len(), |&(end, _)| end);
+        let line_start = self.source[..start].rfind('\n').map_or(0, |i| i + 1);
+        self.comments.push(Comment {
+            line: self.lines,
+            offset: start,
+            text: self.source[start..end].trim_end(),
+            trailing: !self.source[line_start..start].trim().is_empty(),
+        });
+    }
+
     fn get_lexeme(&mut self) -> &'a str {
         let end = self
             .iter
@@ -121,8 +180,10 @@ impl<'a> Scanner<'a> {
 
     fn make_token(&mut self, kind: TokenKind) -> Token<'a> {
         Token {
-            kind: kind,
+            kind,
             line: self.lines,
+            column: self.column(),
+            offset: self.start,
             lexeme: self.get_lexeme(),
         }
     }
@@ -131,22 +192,43 @@ impl<'a> Scanner<'a> {
         Token {
             kind: TokenKind::Error,
             line: self.lines,
+            column: self.column(),
+            offset: self.start,
             lexeme: message,
         }
     }
 
-    fn peek_next(&mut self) -> Option<(usize, char)> {
-        let (n, _) = self.iter.peek()?;
+    // The column the current token starts at.
+    fn column(&self) -> i32 {
+        let line_start = self.source[..self.start].rfind('\n').map_or(0, |i| i + 1);
+        self.source[line_start..self.start].chars().count() as i32 + 1
+    }
 
-        let byte = self.source.as_bytes().get(n + 1)?;
+    fn peek_next(&mut self) -> Option<(usize, char)> {
+        let &(n, c) = self.iter.peek()?;
+        let next = n + c.len_utf8();
 
-        return Some((n + 1, *byte as char));
+        self.source[next..].chars().next().map(|c| (next, c))
     }
 
     fn string(&mut self) -> Token<'a> {
+        if matches!(self.iter.peek(), Some((_, '"'))) && matches!(self.peek_next(), Some((_, '"')))
+        {
+            self.advance();
+            self.advance();
+            return self.long_string();
+        }
+
         while let Some((_, c)) = self.iter.next_if(|&(_, c)| c != '"') {
-            if c == '\n' {
-                self.lines += 1;
+            match c {
+                '\n' => self.lines += 1,
+                // Skip the escaped character so `\"` doesn't end the string.
+                '\\' => {
+                    if let Some((_, '\n')) = self.advance() {
+                        self.lines += 1;
+                    }
+                }
+                _ => (),
             }
         }
 
@@ -157,8 +239,49 @@ impl<'a> Scanner<'a> {
         }
     }
 
+    // A `"""` string runs to the next `"""`, so it can hold newlines and
+    // lone quotes.
+    fn long_string(&mut self) -> Token<'a> {
+        let mut quotes = 0;
+        while let Some((_, c)) = self.advance() {
+            match c {
+                '"' => {
+                    quotes += 1;
+                    if quotes == 3 {
+                        return self.make_token(TokenKind::String);
+                    }
+                }
+                '\n' => {
+                    self.lines += 1;
+                    quotes = 0;
+                }
+                '\\' => {
+                    if let Some((_, '\n')) = self.advance() {
+                        self.lines += 1;
+                    }
+                    quotes = 0;
+                }
+                _ => quotes = 0,
+            }
+        }
+
+        self.make_error_token("Unterminated string.")
+    }
+
+    fn digits(&mut self) {
+        loop {
+            match self.iter.peek().copied() {
+                Some((_, c)) if c.is_ascii_digit() => (),
+                // Underscores may separate digits, as in `1_000_000`.
+                Some((_, '_')) if matches!(self.peek_next(), Some((_, '0'..='9'))) => (),
+                _ => return,
+            }
+            self.advance();
+        }
+    }
+
     fn number(&mut self) -> Token<'a> {
-        self.consume_while(|c| c.is_digit(10));
+        self.digits();
 
         // Look for a fractional part.
         if matches!(self.iter.peek(), Some((_, '.')))
@@ -166,14 +289,36 @@ impl<'a> Scanner<'a> {
         {
             // Consume the ".".
             self.advance();
-            self.consume_while(|c| c.is_digit(10));
+            self.digits();
+        } else if matches!(self.iter.peek(), Some((_, 'n')))
+            && !matches!(self.peek_next(), Some((_, c)) if c.is_alphanumeric() || c == '_')
+        {
+            // An `n` suffix makes a big integer literal.
+            self.advance();
         }
 
         self.make_token(TokenKind::Number)
     }
 
+    // `:name` is a symbol, unless the colon directly follows a word, as in
+    // `f(x:1)`.
+    fn colon(&mut self) -> Token<'a> {
+        let follows_word = self.source[..self.start]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_');
+        let starts_word =
+            matches!(self.iter.peek(), Some(&(_, c)) if c.is_alphabetic() || c == '_');
+        if follows_word || !starts_word {
+            return self.make_token(TokenKind::Colon);
+        }
+
+        self.consume_while(|c| c.is_alphanumeric() || c == '_');
+        self.make_token(TokenKind::Symbol)
+    }
+
     fn identifier(&mut self) -> Token<'a> {
-        self.consume_while(|c| c.is_ascii_alphanumeric() || c == '_');
+        self.consume_while(|c| c.is_alphanumeric() || c == '_');
 
         let lexeme = self.get_lexeme();
         let kind = match lexeme {
@@ -186,22 +331,45 @@ impl<'a> Scanner<'a> {
             "for" => TokenKind::For,
             "fun" => TokenKind::Fun,
             "if" => TokenKind::If,
+            "is" => TokenKind::Is,
+            "loop" => TokenKind::Loop,
+            "match" => TokenKind::Match,
             "nil" => TokenKind::Nil,
             "or" => TokenKind::Or,
-            "print" => TokenKind::Print,
             "return" => TokenKind::Return,
             "super" => TokenKind::Super,
             "this" => TokenKind::This,
             "true" => TokenKind::True,
+            "type" => TokenKind::Type,
             "var" => TokenKind::Var,
             "while" => TokenKind::While,
             _ => TokenKind::Identifier,
         };
 
+        // `and=` and `or=` are the logical assignment operators, as long as
+        // the `=` doesn't start an `==`.
+        let kind = match kind {
+            TokenKind::And | TokenKind::Or
+                if matches!(self.iter.peek(), Some((_, '=')))
+                    && !matches!(self.peek_next(), Some((_, '='))) =>
+            {
+                self.advance();
+                if kind == TokenKind::And {
+                    TokenKind::AndEqual
+                } else {
+                    TokenKind::OrEqual
+                }
+            }
+            kind => kind,
+        };
+        let lexeme = self.get_lexeme();
+
         Token {
-            kind: kind,
-            lexeme: lexeme,
+            kind,
+            lexeme,
             line: self.lines,
+            column: self.column(),
+            offset: self.start,
         }
     }
 }
@@ -220,7 +388,12 @@ impl<'a> Iterator for Scanner<'a> {
             ')' => self.make_token(TokenKind::RightParen),
             '{' => self.make_token(TokenKind::LeftBrace),
             '}' => self.make_token(TokenKind::RightBrace),
+            '[' => self.make_token(TokenKind::LeftBracket),
+            ']' => self.make_token(TokenKind::RightBracket),
             ';' => self.make_token(TokenKind::Semicolon),
+            '&' => self.make_token(TokenKind::Ampersand),
+            '|' => self.make_token(TokenKind::Pipe),
+            ':' => self.colon(),
             ',' => self.make_token(TokenKind::Comma),
             '.' => self.make_token(TokenKind::Dot),
             '-' => self.make_token(TokenKind::Minus),
@@ -237,6 +410,8 @@ impl<'a> Iterator for Scanner<'a> {
             '=' => {
                 if self.match_current('=') {
                     self.make_token(TokenKind::EqualEqual)
+                } else if self.match_current('>') {
+                    self.make_token(TokenKind::Arrow)
                 } else {
                     self.make_token(TokenKind::Equal)
                 }
@@ -255,9 +430,11 @@ impl<'a> Iterator for Scanner<'a> {
                     self.make_token(TokenKind::Greater)
                 }
             }
+            '#' if self.match_current('{') => self.make_token(TokenKind::HashBrace),
+            '#' if self.match_current('(') => self.make_token(TokenKind::HashParen),
             '"' => self.string(),
             '0'..='9' => self.number(),
-            'a'..='z' | 'A'..='Z' | '_' => self.identifier(),
+            c if c.is_alphabetic() || c == '_' => self.identifier(),
             _ => self.make_error_token("Unexpected character."),
         };
 
@@ -265,11 +442,12 @@ impl<'a> Iterator for Scanner<'a> {
     }
 }
 
-pub fn scan_tokens<'a>(source: &'a String) -> Vec<Token<'a>> {
+pub fn scan_tokens<'a>(source: &'a str) -> Vec<Token<'a>> {
+    Scanner::new(source).collect()
+}
+
+pub fn scan_with_comments<'a>(source: &'a str) -> (Vec<Token<'a>>, Vec<Comment<'a>>) {
     let mut scanner = Scanner::new(source);
-    let mut tokens: Vec<Token<'a>> = Vec::new();
-    while let Some(token) = scanner.next() {
-        tokens.push(token)
-    }
-    tokens
+    let tokens = scanner.by_ref().collect();
+    (tokens, scanner.comments)
 }
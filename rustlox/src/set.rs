@@ -0,0 +1,145 @@
+use crate::value::Value;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+// Values that compare by content and never change can be hashed.
+pub fn is_hashable(value: &Value) -> bool {
+    match value {
+        Value::Nil
+        | Value::Bool(_)
+        | Value::Number(_)
+        | Value::Int(_)
+        | Value::BigInt(_)
+        | Value::String(_)
+        | Value::Symbol(_) => true,
+        Value::Tuple(elements) => elements.iter().all(is_hashable),
+        _ => false,
+    }
+}
+
+fn hash_value<H: Hasher>(value: &Value, state: &mut H) {
+    // An int and a float can be equal, and so can 0 and -0, so numbers hash
+    // by their value as a float.
+    let number = match value {
+        // Big ints that fit in an int can equal numbers too.
+        Value::BigInt(value) => value.to_i64().map(|value| value as f64),
+        value => value.as_f64(),
+    };
+    if let Some(number) = number {
+        let bits = if number == 0.0 { 0 } else { number.to_bits() };
+        return bits.hash(state);
+    }
+    std::mem::discriminant(value).hash(state);
+    match value {
+        Value::Bool(value) => value.hash(state),
+        Value::BigInt(value) => value.hash(state),
+        Value::String(handle) | Value::Symbol(handle) => handle.hash(state),
+        Value::Tuple(elements) => {
+            for element in elements.iter() {
+                hash_value(element, state);
+            }
+        }
+        _ => (),
+    }
+}
+
+struct Key(Value);
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for Key {}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_value(&self.0, state)
+    }
+}
+
+// Elements are kept in insertion order so sets print predictably. Removing
+// an element leaves a hole in `entries` until there are enough to compact.
+#[derive(Default)]
+pub struct Set {
+    entries: Vec<Option<Value>>,
+    indices: HashMap<Key, usize>,
+}
+
+impl Set {
+    pub fn iter(&self) -> impl Iterator<Item = &Value> {
+        self.entries.iter().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn contains(&self, value: &Value) -> bool {
+        self.indices.contains_key(&Key(value.clone()))
+    }
+
+    // Callers must check `is_hashable` first.
+    pub fn insert(&mut self, value: Value) -> bool {
+        match self.indices.entry(Key(value.clone())) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(self.entries.len());
+                self.entries.push(Some(value));
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, value: &Value) -> bool {
+        let index = match self.indices.remove(&Key(value.clone())) {
+            Some(index) => index,
+            None => return false,
+        };
+        self.entries[index] = None;
+        if self.entries.len() > 2 * self.indices.len() + 8 {
+            self.compact();
+        }
+        true
+    }
+
+    fn compact(&mut self) {
+        self.entries.retain(Option::is_some);
+        for (index, value) in self.entries.iter().flatten().enumerate() {
+            if let Some(slot) = self.indices.get_mut(&Key(value.clone())) {
+                *slot = index;
+            }
+        }
+    }
+
+    pub fn union(&self, other: &Set) -> Set {
+        let mut set = Set::default();
+        for value in self.iter().chain(other.iter()) {
+            set.insert(value.clone());
+        }
+        set
+    }
+
+    pub fn intersection(&self, other: &Set) -> Set {
+        let mut set = Set::default();
+        for value in self.iter().filter(|value| other.contains(value)) {
+            set.insert(value.clone());
+        }
+        set
+    }
+}
+
+impl std::fmt::Display for Set {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{{")?;
+        for (i, value) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", value)?;
+        }
+        write!(f, "}}")
+    }
+}
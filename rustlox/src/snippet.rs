@@ -0,0 +1,105 @@
+use std::convert::TryFrom;
+use std::ops::Range;
+
+// The line of the script an error is about, with the part it's about
+// underlined:
+//
+//     3 | var a = ;
+//       |         ^
+
+pub fn at(source: &str, span: Range<usize>) -> String {
+    let line_start = source[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[span.start..]
+        .find('\n')
+        .map_or(source.len(), |i| span.start + i);
+    let number = source[..span.start].matches('\n').count() + 1;
+    // Only the part on the first line.
+    let width = source[span.start..span.end.min(line_end)].chars().count();
+    render(
+        number,
+        &source[line_start..line_end],
+        &source[line_start..span.start],
+        width,
+    )
+}
+
+// Where `lexeme` is in `source`, if it's a slice of it.
+pub fn span(source: &str, lexeme: &str) -> Option<Range<usize>> {
+    let start = (lexeme.as_ptr() as usize).checked_sub(source.as_ptr() as usize)?;
+    if start + lexeme.len() > source.len() {
+        return None;
+    }
+    Some(start..start + lexeme.len())
+}
+
+// The line and column, both from 1, that the byte at `offset` is at.
+pub fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = source[..offset].matches('\n').count() + 1;
+    (line, source[line_start..offset].chars().count() + 1)
+}
+
+// A caret at `column` of `line`, or the whole line underlined if the column
+// isn't known.
+pub fn position(source: &str, line: i32, column: i32) -> Option<String> {
+    let text = source
+        .lines()
+        .nth(usize::try_from(line).ok()?.checked_sub(1)?)?;
+    let code = text.trim();
+    if code.is_empty() {
+        return None;
+    }
+    let indent = text.len() - text.trim_start().len();
+    match usize::try_from(column).ok()?.checked_sub(1) {
+        Some(before) => {
+            let start = text
+                .char_indices()
+                .nth(before)
+                .map_or(text.len(), |(i, _)| i);
+            Some(render(line as usize, text, &text[..start], 1))
+        }
+        None => Some(render(
+            line as usize,
+            text,
+            &text[..indent],
+            code.chars().count(),
+        )),
+    }
+}
+
+// The bytes of `line` that `position` underlines: the character at
+// `column`, or the code on the line if the column isn't known.
+pub fn locate(source: &str, line: i32, column: i32) -> Option<Range<usize>> {
+    let number = usize::try_from(line).ok()?.checked_sub(1)?;
+    let mut start = 0;
+    for _ in 0..number {
+        start += source[start..].find('\n')? + 1;
+    }
+    let text = source[start..].split('\n').next().unwrap();
+    match usize::try_from(column).ok()?.checked_sub(1) {
+        Some(before) => {
+            let (index, c) = text.char_indices().nth(before)?;
+            Some(start + index..start + index + c.len_utf8())
+        }
+        None => {
+            let indent = text.len() - text.trim_start().len();
+            Some(start + indent..start + text.trim_end().len())
+        }
+    }
+}
+
+fn render(number: usize, text: &str, before: &str, width: usize) -> String {
+    // Tabs stay tabs, so the carets line up however wide they're shown.
+    let padding: String = before
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    format!(
+        "{:>5} | {}\n{:>5} | {}{}\n",
+        number,
+        text.trim_end(),
+        "",
+        padding,
+        "^".repeat(width.max(1))
+    )
+}
@@ -10,11 +10,14 @@ pub struct Block<'a> {
 #[derive(Debug)]
 pub struct Break<'a> {
     pub keyword: &'a Token<'a>,
+    pub label: Option<&'a Token<'a>>,
+    pub value: Option<Expr<'a>>,
 }
 
 #[derive(Debug)]
 pub struct Continue<'a> {
     pub keyword: &'a Token<'a>,
+    pub label: Option<&'a Token<'a>>,
 }
 
 #[derive(Debug)]
@@ -24,10 +27,11 @@ pub struct Expression<'a> {
 
 #[derive(Debug)]
 pub struct For<'a> {
-    pub initializer: Option<Box<Stmt<'a>>>,
+    pub label: Option<&'a Token<'a>>,
+    pub initializer: Option<&'a Stmt<'a>>,
     pub condition: Option<Expr<'a>>,
     pub increment: Option<Expr<'a>>,
-    pub body: Box<Stmt<'a>>,
+    pub body: &'a Stmt<'a>,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -41,15 +45,20 @@ pub struct Function<'a> {
     pub name: &'a Token<'a>,
     pub params: Vec<&'a Token<'a>>,
     pub body: Vec<Stmt<'a>>,
-    pub kind: FunctionKind,
     pub brace: &'a Token<'a>,
 }
 
 #[derive(Debug)]
 pub struct If<'a> {
     pub condition: Expr<'a>,
-    pub then_branch: Box<Stmt<'a>>,
-    pub else_branch: Option<Box<Stmt<'a>>>,
+    pub then_branch: &'a Stmt<'a>,
+    pub else_branch: Option<&'a Stmt<'a>>,
+}
+
+#[derive(Debug)]
+pub struct Loop<'a> {
+    pub label: Option<&'a Token<'a>>,
+    pub body: &'a Stmt<'a>,
 }
 
 #[derive(Debug)]
@@ -66,14 +75,20 @@ pub struct Return<'a> {
 
 #[derive(Debug)]
 pub struct Var<'a> {
+    pub declarators: Vec<Declarator<'a>>,
+}
+
+#[derive(Debug)]
+pub struct Declarator<'a> {
     pub name: &'a Token<'a>,
     pub initializer: Option<Expr<'a>>,
 }
 
 #[derive(Debug)]
 pub struct While<'a> {
+    pub label: Option<&'a Token<'a>>,
     pub condition: Expr<'a>,
-    pub body: Box<Stmt<'a>>,
+    pub body: &'a Stmt<'a>,
 }
 
 #[derive(Debug)]
@@ -85,6 +100,7 @@ pub enum Stmt<'a> {
     For(For<'a>),
     Function(Function<'a>),
     If(If<'a>),
+    Loop(Loop<'a>),
     Print(Print<'a>),
     Return(Return<'a>),
     Var(Var<'a>),
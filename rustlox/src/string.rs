@@ -1,74 +1,176 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::borrow::Borrow;
+use std::collections::HashSet;
 use std::fmt::Display;
-use std::ops;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, OnceLock};
 
-#[derive(Copy, Clone, PartialEq, Debug)]
-pub struct Handle(usize);
+// A string's contents live behind its handle, so a string can be shown or
+// compared without the VM that made it, and moves between threads with its
+// VM. Handles to the same string compare without looking at the contents.
+#[derive(Clone)]
+pub struct Handle(Arc<Contents>);
+
+enum Contents {
+    Flat(Box<str>),
+    // Concatenating strings builds a rope instead of copying both sides, so a
+    // loop of `+` doesn't copy the whole string each time. A rope is
+    // flattened the first time anything looks at its contents, including
+    // comparing or hashing it.
+    Rope {
+        // Only taken when the rope is dropped.
+        parts: Option<(Handle, Handle)>,
+        len: usize,
+        flat: OnceLock<Box<str>>,
+    },
+}
 
 impl Handle {
-    pub fn as_str(&self) -> InternedString {
-        with_interner(|interner| unsafe {
-            InternedString {
-                string: ::std::mem::transmute::<&str, &str>(interner.get(self.0)),
+    // A string of its own. Strings a VM makes go through its interner instead.
+    pub fn from_str(string: &str) -> Handle {
+        Handle(Arc::new(Contents::Flat(string.into())))
+    }
+
+    pub fn as_str(&self) -> &str {
+        match &*self.0 {
+            Contents::Flat(string) => string,
+            Contents::Rope { flat, .. } => flat.get_or_init(|| self.flatten()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &*self.0 {
+            Contents::Flat(string) => string.len(),
+            Contents::Rope { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Walks the leaves left to right without recursing, since ropes built in
+    // a loop are as deep as the loop is long.
+    fn flatten(&self) -> Box<str> {
+        let mut string = String::with_capacity(self.len());
+        let mut pending = vec![self];
+        while let Some(next) = pending.pop() {
+            match &*next.0 {
+                Contents::Flat(flat) => string.push_str(flat),
+                Contents::Rope { parts, flat, .. } => match (flat.get(), parts) {
+                    (Some(flat), _) => string.push_str(flat),
+                    (None, Some((left, right))) => pending.extend([right, left]),
+                    (None, None) => (),
+                },
             }
-        })
+        }
+        string.into_boxed_str()
     }
+}
 
-    pub fn from_str(string: &str) -> Handle {
-        with_interner(|interner| interner.intern(string))
+impl Drop for Contents {
+    // Takes a rope apart without recursing, for the same reason as
+    // `flatten`.
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        if let Contents::Rope { parts, .. } = self {
+            pending.extend(parts.take());
+        }
+        while let Some((left, right)) = pending.pop() {
+            for part in [left, right] {
+                if let Ok(Contents::Rope { parts, .. }) = Arc::try_unwrap(part.0).as_mut() {
+                    pending.extend(parts.take());
+                }
+            }
+        }
+    }
+}
+
+impl PartialEq for Handle {
+    fn eq(&self, other: &Handle) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+            || (self.len() == other.len() && self.as_str() == other.as_str())
+    }
+}
+
+impl Eq for Handle {}
+
+// Hashes the same as the `str`, so maps keyed by handles can be looked up by
+// `str`.
+impl Hash for Handle {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
     }
 }
 
-impl ops::Add<Handle> for Handle {
-    type Output = Handle;
-    fn add(self, other: Handle) -> <Self as std::ops::Add<Handle>>::Output {
-        let string = format!("{}{}", self, other);
-        Handle::from_str(string.as_str())
+impl Borrow<str> for Handle {
+    fn borrow(&self) -> &str {
+        self.as_str()
     }
 }
 
 impl Display for Handle {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
-        write!(f, "{}", self.as_str().string)
+        write!(f, "{}", self.as_str())
     }
 }
 
+impl std::fmt::Debug for Handle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+// Each VM has its own interner, so VMs on different threads never wait for
+// each other. Interned strings are kept for as long as the VM, and equal
+// ones share a handle. The interner also counts the bytes of every string
+// the VM makes, ropes included, so the VM can limit them.
 #[derive(Default)]
-struct Interner {
-    handle_map: HashMap<Box<str>, Handle>,
-    strings: Vec<Box<str>>,
+pub struct Interner {
+    strings: HashSet<Handle>,
+    bytes: usize,
+    ropes: usize,
 }
 
 impl Interner {
-    fn new() -> Interner {
-        Interner::default()
-    }
-
-    fn intern(&mut self, string: &str) -> Handle {
-        if let Some(&handle) = self.handle_map.get(string) {
-            return handle;
+    pub fn intern(&mut self, string: &str) -> Handle {
+        if let Some(handle) = self.strings.get(string) {
+            return handle.clone();
         }
-
-        let handle = Handle(self.strings.len());
-        let string = string.to_string().into_boxed_str();
-        self.strings.push(string.clone());
-        self.handle_map.insert(string, handle);
+        let handle = Handle::from_str(string);
+        self.bytes += string.len();
+        self.strings.insert(handle.clone());
         handle
     }
 
-    fn get(&self, index: usize) -> &str {
-        &self.strings[index]
+    pub fn contains(&self, string: &str) -> bool {
+        self.strings.contains(string)
     }
-}
 
-fn with_interner<T, F: FnOnce(&mut Interner) -> T>(f: F) -> T {
-    thread_local!(static INTERNER: RefCell<Interner> = {
-        RefCell::new(Interner::new())
-    });
-    INTERNER.with(|interner| f(&mut *interner.borrow_mut()))
-}
+    pub fn concat(&mut self, left: &Handle, right: &Handle) -> Handle {
+        match (left.len(), right.len()) {
+            (0, _) => right.clone(),
+            (_, 0) => left.clone(),
+            (left_len, right_len) => {
+                self.bytes += left_len + right_len;
+                self.ropes += 1;
+                Handle(Arc::new(Contents::Rope {
+                    parts: Some((left.clone(), right.clone())),
+                    len: left_len + right_len,
+                    flat: OnceLock::new(),
+                }))
+            }
+        }
+    }
 
-pub struct InternedString {
-    pub string: &'static str,
+    // The bytes of every string made so far.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    // How many strings are interned and how many bytes they hold, and how
+    // many ropes have been made.
+    pub fn stats(&self) -> (usize, usize, usize) {
+        let bytes = self.strings.iter().map(Handle::len).sum();
+        (self.strings.len(), bytes, self.ropes)
+    }
 }
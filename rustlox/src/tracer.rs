@@ -0,0 +1,68 @@
+use crate::chunk::Chunk;
+use crate::names::Names;
+use crate::value::Value;
+use std::io::{self, Write};
+
+// What `--trace` writes: before each instruction runs, the whole stack and
+// then the instruction. The trace can be limited to the instructions of
+// functions with the given names. The first error writing stops the trace
+// and is kept until `finish` reports it.
+pub struct Tracer {
+    out: Box<dyn Write + Send>,
+    // The functions to trace, or all of them if empty.
+    functions: Vec<String>,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write + Send>, functions: Vec<String>) -> Tracer {
+        Tracer {
+            out,
+            functions,
+            error: None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn traces(&self, function: &str) -> bool {
+        self.functions.is_empty() || self.functions.iter().any(|name| name == function)
+    }
+
+    pub fn record(&mut self, stack: &[Value], chunk: &Chunk, ip: usize, names: &Names) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(error) = self.write(stack, chunk, ip, names) {
+            self.error = Some(error);
+        }
+    }
+
+    fn write(
+        &mut self,
+        stack: &[Value],
+        chunk: &Chunk,
+        ip: usize,
+        names: &Names,
+    ) -> io::Result<()> {
+        write!(self.out, "          ")?;
+        for value in stack {
+            write!(self.out, "[ {} ]", value)?;
+        }
+        writeln!(self.out)?;
+        write!(self.out, "{}", chunk.disassemble_instruction(ip, names))
+    }
+
+    pub fn flush(&mut self) {
+        if self.error.is_none() {
+            self.error = self.out.flush().err();
+        }
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.flush();
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
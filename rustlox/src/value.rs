@@ -1,44 +1,65 @@
+use crate::bigint::BigInt;
 use crate::chunk::Chunk;
+use crate::compiler::Lazy;
+use crate::lock::Lock;
 use crate::native;
+use crate::set::Set;
 use crate::string;
-use std::cell::RefCell;
-use std::rc::Rc;
+use crate::vm::Fiber;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
 
 #[derive(Clone, Debug)]
 pub struct Function {
     pub arity: usize,
-    pub chunk: Rc<Chunk>,
+    pub chunk: Arc<Chunk>,
     pub name: string::Handle,
+    pub params: Arc<[string::Handle]>,
     pub upvalue_count: usize,
+    // Set until the body of a lazily compiled function is compiled.
+    pub lazy: Option<Arc<Lazy>>,
+    // Tells apart the functions made by each run of the same declaration,
+    // which share a chunk.
+    pub identity: u64,
 }
 
 impl Function {
-    pub fn get_name(&self) -> &'static str {
-        match self.name.as_str().string {
+    // A function that is a different object from every other.
+    pub fn made(&self) -> Arc<Function> {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        Arc::new(Function {
+            identity: NEXT.fetch_add(1, Ordering::Relaxed),
+            ..self.clone()
+        })
+    }
+
+    pub fn get_name(&self) -> &str {
+        match self.name.as_str() {
             "" => "<script>",
             value => value,
         }
     }
+}
 
-    pub fn print(&self) {
+impl std::fmt::Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.get_name() {
-            "<script>" => print!("<script>"),
-            name => print!("<fn {}>", name),
+            "<script>" => write!(f, "<script>"),
+            name => write!(f, "<fn {}>", name),
         }
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct Closure {
-    pub function: Function,
-    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
-    pub upvalue_count: usize,
+    pub function: Arc<Function>,
+    pub upvalues: Vec<Arc<Lock<Upvalue>>>,
 }
 
 impl Closure {
-    pub fn new(function: Function) -> Closure {
+    pub fn new(function: Arc<Function>) -> Closure {
         Closure {
-            upvalue_count: function.upvalue_count,
             upvalues: Vec::with_capacity(function.upvalue_count),
             function,
         }
@@ -52,11 +73,23 @@ impl Drop for Closure {
     }
 }
 
+// Made by `{ x: 1 }` literals. Until classes exist, every instance belongs to
+// the implicit `Object` class.
+#[derive(Default)]
+pub struct Instance {
+    pub fields: HashMap<string::Handle, Value>,
+}
+
+// While it's open, an upvalue names the stack slot of the local it captured.
+// The slot is in the stack of `fiber`, or of the main fiber if that's
+// `None`, and only the VM running that fiber can reach it.
 #[derive(Clone, Debug)]
 pub struct Upvalue {
-    pub location: *mut Value,
-    pub next: Option<Rc<RefCell<Upvalue>>>,
-    pub closed: Value,
+    pub slot: usize,
+    pub fiber: Option<Weak<Lock<Fiber>>>,
+    pub next: Option<Arc<Lock<Upvalue>>>,
+    // The value, once the local's slot is gone.
+    pub closed: Option<Value>,
 }
 
 impl Drop for Upvalue {
@@ -67,43 +100,105 @@ impl Drop for Upvalue {
 }
 
 impl Upvalue {
-    pub fn new(location: *mut Value, next: Option<Rc<RefCell<Upvalue>>>) -> Upvalue {
+    pub fn new(
+        slot: usize,
+        fiber: Option<Weak<Lock<Fiber>>>,
+        next: Option<Arc<Lock<Upvalue>>>,
+    ) -> Upvalue {
         Upvalue {
-            location,
+            slot,
+            fiber,
             next,
-            closed: Value::Nil,
+            closed: None,
         }
     }
 
-    pub fn close(&mut self) {
-        unsafe { self.closed = (*self.location).clone() };
-        self.location = &mut self.closed;
+    // `value` is what's in the slot as it goes away.
+    pub fn close(&mut self, value: Value) {
+        self.closed = Some(value);
+        self.fiber = None;
         self.next = None;
     }
-
-    pub fn as_value(&self) -> Value {
-        unsafe { (*self.location).clone() }
-    }
-
-    pub fn set_value(&mut self, value: Value) {
-        unsafe { *self.location = value }
-    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub enum Value {
     Bool(bool),
     Number(f64),
+    // Number literals without a fractional part. Arithmetic on two ints stays
+    // an int until it overflows.
+    Int(i64),
+    // `123n` literals. Arithmetic mixes them with ints but not with floats.
+    BigInt(Arc<BigInt>),
+    #[default]
     Nil,
     String(string::Handle),
-    Function(Function),
+    // Interned like strings, so comparing two symbols compares handles.
+    Symbol(string::Handle),
+    Function(Arc<Function>),
     Native(native::Function),
-    Closure(Closure),
+    Closure(Arc<Closure>),
+    Tuple(Arc<[Value]>),
+    Set(Arc<Lock<Set>>),
+    Instance(Arc<Lock<Instance>>),
+    Fiber(Arc<Lock<Fiber>>),
+    // Doesn't keep its object alive, so `get()` gives nil once the object's
+    // last strong reference is gone.
+    WeakRef(Weak<Lock<Instance>>),
+    Builtin(Builtin),
 }
 
-impl Default for Value {
-    fn default() -> Self {
-        Value::Nil
+// Callables the VM implements itself, because they switch between fibers or
+// are bound to the value they were read from.
+#[derive(Clone)]
+pub enum Builtin {
+    // The `Fiber` global, which holds `create` and `yield`.
+    Fiber,
+    FiberCreate,
+    FiberYield,
+    FiberResume(Arc<Lock<Fiber>>),
+    Set,
+    SetAdd(Arc<Lock<Set>>),
+    SetHas(Arc<Lock<Set>>),
+    SetRemove(Arc<Lock<Set>>),
+    // Compiles and runs a string of source code.
+    Eval,
+    // Prints the VM's state under `--debug`, and does nothing otherwise.
+    Breakpoint,
+    // The `Reflect` global, which holds the introspection functions.
+    Reflect,
+    ReflectGlobals,
+    ReflectName,
+    ReflectArity,
+    ReflectUpvalues,
+    ReflectFields,
+    WeakRef,
+    WeakRefGet(Weak<Lock<Instance>>),
+}
+
+impl PartialEq for Builtin {
+    fn eq(&self, other: &Builtin) -> bool {
+        match (self, other) {
+            (Builtin::Fiber, Builtin::Fiber)
+            | (Builtin::FiberCreate, Builtin::FiberCreate)
+            | (Builtin::FiberYield, Builtin::FiberYield)
+            | (Builtin::Set, Builtin::Set)
+            | (Builtin::Eval, Builtin::Eval)
+            | (Builtin::Breakpoint, Builtin::Breakpoint)
+            | (Builtin::Reflect, Builtin::Reflect)
+            | (Builtin::ReflectGlobals, Builtin::ReflectGlobals)
+            | (Builtin::ReflectName, Builtin::ReflectName)
+            | (Builtin::ReflectArity, Builtin::ReflectArity)
+            | (Builtin::ReflectUpvalues, Builtin::ReflectUpvalues)
+            | (Builtin::ReflectFields, Builtin::ReflectFields)
+            | (Builtin::WeakRef, Builtin::WeakRef) => true,
+            (Builtin::WeakRefGet(a), Builtin::WeakRefGet(b)) => Weak::ptr_eq(a, b),
+            (Builtin::FiberResume(a), Builtin::FiberResume(b)) => Arc::ptr_eq(a, b),
+            (Builtin::SetAdd(a), Builtin::SetAdd(b))
+            | (Builtin::SetHas(a), Builtin::SetHas(b))
+            | (Builtin::SetRemove(a), Builtin::SetRemove(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
     }
 }
 
@@ -112,51 +207,182 @@ impl std::fmt::Debug for Value {
         match self {
             Value::Bool(value) => write!(f, "Value::Bool({})", value),
             Value::Number(value) => write!(f, "Value::Number({})", value),
+            Value::Int(value) => write!(f, "Value::Int({})", value),
+            Value::BigInt(value) => write!(f, "Value::BigInt({})", value),
             Value::Nil => write!(f, "Value::Nil"),
             Value::String(value) => write!(f, "Value::String({})", value),
+            Value::Symbol(value) => write!(f, "Value::Symbol({})", value),
             Value::Function(value) => write!(f, "Value::Function({:?})", value),
             Value::Native(_) => write!(f, "Value::Native(<native fn>)"),
             Value::Closure(value) => write!(f, "Value::Closure({:?})", value),
+            Value::Tuple(value) => write!(f, "Value::Tuple({:?})", value),
+            Value::Set(value) => write!(f, "Value::Set({})", value.borrow()),
+            Value::Instance(_) => write!(f, "Value::Instance({})", self),
+            Value::Fiber(_) => write!(f, "Value::Fiber(<fiber>)"),
+            Value::WeakRef(_) => write!(f, "Value::WeakRef(<weakref>)"),
+            Value::Builtin(_) => write!(f, "Value::Builtin({})", self),
         }
     }
 }
 
 impl PartialEq for Value {
     fn eq(&self, other: &Value) -> bool {
-        match (self, other) {
-            (Value::Bool(a), Value::Bool(b)) => a == b,
-            (Value::Nil, Value::Nil) => true,
-            (Value::Number(a), Value::Number(b)) => a == b,
-            (Value::String(a), Value::String(b)) => a == b,
-            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(&a.chunk, &b.chunk),
-            (Value::Native(a), Value::Native(b)) => *a as usize == *b as usize,
-            _ => false,
+        equal(self, other, &mut Vec::new())
+    }
+}
+
+// Sets, objects and tuples compare by content. `comparing` holds the pairs of
+// objects already being compared further up, so a cycle counts as equal
+// instead of recursing forever.
+fn equal(a: &Value, b: &Value, comparing: &mut Vec<(usize, usize)>) -> bool {
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a == b,
+        (Value::Nil, Value::Nil) => true,
+        (Value::Number(a), Value::Number(b)) => a == b,
+        (Value::Int(a), Value::Int(b)) => a == b,
+        (Value::Int(a), Value::Number(b)) | (Value::Number(b), Value::Int(a)) => *a as f64 == *b,
+        (Value::BigInt(a), Value::BigInt(b)) => a == b,
+        (Value::BigInt(a), Value::Int(b)) | (Value::Int(b), Value::BigInt(a)) => {
+            a.to_i64() == Some(*b)
+        }
+        (Value::BigInt(a), Value::Number(b)) | (Value::Number(b), Value::BigInt(a)) => {
+            a.to_i64().is_some_and(|a| a as f64 == *b)
         }
+        (Value::String(a), Value::String(b)) => a == b,
+        (Value::Symbol(a), Value::Symbol(b)) => a == b,
+        (Value::Function(a), Value::Function(b)) => {
+            Arc::ptr_eq(&a.chunk, &b.chunk) && a.identity == b.identity
+        }
+        (Value::Native(a), Value::Native(b)) => *a as usize == *b as usize,
+        (Value::Closure(a), Value::Closure(b)) => Arc::ptr_eq(a, b),
+        (Value::Tuple(a), Value::Tuple(b)) => {
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| equal(a, b, comparing))
+        }
+        (Value::Set(a), Value::Set(b)) => {
+            // Set elements are hashable, so they can't lead back to a set.
+            let (a, b) = (a.borrow(), b.borrow());
+            a.len() == b.len() && a.iter().all(|value| b.contains(value))
+        }
+        (Value::Instance(a), Value::Instance(b)) => {
+            let pair = (Arc::as_ptr(a) as usize, Arc::as_ptr(b) as usize);
+            if Arc::ptr_eq(a, b) || comparing.contains(&pair) {
+                return true;
+            }
+            comparing.push(pair);
+            let (a, b) = (a.borrow(), b.borrow());
+            let result = a.fields.len() == b.fields.len()
+                && a.fields.iter().all(|(name, value)| {
+                    b.fields
+                        .get(name)
+                        .is_some_and(|other| equal(value, other, comparing))
+                });
+            comparing.pop();
+            result
+        }
+        (Value::Fiber(a), Value::Fiber(b)) => Arc::ptr_eq(a, b),
+        (Value::WeakRef(a), Value::WeakRef(b)) => Weak::ptr_eq(a, b),
+        (Value::Builtin(a), Value::Builtin(b)) => a == b,
+        _ => false,
     }
 }
 
 impl Value {
     pub fn is_falsy(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    // Identity rather than equality: numbers compare bit for bit, so NaN is
+    // itself and 0 is not -0, and collections must be the same collection.
+    // Everything else already compares by identity.
+    pub fn is(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Number(_) | Value::Int(_), Value::Number(_) | Value::Int(_)) => {
+                self.as_f64().map(f64::to_bits) == other.as_f64().map(f64::to_bits)
+            }
+            (Value::Tuple(a), Value::Tuple(b)) => Arc::ptr_eq(a, b),
+            (Value::Set(a), Value::Set(b)) => Arc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Arc::ptr_eq(a, b),
+            _ => self == other,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
         match self {
-            Value::Nil | Value::Bool(false) => true,
-            _ => false,
+            Value::Number(value) => Some(*value),
+            Value::Int(value) => Some(*value as f64),
+            _ => None,
         }
     }
 
-    pub fn print(&self) {
+    // Ints widen to big ints.
+    pub fn as_big_int(&self) -> Option<Arc<BigInt>> {
         match self {
-            Value::Bool(value) => print!("{}", value),
-            Value::Number(value) => print!("{}", value),
-            Value::String(value) => print!("{}", value),
-            Value::Function(function) => function.print(),
-            Value::Native(_) => print!("<native fn>"),
-            Value::Closure(closure) => closure.function.print(),
-            Value::Nil => print!("nil"),
+            Value::BigInt(value) => Some(Arc::clone(value)),
+            Value::Int(value) => Some(Arc::new(BigInt::from_i64(*value))),
+            _ => None,
         }
     }
 
-    pub fn println(&self) {
-        self.print();
-        println!("");
+    // The function behind a function value, if it was declared in Lox.
+    pub fn as_function(&self) -> Option<&Function> {
+        match self {
+            Value::Function(function) => Some(function),
+            Value::Closure(closure) => Some(&closure.function),
+            _ => None,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Bool(_) => "boolean",
+            Value::Number(_) | Value::Int(_) => "number",
+            Value::BigInt(_) => "bigint",
+            Value::Nil => "nil",
+            Value::String(_) => "string",
+            Value::Symbol(_) => "symbol",
+            Value::Function(_) | Value::Native(_) | Value::Closure(_) => "function",
+            Value::Fiber(_) => "fiber",
+            Value::WeakRef(_) => "weakref",
+            Value::Tuple(_) => "tuple",
+            Value::Set(_) => "set",
+            Value::Instance(_) => "object",
+            Value::Builtin(Builtin::Fiber | Builtin::Reflect) => "builtin",
+            Value::Builtin(_) => "function",
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Number(value) => write!(f, "{}", value),
+            Value::Int(value) => write!(f, "{}", value),
+            Value::BigInt(value) => write!(f, "{}", value),
+            Value::String(value) => write!(f, "{}", value),
+            Value::Symbol(value) => write!(f, ":{}", value),
+            Value::Function(function) => write!(f, "{}", function),
+            Value::Native(_) => write!(f, "<native fn>"),
+            Value::Closure(closure) => write!(f, "{}", closure.function),
+            Value::Fiber(_) => write!(f, "<fiber>"),
+            Value::WeakRef(_) => write!(f, "<weakref>"),
+            Value::Set(set) => write!(f, "{}", set.borrow()),
+            Value::Instance(_) => write!(f, "Object instance"),
+            Value::Tuple(elements) => {
+                write!(f, "#(")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, ")")
+            }
+            Value::Builtin(Builtin::Fiber) => write!(f, "Fiber"),
+            Value::Builtin(Builtin::Reflect) => write!(f, "Reflect"),
+            Value::Builtin(_) => write!(f, "<native fn>"),
+            Value::Nil => write!(f, "nil"),
+        }
     }
 }
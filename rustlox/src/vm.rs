@@ -1,509 +1,2532 @@
+use crate::bigint::BigInt;
 use crate::chunk::*;
 use crate::compiler::*;
+use crate::coverage::Coverage;
+use crate::gc::{self, Heap, Marker};
+use crate::lock::Lock;
+use crate::names::Names;
 use crate::native;
-use crate::scanner;
+use crate::output;
+use crate::prelude;
+use crate::profile::Profile;
+use crate::sampler::Sampler;
+use crate::scanner::{self, TokenKind};
+use crate::set::{self, Set};
+use crate::snippet;
 use crate::string;
+use crate::tracer::Tracer;
 use crate::value::*;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use std::rc::Rc;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::sync::{Arc, Weak};
+use std::time::Instant;
 
-fn with_vm<T, F: FnOnce(&mut VM) -> T>(f: F) -> T {
-    thread_local!(static STATIC_VM: RefCell<VM> = {
-        RefCell::new(VM::new())
-    });
-    STATIC_VM.with(|vm| f(&mut *vm.borrow_mut()))
-}
-
-#[derive(Default)]
+// Functions that capture nothing are called without a closure.
 struct CallFrame {
-    closure: Option<Closure>,
+    function: Arc<Function>,
+    closure: Option<Arc<Closure>>,
     ip: usize,
     starts_at: usize,
 }
 
 impl CallFrame {
-    pub fn reset(&mut self) {
-        self.closure = None;
-        self.starts_at = 0;
-        self.ip = 0;
+    // Where the frame is, as stack traces show it.
+    fn location(&self) -> String {
+        let Position { line, column } = self.function.chunk.position_at(self.ip - 1);
+        let file = if self.in_prelude() { "prelude " } else { "" };
+        match self.function.get_name() {
+            "<script>" => format!("[{}line {}:{}] in script", file, line, column),
+            name => format!("[{}line {}:{}] in {}()", file, line, column, name),
+        }
+    }
+
+    fn snippet(&self) -> Option<String> {
+        let chunk = &self.function.chunk;
+        let Position { line, column } = chunk.position_at(self.ip - 1);
+        let snippet = snippet::position(chunk.source.as_ref()?, line, column)?;
+        if self.in_prelude() {
+            return Some(format!("In the prelude:\n{}", snippet));
+        }
+        Some(snippet)
+    }
+
+    fn in_prelude(&self) -> bool {
+        self.function
+            .chunk
+            .source
+            .as_ref()
+            .is_some_and(prelude::is_prelude)
+    }
+
+    // The call the frame is stopped at, if it's at one, with how many
+    // arguments it passes.
+    fn call_site(&self, call_sites: &mut CallSites) -> Option<(String, usize)> {
+        let chunk = &self.function.chunk;
+        call_sites.at(chunk.source.as_ref()?, chunk.position_at(self.ip - 1))
+    }
+}
+
+// Finds the calls in a script from where their `)` is, for stack traces.
+// Each script is scanned once, however many frames are in it.
+#[derive(Default)]
+struct CallSites {
+    tokens: HashMap<usize, Vec<(TokenKind, Range<usize>)>>,
+}
+
+impl CallSites {
+    fn at(&mut self, source: &str, position: Position) -> Option<(String, usize)> {
+        let tokens = self
+            .tokens
+            .entry(source.as_ptr() as usize)
+            .or_insert_with(|| {
+                scanner::scan_tokens(source)
+                    .iter()
+                    .map(|token| (token.kind, token.span()))
+                    .collect()
+            });
+        let offset = snippet::locate(source, position.line, position.column)?.start;
+        let close = tokens
+            .binary_search_by_key(&offset, |(_, span)| span.start)
+            .ok()?;
+        if tokens[close].0 != TokenKind::RightParen {
+            return None;
+        }
+
+        // Walks back from a closing bracket to the one it closes, counting
+        // the commas directly between them.
+        let opening = |close: usize| {
+            let mut depth = 0;
+            let mut commas = 0;
+            for index in (0..=close).rev() {
+                match tokens[index].0 {
+                    TokenKind::RightParen | TokenKind::RightBracket | TokenKind::RightBrace => {
+                        depth += 1
+                    }
+                    TokenKind::LeftParen
+                    | TokenKind::LeftBracket
+                    | TokenKind::LeftBrace
+                    | TokenKind::HashBrace
+                    | TokenKind::HashParen => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some((index, commas));
+                        }
+                    }
+                    TokenKind::Comma if depth == 1 => commas += 1,
+                    _ => (),
+                }
+            }
+            None
+        };
+        let (open, commas) = opening(close)?;
+        let args = if open + 1 == close { 0 } else { commas + 1 };
+
+        // The callee is a name, a chain of fields, or anything called or
+        // indexed, like `a.b`, `f(x)` or `fs[0]`.
+        let mut start = open;
+        while start > 0 {
+            match tokens[start - 1].0 {
+                TokenKind::Identifier | TokenKind::This | TokenKind::Super => {
+                    start -= 1;
+                    if start == 0 || tokens[start - 1].0 != TokenKind::Dot {
+                        break;
+                    }
+                    start -= 1;
+                }
+                TokenKind::RightParen | TokenKind::RightBracket => start = opening(start - 1)?.0,
+                _ => break,
+            }
+        }
+
+        let text = &source[tokens[start].1.start..tokens[close].1.end];
+        let text: Vec<&str> = text.split_whitespace().collect();
+        Some((shorten(&text.join(" "), 60), args))
+    }
+}
+
+// Cuts `text` down to at most `limit` characters, ending in `...` if it's
+// longer.
+fn shorten(text: &str, limit: usize) -> String {
+    if text.chars().count() <= limit {
+        return text.to_string();
+    }
+    let kept: String = text.chars().take(limit - 3).collect();
+    kept + "..."
+}
+
+// The most stack slots of a frame a stack trace shows.
+const TRACE_SLOTS: usize = 8;
+
+impl CallFrame {
+    #[inline(always)]
+    fn upvalues(&self) -> &[Arc<Lock<Upvalue>>] {
+        self.closure
+            .as_ref()
+            .map_or(&[], |closure| &closure.upvalues)
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Debug, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub enum InterpretError {
     CompileError,
     RuntimeError,
     InternalError(&'static str),
 }
 
-const CALL_FRAME_MAX: usize = 64;
-const CALL_FRAME_DEFAULT: CallFrame = CallFrame {
-    closure: None,
-    ip: 0,
-    starts_at: 0,
+// Stacks start small and double as they fill, up to the configured size.
+const STACK_INITIAL: usize = 256;
+
+#[derive(Copy, Clone, PartialEq)]
+enum FiberStatus {
+    New,
+    Suspended,
+    Running,
+    Done,
+}
+
+// A fiber's stack, frames and open upvalues. The running fiber's state is
+// swapped into the VM, and while it runs these fields hold the state of the
+// fiber that resumed it. Swapping a stack doesn't move the values open
+// upvalues point at; growing one does, so `grow_stack` moves them along.
+pub struct Fiber {
+    status: FiberStatus,
+    caller: Option<Arc<Lock<Fiber>>>,
+
+    stack: Vec<Value>,
+    stack_count: usize,
+
+    frames: Vec<CallFrame>,
+
+    open_upvalues: Option<Arc<Lock<Upvalue>>>,
+}
+
+impl Fiber {
+    fn new(function: Value) -> Fiber {
+        let mut stack = vec![Value::Nil; STACK_INITIAL];
+        stack[0] = function;
+        Fiber {
+            status: FiberStatus::New,
+            caller: None,
+            stack,
+            stack_count: 1,
+            frames: Vec::new(),
+            open_upvalues: None,
+        }
+    }
+}
+
+impl Fiber {
+    pub fn mark_roots(&self, marker: &mut Marker) {
+        for value in &self.stack[..self.stack_count] {
+            marker.mark_value(value);
+        }
+        for closure in self
+            .frames
+            .iter()
+            .filter_map(|frame| frame.closure.as_ref())
+        {
+            marker.mark_value(&Value::Closure(Arc::clone(closure)));
+        }
+        marker.mark_open_upvalues(&self.open_upvalues);
+        if let Some(caller) = &self.caller {
+            marker.mark_value(&Value::Fiber(Arc::clone(caller)));
+        }
+    }
+}
+
+impl Drop for Fiber {
+    // Closures that outlive a suspended fiber must stop pointing into its
+    // stack.
+    fn drop(&mut self) {
+        let mut open_upvalues = self.open_upvalues.take();
+        while let Some(rc) = open_upvalues {
+            let mut upvalue = rc.borrow_mut();
+            open_upvalues = upvalue.next.take();
+            let value = std::mem::take(&mut self.stack[upvalue.slot]);
+            upvalue.close(value);
+        }
+    }
+}
+
+// Whether an upvalue of `owner` points into the stack of `fiber`, where
+// `None` is the main fiber for both.
+fn same_fiber(owner: &Option<Weak<Lock<Fiber>>>, fiber: &Option<Arc<Lock<Fiber>>>) -> bool {
+    match (owner, fiber) {
+        (None, None) => true,
+        (Some(owner), Some(fiber)) => std::ptr::eq(owner.as_ptr(), Arc::as_ptr(fiber)),
+        _ => false,
+    }
+}
+
+// A VM and everything it allocates can be moved to another thread, so a
+// pool of workers can each own one.
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<VM>();
+    assert_send::<Value>();
 };
-const STACK_MAX: usize = 256;
-const STACK_DEFAULT: Value = Value::Nil;
 
 pub struct VM {
-    globals: HashMap<&'static str, Value>,
+    // Indexed by name. `None` until the global is defined.
+    globals: Vec<Option<Value>>,
+    // The names the VM's code was compiled with, and its strings.
+    names: Names,
+    // The prelude's functions, once a script has used one.
+    prelude: Option<HashMap<String, Arc<Function>>>,
 
-    stack: [Value; STACK_MAX],
+    stack: Vec<Value>,
     stack_count: usize,
 
-    frames: [CallFrame; CALL_FRAME_MAX],
-    frame_count: usize,
+    frames: Vec<CallFrame>,
+
+    open_upvalues: Option<Arc<Lock<Upvalue>>>,
 
-    open_upvalues: Option<Rc<RefCell<Upvalue>>>,
+    // The running fiber, or `None` while the main script runs.
+    fiber: Option<Arc<Lock<Fiber>>>,
+    // The frames of the fibers waiting for the running one to yield or
+    // return. They count toward `max_call_depth`, so fibers that keep
+    // resuming new fibers overflow like calls do.
+    waiting_frames: usize,
+
+    // The options the script was compiled with, which `eval` reuses.
+    options: CompileOptions,
+
+    heap: Heap,
+    // An incremental collection that's still marking.
+    marking: Option<Marker>,
+
+    config: Config,
+    // Whether instructions go through `run_instrumented`.
+    instrumented: bool,
+
+    profile: Option<Profile>,
+    sampler: Option<Sampler>,
+    coverage: Option<Coverage>,
+    tracer: Option<Tracer>,
 }
 
-type Result<T> = std::result::Result<T, InterpretError>;
+// How a VM is set up, fixed when it's built.
+struct Config {
+    // The most slots a fiber's stack can grow to.
+    stack_size: usize,
+    // How deep calls can nest before a stack overflow, counting the frames
+    // of every fiber waiting on the running one.
+    max_call_depth: usize,
+    // Where to write the stack and each instruction as it runs, if anywhere.
+    trace: Option<Box<dyn Write + Send>>,
+    // Only trace the instructions of functions with these names.
+    trace_functions: Vec<String>,
+    // How many tracked objects the heap holds before its first collection.
+    gc_threshold: usize,
+    // Spread each collection's marking out between allocations.
+    incremental_gc: bool,
+    // The most bytes of strings the VM can make before making another is a
+    // runtime error. The VM keeps its interned strings for as long as it
+    // lives.
+    max_interned_bytes: Option<usize>,
+    // The predefined globals scripts can use, or `None` for all of them.
+    // This covers the natives, builtins like `eval`, and the prelude's
+    // functions, so an embedder can sandbox a script.
+    predefined: Option<Vec<String>>,
+    // Make `breakpoint()` print the stack, frames and globals.
+    debug: bool,
+    // How many of the innermost frames of a runtime error's stack trace
+    // show the values in their stack slots.
+    trace_depth: usize,
+}
 
-pub fn interpret(source: &String) -> Result<()> {
-    with_vm(|vm| {
-        let tokens = scanner::scan_tokens(source);
-        if tokens.is_empty() {
-            return Ok(());
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            stack_size: 1 << 20,
+            max_call_depth: 1024,
+            trace: None,
+            trace_functions: Vec::new(),
+            gc_threshold: gc::INITIAL_THRESHOLD,
+            incremental_gc: false,
+            max_interned_bytes: None,
+            predefined: None,
+            debug: false,
+            trace_depth: 0,
         }
-        let closure = Closure::new(compile(tokens)?);
-        vm.push(Value::Closure(closure.clone()))?;
-        vm.call(closure, 0).ok();
-        vm.run()
-    })
+    }
 }
 
-impl VM {
-    pub fn new() -> VM {
-        let mut vm: VM = VM {
-            globals: Default::default(),
+#[derive(Default)]
+pub struct Builder {
+    config: Config,
+}
 
-            stack_count: Default::default(),
-            stack: [STACK_DEFAULT; STACK_MAX],
+impl Builder {
+    pub fn stack_size(mut self, slots: usize) -> Builder {
+        self.config.stack_size = slots.max(STACK_INITIAL);
+        self
+    }
 
-            frame_count: Default::default(),
-            frames: [CALL_FRAME_DEFAULT; CALL_FRAME_MAX],
+    pub fn max_call_depth(mut self, depth: usize) -> Builder {
+        self.config.max_call_depth = depth;
+        self
+    }
 
-            open_upvalues: Default::default(),
+    pub fn trace(mut self, trace: bool) -> Builder {
+        self.config.trace = match trace {
+            true => Some(Box::new(io::stdout())),
+            false => None,
         };
+        self
+    }
 
-        vm.define_native("clock", native::clock);
-
-        vm
+    pub fn trace_to(mut self, out: impl Write + Send + 'static) -> Builder {
+        self.config.trace = Some(Box::new(out));
+        self
     }
 
-    fn reset_stack(&mut self) {
-        self.stack_count = 0;
-        for frame in self.frames.iter_mut() {
-            frame.reset();
-        }
-        self.frame_count = 0;
+    pub fn trace_function(mut self, name: &str) -> Builder {
+        self.config.trace_functions.push(name.to_string());
+        self
     }
 
-    #[inline(always)]
-    fn current_frame(&self) -> &CallFrame {
-        &self.frames[self.frame_count - 1]
+    pub fn gc_threshold(mut self, objects: usize) -> Builder {
+        self.config.gc_threshold = objects;
+        self
     }
 
-    #[inline(always)]
-    fn current_frame_mut(&mut self) -> &mut CallFrame {
-        &mut self.frames[self.frame_count - 1]
+    pub fn incremental_gc(mut self, incremental: bool) -> Builder {
+        self.config.incremental_gc = incremental;
+        self
     }
 
-    #[inline(always)]
-    fn current_chunk(&self) -> &Chunk {
-        &self
-            .current_frame()
-            .closure
-            .as_ref()
-            .unwrap()
-            .function
-            .chunk
+    pub fn max_interned_bytes(mut self, bytes: usize) -> Builder {
+        self.config.max_interned_bytes = Some(bytes);
+        self
     }
 
-    fn runtime_error<'a>(&mut self, string: &'a str) -> Result<()> {
-        eprintln!("{}", string);
+    pub fn predefined(mut self, names: &[&str]) -> Builder {
+        self.config.predefined = Some(names.iter().map(|name| name.to_string()).collect());
+        self
+    }
 
-        for frame in self.frames[0..self.frame_count].iter().rev() {
-            let function = &frame.closure.as_ref().unwrap().function;
-            let line = function.chunk.lines[frame.ip - 1];
+    pub fn debug(mut self, debug: bool) -> Builder {
+        self.config.debug = debug;
+        self
+    }
 
-            eprint!("[line {}] in ", line);
-            match function.get_name() {
-                "<script>" => eprintln!("script"),
-                name => eprintln!("{}()", name),
-            }
-        }
-        self.reset_stack();
-        Err(InterpretError::RuntimeError)
+    pub fn trace_depth(mut self, frames: usize) -> Builder {
+        self.config.trace_depth = frames;
+        self
     }
 
-    fn define_native(&mut self, name: &'static str, function: native::Function) {
-        self.globals.insert(name, Value::Native(function));
+    pub fn build(self) -> VM {
+        VM::new(self.config)
     }
+}
 
-    #[inline(always)]
-    fn push(&mut self, value: Value) -> Result<()> {
-        if self.stack_count == STACK_MAX {
-            return self.runtime_error("Stack overflow.");
+type Result<T> = std::result::Result<T, InterpretError>;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapStats {
+    // Bytes allocated for objects over the VM's life, estimated where it
+    // makes them.
+    pub bytes_allocated: usize,
+    // Instances and upvalues the collector tracks, including unreachable
+    // ones it hasn't swept yet.
+    pub tracked: usize,
+    // Objects reachable from the VM.
+    pub instances: usize,
+    pub closures: usize,
+    pub upvalues: usize,
+    pub fibers: usize,
+    // From the VM's own interner.
+    pub interned_strings: usize,
+    pub interned_bytes: usize,
+    pub ropes: usize,
+}
+
+impl std::fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rows = [
+            ("bytes allocated", self.bytes_allocated),
+            ("tracked objects", self.tracked),
+            ("live instances", self.instances),
+            ("live closures", self.closures),
+            ("live upvalues", self.upvalues),
+            ("live fibers", self.fibers),
+            ("interned strings", self.interned_strings),
+            ("interned bytes", self.interned_bytes),
+            ("ropes", self.ropes),
+        ];
+        for (name, value) in rows {
+            writeln!(f, "{:<24} {:>12}", name, value)?;
         }
-        self.stack[self.stack_count] = value;
-        self.stack_count += 1;
         Ok(())
     }
+}
 
-    #[inline(always)]
-    fn pop(&mut self) -> Result<Value> {
-        if self.stack_count == 0 {
-            return Err(InterpretError::InternalError("Can't pop on empty stack."));
+const BUILTINS: &[(&str, Builtin)] = &[
+    ("Fiber", Builtin::Fiber),
+    ("Set", Builtin::Set),
+    ("eval", Builtin::Eval),
+    ("breakpoint", Builtin::Breakpoint),
+    ("Reflect", Builtin::Reflect),
+    ("WeakRef", Builtin::WeakRef),
+];
+
+pub fn is_predefined_global(name: &str) -> bool {
+    BUILTINS.iter().any(|(builtin, _)| *builtin == name)
+        || native::lookup(name).is_some()
+        || prelude::names().any(|function| function == name)
+}
+
+// Where the identifier that ends at `pos` in `line` starts, and the names
+// it could be the start of. Property names after a `.` aren't completed.
+pub fn completions<'n>(names: &[&'n str], line: &str, pos: usize) -> (usize, Vec<&'n str>) {
+    let start = line[..pos]
+        .rfind(|c: char| !c.is_alphanumeric() && c != '_')
+        .map_or(0, |index| index + 1);
+    let prefix = &line[start..pos];
+    if prefix.is_empty() || line[..start].ends_with('.') {
+        return (pos, Vec::new());
+    }
+    let candidates = names
+        .iter()
+        .copied()
+        .filter(|name| name.starts_with(prefix))
+        .collect();
+    (start, candidates)
+}
+
+// Two ints give an int unless `int` can't represent the result, in which case
+// the operation falls back to floats like any other pair of numbers.
+pub fn arithmetic(
+    a: &Value,
+    b: &Value,
+    int: fn(i64, i64) -> Option<i64>,
+    float: fn(f64, f64) -> f64,
+) -> Option<Value> {
+    if let (Value::Int(a), Value::Int(b)) = (a, b) {
+        if let Some(value) = int(*a, *b) {
+            return Some(Value::Int(value));
         }
+    }
+    Some(Value::Number(float(a.as_f64()?, b.as_f64()?)))
+}
 
-        self.stack_count -= 1;
-        Ok(std::mem::take(&mut self.stack[self.stack_count]))
+// Compares two ints exactly and any other pair of numbers as floats. `None`
+// when the operands need the general comparison.
+pub fn compare_numbers(
+    a: &Value,
+    b: &Value,
+    int: fn(&i64, &i64) -> bool,
+    float: fn(&f64, &f64) -> bool,
+) -> Option<bool> {
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => Some(int(a, b)),
+        _ => Some(float(&a.as_f64()?, &b.as_f64()?)),
     }
+}
 
-    #[inline(always)]
-    fn peek(&self, index: usize) -> Result<&Value> {
-        self.stack
-            .get(self.stack_count - 1 - index)
-            .ok_or(InterpretError::InternalError("Can't peek on empty stack."))
+pub fn negate(value: &Value) -> Option<Value> {
+    match value {
+        Value::Number(num) => Some(Value::Number(-num)),
+        // Ints have no negative zero, so keep the float one.
+        Value::Int(0) => Some(Value::Number(-0.0)),
+        Value::BigInt(num) => Some(Value::BigInt(Arc::new(num.neg()))),
+        Value::Int(num) => Some(
+            num.checked_neg()
+                .map_or(Value::Number(-(*num as f64)), Value::Int),
+        ),
+        _ => None,
     }
+}
 
-    #[inline(always)]
-    fn call(&mut self, closure: Closure, arg_count: usize) -> Result<()> {
-        if arg_count != closure.function.arity {
-            return self.runtime_error(
-                format!(
-                    "Expected {} arguments but got {}.",
-                    closure.function.arity, arg_count
-                )
-                .as_str(),
-            );
-        }
+fn string_tuple(mut strings: Vec<string::Handle>) -> Value {
+    strings.sort_unstable_by(|a, b| a.as_str().cmp(b.as_str()));
+    Value::Tuple(strings.into_iter().map(Value::String).collect())
+}
 
-        let starts_at = self.stack_count - arg_count - 1;
-        let frame = &mut self.frames[self.frame_count];
-        frame.starts_at = starts_at;
-        frame.closure = Some(closure);
-        frame.ip = 0;
-        self.frame_count += 1;
+fn is_big_int(value: &Value) -> bool {
+    matches!(value, Value::BigInt(_))
+}
 
-        if self.frame_count == CALL_FRAME_MAX {
-            return self.runtime_error("Stack overflow.");
-        }
+// Big int division truncates, like integer division in most languages.
+fn big_division(a: &BigInt, b: &BigInt) -> Option<BigInt> {
+    if b.is_zero() {
+        return None;
+    }
+    Some(a.div_rem(b).0)
+}
 
-        Ok(())
+// Dividing ints only gives an int when nothing is left over.
+pub fn exact_division(a: i64, b: i64) -> Option<i64> {
+    match a.checked_rem(b)? {
+        0 => a.checked_div(b),
+        _ => None,
     }
+}
 
-    #[inline(always)]
-    fn call_native(&mut self, function: native::Function, arg_count: usize) -> Result<()> {
-        let arg_start = self.stack_count - arg_count - 1;
-        let result = function(&self.stack[arg_start..]);
-        self.stack_count -= arg_count;
-        self.stack[self.stack_count - 1] = result;
-        Ok(())
+impl VM {
+    pub fn builder() -> Builder {
+        Builder::default()
     }
 
-    #[inline(always)]
-    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<()> {
-        match callee {
-            Value::Closure(closure) => self.call(closure, arg_count),
-            Value::Native(function) => self.call_native(function, arg_count),
-            _ => self.runtime_error("Can only call functions and classes."),
+    // Globals stay defined between calls, so a REPL can run one line at a
+    // time.
+    pub fn interpret(&mut self, source: &str, options: &CompileOptions) -> Result<Value> {
+        let source = Arc::from(source);
+        let tokens = scanner::scan_tokens(&source);
+        if tokens.is_empty() {
+            return Ok(Value::Nil);
+        }
+        let defined: HashSet<String> = self.global_names().into_iter().map(String::from).collect();
+        let function = Arc::new(compile_with(
+            &source,
+            tokens,
+            options,
+            &mut self.names,
+            Some(&|name| defined.contains(name)),
+        )?);
+        self.check_string_bytes(0)?;
+        self.options = options.clone();
+        if options.profile && self.profile.is_none() {
+            self.profile = Some(Profile::default());
         }
+        if let (Some(path), None) = (&options.sample, &self.sampler) {
+            self.sampler = Some(Sampler::start(path.clone()));
+        }
+        if options.coverage && self.coverage.is_none() {
+            self.coverage = Some(Coverage::new(&function));
+        }
+        self.instrumented = self.tracer.is_some()
+            || self.profile.is_some()
+            || self.sampler.is_some()
+            || self.coverage.is_some();
+        self.push(Value::Function(function.clone()))?;
+        self.call(function, None, 0).ok();
+        let result = self.run();
+        if let Some(tracer) = &mut self.tracer {
+            tracer.flush();
+        }
+        result
     }
 
-    #[inline(always)]
-    fn capture_upvalue(&mut self, location: *mut Value) -> Rc<RefCell<Upvalue>> {
-        let mut previous: Option<Rc<RefCell<Upvalue>>> = None;
-        let mut current: &mut Option<Rc<RefCell<Upvalue>>> = &mut self.open_upvalues;
-        let mut _temp: Option<Rc<RefCell<Upvalue>>> = None;
-        while current.is_some()
-            && current.as_ref().unwrap().borrow().location as usize > location as usize
-        {
-            previous = Some(Rc::clone(&current.as_ref().unwrap()));
-            _temp = if let Some(value) = &previous.as_ref().unwrap().borrow().next {
-                Some(Rc::clone(value))
-            } else {
-                None
-            };
-            current = &mut _temp;
+    pub fn print_profile(&self) {
+        if let Some(profile) = &self.profile {
+            eprint!("{}", profile.report());
         }
+    }
 
-        if let Some(value) = current {
-            let upvalue = value.borrow();
-            if upvalue.location as usize == location as usize {
-                return Rc::clone(&value);
-            }
+    // Counts the objects reachable from the VM, so it takes as long as a
+    // collection's marking.
+    pub fn heap_stats(&self) -> HeapStats {
+        let mut marker = self.start_marking();
+        marker.trace();
+        let (interned_strings, interned_bytes, ropes) = self.names.strings.stats();
+        HeapStats {
+            bytes_allocated: self.heap.allocated(),
+            tracked: self.heap.tracked(),
+            instances: marker.count::<Lock<Instance>>(),
+            closures: marker.count::<Closure>(),
+            upvalues: marker.count::<Lock<Upvalue>>(),
+            fibers: marker.count::<Lock<Fiber>>(),
+            interned_strings,
+            interned_bytes,
+            ropes,
         }
+    }
 
-        let created_upvalue = Rc::new(RefCell::new(Upvalue::new(
-            location,
-            current.as_ref().map(|value| Rc::clone(&value)),
-        )));
+    // Every global a script run by this VM could use right now: the ones it
+    // has defined and the predefined ones it's allowed, sorted.
+    pub fn global_names(&self) -> Vec<&str> {
+        let defined = self
+            .globals
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| value.is_some())
+            .map(|(slot, _)| self.names.name(slot))
+            .filter(|name| prelude::private_name(name).is_none());
+        let predefined = BUILTINS
+            .iter()
+            .map(|(name, _)| *name)
+            .chain(native::names())
+            .chain(prelude::names())
+            .filter(|name| self.allows(name));
+        let mut names: Vec<&str> = defined.collect();
+        for name in predefined {
+            names.push(name);
+        }
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
 
-        if previous.is_none() {
-            self.open_upvalues = Some(Rc::clone(&created_upvalue));
-        } else {
-            previous.as_mut().unwrap().borrow_mut().next = Some(Rc::clone(&created_upvalue));
+    // The script's source annotated with how often each line ran.
+    pub fn coverage_listing(&self, source: &str) -> Option<String> {
+        Some(self.coverage.as_ref()?.listing(source))
+    }
+
+    // The same counts as an lcov tracefile.
+    pub fn coverage_lcov(&self, source_path: &str) -> Option<String> {
+        Some(self.coverage.as_ref()?.lcov(source_path))
+    }
+
+    // Reports the first error writing the trace, if any.
+    pub fn finish_trace(&mut self) -> io::Result<()> {
+        match &mut self.tracer {
+            Some(tracer) => tracer.finish(),
+            None => Ok(()),
         }
+    }
 
-        created_upvalue
+    pub fn write_samples(&self) -> std::io::Result<()> {
+        match &self.sampler {
+            Some(sampler) => sampler.write(),
+            None => Ok(()),
+        }
     }
 
-    #[inline(always)]
-    fn close_upvalues(&mut self, last: *const Value) {
-        while self.open_upvalues.is_some()
-            && self.open_upvalues.as_ref().unwrap().borrow().location as usize >= last as usize
-        {
-            let rc = self.open_upvalues.as_ref().unwrap();
-            let mut upvalue = rc.borrow_mut();
-            let next = upvalue.next.as_ref().map(|value| Rc::clone(&value));
-            upvalue.close();
-            drop(upvalue);
-            self.open_upvalues = next;
+    fn new(mut config: Config) -> VM {
+        let tracer = config
+            .trace
+            .take()
+            .map(|out| Tracer::new(out, config.trace_functions.clone()));
+        VM {
+            globals: Default::default(),
+            names: Names::default(),
+            prelude: None,
+
+            stack_count: Default::default(),
+            stack: vec![Value::Nil; STACK_INITIAL],
+
+            frames: Vec::new(),
+
+            open_upvalues: Default::default(),
+
+            fiber: None,
+            waiting_frames: 0,
+
+            options: Default::default(),
+
+            heap: Heap::new(config.gc_threshold),
+            marking: None,
+
+            config,
+            instrumented: false,
+
+            profile: None,
+            sampler: None,
+            coverage: None,
+            tracer,
         }
     }
 
-    #[inline(always)]
-    fn read_u8(&mut self) -> Result<u8> {
-        let frame = self.current_frame_mut();
-        let ip = frame.ip;
-        frame.ip += 1;
-        let chunk = self.current_chunk();
-        chunk
-            .code
-            .get(ip)
-            .map(|value| *value)
-            .ok_or(InterpretError::InternalError("Failed to read byte."))
+    fn reset_stack(&mut self) {
+        self.truncate_stack(0);
+        self.frames.clear();
+        self.waiting_frames = 0;
     }
 
     #[inline(always)]
-    fn read_constant(&mut self) -> Result<&Value> {
-        let constant: usize = self.read_u8()?.into();
-        self.current_chunk()
-            .constants
-            .get(constant)
-            .ok_or(InterpretError::InternalError("Failed to read constant."))
+    fn current_frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
     }
 
     #[inline(always)]
-    fn read_u16(&mut self) -> Result<u16> {
-        let byte1: u16 = self.read_u8()?.into();
-        let byte2: u16 = self.read_u8()?.into();
-        Ok((byte1 << 8) | byte2)
+    fn current_frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
     }
 
     #[inline(always)]
-    fn read_string(&mut self) -> Result<&string::Handle> {
-        match self.read_constant()? {
-            Value::String(handle) => Ok(handle),
-            _ => Err(InterpretError::InternalError("Value was not a string.")),
-        }
+    fn current_chunk(&self) -> &Chunk {
+        &self.current_frame().function.chunk
     }
 
-    fn run(&mut self) -> Result<()> {
-        macro_rules! binary_op {
-            ($op: tt, $variant: ident) => {{
-                let value = match (self.pop()?, self.pop()?) {
-                (Value::Number(b), Value::Number(a)) => (a $op b),
-                _ => {
-                    return self.runtime_error("Operands must be numbers.");
-                }
-                };
-
-                self.push(Value::$variant(value))?
-            }};
+    fn runtime_error<T>(&mut self, string: &str) -> Result<T> {
+        output::err(format_args!("{}\n", string));
+        if let Some(snippet) = self.frames.last().and_then(CallFrame::snippet) {
+            output::err(format_args!("{}", snippet));
         }
 
+        // Each frame says which call it's stopped at, and the innermost
+        // `trace_depth` frames list their slots after the callee's.
+        let mut call_sites = CallSites::default();
+        let mut depth = 0;
         loop {
-            {
-                #![cfg(feature = "trace-execution")]
-                print!("          ");
-                for i in 0..self.stack_count {
-                    print!("[ ");
-                    self.stack[i].print();
-                    print!(" ]");
-                }
-                println!("");
-                let ip = self.current_frame().ip;
-                self.current_chunk().disassemble_instruction(ip);
-            }
-
-            let instruction = match self.read_u8()?.try_into() {
-                Ok(op) => op,
-                Err(value) => {
-                    let message = format!("Got unexpected instruction: '{}'", value);
-                    return self.runtime_error(message.as_str());
+            let mut end = self.stack_count;
+            for frame in self.frames.iter().rev() {
+                let mut location = frame.location();
+                if let Some((call, args)) = frame.call_site(&mut call_sites) {
+                    let plural = if args == 1 { "" } else { "s" };
+                    location = format!(
+                        "{}, calling '{}' with {} argument{}",
+                        location, call, args, plural
+                    );
                 }
-            };
+                output::err(format_args!("{}\n", location));
 
-            match instruction {
-                Op::Constant => {
-                    let constant = self.read_constant()?.clone();
-                    self.push(constant)?
-                }
-                Op::Nil => self.push(Value::Nil)?,
-                Op::True => self.push(Value::Bool(true))?,
-                Op::False => self.push(Value::Bool(false))?,
-                Op::Pop => {
-                    self.pop()?;
-                }
-                Op::GetLocal => {
-                    let slot: usize = self.read_u8()?.into();
-                    let offset = self.current_frame().starts_at;
-                    self.push(self.stack[slot + offset].clone())?
-                }
-                Op::SetLocal => {
-                    let slot: usize = self.read_u8()?.into();
-                    let offset = self.current_frame().starts_at;
-                    self.stack[slot + offset] = self.peek(0)?.clone();
-                }
-                Op::GetGlobal => {
-                    let name = self.read_string()?.as_str().string;
-                    match self.globals.get(name) {
-                        Some(value) => {
-                            let clone = value.clone();
-                            self.push(clone)?
-                        }
-                        _ => {
-                            let error = format!("Undefined variable '{}'.", name);
-                            return self.runtime_error(error.as_str());
-                        }
+                if depth < self.config.trace_depth {
+                    let slots = &self.stack[frame.starts_at..end.max(frame.starts_at)];
+                    for (slot, value) in slots.iter().enumerate().skip(1).take(TRACE_SLOTS) {
+                        let value = shorten(&value.to_string(), 40);
+                        output::err(format_args!("  {:>4}: {}\n", slot, value));
                     }
-                }
-                Op::DefineGlobal => {
-                    let name = self.read_string()?.as_str().string;
-                    let value = self.pop()?;
-                    self.globals.insert(name, value);
-                }
-                Op::SetGlobal => {
-                    let name = self.read_string()?;
-                    let string = name.as_str().string;
-                    if self.globals.insert(string, self.peek(0)?.clone()).is_none() {
-                        self.globals.remove(string);
-                        let error = format!("Undefined variable '{}'.", string);
-                        return self.runtime_error(error.as_str());
+                    if slots.len() > TRACE_SLOTS + 1 {
+                        let more = slots.len() - TRACE_SLOTS - 1;
+                        output::err(format_args!("        ...and {} more\n", more));
                     }
                 }
-                Op::GetUpvalue => {
-                    let slot = self.read_u8()? as usize;
-                    let value = self.current_frame().closure.as_ref().unwrap().upvalues[slot]
-                        .borrow()
-                        .as_value();
-                    self.push(value)?
-                }
-                Op::SetUpvalue => {
-                    let slot = self.read_u8()? as usize;
-                    let value = self.peek(0)?.clone();
-                    let mut upvalue = self.current_frame_mut().closure.as_mut().unwrap().upvalues
-                        [slot]
-                        .borrow_mut();
-
-                    upvalue.set_value(value);
-                }
-                Op::Equal => {
-                    let b = self.pop()?;
-                    let a = self.pop()?;
-                    self.push(Value::Bool(a == b))?
-                }
-                Op::Greater => binary_op!(>, Bool),
-                Op::Less => binary_op!(<, Bool),
-                Op::Add => {
-                    let value = match (self.pop()?, self.pop()?) {
-                        (Value::Number(b), Value::Number(a)) => Value::Number(a + b),
-                        (Value::String(b), Value::String(a)) => Value::String(a + b),
-                        _ => {
-                            return self
-                                .runtime_error("Operands must be two numbers or two strings.");
-                        }
-                    };
+                depth += 1;
+                end = frame.starts_at;
+            }
 
-                    self.push(value)?
+            // Unwind into each fiber's resumer until the main script is back.
+            match self.fiber.take() {
+                Some(fiber) => {
+                    let mut fiber = fiber.borrow_mut();
+                    self.swap_fiber_state(&mut fiber);
+                    fiber.status = FiberStatus::Done;
+                    self.fiber = fiber.caller.take();
                 }
-                Op::Subtract => binary_op!(-, Number),
-                Op::Multiply => binary_op!(*, Number),
-                Op::Divide => binary_op!(/, Number),
-                Op::Not => {
-                    let value = self.pop()?.is_falsy();
-                    self.push(Value::Bool(value))?
+                None => break,
+            }
+        }
+        self.reset_stack();
+        Err(InterpretError::RuntimeError)
+    }
+
+    // What `breakpoint()` prints under `--debug`: each frame of the running
+    // fiber, innermost first, with the stack slots it owns, then the globals
+    // the script has defined.
+    fn print_state(&mut self) {
+        eprintln!("-- breakpoint");
+        let mut end = self.stack_count;
+        for frame in self.frames.iter().rev() {
+            eprintln!("{}", frame.location());
+            for (slot, value) in self.stack[frame.starts_at..end].iter().enumerate() {
+                eprintln!("  {:>4}: {}", slot, value);
+            }
+            end = frame.starts_at;
+        }
+        eprintln!("globals:");
+        for (name, value) in self.script_globals() {
+            eprintln!("  {} = {}", name, value);
+        }
+        eprintln!("--");
+    }
+
+    fn swap_fiber_state(&mut self, fiber: &mut Fiber) {
+        std::mem::swap(&mut self.stack, &mut fiber.stack);
+        std::mem::swap(&mut self.stack_count, &mut fiber.stack_count);
+        std::mem::swap(&mut self.frames, &mut fiber.frames);
+        std::mem::swap(&mut self.open_upvalues, &mut fiber.open_upvalues);
+        // The stack that was just put away stops being a root.
+        if let Some(marker) = &mut self.marking {
+            fiber.mark_roots(marker);
+        }
+    }
+
+    fn resume(&mut self, fiber: Arc<Lock<Fiber>>, arg_count: usize) -> Result<()> {
+        let status = fiber.borrow().status;
+        match status {
+            FiberStatus::Running => return self.runtime_error("Fiber is already running."),
+            FiberStatus::Done => return self.runtime_error("Can't resume a finished fiber."),
+            FiberStatus::New | FiberStatus::Suspended => (),
+        }
+        // Resuming counts as a call.
+        if self.waiting_frames + self.frames.len() >= self.config.max_call_depth {
+            return self.runtime_error("Stack overflow.");
+        }
+
+        let value = if arg_count == 1 {
+            self.pop()?
+        } else {
+            Value::Nil
+        };
+        self.pop()?;
+
+        self.waiting_frames += self.frames.len();
+        {
+            let mut next = fiber.borrow_mut();
+            self.swap_fiber_state(&mut next);
+            next.status = FiberStatus::Running;
+            next.caller = self.fiber.take();
+        }
+        self.fiber = Some(fiber);
+
+        // A new fiber passes the value to its function. A suspended one
+        // returns it from the `Fiber.yield()` call it stopped at.
+        if status == FiberStatus::Suspended {
+            return self.push(value);
+        }
+        let function = self.stack[0].clone();
+        let arity = match function.as_function() {
+            Some(function) => function.arity,
+            None => return Err(InterpretError::InternalError("Fiber without a function.")),
+        };
+        if arity == 1 {
+            self.push(value)?;
+        }
+        self.call_value(function, arity)
+    }
+
+    // Hands control and `value` back to whatever resumed the running fiber.
+    fn leave_fiber(&mut self, status: FiberStatus, value: Value) -> Result<()> {
+        let fiber = match self.fiber.take() {
+            Some(fiber) => fiber,
+            None => return self.runtime_error("Can't yield from the main fiber."),
+        };
+
+        {
+            let mut current = fiber.borrow_mut();
+            self.swap_fiber_state(&mut current);
+            current.status = status;
+            self.fiber = current.caller.take();
+        }
+        self.waiting_frames -= self.frames.len();
+        self.push(value)
+    }
+
+    fn should_collect(&self) -> bool {
+        self.marking.is_some() || self.heap.should_collect()
+    }
+
+    // Only called at the start of an instruction, when every live value is
+    // reachable from the roots.
+    fn collect_garbage(&mut self) {
+        let mut marker = match self.marking.take() {
+            Some(marker) => marker,
+            None => {
+                #[cfg(feature = "log-gc")]
+                println!("-- gc begin ({} tracked)", self.heap.tracked());
+
+                self.start_marking()
+            }
+        };
+        if self.config.incremental_gc {
+            if marker.trace_some(gc::MARK_BUDGET) {
+                self.marking = Some(marker);
+                return;
+            }
+            self.mark_roots(&mut marker);
+            self.heap.mark_new(&mut marker);
+        }
+        marker.trace();
+
+        let _emptied = self.heap.sweep(&marker);
+        #[cfg(feature = "log-gc")]
+        println!(
+            "-- gc end: emptied {} objects, {} still tracked",
+            _emptied,
+            self.heap.tracked()
+        );
+    }
+
+    fn start_marking(&self) -> Marker {
+        let mut marker = Marker::new(&self.heap);
+        for value in self.globals.iter().flatten() {
+            marker.mark_value(value);
+        }
+        self.mark_roots(&mut marker);
+        marker
+    }
+
+    // The roots other than globals, which an incremental collection marks
+    // again once it's done marking everything else.
+    fn mark_roots(&self, marker: &mut Marker) {
+        for value in &self.stack[..self.stack_count] {
+            marker.mark_value(value);
+        }
+        for closure in self
+            .frames
+            .iter()
+            .filter_map(|frame| frame.closure.as_ref())
+        {
+            marker.mark_value(&Value::Closure(Arc::clone(closure)));
+        }
+        marker.mark_open_upvalues(&self.open_upvalues);
+        // The running fiber holds the state of the fibers that resumed it.
+        if let Some(fiber) = &self.fiber {
+            marker.mark_value(&Value::Fiber(Arc::clone(fiber)));
+        }
+    }
+
+    // While an incremental collection is marking, a value stored somewhere
+    // the marker may already have scanned is marked right away.
+    #[inline(always)]
+    fn write_barrier(&mut self, value: &Value) {
+        if let Some(marker) = &mut self.marking {
+            marker.mark_value(value);
+        }
+    }
+
+    fn global_mut(&mut self, slot: usize) -> &mut Option<Value> {
+        if slot >= self.globals.len() {
+            self.globals.resize(slot + 1, None);
+        }
+        &mut self.globals[slot]
+    }
+
+    // Whether the predefined global `name` may be used.
+    fn allows(&self, name: &str) -> bool {
+        match &self.config.predefined {
+            Some(names) => names.iter().any(|allowed| allowed == name),
+            None => true,
+        }
+    }
+
+    fn lookup_global(&mut self, slot: usize) -> Option<&Value> {
+        let global = self.global_mut(slot);
+        if global.is_none() {
+            let name = self.names.handle(slot).clone();
+            if !self.allows(name.as_str()) && prelude::private_name(name.as_str()).is_none() {
+                return None;
+            }
+            let value = self.predefined_global(name.as_str())?;
+            *self.global_mut(slot) = Some(value);
+        }
+        self.globals[slot].as_ref()
+    }
+
+    // Globals every program starts with: the natives, the builtins and the
+    // prelude's functions.
+    fn predefined_global(&mut self, name: &str) -> Option<Value> {
+        if let Some((_, builtin)) = BUILTINS.iter().find(|(builtin, _)| *builtin == name) {
+            return Some(Value::Builtin(builtin.clone()));
+        }
+        if let Some(native) = native::lookup(name) {
+            return Some(Value::Native(native));
+        }
+        let name = prelude::private_name(name).unwrap_or(name);
+        if !prelude::names().any(|function| function == name) {
+            return None;
+        }
+        if self.prelude.is_none() {
+            self.prelude = Some(prelude::compile(&mut self.names));
+        }
+        let function = self.prelude.as_ref()?.get(name)?;
+        Some(Value::Function(function.clone()))
+    }
+
+    // The globals the script defined, sorted by name. Predefined globals only
+    // land in the table once they're used, so they're left out unless the
+    // script replaced them.
+    fn script_globals(&mut self) -> Vec<(string::Handle, Value)> {
+        let mut globals = Vec::new();
+        for slot in 0..self.globals.len() {
+            let value = match &self.globals[slot] {
+                Some(value) => value.clone(),
+                None => continue,
+            };
+            let name = self.names.handle(slot).clone();
+            if self.predefined_global(name.as_str()).as_ref() != Some(&value) {
+                globals.push((name, value));
+            }
+        }
+        globals.sort_unstable_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+        globals
+    }
+
+    #[inline(always)]
+    fn push(&mut self, value: Value) -> Result<()> {
+        if self.stack_count == self.stack.len() {
+            self.grow_stack()?;
+        }
+        self.stack[self.stack_count] = value;
+        self.stack_count += 1;
+        Ok(())
+    }
+
+    fn grow_stack(&mut self) -> Result<()> {
+        if self.stack.len() == self.config.stack_size {
+            return self.runtime_error("Stack overflow.");
+        }
+        self.stack.resize(
+            (self.stack.len() * 2).min(self.config.stack_size),
+            Value::Nil,
+        );
+        Ok(())
+    }
+
+    // Clears the slots above `count` as well, so values don't stay alive just
+    // because they were once on the stack.
+    fn truncate_stack(&mut self, count: usize) {
+        for value in &mut self.stack[count..self.stack_count] {
+            *value = Value::Nil;
+        }
+        self.stack_count = count;
+    }
+
+    // Replaces the top `count` values with `value`. The stack held them, so
+    // it has room for it.
+    #[inline(always)]
+    fn replace_top(&mut self, count: usize, value: Value) {
+        self.truncate_stack(self.stack_count - count + 1);
+        self.stack[self.stack_count - 1] = value;
+    }
+
+    #[inline(always)]
+    fn pop(&mut self) -> Result<Value> {
+        if self.stack_count == 0 {
+            return Err(InterpretError::InternalError("Can't pop on empty stack."));
+        }
+
+        self.stack_count -= 1;
+        Ok(std::mem::take(&mut self.stack[self.stack_count]))
+    }
+
+    #[inline(always)]
+    fn peek(&self, index: usize) -> Result<&Value> {
+        self.stack
+            .get(self.stack_count - 1 - index)
+            .ok_or(InterpretError::InternalError("Can't peek on empty stack."))
+    }
+
+    #[inline(always)]
+    fn call(
+        &mut self,
+        function: Arc<Function>,
+        closure: Option<Arc<Closure>>,
+        arg_count: usize,
+    ) -> Result<()> {
+        if arg_count != function.arity {
+            return self.runtime_error(
+                format!(
+                    "Expected {} arguments but got {}.",
+                    function.arity, arg_count
+                )
+                .as_str(),
+            );
+        }
+
+        if self.waiting_frames + self.frames.len() >= self.config.max_call_depth {
+            return self.runtime_error("Stack overflow.");
+        }
+
+        let function = match &function.lazy {
+            Some(lazy) => match lazy.chunk(&mut self.names) {
+                Ok(chunk) => {
+                    let function = Arc::new(Function {
+                        chunk,
+                        lazy: None,
+                        ..(*function).clone()
+                    });
+                    if let Some(coverage) = &mut self.coverage {
+                        coverage.add(&function);
+                    }
+                    function
                 }
-                Op::Negate => {
-                    let num = match self.pop()? {
-                        Value::Number(num) => num,
-                        _ => {
-                            return self.runtime_error("Operand must be a number.");
-                        }
-                    };
-                    self.push(Value::Number(-num))?
+                Err(error) => {
+                    self.reset_stack();
+                    return Err(error);
                 }
-                Op::Print => {
-                    self.pop()?.println();
+            },
+            None => function,
+        };
+
+        if let Some(profile) = &mut self.profile {
+            profile.record_call(&function);
+        }
+        self.frames.push(CallFrame {
+            function,
+            closure,
+            ip: 0,
+            starts_at: self.stack_count - arg_count - 1,
+        });
+
+        Ok(())
+    }
+
+    // Moves each keyword argument into the stack slot of the parameter it
+    // names. The parser has already rejected repeated names, so every
+    // parameter after the positional ones gets exactly one argument.
+    fn order_keyword_arguments(
+        &mut self,
+        function: &Function,
+        arg_count: usize,
+        names: &[string::Handle],
+    ) -> Result<()> {
+        let params = &function.params;
+        if arg_count != params.len() {
+            let message = format!("Expected {} arguments but got {}.", params.len(), arg_count);
+            return self.runtime_error(message.as_str());
+        }
+
+        let positional = arg_count - names.len();
+        let start = self.stack_count - names.len();
+        let mut ordered = vec![Value::Nil; names.len()];
+        for (i, name) in names.iter().enumerate() {
+            match params.iter().position(|param| param == name) {
+                Some(slot) if slot >= positional => {
+                    ordered[slot - positional] = self.stack[start + i].clone();
                 }
-                Op::Jump => {
-                    let offset: usize = self.read_u16()?.into();
-                    let mut frame = self.current_frame_mut();
-                    frame.ip += offset;
+                Some(_) => {
+                    let message = format!("Argument '{}' was already passed by position.", name);
+                    return self.runtime_error(message.as_str());
                 }
-                Op::JumpIfFalse => {
-                    let offset: usize = self.read_u16()?.into();
-                    if self.peek(0)?.is_falsy() {
-                        let frame = self.current_frame_mut();
-                        frame.ip += offset
-                    }
+                None => {
+                    let message = format!("Unexpected argument '{}'.", name);
+                    return self.runtime_error(message.as_str());
                 }
-                Op::Loop => {
-                    let offset = self.read_u16()?;
-                    let frame = self.current_frame_mut();
-                    frame.ip -= offset as usize;
+            }
+        }
+        for (i, value) in ordered.into_iter().enumerate() {
+            self.stack[start + i] = value;
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn call_native(&mut self, function: native::Function, arg_count: usize) -> Result<()> {
+        let arg_start = self.stack_count - arg_count - 1;
+        let result = function(&self.stack[arg_start + 1..self.stack_count]);
+        self.truncate_stack(self.stack_count - arg_count);
+        self.stack[self.stack_count - 1] = result;
+        Ok(())
+    }
+
+    fn call_builtin(&mut self, builtin: Builtin, arg_count: usize) -> Result<()> {
+        match builtin {
+            Builtin::Fiber | Builtin::Reflect => {
+                self.runtime_error("Can only call functions and classes.")
+            }
+            Builtin::Set => {
+                let start = self.stack_count - arg_count;
+                let set = self.collect_set(start)?;
+                self.truncate_stack(start - 1);
+                self.push(set)
+            }
+            Builtin::ReflectGlobals | Builtin::WeakRefGet(_) if arg_count != 0 => {
+                let message = format!("Expected 0 arguments but got {}.", arg_count);
+                self.runtime_error(message.as_str())
+            }
+            Builtin::SetAdd(_)
+            | Builtin::SetHas(_)
+            | Builtin::SetRemove(_)
+            | Builtin::ReflectName
+            | Builtin::ReflectArity
+            | Builtin::ReflectUpvalues
+            | Builtin::ReflectFields
+                if arg_count != 1 =>
+            {
+                let message = format!("Expected 1 argument but got {}.", arg_count);
+                self.runtime_error(message.as_str())
+            }
+            _ if arg_count > 1 => {
+                let message = format!("Expected at most 1 argument but got {}.", arg_count);
+                self.runtime_error(message.as_str())
+            }
+            Builtin::FiberCreate => {
+                let function = self.peek(0)?.clone();
+                let arity = match function.as_function() {
+                    Some(function) if arg_count == 1 => function.arity,
+                    _ => return self.runtime_error("Fiber.create() expects a function."),
+                };
+                if arity > 1 {
+                    return self.runtime_error("A fiber's function takes at most 1 parameter.");
                 }
-                Op::Call => {
-                    let arg_count = self.read_u8()? as usize;
-                    let callee = self.peek(arg_count)?.clone();
-                    self.call_value(callee, arg_count)?;
+                self.truncate_stack(self.stack_count - 2);
+                self.heap.count_allocation(
+                    gc::arc_size::<Lock<Fiber>>() + STACK_INITIAL * std::mem::size_of::<Value>(),
+                );
+                self.push(Value::Fiber(Arc::new(Lock::new(Fiber::new(function)))))
+            }
+            Builtin::FiberYield => {
+                let value = if arg_count == 1 {
+                    self.pop()?
+                } else {
+                    Value::Nil
+                };
+                self.pop()?;
+                self.leave_fiber(FiberStatus::Suspended, value)
+            }
+            Builtin::FiberResume(fiber) => self.resume(fiber, arg_count),
+            Builtin::ReflectGlobals => {
+                let names = self.script_globals().into_iter().map(|(name, _)| name);
+                let names = string_tuple(names.collect());
+                self.pop()?;
+                self.push(names)
+            }
+            Builtin::ReflectName | Builtin::ReflectArity | Builtin::ReflectUpvalues => {
+                let value = match self.pop()?.as_function() {
+                    Some(function) => match builtin {
+                        Builtin::ReflectName => Value::String(self.intern(function.get_name())?),
+                        Builtin::ReflectArity => Value::Int(function.arity as i64),
+                        _ => Value::Int(function.upvalue_count as i64),
+                    },
+                    None => {
+                        return self
+                            .runtime_error("Only functions declared with 'fun' can be inspected.")
+                    }
+                };
+                self.pop()?;
+                self.push(value)
+            }
+            Builtin::ReflectFields => {
+                let names = match self.pop()? {
+                    Value::Instance(instance) => {
+                        let names = instance.borrow().fields.keys().cloned().collect();
+                        string_tuple(names)
+                    }
+                    _ => return self.runtime_error("Only objects have fields."),
+                };
+                self.pop()?;
+                self.push(names)
+            }
+            Builtin::WeakRef => {
+                let weak = match self.peek(0)? {
+                    Value::Instance(instance) if arg_count == 1 => Arc::downgrade(instance),
+                    _ => return self.runtime_error("WeakRef() expects an object."),
+                };
+                self.truncate_stack(self.stack_count - 2);
+                self.push(Value::WeakRef(weak))
+            }
+            Builtin::WeakRefGet(weak) => {
+                self.pop()?;
+                self.push(weak.upgrade().map_or(Value::Nil, Value::Instance))
+            }
+            Builtin::Eval => {
+                let source = match self.peek(0)? {
+                    Value::String(source) if arg_count == 1 => Arc::<str>::from(source.to_string()),
+                    _ => return self.runtime_error("eval() expects a string."),
+                };
+                self.truncate_stack(self.stack_count - 2);
+                let tokens = scanner::scan_tokens(&source);
+                if tokens.is_empty() {
+                    return self.push(Value::Nil);
                 }
-                Op::Closure => {
-                    let fun = match self.read_constant()? {
-                        Value::Function(fun) => Ok(fun.clone()),
-                        _ => Err(InterpretError::InternalError(
-                            "Expected function for closure",
-                        )),
-                    }?;
-                    let upvalue_count = fun.upvalue_count;
-                    let mut closure = Closure::new(fun);
-                    let offset = self.current_frame().starts_at;
-                    for i in 0..upvalue_count {
-                        let is_local = self.read_u8()?;
-                        let index = self.read_u8()? as usize;
-                        let upvalue = if is_local == 1 {
-                            let value: *mut Value = &mut self.stack[offset + index];
-                            self.capture_upvalue(value)
-                        } else {
-                            self.current_frame().closure.as_ref().unwrap().upvalues[i].clone()
-                        };
-                        closure.upvalues.push(upvalue)
+                let options = CompileOptions {
+                    dump_ast: false,
+                    dump_ir: false,
+                    disassemble: false,
+                    ..self.options.clone()
+                };
+                let function = match compile_with(&source, tokens, &options, &mut self.names, None)
+                {
+                    Ok(function) => Arc::new(function),
+                    Err(_) => {
+                        return self.runtime_error("Could not compile the source given to eval().")
                     }
-                    self.push(Value::Closure(closure))?
+                };
+                // Compiling interned the code's names and strings.
+                self.check_string_bytes(0)?;
+                // The source runs as a script of its own, so its top-level
+                // declarations are globals and its result is the return value.
+                self.push(Value::Function(function.clone()))?;
+                self.call(function, None, 0)
+            }
+            Builtin::Breakpoint => {
+                if arg_count != 0 {
+                    return self.runtime_error("breakpoint() expects no arguments.");
                 }
-                Op::CloseUpvalue => {
-                    self.close_upvalues(&self.stack[self.stack_count - 1]);
-                    self.pop()?;
+                self.pop()?;
+                if self.config.debug {
+                    self.print_state();
                 }
-                Op::Return => {
-                    let result = self.pop()?;
-                    let starts_at = self.current_frame().starts_at;
-                    self.close_upvalues(&self.stack[starts_at]);
-                    self.frame_count -= 1;
-                    if self.frame_count == 0 {
-                        self.pop()?;
-                        return Ok(());
-                    }
-
-                    self.stack_count = starts_at;
-                    self.push(result)?
+                self.push(Value::Nil)
+            }
+            Builtin::SetAdd(set) => {
+                let value = self.pop()?;
+                self.pop()?;
+                if !set::is_hashable(&value) {
+                    return self.unhashable_error(&value);
                 }
+                set.borrow_mut().insert(value);
+                self.push(Value::Nil)
+            }
+            Builtin::SetHas(set) => {
+                let value = self.pop()?;
+                self.pop()?;
+                self.push(Value::Bool(set.borrow().contains(&value)))
+            }
+            Builtin::SetRemove(set) => {
+                let value = self.pop()?;
+                self.pop()?;
+                self.push(Value::Bool(set.borrow_mut().remove(&value)))
+            }
+        }
+    }
+
+    // Builds a set from the values on the stack above `start`.
+    fn collect_set(&mut self, start: usize) -> Result<Value> {
+        let mut set = Set::default();
+        for i in start..self.stack_count {
+            let value = std::mem::replace(&mut self.stack[i], Value::Nil);
+            if !set::is_hashable(&value) {
+                return self.unhashable_error(&value);
+            }
+            set.insert(value);
+        }
+        self.heap.count_allocation(gc::arc_size::<Lock<Set>>());
+        Ok(Value::Set(Arc::new(Lock::new(set))))
+    }
+
+    fn unhashable_error<T>(&mut self, value: &Value) -> Result<T> {
+        let message = format!("Can't put a {} in a set.", value.type_name());
+        self.runtime_error(message.as_str())
+    }
+
+    #[inline(always)]
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<()> {
+        match callee {
+            Value::Function(function) => self.call(function, None, arg_count),
+            Value::Closure(closure) => {
+                self.call(closure.function.clone(), Some(closure), arg_count)
+            }
+            Value::Native(function) => self.call_native(function, arg_count),
+            Value::Builtin(builtin) => self.call_builtin(builtin, arg_count),
+            _ => self.runtime_error("Can only call functions and classes."),
+        }
+    }
+
+    #[inline(always)]
+    fn capture_upvalue(&mut self, slot: usize) -> Arc<Lock<Upvalue>> {
+        let mut previous: Option<Arc<Lock<Upvalue>>> = None;
+        let mut current: &mut Option<Arc<Lock<Upvalue>>> = &mut self.open_upvalues;
+        let mut _temp: Option<Arc<Lock<Upvalue>>> = None;
+        while current.is_some() && current.as_ref().unwrap().borrow().slot > slot {
+            previous = Some(Arc::clone(current.as_ref().unwrap()));
+            _temp = if let Some(value) = &previous.as_ref().unwrap().borrow().next {
+                Some(Arc::clone(value))
+            } else {
+                None
+            };
+            current = &mut _temp;
+        }
+
+        if let Some(value) = current {
+            let upvalue = value.borrow();
+            if upvalue.slot == slot {
+                return Arc::clone(value);
+            }
+        }
+
+        let created_upvalue = Arc::new(Lock::new(Upvalue::new(
+            slot,
+            self.fiber.as_ref().map(Arc::downgrade),
+            current.as_ref().map(Arc::clone),
+        )));
+        self.heap.track_upvalue(&created_upvalue);
+
+        match previous {
+            None => self.open_upvalues = Some(Arc::clone(&created_upvalue)),
+            Some(previous) => previous.borrow_mut().next = Some(Arc::clone(&created_upvalue)),
+        }
+
+        created_upvalue
+    }
+
+    #[inline(always)]
+    fn close_upvalues(&mut self, last: usize) {
+        while self.open_upvalues.is_some()
+            && self.open_upvalues.as_ref().unwrap().borrow().slot >= last
+        {
+            let rc = self.open_upvalues.as_ref().unwrap();
+            let mut upvalue = rc.borrow_mut();
+            let next = upvalue.next.as_ref().map(Arc::clone);
+            let value = self.stack[upvalue.slot].clone();
+            if let Some(marker) = &mut self.marking {
+                marker.mark_value(&value);
             }
+            upvalue.close(value);
+            drop(upvalue);
+            self.open_upvalues = next;
         }
     }
+
+    #[inline(always)]
+    fn read_u8(&mut self) -> Result<u8> {
+        let frame = self.current_frame_mut();
+        let ip = frame.ip;
+        frame.ip += 1;
+        let chunk = self.current_chunk();
+        chunk
+            .code
+            .get(ip)
+            .copied()
+            .ok_or(InterpretError::InternalError("Failed to read byte."))
+    }
+
+    #[inline(always)]
+    fn read_constant(&mut self) -> Result<&Value> {
+        let constant: usize = self.read_u8()?.into();
+        self.current_chunk()
+            .constants
+            .get(constant)
+            .ok_or(InterpretError::InternalError("Failed to read constant."))
+    }
+
+    // A 24-bit constant index, for the long forms of instructions.
+    fn read_long_constant(&mut self) -> Result<&Value> {
+        let mut constant = 0;
+        for _ in 0..3 {
+            constant = (constant << 8) | self.read_u8()? as usize;
+        }
+        self.current_chunk()
+            .constants
+            .get(constant)
+            .ok_or(InterpretError::InternalError("Failed to read constant."))
+    }
+
+    #[inline(always)]
+    fn read_u16(&mut self) -> Result<u16> {
+        let byte1: u16 = self.read_u8()?.into();
+        let byte2: u16 = self.read_u8()?.into();
+        Ok((byte1 << 8) | byte2)
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let high: u32 = self.read_u16()?.into();
+        let low: u32 = self.read_u16()?.into();
+        Ok((high << 16) | low)
+    }
+
+    #[inline(always)]
+    fn read_name(&mut self) -> Result<string::Handle> {
+        let index: usize = self.read_u16()?.into();
+        Ok(self.names.handle(index).clone())
+    }
+
+    // A negative index is out of range, so it comes back as `None`.
+    fn index(&mut self, index: Value) -> Result<Option<usize>> {
+        let index = match index {
+            Value::Int(index) => index,
+            Value::BigInt(index) => match index.to_i64() {
+                Some(index) => index,
+                None => return Ok(None),
+            },
+            Value::Number(index) if index.fract() == 0.0 => index as i64,
+            Value::Number(_) => return self.runtime_error("Index must be an integer."),
+            _ => return self.runtime_error("Index must be a number."),
+        };
+        Ok(index.try_into().ok())
+    }
+
+    // Big ints combine with ints but not with floats, which would quietly lose
+    // the precision a big int is there to keep.
+    fn big_operands(
+        &mut self,
+        a: &Value,
+        b: &Value,
+        error: &str,
+    ) -> Result<(Arc<BigInt>, Arc<BigInt>)> {
+        match (a.as_big_int(), b.as_big_int()) {
+            (Some(a), Some(b)) => Ok((a, b)),
+            _ if matches!(a, Value::Number(_)) || matches!(b, Value::Number(_)) => {
+                self.runtime_error("Can't mix big integers and floats.")
+            }
+            _ => self.runtime_error(error),
+        }
+    }
+
+    fn big_arithmetic(
+        &mut self,
+        a: &Value,
+        b: &Value,
+        op: fn(&BigInt, &BigInt) -> Option<BigInt>,
+        error: &str,
+    ) -> Result<Value> {
+        let (a, b) = self.big_operands(a, b, error)?;
+        match op(&a, &b) {
+            Some(value) => Ok(Value::BigInt(Arc::new(value))),
+            None => self.runtime_error("Division by zero."),
+        }
+    }
+
+    fn add(&mut self) -> Result<()> {
+        if let Some(value) =
+            arithmetic(self.peek(1)?, self.peek(0)?, i64::checked_add, |a, b| a + b)
+        {
+            self.replace_top(2, value);
+            return Ok(());
+        }
+        let value = match (self.pop()?, self.pop()?) {
+            (Value::String(b), Value::String(a)) => Value::String(self.concat(&a, &b)?),
+            (b @ (Value::Number(_) | Value::Int(_) | Value::BigInt(_)), Value::String(a))
+                if !self.options.strict_concat =>
+            {
+                let b = self.intern(&b.to_string())?;
+                Value::String(self.concat(&a, &b)?)
+            }
+            (Value::String(b), a @ (Value::Number(_) | Value::Int(_) | Value::BigInt(_)))
+                if !self.options.strict_concat =>
+            {
+                let a = self.intern(&a.to_string())?;
+                Value::String(self.concat(&a, &b)?)
+            }
+            (b, a) if is_big_int(&a) || is_big_int(&b) => self.big_arithmetic(
+                &a,
+                &b,
+                |a, b| Some(a.add(b)),
+                "Operands must be two numbers or two strings.",
+            )?,
+            (b, a) => match arithmetic(&a, &b, i64::checked_add, |a, b| a + b) {
+                Some(value) => value,
+                None => {
+                    return self.runtime_error("Operands must be two numbers or two strings.");
+                }
+            },
+        };
+
+        self.push(value)
+    }
+
+    // A rope's contents are only interned once something reads them, so a
+    // new string counts against the limit at its full length right away.
+    fn concat(&mut self, a: &string::Handle, b: &string::Handle) -> Result<string::Handle> {
+        self.check_string_bytes(a.len() + b.len())?;
+        Ok(self.names.strings.concat(a, b))
+    }
+
+    // Every string the VM makes while running goes through here or
+    // `concat`, so they all count against the limit.
+    fn intern(&mut self, string: &str) -> Result<string::Handle> {
+        if !self.names.strings.contains(string) {
+            self.check_string_bytes(string.len())?;
+        }
+        Ok(self.names.strings.intern(string))
+    }
+
+    // Whether `bytes` more of strings fit in this VM's limit.
+    fn check_string_bytes(&mut self, bytes: usize) -> Result<()> {
+        match self.config.max_interned_bytes {
+            Some(max) if self.names.strings.bytes() + bytes > max => {
+                self.runtime_error("String memory limit exceeded.")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn compare(
+        &mut self,
+        int: fn(&i64, &i64) -> bool,
+        big: fn(&BigInt, &BigInt) -> bool,
+        float: fn(&f64, &f64) -> bool,
+    ) -> Result<()> {
+        if let Some(value) = compare_numbers(self.peek(1)?, self.peek(0)?, int, float) {
+            self.replace_top(2, Value::Bool(value));
+            return Ok(());
+        }
+        let value = match (self.pop()?, self.pop()?) {
+            (b, a) if is_big_int(&a) || is_big_int(&b) => {
+                let (a, b) = self.big_operands(&a, &b, "Operands must be numbers.")?;
+                big(&a, &b)
+            }
+            (b, a) => match (a.as_f64(), b.as_f64()) {
+                (Some(a), Some(b)) => float(&a, &b),
+                _ => return self.runtime_error("Operands must be numbers."),
+            },
+        };
+
+        self.push(Value::Bool(value))
+    }
+
+    fn arithmetic(
+        &mut self,
+        int: fn(i64, i64) -> Option<i64>,
+        big: fn(&BigInt, &BigInt) -> Option<BigInt>,
+        float: fn(f64, f64) -> f64,
+    ) -> Result<()> {
+        if let Some(value) = arithmetic(self.peek(1)?, self.peek(0)?, int, float) {
+            self.replace_top(2, value);
+            return Ok(());
+        }
+        let b = self.pop()?;
+        let a = self.pop()?;
+        let value = if is_big_int(&a) || is_big_int(&b) {
+            self.big_arithmetic(&a, &b, big, "Operands must be numbers.")?
+        } else {
+            match arithmetic(&a, &b, int, float) {
+                Some(value) => value,
+                None => return self.runtime_error("Operands must be numbers."),
+            }
+        };
+        self.push(value)
+    }
+
+    fn run(&mut self) -> Result<Value> {
+        if self.instrumented {
+            return self.run_instrumented();
+        }
+        loop {
+            let instruction = self.read_u8()?;
+            if let Some(result) = HANDLERS[instruction as usize](self)? {
+                return Ok(result);
+            }
+        }
+    }
+
+    // Runs instructions the slow way, for tracing and profiling.
+    #[inline(never)]
+    fn run_instrumented(&mut self) -> Result<Value> {
+        loop {
+            let instruction = self.read_u8()?;
+            if let Some(result) = self.run_instruction_instrumented(instruction)? {
+                return Ok(result);
+            }
+        }
+    }
+
+    fn run_instruction_instrumented(&mut self, instruction: u8) -> Result<Option<Value>> {
+        if let Some(tracer) = &mut self.tracer {
+            let frame = self.frames.last().unwrap();
+            if tracer.traces(frame.function.get_name()) {
+                let stack = &self.stack[..self.stack_count];
+                tracer.record(stack, &frame.function.chunk, frame.ip - 1, &self.names);
+            }
+        }
+        if let Some(coverage) = &mut self.coverage {
+            let frame = self.frames.last().unwrap();
+            coverage.record(&frame.function.chunk, frame.ip - 1, self.frames.len());
+        }
+        if let Some(sampler) = &mut self.sampler {
+            if sampler.is_due() {
+                sampler.record(self.frames.iter().map(|frame| frame.function.get_name()));
+            }
+        }
+        if self.profile.is_none() {
+            return HANDLERS[instruction as usize](self);
+        }
+        let function = self.current_frame().function.clone();
+        let start = Instant::now();
+        let result = HANDLERS[instruction as usize](self);
+        let time = start.elapsed();
+        if let Some(profile) = &mut self.profile {
+            profile.record(instruction, &function, time);
+        }
+        result
+    }
+}
+
+// Each instruction's handler returns the script's result once the script
+// returns, and `None` to keep running.
+type Handler = fn(&mut VM) -> Result<Option<Value>>;
+
+// Indexed by opcode, so dispatching skips decoding the byte into an `Op`.
+static HANDLERS: [Handler; 256] = handlers();
+
+const fn handlers() -> [Handler; 256] {
+    let mut table: [Handler; 256] = [VM::op_unknown; 256];
+    table[Op::Constant as usize] = VM::op_constant;
+    table[Op::ConstantLong as usize] = VM::op_constant_long;
+    table[Op::Nil as usize] = VM::op_nil;
+    table[Op::True as usize] = VM::op_true;
+    table[Op::False as usize] = VM::op_false;
+    table[Op::Zero as usize] = VM::op_zero;
+    table[Op::One as usize] = VM::op_one;
+    table[Op::MinusOne as usize] = VM::op_minus_one;
+    table[Op::SmallInt as usize] = VM::op_small_int;
+    table[Op::Pop as usize] = VM::op_pop;
+    table[Op::PopN as usize] = VM::op_pop_n;
+    table[Op::Dup as usize] = VM::op_dup;
+    table[Op::Swap as usize] = VM::op_swap;
+    table[Op::GetLocal as usize] = VM::op_get_local;
+    table[Op::SetLocal as usize] = VM::op_set_local;
+    table[Op::GetGlobal as usize] = VM::op_get_global;
+    table[Op::DefineGlobal as usize] = VM::op_define_global;
+    table[Op::SetGlobal as usize] = VM::op_set_global;
+    table[Op::GetUpvalue as usize] = VM::op_get_upvalue;
+    table[Op::SetUpvalue as usize] = VM::op_set_upvalue;
+    table[Op::GetLocalLong as usize] = VM::op_get_local_long;
+    table[Op::SetLocalLong as usize] = VM::op_set_local_long;
+    table[Op::GetUpvalueLong as usize] = VM::op_get_upvalue_long;
+    table[Op::SetUpvalueLong as usize] = VM::op_set_upvalue_long;
+    table[Op::GetProperty as usize] = VM::op_get_property;
+    table[Op::SetProperty as usize] = VM::op_set_property;
+    table[Op::Equal as usize] = VM::op_equal;
+    table[Op::Identical as usize] = VM::op_identical;
+    table[Op::Greater as usize] = VM::op_greater;
+    table[Op::Less as usize] = VM::op_less;
+    table[Op::Add as usize] = VM::op_add;
+    table[Op::Subtract as usize] = VM::op_subtract;
+    table[Op::Multiply as usize] = VM::op_multiply;
+    table[Op::Divide as usize] = VM::op_divide;
+    table[Op::Not as usize] = VM::op_not;
+    table[Op::Negate as usize] = VM::op_negate;
+    table[Op::Type as usize] = VM::op_type;
+    table[Op::Index as usize] = VM::op_index;
+    table[Op::Tuple as usize] = VM::op_tuple;
+    table[Op::Set as usize] = VM::op_set;
+    table[Op::Object as usize] = VM::op_object;
+    table[Op::ObjectLong as usize] = VM::op_object_long;
+    table[Op::Union as usize] = VM::op_union;
+    table[Op::Intersection as usize] = VM::op_intersection;
+    table[Op::Jump as usize] = VM::op_jump;
+    table[Op::JumpIfFalse as usize] = VM::op_jump_if_false;
+    table[Op::JumpIfTrue as usize] = VM::op_jump_if_true;
+    table[Op::Loop as usize] = VM::op_loop;
+    table[Op::JumpLong as usize] = VM::op_jump_long;
+    table[Op::JumpIfFalseLong as usize] = VM::op_jump_if_false_long;
+    table[Op::JumpIfTrueLong as usize] = VM::op_jump_if_true_long;
+    table[Op::LoopLong as usize] = VM::op_loop_long;
+    table[Op::LessJumpIfFalse as usize] = VM::op_less_jump_if_false;
+    table[Op::LessJumpIfTrue as usize] = VM::op_less_jump_if_true;
+    table[Op::GreaterJumpIfFalse as usize] = VM::op_greater_jump_if_false;
+    table[Op::GreaterJumpIfTrue as usize] = VM::op_greater_jump_if_true;
+    table[Op::EqualJumpIfFalse as usize] = VM::op_equal_jump_if_false;
+    table[Op::EqualJumpIfTrue as usize] = VM::op_equal_jump_if_true;
+    table[Op::Call as usize] = VM::op_call;
+    table[Op::CallKeywords as usize] = VM::op_call_keywords;
+    table[Op::Invoke as usize] = VM::op_invoke;
+    table[Op::Closure as usize] = VM::op_closure;
+    table[Op::ClosureLong as usize] = VM::op_closure_long;
+    table[Op::CloseUpvalue as usize] = VM::op_close_upvalue;
+    table[Op::AddLocalConstant as usize] = VM::op_add_local_constant;
+    table[Op::AddLocalSmallInt as usize] = VM::op_add_local_small_int;
+    table[Op::LessLocalsJumpIfFalse as usize] = VM::op_less_locals_jump_if_false;
+    table[Op::Return as usize] = VM::op_return;
+    table
+}
+
+impl VM {
+    fn op_unknown(&mut self) -> Result<Option<Value>> {
+        let ip = self.current_frame().ip;
+        let message = format!(
+            "Got unexpected instruction: '{}'",
+            self.current_chunk().code[ip - 1]
+        );
+        self.runtime_error(message.as_str())
+    }
+
+    fn op_constant(&mut self) -> Result<Option<Value>> {
+        let constant = self.read_constant()?.clone();
+        self.push(constant)?;
+        Ok(None)
+    }
+
+    fn op_constant_long(&mut self) -> Result<Option<Value>> {
+        let constant = self.read_long_constant()?.clone();
+        self.push(constant)?;
+        Ok(None)
+    }
+
+    fn op_nil(&mut self) -> Result<Option<Value>> {
+        self.push(Value::Nil)?;
+        Ok(None)
+    }
+
+    fn op_true(&mut self) -> Result<Option<Value>> {
+        self.push(Value::Bool(true))?;
+        Ok(None)
+    }
+
+    fn op_false(&mut self) -> Result<Option<Value>> {
+        self.push(Value::Bool(false))?;
+        Ok(None)
+    }
+
+    fn op_zero(&mut self) -> Result<Option<Value>> {
+        self.push(Value::Int(0))?;
+        Ok(None)
+    }
+
+    fn op_one(&mut self) -> Result<Option<Value>> {
+        self.push(Value::Int(1))?;
+        Ok(None)
+    }
+
+    fn op_minus_one(&mut self) -> Result<Option<Value>> {
+        self.push(Value::Int(-1))?;
+        Ok(None)
+    }
+
+    fn op_small_int(&mut self) -> Result<Option<Value>> {
+        let value = self.read_u8()? as i8;
+        self.push(Value::Int(value.into()))?;
+        Ok(None)
+    }
+
+    fn op_pop(&mut self) -> Result<Option<Value>> {
+        self.pop()?;
+        Ok(None)
+    }
+
+    fn op_pop_n(&mut self) -> Result<Option<Value>> {
+        let count = self.read_u8()? as usize;
+        self.truncate_stack(self.stack_count - count);
+        Ok(None)
+    }
+
+    fn op_dup(&mut self) -> Result<Option<Value>> {
+        let value = self.peek(0)?.clone();
+        self.push(value)?;
+        Ok(None)
+    }
+
+    fn op_swap(&mut self) -> Result<Option<Value>> {
+        self.stack.swap(self.stack_count - 1, self.stack_count - 2);
+        Ok(None)
+    }
+
+    fn get_local(&mut self, slot: usize) -> Result<Option<Value>> {
+        let offset = self.current_frame().starts_at;
+        self.push(self.stack[slot + offset].clone())?;
+        Ok(None)
+    }
+
+    fn op_get_local(&mut self) -> Result<Option<Value>> {
+        let slot = self.read_u8()?.into();
+        self.get_local(slot)
+    }
+
+    fn op_get_local_long(&mut self) -> Result<Option<Value>> {
+        let slot = self.read_u16()?.into();
+        self.get_local(slot)
+    }
+
+    fn set_local(&mut self, slot: usize) -> Result<Option<Value>> {
+        let offset = self.current_frame().starts_at;
+        self.stack[slot + offset] = self.peek(0)?.clone();
+        Ok(None)
+    }
+
+    fn op_set_local(&mut self) -> Result<Option<Value>> {
+        let slot = self.read_u8()?.into();
+        self.set_local(slot)
+    }
+
+    fn op_set_local_long(&mut self) -> Result<Option<Value>> {
+        let slot = self.read_u16()?.into();
+        self.set_local(slot)
+    }
+
+    fn op_get_global(&mut self) -> Result<Option<Value>> {
+        let slot = self.read_u16()?.into();
+        match self.lookup_global(slot) {
+            Some(value) => {
+                let clone = value.clone();
+                self.push(clone)?
+            }
+            _ => {
+                let error = format!("Undefined variable '{}'.", self.names.name(slot));
+                return self.runtime_error(error.as_str());
+            }
+        }
+        Ok(None)
+    }
+
+    fn op_define_global(&mut self) -> Result<Option<Value>> {
+        let slot = self.read_u16()?.into();
+        let value = self.pop()?;
+        self.write_barrier(&value);
+        *self.global_mut(slot) = Some(value);
+        Ok(None)
+    }
+
+    fn op_set_global(&mut self) -> Result<Option<Value>> {
+        let slot = self.read_u16()?.into();
+        if self.lookup_global(slot).is_none() {
+            let error = format!("Undefined variable '{}'.", self.names.name(slot));
+            return self.runtime_error(error.as_str());
+        }
+        let value = self.peek(0)?.clone();
+        self.write_barrier(&value);
+        *self.global_mut(slot) = Some(value);
+        Ok(None)
+    }
+
+    fn get_upvalue(&mut self, slot: usize) -> Result<Option<Value>> {
+        let upvalue = self.frames.last().unwrap().upvalues()[slot].borrow();
+        let value = match &upvalue.closed {
+            Some(value) => value.clone(),
+            None if same_fiber(&upvalue.fiber, &self.fiber) => self.stack[upvalue.slot].clone(),
+            None => {
+                let (fiber, slot) = (upvalue.fiber.clone(), upvalue.slot);
+                drop(upvalue);
+                let value = self.with_stack_of(&fiber, |stack| stack[slot].clone());
+                self.push(value)?;
+                return Ok(None);
+            }
+        };
+        drop(upvalue);
+        self.push(value)?;
+        Ok(None)
+    }
+
+    // Runs `f` on the stack an open upvalue of `fiber` points into, wherever
+    // the stack is kept while that fiber isn't running.
+    fn with_stack_of<T>(
+        &mut self,
+        fiber: &Option<Weak<Lock<Fiber>>>,
+        f: impl FnOnce(&mut Vec<Value>) -> T,
+    ) -> T {
+        if same_fiber(fiber, &self.fiber) {
+            return f(&mut self.stack);
+        }
+        // A fiber that resumed another left its stack with that one.
+        let mut next = self.fiber.clone();
+        while let Some(resumed) = next {
+            let mut resumed = resumed.borrow_mut();
+            if same_fiber(fiber, &resumed.caller) {
+                return f(&mut resumed.stack);
+            }
+            next = resumed.caller.clone();
+        }
+        // Otherwise the fiber isn't running and keeps its own stack. It closes
+        // its upvalues when it's dropped, so it's still alive.
+        let suspended = fiber
+            .as_ref()
+            .and_then(Weak::upgrade)
+            .expect("A fiber with open upvalues should be alive.");
+        let mut suspended = suspended.borrow_mut();
+        f(&mut suspended.stack)
+    }
+
+    fn op_get_upvalue(&mut self) -> Result<Option<Value>> {
+        let slot = self.read_u8()?.into();
+        self.get_upvalue(slot)
+    }
+
+    fn op_get_upvalue_long(&mut self) -> Result<Option<Value>> {
+        let slot = self.read_u16()?.into();
+        self.get_upvalue(slot)
+    }
+
+    fn set_upvalue(&mut self, slot: usize) -> Result<Option<Value>> {
+        let value = self.peek(0)?.clone();
+        self.write_barrier(&value);
+        let mut upvalue = self.frames.last().unwrap().upvalues()[slot].borrow_mut();
+        if let Some(closed) = &mut upvalue.closed {
+            *closed = value;
+            return Ok(None);
+        }
+        if same_fiber(&upvalue.fiber, &self.fiber) {
+            self.stack[upvalue.slot] = value;
+            return Ok(None);
+        }
+        let (fiber, slot) = (upvalue.fiber.clone(), upvalue.slot);
+        drop(upvalue);
+        self.with_stack_of(&fiber, |stack| stack[slot] = value);
+        Ok(None)
+    }
+
+    fn op_set_upvalue(&mut self) -> Result<Option<Value>> {
+        let slot = self.read_u8()?.into();
+        self.set_upvalue(slot)
+    }
+
+    fn op_set_upvalue_long(&mut self) -> Result<Option<Value>> {
+        let slot = self.read_u16()?.into();
+        self.set_upvalue(slot)
+    }
+
+    fn op_get_property(&mut self) -> Result<Option<Value>> {
+        let name = self.read_name()?;
+        let value = self.property(0, &name)?;
+        self.pop()?;
+        self.push(value)?;
+        Ok(None)
+    }
+
+    fn op_invoke(&mut self) -> Result<Option<Value>> {
+        let name = self.read_name()?;
+        let arg_count = self.read_u8()? as usize;
+        let callee = self.property(arg_count, &name)?;
+        let slot = self.stack_count - 1 - arg_count;
+        self.stack[slot] = callee.clone();
+        self.call_value(callee, arg_count)?;
+        Ok(None)
+    }
+
+    // There are no classes yet, only instance fields and the builtins'
+    // methods.
+    fn property(&mut self, distance: usize, name: &string::Handle) -> Result<Value> {
+        let value = match (self.peek(distance)?, name.as_str()) {
+            (Value::Builtin(Builtin::Fiber), "create") => Value::Builtin(Builtin::FiberCreate),
+            (Value::Builtin(Builtin::Fiber), "yield") => Value::Builtin(Builtin::FiberYield),
+            (Value::Fiber(fiber), "resume") => {
+                Value::Builtin(Builtin::FiberResume(Arc::clone(fiber)))
+            }
+            (Value::Fiber(fiber), "isDone") => {
+                Value::Bool(fiber.borrow().status == FiberStatus::Done)
+            }
+            (Value::Instance(instance), _) => {
+                let field = instance.borrow().fields.get(name).cloned();
+                match field {
+                    Some(value) => value,
+                    None => {
+                        let error = format!("Undefined property '{}'.", name);
+                        return self.runtime_error(error.as_str());
+                    }
+                }
+            }
+            (Value::Set(set), "add") => Value::Builtin(Builtin::SetAdd(Arc::clone(set))),
+            (Value::Set(set), "has") => Value::Builtin(Builtin::SetHas(Arc::clone(set))),
+            (Value::Set(set), "remove") => Value::Builtin(Builtin::SetRemove(Arc::clone(set))),
+            (Value::Builtin(Builtin::Reflect), "globals") => {
+                Value::Builtin(Builtin::ReflectGlobals)
+            }
+            (Value::Builtin(Builtin::Reflect), "name") => Value::Builtin(Builtin::ReflectName),
+            (Value::Builtin(Builtin::Reflect), "arity") => Value::Builtin(Builtin::ReflectArity),
+            (Value::Builtin(Builtin::Reflect), "upvalues") => {
+                Value::Builtin(Builtin::ReflectUpvalues)
+            }
+            (Value::Builtin(Builtin::Reflect), "fields") => Value::Builtin(Builtin::ReflectFields),
+            (Value::WeakRef(weak), "get") => Value::Builtin(Builtin::WeakRefGet(Weak::clone(weak))),
+            (
+                Value::Builtin(Builtin::Fiber | Builtin::Reflect)
+                | Value::Fiber(_)
+                | Value::Set(_)
+                | Value::WeakRef(_),
+                _,
+            ) => {
+                let error = format!("Undefined property '{}'.", name);
+                return self.runtime_error(error.as_str());
+            }
+            _ => return self.runtime_error("Only instances have properties."),
+        };
+        Ok(value)
+    }
+
+    fn op_set_property(&mut self) -> Result<Option<Value>> {
+        let name = self.read_name()?;
+        let instance = match self.peek(1)? {
+            Value::Instance(instance) => Arc::clone(instance),
+            _ => return self.runtime_error("Only instances have fields."),
+        };
+        let value = self.pop()?;
+        self.write_barrier(&value);
+        instance.borrow_mut().fields.insert(name, value.clone());
+        self.pop()?;
+        self.push(value)?;
+        Ok(None)
+    }
+
+    fn op_equal(&mut self) -> Result<Option<Value>> {
+        let value = self.peek(1)? == self.peek(0)?;
+        self.replace_top(2, Value::Bool(value));
+        Ok(None)
+    }
+
+    fn op_identical(&mut self) -> Result<Option<Value>> {
+        let value = self.peek(1)?.is(self.peek(0)?);
+        self.replace_top(2, Value::Bool(value));
+        Ok(None)
+    }
+
+    fn op_greater(&mut self) -> Result<Option<Value>> {
+        self.compare(i64::gt, BigInt::gt, f64::gt)?;
+        Ok(None)
+    }
+
+    fn op_less(&mut self) -> Result<Option<Value>> {
+        self.compare(i64::lt, BigInt::lt, f64::lt)?;
+        Ok(None)
+    }
+
+    fn op_add(&mut self) -> Result<Option<Value>> {
+        self.add()?;
+        Ok(None)
+    }
+
+    fn op_subtract(&mut self) -> Result<Option<Value>> {
+        self.arithmetic(i64::checked_sub, |a, b| Some(a.sub(b)), |a, b| a - b)?;
+        Ok(None)
+    }
+
+    fn op_multiply(&mut self) -> Result<Option<Value>> {
+        self.arithmetic(i64::checked_mul, |a, b| Some(a.mul(b)), |a, b| a * b)?;
+        Ok(None)
+    }
+
+    fn op_divide(&mut self) -> Result<Option<Value>> {
+        self.arithmetic(exact_division, big_division, |a, b| a / b)?;
+        Ok(None)
+    }
+
+    fn op_not(&mut self) -> Result<Option<Value>> {
+        let value = self.peek(0)?.is_falsy();
+        self.replace_top(1, Value::Bool(value));
+        Ok(None)
+    }
+
+    fn op_negate(&mut self) -> Result<Option<Value>> {
+        match negate(self.peek(0)?) {
+            Some(value) => self.replace_top(1, value),
+            None => return self.runtime_error("Operand must be a number."),
+        }
+        Ok(None)
+    }
+
+    fn op_type(&mut self) -> Result<Option<Value>> {
+        let name = self.pop()?.type_name();
+        let name = self.intern(name)?;
+        self.push(Value::String(name))?;
+        Ok(None)
+    }
+
+    fn op_index(&mut self) -> Result<Option<Value>> {
+        let index = self.pop()?;
+        let value = match self.pop()? {
+            Value::String(string) => {
+                let index = self.index(index)?;
+                match index.and_then(|index| string.as_str().chars().nth(index)) {
+                    Some(c) => Value::String(self.intern(c.encode_utf8(&mut [0; 4]))?),
+                    None => return self.runtime_error("String index out of range."),
+                }
+            }
+            Value::Tuple(elements) => {
+                let index = self.index(index)?;
+                match index.and_then(|index| elements.get(index)) {
+                    Some(element) => element.clone(),
+                    None => return self.runtime_error("Tuple index out of range."),
+                }
+            }
+            _ => return self.runtime_error("Only strings and tuples can be indexed."),
+        };
+        self.push(value)?;
+        Ok(None)
+    }
+
+    fn op_tuple(&mut self) -> Result<Option<Value>> {
+        let count = self.read_u8()? as usize;
+        let start = self.stack_count - count;
+        let elements: Arc<[Value]> = self.stack[start..self.stack_count]
+            .iter_mut()
+            .map(|value| std::mem::replace(value, Value::Nil))
+            .collect();
+        self.stack_count = start;
+        self.heap
+            .count_allocation(gc::arc_size::<()>() + count * std::mem::size_of::<Value>());
+        self.push(Value::Tuple(elements))?;
+        Ok(None)
+    }
+
+    fn op_set(&mut self) -> Result<Option<Value>> {
+        let count = self.read_u8()? as usize;
+        let start = self.stack_count - count;
+        let set = self.collect_set(start)?;
+        self.stack_count = start;
+        self.push(set)?;
+        Ok(None)
+    }
+
+    fn op_object(&mut self) -> Result<Option<Value>> {
+        let names = self.read_constant()?.clone();
+        self.object(names)
+    }
+
+    fn op_object_long(&mut self) -> Result<Option<Value>> {
+        let names = self.read_long_constant()?.clone();
+        self.object(names)
+    }
+
+    fn object(&mut self, names: Value) -> Result<Option<Value>> {
+        if self.should_collect() {
+            self.collect_garbage();
+        }
+        let names = match names {
+            Value::Tuple(names) => names,
+            _ => return Err(InterpretError::InternalError("Expected field names.")),
+        };
+        let start = self.stack_count - names.len();
+        let mut instance = Instance::default();
+        for (name, i) in names.iter().zip(start..) {
+            if let Value::String(name) = name {
+                let value = std::mem::replace(&mut self.stack[i], Value::Nil);
+                instance.fields.insert(name.clone(), value);
+            }
+        }
+        self.stack_count = start;
+        let instance = Arc::new(Lock::new(instance));
+        self.heap.track_instance(&instance);
+        self.push(Value::Instance(instance))?;
+        Ok(None)
+    }
+
+    fn set_operation(&mut self, op: fn(&Set, &Set) -> Set) -> Result<Option<Value>> {
+        let (a, b) = match (self.peek(1)?, self.peek(0)?) {
+            (Value::Set(a), Value::Set(b)) => (a.borrow(), b.borrow()),
+            _ => return self.runtime_error("Operands must be two sets."),
+        };
+        let set = op(&a, &b);
+        drop((a, b));
+        self.truncate_stack(self.stack_count - 2);
+        self.heap.count_allocation(gc::arc_size::<Lock<Set>>());
+        self.push(Value::Set(Arc::new(Lock::new(set))))?;
+        Ok(None)
+    }
+
+    fn op_union(&mut self) -> Result<Option<Value>> {
+        self.set_operation(Set::union)
+    }
+
+    fn op_intersection(&mut self) -> Result<Option<Value>> {
+        self.set_operation(Set::intersection)
+    }
+
+    fn op_jump(&mut self) -> Result<Option<Value>> {
+        let offset: usize = self.read_u16()?.into();
+        self.current_frame_mut().ip += offset;
+        Ok(None)
+    }
+
+    fn op_jump_if_false(&mut self) -> Result<Option<Value>> {
+        let offset: usize = self.read_u16()?.into();
+        if self.peek(0)?.is_falsy() {
+            self.current_frame_mut().ip += offset;
+        }
+        Ok(None)
+    }
+
+    fn op_jump_if_true(&mut self) -> Result<Option<Value>> {
+        let offset: usize = self.read_u16()?.into();
+        if !self.peek(0)?.is_falsy() {
+            self.current_frame_mut().ip += offset;
+        }
+        Ok(None)
+    }
+
+    fn op_loop(&mut self) -> Result<Option<Value>> {
+        let offset: usize = self.read_u16()?.into();
+        self.current_frame_mut().ip -= offset;
+        Ok(None)
+    }
+
+    fn op_jump_long(&mut self) -> Result<Option<Value>> {
+        let offset = self.read_u32()? as usize;
+        self.current_frame_mut().ip += offset;
+        Ok(None)
+    }
+
+    fn op_jump_if_false_long(&mut self) -> Result<Option<Value>> {
+        let offset = self.read_u32()? as usize;
+        if self.peek(0)?.is_falsy() {
+            self.current_frame_mut().ip += offset;
+        }
+        Ok(None)
+    }
+
+    fn op_jump_if_true_long(&mut self) -> Result<Option<Value>> {
+        let offset = self.read_u32()? as usize;
+        if !self.peek(0)?.is_falsy() {
+            self.current_frame_mut().ip += offset;
+        }
+        Ok(None)
+    }
+
+    fn op_loop_long(&mut self) -> Result<Option<Value>> {
+        let offset = self.read_u32()? as usize;
+        self.current_frame_mut().ip -= offset;
+        Ok(None)
+    }
+
+    fn op_call(&mut self) -> Result<Option<Value>> {
+        let arg_count = self.read_u8()? as usize;
+        let callee = self.peek(arg_count)?.clone();
+        self.call_value(callee, arg_count)?;
+        Ok(None)
+    }
+
+    fn op_call_keywords(&mut self) -> Result<Option<Value>> {
+        let arg_count = self.read_u8()? as usize;
+        let keyword_count = self.read_u8()? as usize;
+        let mut names = Vec::with_capacity(keyword_count);
+        for _ in 0..keyword_count {
+            names.push(self.read_name()?);
+        }
+        let callee = self.peek(arg_count)?.clone();
+        let function = match callee.as_function() {
+            Some(function) => function.clone(),
+            None => {
+                return self
+                    .runtime_error("Only functions declared with 'fun' take keyword arguments.")
+            }
+        };
+        self.order_keyword_arguments(&function, arg_count, &names)?;
+        self.call_value(callee, arg_count)?;
+        Ok(None)
+    }
+
+    fn op_closure(&mut self) -> Result<Option<Value>> {
+        let function = self.read_constant()?.clone();
+        self.closure(function)
+    }
+
+    fn op_closure_long(&mut self) -> Result<Option<Value>> {
+        let function = self.read_long_constant()?.clone();
+        self.closure(function)
+    }
+
+    fn closure(&mut self, function: Value) -> Result<Option<Value>> {
+        if self.should_collect() {
+            self.collect_garbage();
+        }
+        let fun = match function {
+            Value::Function(fun) => fun,
+            _ => {
+                return Err(InterpretError::InternalError(
+                    "Expected function for closure",
+                ))
+            }
+        };
+        // Without upvalues there's nothing to allocate, but each run of the
+        // declaration still makes a function of its own.
+        if fun.upvalue_count == 0 {
+            self.push(Value::Function(fun.made()))?;
+            return Ok(None);
+        }
+        let upvalue_count = fun.upvalue_count;
+        let mut closure = Closure::new(fun);
+        let offset = self.current_frame().starts_at;
+        for _ in 0..upvalue_count {
+            let is_local = self.read_u8()?;
+            let index = self.read_u16()? as usize;
+            let upvalue = if is_local == 1 {
+                self.capture_upvalue(offset + index)
+            } else {
+                self.current_frame().upvalues()[index].clone()
+            };
+            closure.upvalues.push(upvalue)
+        }
+        self.heap.count_allocation(
+            gc::arc_size::<Closure>() + upvalue_count * std::mem::size_of::<Arc<Lock<Upvalue>>>(),
+        );
+        self.push(Value::Closure(Arc::new(closure)))?;
+        Ok(None)
+    }
+
+    fn op_close_upvalue(&mut self) -> Result<Option<Value>> {
+        self.close_upvalues(self.stack_count - 1);
+        self.pop()?;
+        Ok(None)
+    }
+
+    // The general `+`, for when a superinstruction's fast path doesn't apply.
+    fn add_values(&mut self, a: Value, b: Value) -> Result<()> {
+        self.push(a)?;
+        self.push(b)?;
+        self.add()
+    }
+
+    fn op_add_local_constant(&mut self) -> Result<Option<Value>> {
+        let slot = self.read_u8()? as usize;
+        let constant = self.read_u8()? as usize;
+        let frame = self.current_frame();
+        let a = &self.stack[frame.starts_at + slot];
+        let b = &frame.function.chunk.constants[constant];
+        match arithmetic(a, b, i64::checked_add, |a, b| a + b) {
+            Some(value) => self.push(value)?,
+            None => {
+                let (a, b) = (a.clone(), b.clone());
+                self.add_values(a, b)?
+            }
+        }
+        Ok(None)
+    }
+
+    fn op_add_local_small_int(&mut self) -> Result<Option<Value>> {
+        let slot = self.read_u8()? as usize;
+        let b = Value::Int((self.read_u8()? as i8).into());
+        let a = &self.stack[self.current_frame().starts_at + slot];
+        match arithmetic(a, &b, i64::checked_add, |a, b| a + b) {
+            Some(value) => self.push(value)?,
+            None => {
+                let a = a.clone();
+                self.add_values(a, b)?
+            }
+        }
+        Ok(None)
+    }
+
+    fn op_less_locals_jump_if_false(&mut self) -> Result<Option<Value>> {
+        let offset = self.current_frame().starts_at;
+        let a = offset + self.read_u8()? as usize;
+        let b = offset + self.read_u8()? as usize;
+        let jump: usize = self.read_u16()?.into();
+        let less = match compare_numbers(&self.stack[a], &self.stack[b], i64::lt, f64::lt) {
+            Some(less) => less,
+            None => {
+                let (a, b) = (self.stack[a].clone(), self.stack[b].clone());
+                self.push(a)?;
+                self.push(b)?;
+                self.compare(i64::lt, BigInt::lt, f64::lt)?;
+                !self.pop()?.is_falsy()
+            }
+        };
+        if !less {
+            self.current_frame_mut().ip += jump;
+        }
+        Ok(None)
+    }
+
+    // Pops the top two values and jumps if comparing them gives `jump_if`.
+    fn compare_jump(
+        &mut self,
+        int: fn(&i64, &i64) -> bool,
+        big: fn(&BigInt, &BigInt) -> bool,
+        float: fn(&f64, &f64) -> bool,
+        jump_if: bool,
+    ) -> Result<Option<Value>> {
+        let jump: usize = self.read_u16()?.into();
+        let result = match compare_numbers(self.peek(1)?, self.peek(0)?, int, float) {
+            Some(result) => {
+                self.truncate_stack(self.stack_count - 2);
+                result
+            }
+            None => {
+                self.compare(int, big, float)?;
+                !self.pop()?.is_falsy()
+            }
+        };
+        if result == jump_if {
+            self.current_frame_mut().ip += jump;
+        }
+        Ok(None)
+    }
+
+    fn op_less_jump_if_false(&mut self) -> Result<Option<Value>> {
+        self.compare_jump(i64::lt, BigInt::lt, f64::lt, false)
+    }
+
+    fn op_less_jump_if_true(&mut self) -> Result<Option<Value>> {
+        self.compare_jump(i64::lt, BigInt::lt, f64::lt, true)
+    }
+
+    fn op_greater_jump_if_false(&mut self) -> Result<Option<Value>> {
+        self.compare_jump(i64::gt, BigInt::gt, f64::gt, false)
+    }
+
+    fn op_greater_jump_if_true(&mut self) -> Result<Option<Value>> {
+        self.compare_jump(i64::gt, BigInt::gt, f64::gt, true)
+    }
+
+    fn equal_jump(&mut self, jump_if: bool) -> Result<Option<Value>> {
+        let jump: usize = self.read_u16()?.into();
+        let equal = self.peek(1)? == self.peek(0)?;
+        self.truncate_stack(self.stack_count - 2);
+        if equal == jump_if {
+            self.current_frame_mut().ip += jump;
+        }
+        Ok(None)
+    }
+
+    fn op_equal_jump_if_false(&mut self) -> Result<Option<Value>> {
+        self.equal_jump(false)
+    }
+
+    fn op_equal_jump_if_true(&mut self) -> Result<Option<Value>> {
+        self.equal_jump(true)
+    }
+
+    fn op_return(&mut self) -> Result<Option<Value>> {
+        let result = self.pop()?;
+        let starts_at = self.current_frame().starts_at;
+        self.close_upvalues(starts_at);
+        self.frames.pop();
+        if self.frames.is_empty() {
+            self.pop()?;
+            if self.fiber.is_none() {
+                return Ok(Some(result));
+            }
+            // A fiber's function returned, so resume() returns its result.
+            self.leave_fiber(FiberStatus::Done, result)?;
+            return Ok(None);
+        }
+
+        self.truncate_stack(starts_at);
+        self.push(result)?;
+        Ok(None)
+    }
 }
@@ -13,11 +13,15 @@ const parseTestFile = async (filename: string): Promise<FileExpectation> => {
     return { code: 70, error: runtimeError };
   }
 
+  // Compile errors are reported on the line of the comment.
   const errorAtRegExp = /\/\/ (Error at '.+)/;
+  const errorLine = file.split("\n").findIndex((line) =>
+    errorAtRegExp.test(line)
+  );
 
-  if (errorAtRegExp.test(file)) {
+  if (errorLine !== -1) {
     const [, error] = errorAtRegExp.exec(file)!;
-    return { code: 65, error };
+    return { code: 65, error: `[line ${errorLine + 1}] ${error}` };
   }
 
   const parseErrorRegExp = /\/\/ \[/g;
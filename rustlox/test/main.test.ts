@@ -26,7 +26,11 @@ Object.entries(filesAndAssertions).map(([filename, fileResult]) => {
       "error" in fileResult
     ) {
       const [actualError] = stderr.split("\n");
-      assertEquals(actualError, fileResult.error);
+      // The expectations don't say which column a compile error is at.
+      assertEquals(
+        actualError.replace(/^\[line (\d+):\d+\]/, "[line $1]"),
+        fileResult.error,
+      );
     } else {
       unreachable();
     }
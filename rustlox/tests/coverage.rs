@@ -0,0 +1,85 @@
+mod common;
+
+use common::{rustlox, script, stderr, stdout};
+use std::path::Path;
+
+const SCRIPT: &str = "\
+fun f(flag) {
+  if (flag) {
+    return 1;
+  }
+  return 2;
+}
+print f(false);
+for (var i = 0; i < 3; i = i + 1) {
+  print f(true) + f(true);
+}
+";
+
+#[test]
+fn listing() {
+    let path = script("coverage_listing.lox", SCRIPT);
+    let output = rustlox(&["--coverage", &path]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "2\n2\n2\n2\n");
+    assert_eq!(
+        stderr(&output),
+        "        1:    1: fun f(flag) {
+        7:    2:   if (flag) {
+        6:    3:     return 1;
+        1:    4:   }
+        1:    5:   return 2;
+    #####:    6: }
+        1:    7: print f(false);
+        4:    8: for (var i = 0; i < 3; i = i + 1) {
+        3:    9:   print f(true) + f(true);
+        4:   10: }
+"
+    );
+}
+
+#[test]
+fn lcov() {
+    let path = script("coverage_lcov.lox", SCRIPT);
+    let tracefile = Path::new(env!("CARGO_TARGET_TMPDIR")).join("coverage.info");
+    let output = rustlox(&[&format!("--coverage={}", tracefile.display()), &path]);
+    assert!(output.status.success());
+    assert_eq!(stderr(&output), "");
+    assert_eq!(
+        std::fs::read_to_string(tracefile).unwrap(),
+        format!(
+            "TN:\nSF:{}\nDA:1,1\nDA:2,7\nDA:3,6\nDA:4,1\nDA:5,1\nDA:6,0\nDA:7,1\nDA:8,4\nDA:9,3\nDA:10,4\n\
+             LF:10\nLH:9\nend_of_record\n",
+            path
+        )
+    );
+}
+
+#[test]
+fn functions_never_called() {
+    let path = script(
+        "coverage_uncalled.lox",
+        "fun f() {\n  print 1;\n}\nprint 2;\n",
+    );
+    let output = rustlox(&["--coverage", &path]);
+    assert_eq!(
+        stderr(&output),
+        "        1:    1: fun f() {
+    #####:    2:   print 1;
+    #####:    3: }
+        1:    4: print 2;
+"
+    );
+
+    // Under `--lazy`, an uncalled function's body isn't compiled, so its
+    // lines aren't known to have code.
+    let output = rustlox(&["--coverage", "--lazy", &path]);
+    assert_eq!(
+        stderr(&output),
+        "        1:    1: fun f() {
+        -:    2:   print 1;
+        -:    3: }
+        1:    4: print 2;
+"
+    );
+}
@@ -0,0 +1,79 @@
+mod common;
+
+use common::{repl, rustlox, script, stdout};
+
+// The script is compiled and listed, function by function, but not run.
+#[test]
+fn disassemble_lists_without_running() {
+    let path = script(
+        "disassemble.lox",
+        "fun f(a) { return a + 1; }\nprint f(2);\n",
+    );
+    let output = rustlox(&["--disassemble", &path]);
+    assert!(output.status.success());
+    let listing = stdout(&output);
+    let headers: Vec<&str> = listing
+        .lines()
+        .filter(|line| line.starts_with("=="))
+        .collect();
+    assert_eq!(headers, ["== <script> ==", "== f =="]);
+    assert!(listing.contains("| OP_DEFINE_GLOBAL    0 'f'"));
+    assert!(!listing.lines().any(|line| line == "3"));
+}
+
+#[test]
+fn disassemble_skips_lazy_bodies() {
+    let path = script("disassemble_lazy.lox", "fun f() { return 1; }\n");
+    let output = rustlox(&["--disassemble", "--lazy", &path]);
+    assert!(stdout(&output).starts_with("== <script> ==\n"));
+    assert!(!stdout(&output).contains("== f =="));
+}
+
+// In the REPL, `:disassemble` turns the listing on for the lines after it,
+// which still run, and off again.
+#[test]
+fn repl_disassemble_toggles() {
+    let output = repl(&[], "1;\n:disassemble\n2;\n:disassemble\n3;\n");
+    let printed = stdout(&output);
+    let lines: Vec<&str> = printed.lines().collect();
+    assert_eq!(lines.first(), Some(&"1"));
+    assert_eq!(lines.get(1), Some(&"== <script> =="));
+    assert!(lines.contains(&"2"));
+    assert_eq!(
+        lines.iter().filter(|line| line.starts_with("==")).count(),
+        1
+    );
+    assert_eq!(lines.last(), Some(&"3"));
+}
+
+// Each function is shown as compiled without folding constants, then as
+// each stage changed it.
+#[test]
+fn dump_ir_stages() {
+    let path = script("dump_ir.lox", "var a = 1 + 2 * 3;\n");
+    let output = rustlox(&["--dump-ir", &path]);
+    assert!(output.status.success());
+    let dump = stdout(&output);
+    let headers: Vec<&str> = dump.lines().filter(|line| line.starts_with("==")).collect();
+    assert_eq!(
+        headers,
+        [
+            "== <script> [raw] ==",
+            "== <script> [fold] ==",
+            "== <script> [thread-jumps] ==",
+            "== <script> [fuse] ==",
+        ]
+    );
+    let raw: Vec<&str> = dump.lines().skip(1).take(5).collect();
+    assert_eq!(
+        raw,
+        [
+            "0000    1 OP_ONE",
+            "0001    | OP_SMALL_INT        2",
+            "0003    | OP_SMALL_INT        3",
+            "0005    | OP_MULTIPLY",
+            "0006    | OP_ADD",
+        ]
+    );
+    assert!(dump.contains("+ 0000    1 OP_SMALL_INT        7\n"));
+}
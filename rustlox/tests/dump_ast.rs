@@ -0,0 +1,33 @@
+mod common;
+
+use common::{rustlox, script, stdout};
+
+// The tree is printed as the parser built it, before constant folding, and
+// the script isn't run.
+#[test]
+fn dump_ast_prints_s_expressions() {
+    let path = script(
+        "dump_ast.lox",
+        "var a = 1 + 2 * 3;\n\
+         fun f(x) { if (x) return -x; else return \"s\"; }\n\
+         while (a > 0) a = a - 1;\n\
+         print f(a) and true;\n",
+    );
+    let output = rustlox(&["--dump-ast", &path]);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "(var (a (+ 1 (* 2 3))))\n\
+         (fun f (x)\n  (if x\n    (return (- x))\n    (return \"s\")))\n\
+         (while (> a 0)\n  (; (= a (- a 1))))\n\
+         (print (and (call f a) true))\n"
+    );
+}
+
+#[test]
+fn dump_ast_needs_a_tree() {
+    let path = script("dump_ast_error.lox", "var a = ;\n");
+    let output = rustlox(&["--dump-ast", &path]);
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(stdout(&output), "");
+}
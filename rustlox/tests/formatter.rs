@@ -0,0 +1,106 @@
+mod common;
+
+use common::{rustlox, script, stdout};
+use rustlox::formatter::format;
+
+#[test]
+fn layout() {
+    assert_eq!(
+        format("fun f(a,b){if(a)return b;else{return -a;}}\nprint f( 1 ,2 );").unwrap(),
+        "fun f(a, b) {\n  if (a) return b;\n  else {\n    return -a;\n  }\n}\nprint f(1, 2);\n"
+    );
+}
+
+#[test]
+fn blank_lines() {
+    assert_eq!(
+        format("var a = 1;\n\n\n\nvar b = 2;\nvar c = 3;\n").unwrap(),
+        "var a = 1;\n\nvar b = 2;\nvar c = 3;\n"
+    );
+}
+
+#[test]
+fn comments_stay_where_they_were() {
+    let source = "\
+// Leading.
+var x = true; // After x.
+
+{ // Opens.
+  print x;
+  // Closes.
+}
+if (x) print 1; // Then.
+else print 2; // Else.
+if (x) {
+  print 3;
+} // Block.
+else {
+  print 4;
+}
+";
+    assert_eq!(format(source).unwrap(), source);
+}
+
+#[test]
+fn comment_inside_a_statement() {
+    assert_eq!(
+        format("var x = 1 + // Mid.\n  2;\nprint x;\n").unwrap(),
+        "var x = 1 + 2; // Mid.\nprint x;\n"
+    );
+}
+
+#[test]
+fn comment_after_a_joined_statement() {
+    assert_eq!(
+        format("var x = 1 + // One.\n  2; // Two.\nprint x;\n").unwrap(),
+        "// One.\nvar x = 1 + 2; // Two.\nprint x;\n"
+    );
+    assert_eq!(
+        format("{\n  var y = 1 + // One.\n    2; // Two.\n}\n").unwrap(),
+        "{\n  // One.\n  var y = 1 + 2; // Two.\n}\n"
+    );
+}
+
+#[test]
+fn formatted_output_is_stable() {
+    let source = "var a=#(1,2,);for(var i=0;i<2;i=i+1)if(i)print a;else print i;// Done.\n";
+    let once = format(source).unwrap();
+    assert_eq!(format(&once).unwrap(), once);
+}
+
+#[test]
+fn check_lists_unformatted_files() {
+    let formatted = script("fmt_formatted.lox", "print 1;\n");
+    let unformatted = script("fmt_unformatted.lox", "print   1 ;\n");
+
+    let output = rustlox(&["fmt", "--check", &formatted]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "");
+
+    let output = rustlox(&["fmt", "--check", &formatted, &unformatted]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), format!("{}\n", unformatted));
+    // Checking leaves the file alone.
+    assert_eq!(
+        std::fs::read_to_string(&unformatted).unwrap(),
+        "print   1 ;\n"
+    );
+}
+
+#[test]
+fn fmt_rewrites_files() {
+    let path = script("fmt_rewrite.lox", "print   1 ; // One.\n");
+    let output = rustlox(&["fmt", &path]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "print 1; // One.\n"
+    );
+}
+
+#[test]
+fn fmt_reports_parse_errors() {
+    let path = script("fmt_parse_error.lox", "print (;\n");
+    let output = rustlox(&["fmt", "--check", &path]);
+    assert_eq!(output.status.code(), Some(65));
+}
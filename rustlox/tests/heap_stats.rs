@@ -0,0 +1,68 @@
+mod common;
+
+use common::{rustlox, script, stderr, stdout};
+use rustlox::compiler::CompileOptions;
+use rustlox::vm::VM;
+
+const SOURCE: &str = "var o = {a: 1};\n\
+                      fun make() { var x = 1; fun get() { return x; } return get; }\n\
+                      var g = make();\n";
+
+#[test]
+fn heap_stats_after_the_script() {
+    let path = script("heap_stats.lox", &format!("{}print 1;\n", SOURCE));
+    let output = rustlox(&["--heap-stats", &path]);
+    assert_eq!(stdout(&output), "1\n");
+    let report = stderr(&output);
+    let rows: Vec<(&str, usize)> = report
+        .lines()
+        .map(|line| {
+            let (name, value) = line.rsplit_once(' ').unwrap();
+            (name.trim_end(), value.parse().unwrap())
+        })
+        .collect();
+    let names: Vec<&str> = rows.iter().map(|(name, _)| *name).collect();
+    assert_eq!(
+        names,
+        [
+            "bytes allocated",
+            "tracked objects",
+            "live instances",
+            "live closures",
+            "live upvalues",
+            "live fibers",
+            "interned strings",
+            "interned bytes",
+            "ropes",
+        ]
+    );
+    assert_eq!(
+        rows[2..6],
+        [
+            ("live instances", 1),
+            ("live closures", 1),
+            ("live upvalues", 1),
+            ("live fibers", 0)
+        ]
+    );
+    assert!(rows[0].1 > 0 && rows[1].1 >= 2, "{}", report);
+}
+
+// Only what's still reachable is counted as live.
+#[test]
+fn heap_stats_counts_what_is_reachable() {
+    let options = CompileOptions::default();
+    let mut vm = VM::builder().build();
+    vm.interpret(SOURCE, &options).unwrap();
+    let before = vm.heap_stats();
+    assert_eq!(
+        (before.instances, before.closures, before.upvalues),
+        (1, 1, 1)
+    );
+
+    vm.interpret("o = nil; g = nil;", &options).unwrap();
+    let after = vm.heap_stats();
+    assert_eq!((after.instances, after.closures, after.upvalues), (0, 0, 0));
+    assert!(after.bytes_allocated >= before.bytes_allocated);
+    assert_eq!(after.to_string().lines().count(), 9);
+}
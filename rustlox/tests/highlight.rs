@@ -0,0 +1,68 @@
+mod common;
+
+use common::{rustlox, script, stdout};
+use rustlox::highlight::{ansi, html};
+
+// What's left once the color escape sequences are taken out.
+fn plain(text: &str) -> String {
+    let mut plain = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('\u{1b}') {
+        plain.push_str(&rest[..start]);
+        rest = &rest[start + rest[start..].find('m').unwrap() + 1..];
+    }
+    plain + rest
+}
+
+#[test]
+fn html_escapes_text() {
+    assert_eq!(
+        html("var s = \"<a & b>\"; // x < y\n"),
+        "<pre class=\"lox\"><code>\
+<span class=\"var keyword\">var</span> \
+<span class=\"identifier\">s</span> \
+<span class=\"equal\">=</span> \
+<span class=\"string\">&quot;&lt;a &amp; b&gt;&quot;</span>\
+<span class=\"semicolon\">;</span> \
+<span class=\"comment\">// x &lt; y</span>\n\
+</code></pre>\n"
+    );
+}
+
+#[test]
+fn html_copies_what_the_scanner_skips() {
+    assert_eq!(
+        html("1 @ <\n\"open"),
+        "<pre class=\"lox\"><code>\
+<span class=\"number\">1</span> @ <span class=\"less\">&lt;</span>\n&quot;open\
+</code></pre>\n"
+    );
+}
+
+#[test]
+fn ansi_colors_tokens() {
+    let colored = ansi("fun f() { return \"s\"; } // done\n");
+    assert!(colored.contains("\u{1b}[35mfun\u{1b}[0m"));
+    assert!(colored.contains("\u{1b}[32m\"s\"\u{1b}[0m"));
+    assert!(colored.contains("\u{1b}[90m// done\u{1b}[0m"));
+}
+
+#[test]
+fn ansi_keeps_the_source() {
+    let source = "var a = 1 @ 2; // x\n\n  print   a;\n\"open";
+    assert_eq!(plain(&ansi(source)), source);
+}
+
+#[test]
+fn highlight_subcommand() {
+    let path = script("highlight.lox", "print 1 < 2;\n");
+    let output = rustlox(&["highlight", "--html", &path]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), html("print 1 < 2;\n"));
+
+    let output = rustlox(&["highlight", &path]);
+    assert_eq!(stdout(&output), ansi("print 1 < 2;\n"));
+
+    let output = rustlox(&["highlight", "--xml", &path]);
+    assert_eq!(output.status.code(), Some(64));
+}
@@ -0,0 +1,126 @@
+mod common;
+
+use common::{rustlox, script, stderr};
+use rustlox::lint::{Config, Level, Rule};
+
+// The line and message of each diagnostic, without the column.
+fn diagnostics(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|line| line.starts_with("[line"))
+        .map(|line| {
+            let (position, rest) = line.split_once(']').unwrap();
+            format!("{}]{}", position.split(':').next().unwrap(), rest)
+        })
+        .collect()
+}
+
+#[test]
+fn rules() {
+    let path = script(
+        "lint_rules.lox",
+        "\
+fun f(x) {
+  {
+    var x = 1;
+    print x;
+  }
+}
+if (true) {}
+var a = 1;
+a = a;
+while (false) print a;
+",
+    );
+    let output = rustlox(&["lint", &path]);
+    assert!(output.status.success());
+    assert_eq!(
+        diagnostics(&stderr(&output)),
+        [
+            "[line 3] Warning at 'x': Shadows a variable in an enclosing scope. (shadowed-variable)",
+            "[line 7] Warning at 'true': Condition is always true. (constant-condition)",
+            "[line 7] Warning at '{': Block is empty. (empty-block)",
+            "[line 9] Warning at 'a': Variable is assigned to itself. (self-assignment)",
+            "[line 10] Warning at 'false': Condition is always false. (constant-condition)",
+        ]
+    );
+    assert!(stderr(&output).starts_with(&format!("{}\n", path)));
+}
+
+#[test]
+fn clean_scripts_print_nothing() {
+    let path = script("lint_clean.lox", "var a = 1;\nprint a;\n");
+    let output = rustlox(&["lint", &path]);
+    assert!(output.status.success());
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn lint_allow() {
+    let path = script(
+        "lint_allow.lox",
+        "\
+var a = 1;
+a = a; // lint-allow
+// lint-allow: self-assignment
+a = a;
+a = a; // lint-allow: empty-block, constant-condition
+if (true) {} // lint-allow: empty-block
+",
+    );
+    let output = rustlox(&["lint", &path]);
+    assert_eq!(
+        diagnostics(&stderr(&output)),
+        [
+            "[line 5] Warning at 'a': Variable is assigned to itself. (self-assignment)",
+            "[line 6] Warning at 'true': Condition is always true. (constant-condition)",
+        ]
+    );
+}
+
+#[test]
+fn lint_allow_unknown_rule() {
+    let path = script(
+        "lint_allow_unknown.lox",
+        "var a = 1;\na = a; // lint-allow: nope\n",
+    );
+    let output = rustlox(&["lint", &path]);
+    assert_eq!(output.status.code(), Some(65));
+    assert!(stderr(&output).contains("line 2: Unknown rule 'nope'."));
+}
+
+#[test]
+fn config_levels() {
+    let path = script("lint_levels.lox", "if (true) {}\n");
+    let config = script(
+        "lint_levels.loxlint",
+        "# Blocks are fine.\nempty-block = off\nconstant-condition = error\n",
+    );
+    let output = rustlox(&["lint", &format!("--config={}", config), &path]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        diagnostics(&stderr(&output)),
+        ["[line 1] Error at 'true': Condition is always true. (constant-condition)"]
+    );
+}
+
+#[test]
+fn config_parse() {
+    let config = Config::parse("empty-block = off # Comment.\n\nself-assignment=error\n").unwrap();
+    assert_eq!(config.level(Rule::EmptyBlock), Level::Off);
+    assert_eq!(config.level(Rule::SelfAssignment), Level::Error);
+    assert_eq!(config.level(Rule::ShadowedVariable), Level::Warn);
+
+    assert_eq!(
+        Config::parse("empty-block").unwrap_err(),
+        "line 1: Expect '=' after rule name."
+    );
+    assert_eq!(
+        Config::parse("\nnope = off").unwrap_err(),
+        "line 2: Unknown rule 'nope'."
+    );
+    assert_eq!(
+        Config::parse("empty-block = loud").unwrap_err(),
+        "line 1: Expect 'off', 'warn' or 'error' after '='."
+    );
+}
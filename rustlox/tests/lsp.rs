@@ -0,0 +1,234 @@
+use serde_json::{json, Value};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+const URI: &str = "file:///script.lox";
+
+fn frame(message: &Value) -> String {
+    let body = message.to_string();
+    format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+}
+
+// Sends the messages to a server and returns its exit status and everything
+// it sent back.
+fn session(messages: &[Value]) -> (Option<i32>, Vec<Value>) {
+    let input: String = messages.iter().map(frame).collect();
+    raw_session(&input)
+}
+
+// Like `session`, with the input already framed.
+fn raw_session(input: &str) -> (Option<i32>, Vec<Value>) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rustlox"))
+        .arg("lsp")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut rest = stdout.as_str();
+    let mut replies = Vec::new();
+    while !rest.is_empty() {
+        let (header, body) = rest.split_once("\r\n\r\n").unwrap();
+        let length: usize = header
+            .strip_prefix("Content-Length: ")
+            .unwrap()
+            .parse()
+            .unwrap();
+        replies.push(serde_json::from_str(&body[..length]).unwrap());
+        rest = &body[length..];
+    }
+    (output.status.code(), replies)
+}
+
+fn open(text: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/didOpen",
+        "params": {
+            "textDocument": { "uri": URI, "languageId": "lox", "version": 1, "text": text },
+        },
+    })
+}
+
+fn request(id: u64, method: &str, line: u64, character: u64) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "method": method,
+        "params": {
+            "textDocument": { "uri": URI },
+            "position": { "line": line, "character": character },
+        },
+    })
+}
+
+fn shutdown(id: u64) -> [Value; 2] {
+    [
+        json!({ "jsonrpc": "2.0", "id": id, "method": "shutdown" }),
+        json!({ "jsonrpc": "2.0", "method": "exit" }),
+    ]
+}
+
+fn reply(replies: &[Value], id: u64) -> &Value {
+    replies.iter().find(|reply| reply["id"] == id).unwrap()
+}
+
+fn diagnostics(replies: &[Value]) -> Vec<&Value> {
+    replies
+        .iter()
+        .filter(|reply| reply["method"] == "textDocument/publishDiagnostics")
+        .collect()
+}
+
+#[test]
+fn initialize_and_shut_down() {
+    let mut messages = vec![json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "initialize",
+        "params": { "capabilities": {} },
+    })];
+    messages.extend(shutdown(2));
+    let (status, replies) = session(&messages);
+    assert_eq!(status, Some(0));
+    let capabilities = &reply(&replies, 1)["result"]["capabilities"];
+    assert_eq!(capabilities["textDocumentSync"], 1);
+    assert_eq!(capabilities["definitionProvider"], true);
+    assert_eq!(capabilities["hoverProvider"], true);
+    assert_eq!(reply(&replies, 2)["result"], Value::Null);
+}
+
+#[test]
+fn exit_without_shutdown() {
+    let (status, _) = session(&[json!({ "jsonrpc": "2.0", "method": "exit" })]);
+    assert_eq!(status, Some(1));
+}
+
+#[test]
+fn unknown_request() {
+    let (_, replies) = session(&[json!({ "jsonrpc": "2.0", "id": 1, "method": "nope" })]);
+    assert_eq!(reply(&replies, 1)["error"]["code"], -32601);
+}
+
+#[test]
+fn malformed_messages_get_a_parse_error() {
+    let malformed = "{\"jsonrpc\": nope}";
+    let mut input = format!("Content-Length: {}\r\n\r\n{}", malformed.len(), malformed);
+    input.extend(shutdown(1).iter().map(frame));
+    let (status, replies) = raw_session(&input);
+    assert_eq!(replies[0]["id"], Value::Null);
+    assert_eq!(replies[0]["error"]["code"], -32700);
+    assert_eq!(reply(&replies, 1)["result"], Value::Null);
+    assert_eq!(status, Some(0));
+}
+
+#[test]
+fn publishes_diagnostics_on_open_and_change() {
+    let change = json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/didChange",
+        "params": {
+            "textDocument": { "uri": URI, "version": 2 },
+            "contentChanges": [{ "text": "print 1;\n" }],
+        },
+    });
+    let (_, replies) = session(&[open("fun f(a) {}\nprint (;\n"), change]);
+    let published = diagnostics(&replies);
+    assert_eq!(published.len(), 2);
+
+    let opened = published[0]["params"]["diagnostics"].as_array().unwrap();
+    assert_eq!(published[0]["params"]["uri"], URI);
+    assert_eq!(opened.len(), 1);
+    assert_eq!(opened[0]["message"], "Expect expression.");
+    assert_eq!(opened[0]["severity"], 1);
+    assert_eq!(
+        opened[0]["range"],
+        json!({
+            "start": { "line": 1, "character": 7 },
+            "end": { "line": 1, "character": 8 },
+        })
+    );
+
+    assert_eq!(published[1]["params"]["diagnostics"], json!([]));
+}
+
+#[test]
+fn warnings_are_diagnostics_too() {
+    let (_, replies) = session(&[open("fun f(a) {}\n")]);
+    let published = diagnostics(&replies);
+    let warnings = published[0]["params"]["diagnostics"].as_array().unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0]["message"], "Unused parameter.");
+    assert_eq!(warnings[0]["severity"], 2);
+}
+
+const SCRIPT: &str = "\
+var count = 0;
+fun add(amount) {
+  count = count + amount;
+}
+add(2);
+clock();
+";
+
+#[test]
+fn definition() {
+    let (_, replies) = session(&[
+        open(SCRIPT),
+        // `amount` in the body.
+        request(1, "textDocument/definition", 2, 20),
+        // `add` in the call, with the cursor just past it.
+        request(2, "textDocument/definition", 4, 3),
+        // A built-in has nowhere to go.
+        request(3, "textDocument/definition", 5, 1),
+    ]);
+    assert_eq!(
+        reply(&replies, 1)["result"],
+        json!({
+            "uri": URI,
+            "range": {
+                "start": { "line": 1, "character": 8 },
+                "end": { "line": 1, "character": 14 },
+            },
+        })
+    );
+    assert_eq!(
+        reply(&replies, 2)["result"]["range"]["start"],
+        json!({ "line": 1, "character": 4 })
+    );
+    assert_eq!(reply(&replies, 3)["result"], Value::Null);
+}
+
+#[test]
+fn hover() {
+    let (_, replies) = session(&[
+        open(SCRIPT),
+        request(1, "textDocument/hover", 2, 4),
+        request(2, "textDocument/hover", 2, 20),
+        request(3, "textDocument/hover", 4, 0),
+        request(4, "textDocument/hover", 5, 0),
+        // Whitespace.
+        request(5, "textDocument/hover", 1, 15),
+    ]);
+    let value = |id| reply(&replies, id)["result"]["contents"]["value"].clone();
+    assert_eq!(value(1), "```lox\n(global) var count\n```");
+    assert_eq!(value(2), "```lox\n(parameter) amount\n```");
+    assert_eq!(value(3), "```lox\n(global) fun add(amount)\n```");
+    assert_eq!(value(4), "```lox\n(built-in) clock\n```");
+    assert_eq!(reply(&replies, 5)["result"], Value::Null);
+    assert_eq!(
+        reply(&replies, 1)["result"]["range"],
+        json!({
+            "start": { "line": 2, "character": 2 },
+            "end": { "line": 2, "character": 7 },
+        })
+    );
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use common::{repl, script, stderr, stdout};
+use std::path::Path;
+
+#[test]
+fn results_are_printed() {
+    let output = repl(&[], "var a = 2;\na * 3;\nnil;\nprint \"hi\";\n");
+    assert_eq!(stdout(&output), "6\nhi\n");
+    assert!(output.status.success());
+}
+
+// Only the lines that ran make it into the saved session, so loading it
+// back doesn't repeat the errors.
+#[test]
+fn save_keeps_the_lines_that_ran() {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("repl_save.lox");
+    let path = path.to_str().unwrap();
+    let input = format!(
+        "var a = 1;\nprint nope;\nvar b = ;\nfun twice(n) {{ return n * 2; }}\n:save {}\n",
+        path
+    );
+    let output = repl(&[], &input);
+    assert!(stderr(&output).contains("Undefined variable 'nope'."));
+    assert_eq!(
+        std::fs::read_to_string(path).unwrap(),
+        "var a = 1;\nfun twice(n) { return n * 2; }\n"
+    );
+}
+
+#[test]
+fn load_runs_the_file_in_the_session() {
+    let path = script(
+        "repl_load.lox",
+        "var loaded = 20;\nfun add(n) { return loaded + n; }\n",
+    );
+    let output = repl(&[], &format!(":load {}\nadd(1);\nloaded;\n", path));
+    assert_eq!(stdout(&output), "21\n20\n");
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn save_and_load_need_a_path() {
+    let output = repl(&[], ":save\n:load   \n1;\n");
+    assert_eq!(
+        stderr(&output),
+        "Usage: :save <path>\nUsage: :load <path>\n"
+    );
+    assert_eq!(stdout(&output), "1\n");
+}
+
+#[test]
+fn load_reports_a_missing_file() {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("repl_missing.lox");
+    let path = path.to_str().unwrap();
+    let output = repl(&[], &format!(":load {}\n1;\n", path));
+    assert!(stderr(&output).starts_with(&format!("Could not load '{}': ", path)));
+    assert_eq!(stdout(&output), "1\n");
+}
@@ -0,0 +1,39 @@
+mod common;
+
+use common::{rustlox, script, stdout};
+use std::path::Path;
+
+#[test]
+fn samples_are_collapsed_stacks() {
+    let path = script(
+        "sampled.lox",
+        "\
+fun slow(n) {
+  var total = 0;
+  for (var i = 0; i < n; i = i + 1) total = total + i;
+  return total;
+}
+fun outer() { return slow(200000); }
+print outer();
+",
+    );
+    let samples = Path::new(env!("CARGO_TARGET_TMPDIR")).join("sampled.folded");
+    let output = rustlox(&[&format!("--sample={}", samples.display()), &path]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "19999900000\n");
+
+    let folded = std::fs::read_to_string(samples).unwrap();
+    let mut total = 0;
+    for line in folded.lines() {
+        let (stack, count) = line.rsplit_once(' ').unwrap();
+        assert!(stack.starts_with("<script>"), "{}", line);
+        total += count.parse::<u64>().unwrap();
+    }
+    // Nearly all the time goes to the loop, sampled every millisecond.
+    assert!(folded.contains("<script>;outer;slow "), "{}", folded);
+    assert!(total > 0);
+    let stacks: Vec<&str> = folded.lines().collect();
+    let mut sorted = stacks.clone();
+    sorted.sort_unstable();
+    assert_eq!(stacks, sorted);
+}
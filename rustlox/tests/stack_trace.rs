@@ -0,0 +1,122 @@
+mod common;
+
+use common::{rustlox, script, stderr};
+
+const SCRIPT: &str = "\
+fun inner(x, y) {
+  var local = x + 1;
+  return local + nil;
+}
+fun outer(n) {
+  var label = \"outer\";
+  return inner(n, \"two\");
+}
+outer(1);
+";
+
+// The stack trace, after the message and its snippet.
+fn trace(output: &str) -> Vec<&str> {
+    output.lines().skip(3).collect()
+}
+
+#[test]
+fn frames_name_their_call_sites() {
+    let path = script("trace_call_sites.lox", SCRIPT);
+    let output = rustlox(&[&path]);
+    assert_eq!(output.status.code(), Some(70));
+    let stderr = stderr(&output);
+    assert!(stderr.starts_with("Operands must be two numbers or two strings.\n"));
+    assert_eq!(
+        trace(&stderr),
+        [
+            "[line 3:16] in inner()",
+            "[line 7:24] in outer(), calling 'inner(n, \"two\")' with 2 arguments",
+            "[line 9:8] in script, calling 'outer(1)' with 1 argument",
+        ]
+    );
+}
+
+#[test]
+fn prelude_frames_are_labelled() {
+    let path = script("trace_prelude.lox", "print abs(\"x\");\n");
+    let output = rustlox(&[&path]);
+    assert_eq!(output.status.code(), Some(70));
+    let stderr = stderr(&output);
+    assert!(stderr.starts_with("Operands must be numbers.\nIn the prelude:\n"));
+    assert_eq!(
+        stderr.lines().skip(4).collect::<Vec<_>>(),
+        [
+            "[prelude line 4:9] in abs()",
+            "[line 1:14] in script, calling 'abs(\"x\")' with 1 argument",
+        ]
+    );
+}
+
+#[test]
+fn trace_depth_lists_slots() {
+    let path = script("trace_depth.lox", SCRIPT);
+    let output = rustlox(&["--trace-depth=2", &path]);
+    assert_eq!(
+        trace(&stderr(&output)),
+        [
+            "[line 3:16] in inner()",
+            "     1: 1",
+            "     2: two",
+            "     3: 2",
+            "[line 7:24] in outer(), calling 'inner(n, \"two\")' with 2 arguments",
+            "     1: 1",
+            "     2: outer",
+            "[line 9:8] in script, calling 'outer(1)' with 1 argument",
+        ]
+    );
+
+    let output = rustlox(&["--trace-depth=1", &path]);
+    assert_eq!(trace(&stderr(&output)).len(), 6);
+}
+
+#[test]
+fn long_traces_are_cut() {
+    let path = script(
+        "trace_long.lox",
+        &format!(
+            "\
+fun f(a, b, c, d, e, f, g, h, i, j) {{
+  var long = \"{}\";
+  return -long;
+}}
+f(1, 2, 3, 4, 5, 6, 7, 8, 9,
+  \"a long argument that goes on and on\");
+",
+            "x".repeat(60)
+        ),
+    );
+    let output = rustlox(&["--trace-depth=1", &path]);
+    let stderr = stderr(&output);
+    let trace = trace(&stderr);
+    assert_eq!(trace.len(), 11);
+    assert_eq!(trace[1], "     1: 1");
+    assert_eq!(trace[8], "     8: 8");
+    // The operand of the `-` is on the stack too.
+    assert_eq!(trace[9], "        ...and 4 more");
+    // The call is put on one line and cut to 60 characters.
+    assert_eq!(
+        trace[10],
+        "[line 6:40] in script, calling \
+'f(1, 2, 3, 4, 5, 6, 7, 8, 9, \"a long argument that goes o...' with 10 arguments"
+    );
+}
+
+#[test]
+fn long_values_are_cut() {
+    let path = script(
+        "trace_long_value.lox",
+        &format!(
+            "fun f() {{\n  var long = \"{}\";\n  return -long;\n}}\nf();\n",
+            "y".repeat(60)
+        ),
+    );
+    let output = rustlox(&["--trace-depth=1", &path]);
+    let stderr = stderr(&output);
+    let slot = trace(&stderr)[1];
+    assert_eq!(slot, format!("     1: {}...", "y".repeat(37)));
+}
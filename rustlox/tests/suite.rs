@@ -0,0 +1,37 @@
+mod common;
+
+use common::{rustlox, stdout};
+
+// The scripts under test/ that `rustlox test` fails with these flags.
+fn failures(flags: &[&str]) -> Vec<String> {
+    let mut args = vec!["test"];
+    args.extend(flags);
+    args.push("test");
+    let output = rustlox(&args);
+    stdout(&output)
+        .lines()
+        .filter_map(|line| line.strip_prefix("FAIL "))
+        .map(String::from)
+        .collect()
+}
+
+#[test]
+fn every_test_passes() {
+    assert_eq!(failures(&[]), Vec::<String>::new());
+}
+
+#[test]
+fn lazy_compilation_passes_the_same_tests() {
+    // A lazy function's compile errors are only reported when it's first
+    // called, which these scripts never do.
+    let expected = [
+        "test/dead_code/errors_still_reported.lox",
+        "test/strict/undeclared_assignment.lox",
+    ];
+    assert_eq!(failures(&["--lazy"]), expected);
+}
+
+#[test]
+fn incremental_gc_passes_the_same_tests() {
+    assert_eq!(failures(&["--incremental-gc"]), Vec::<String>::new());
+}
@@ -0,0 +1,77 @@
+mod common;
+
+use common::{rustlox, script, stderr, stdout};
+use std::path::Path;
+
+const SOURCE: &str = "fun inner(n) { return n + 1; }\n\
+                      fun outer(n) { return inner(n) * 2; }\n\
+                      print outer(1);\n";
+
+// The instructions traced, without the stack snapshots between them or what
+// the script printed.
+fn instructions(trace: &str) -> Vec<&str> {
+    trace
+        .lines()
+        .filter(|line| !line.starts_with(' '))
+        .filter_map(|line| line.split_whitespace().nth(2))
+        .collect()
+}
+
+#[test]
+fn trace_goes_to_stdout() {
+    let path = script("trace_stdout.lox", SOURCE);
+    let output = rustlox(&["--trace", &path]);
+    let trace = stdout(&output);
+    assert!(trace.starts_with("          [ <script> ]\n0000    1 OP_CLOSURE"));
+    assert!(trace.contains("[ <fn outer> ][ 1 ][ <fn inner> ][ 1 ][ 2 ]\n"));
+    assert!(trace.lines().any(|line| line == "4"));
+}
+
+#[test]
+fn trace_to_a_file() {
+    let path = script("trace_file.lox", SOURCE);
+    let out = Path::new(env!("CARGO_TARGET_TMPDIR")).join("trace_file.out");
+    let output = rustlox(&[&format!("--trace={}", out.display()), &path]);
+    assert_eq!(stdout(&output), "4\n");
+    let trace = std::fs::read_to_string(&out).unwrap();
+    assert_eq!(
+        trace,
+        stdout(&rustlox(&["--trace", &path])).replace("4\n", "")
+    );
+}
+
+#[test]
+fn trace_function_filters() {
+    let path = script("trace_function.lox", SOURCE);
+    let output = rustlox(&["--trace", "--trace-function=inner", &path]);
+    let trace = stdout(&output);
+    assert_eq!(
+        instructions(&trace),
+        ["OP_ADD_LOCAL_SMALL_INT", "OP_RETURN"]
+    );
+
+    let output = rustlox(&[
+        "--trace",
+        "--trace-function=inner",
+        "--trace-function=outer",
+        &path,
+    ]);
+    let trace = stdout(&output);
+    let traced = instructions(&trace);
+    assert_eq!(traced.first(), Some(&"OP_GET_GLOBAL"));
+    assert!(traced.contains(&"OP_ADD_LOCAL_SMALL_INT"));
+    assert!(traced.contains(&"OP_MULTIPLY"));
+    assert!(!traced.contains(&"OP_CLOSURE"));
+
+    // A filter on its own doesn't turn tracing on.
+    assert_eq!(stdout(&rustlox(&["--trace-function=inner", &path])), "4\n");
+}
+
+#[test]
+fn trace_file_that_cannot_be_created() {
+    let path = script("trace_bad_file.lox", SOURCE);
+    let out = Path::new(env!("CARGO_TARGET_TMPDIR")).join("missing/trace.out");
+    let output = rustlox(&[&format!("--trace={}", out.display()), &path]);
+    assert!(stderr(&output).starts_with(&format!("Could not open '{}': ", out.display())));
+    assert_eq!(stdout(&output), "");
+}
@@ -0,0 +1,141 @@
+use rustlox::compiler::CompileOptions;
+use rustlox::value::Value;
+use rustlox::vm::{completions, VM};
+
+// Only the predefined globals on the list exist, whether they're natives,
+// builtins or the prelude's functions.
+#[test]
+fn predefined_allowlist() {
+    let options = CompileOptions::default();
+    let mut vm = VM::builder().predefined(&["clock", "max"]).build();
+    assert!(vm.interpret("clock();", &options).is_ok());
+    assert!(vm.interpret("max(1, 2);", &options).is_ok());
+    for denied in [
+        "eval(\"1;\");",
+        "Reflect;",
+        "Fiber;",
+        "Set;",
+        "min(1, 2);",
+        "println();",
+    ] {
+        assert!(vm.interpret(denied, &options).is_err(), "{}", denied);
+    }
+    assert_eq!(vm.global_names(), ["clock", "max"]);
+}
+
+// What the REPL completes on Tab.
+#[test]
+fn globals_defined_so_far_complete() {
+    let options = CompileOptions::default();
+    let mut vm = VM::builder().build();
+    vm.interpret("var total = 1; fun tally() {}", &options)
+        .unwrap();
+    // Failing lines define nothing.
+    assert!(vm.interpret("var tangent = nope;", &options).is_err());
+    let names = vm.global_names();
+    assert!(names.windows(2).all(|pair| pair[0] < pair[1]));
+
+    assert_eq!(completions(&names, "print ta", 8), (6, vec!["tally"]));
+    let (start, candidates) = completions(&names, "f(t)", 3);
+    assert_eq!(start, 2);
+    assert!(candidates.contains(&"total") && candidates.contains(&"tally"));
+    assert!(!candidates.contains(&"tangent"));
+    // Only what's before the cursor counts.
+    assert_eq!(completions(&names, "tot = 1", 2), (0, vec!["total"]));
+    assert_eq!(completions(&names, "cloc", 4), (0, vec!["clock"]));
+    // Nothing to complete, or a property name.
+    assert_eq!(completions(&names, "print ", 6), (6, vec![]));
+    assert_eq!(completions(&names, "a.to", 4), (4, vec![]));
+}
+
+#[test]
+fn everything_predefined_by_default() {
+    let options = CompileOptions::default();
+    let mut vm = VM::builder().build();
+    assert!(vm
+        .interpret("eval(\"1;\"); Reflect; min(1, 2);", &options)
+        .is_ok());
+}
+
+// Makes lots of garbage, some of it cyclic, while keeping every tenth node
+// of a list alive through a closure.
+const GARBAGE: &str = "
+var kept = nil;
+var every = 0;
+for (var i = 0; i < 2000; i = i + 1) {
+  var node = { value: i, next: kept };
+  var cycle = { other: nil };
+  cycle.other = { other: cycle };
+  fun value() { return node.value; }
+  every = every + 1;
+  if (every == 10) {
+    every = 0;
+    kept = node;
+    node.get = value;
+  }
+}
+var sum = 0;
+for (var node = kept; node != nil; node = node.next) sum = sum + node.get();
+sum;
+";
+
+#[test]
+fn incremental_gc_keeps_what_is_reachable() {
+    for incremental in [false, true] {
+        let mut vm = VM::builder()
+            .incremental_gc(incremental)
+            .gc_threshold(64)
+            .build();
+        let sum = vm.interpret(GARBAGE, &CompileOptions::default()).unwrap();
+        assert_eq!(sum, Value::Int(200800), "incremental: {}", incremental);
+        // The list is still reachable from `kept`.
+        let stats = vm.heap_stats();
+        assert_eq!(stats.instances, 200);
+        assert_eq!(stats.closures, 200);
+        // Most of the 8000 objects made were collected along the way.
+        assert!(stats.tracked < 1000, "{:?}", stats);
+    }
+}
+
+// Each VM numbers names in the order its own scripts use them, so VMs on
+// other threads can't disturb which global a slot holds.
+#[test]
+fn vms_on_other_threads_keep_their_own_names() {
+    let workers: Vec<_> = (0..4)
+        .map(|worker| {
+            std::thread::spawn(move || {
+                let options = CompileOptions::default();
+                let mut vm = VM::builder().build();
+                let names = ["a", "b", "c", "d"];
+                for name in names.iter().cycle().skip(worker).take(4) {
+                    let source = format!("var {} = \"{}\" + \"{}\";", name, name, worker);
+                    vm.interpret(&source, &options).unwrap();
+                }
+                vm.interpret("a + b + c + d;", &options).unwrap()
+            })
+        })
+        .collect();
+    for (worker, handle) in workers.into_iter().enumerate() {
+        let expected = format!("a{0}b{0}c{0}d{0}", worker);
+        assert_eq!(handle.join().unwrap().to_string(), expected);
+    }
+}
+
+// Each VM counts the strings it makes against its own limit, however it
+// makes them.
+#[test]
+fn string_limit_counts_each_vms_own_strings() {
+    let options = CompileOptions::default();
+    let mut numbers = VM::builder().max_interned_bytes(1000).build();
+    let converting = "var i = 0; while (true) { var s = \"\" + i; i = i + 1; }";
+    assert!(numbers.interpret(converting, &options).is_err());
+
+    let literal = "x".repeat(400);
+    let mut evaluated = VM::builder().max_interned_bytes(700).build();
+    let source = format!("eval(\"\\\"{}\\\";\");", literal);
+    assert!(evaluated.interpret(&source, &options).is_err());
+
+    let mut other = VM::builder().max_interned_bytes(700).build();
+    let value = other.interpret("\"abc\" + \"def\";", &options).unwrap();
+    assert_eq!(value.to_string(), "abcdef");
+}
@@ -0,0 +1,126 @@
+mod common;
+
+use common::{rustlox, script, stderr, stdout};
+
+const SCRIPT: &str = "\
+fun f(unused) {
+  var never;
+  var z = 1;
+  print z;
+  z = 2;
+  return;
+  print \"unreachable\";
+}
+f(1);
+";
+
+// The warnings are the line each is reported at and its message.
+fn warnings(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|line| line.starts_with("[line"))
+        .map(|line| {
+            let (position, rest) = line.split_once(']').unwrap();
+            let line = position.split(':').next().unwrap();
+            format!("{}]{}", line, rest)
+        })
+        .collect()
+}
+
+#[test]
+fn warnings_are_off_by_default() {
+    let path = script("warnings_default.lox", SCRIPT);
+    let output = rustlox(&[&path]);
+    assert!(output.status.success());
+    assert_eq!(stderr(&output), "");
+    assert_eq!(stdout(&output), "1\n");
+}
+
+#[test]
+fn warnings_are_reported() {
+    let path = script("warnings_reported.lox", SCRIPT);
+    let output = rustlox(&["--warnings", &path]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "1\n");
+    assert_eq!(
+        warnings(&stderr(&output)),
+        [
+            "[line 6] Warning at 'return': Code after this statement is unreachable.",
+            "[line 1] Warning at 'unused': Unused parameter.",
+            "[line 2] Warning at 'never': Unused local variable.",
+            "[line 5] Warning at 'z': Assigned value is never read.",
+        ]
+    );
+}
+
+#[test]
+fn deny_warnings_fails_to_compile() {
+    let path = script("warnings_denied.lox", SCRIPT);
+    let output = rustlox(&["--deny-warnings", &path]);
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(
+        warnings(&stderr(&output)),
+        [
+            "[line 6] Error at 'return': Code after this statement is unreachable.",
+            "[line 1] Error at 'unused': Unused parameter.",
+            "[line 2] Error at 'never': Unused local variable.",
+            "[line 5] Error at 'z': Assigned value is never read.",
+        ]
+    );
+}
+
+#[test]
+fn stores_read_later_are_used() {
+    let path = script(
+        "warnings_live.lox",
+        "\
+fun f(flag) {
+  var i = 0;
+  while (i < 3) i = i + 1;
+  var j = 0;
+  if (flag) j = 1;
+  print j;
+  var k = 0;
+  fun get() { return k; }
+  k = 1;
+  return get() + i;
+}
+print f(true);
+",
+    );
+    let output = rustlox(&["--deny-warnings", &path]);
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn stores_overwritten_on_every_path_are_dead() {
+    let path = script(
+        "warnings_overwritten.lox",
+        "\
+fun f(flag) {
+  var j = 0;
+  j = 1;
+  if (flag) j = 2;
+  else j = 3;
+  return j;
+}
+f(true);
+",
+    );
+    let output = rustlox(&["--warnings", &path]);
+    assert_eq!(
+        warnings(&stderr(&output)),
+        ["[line 3] Warning at 'j': Assigned value is never read."]
+    );
+}
+
+#[test]
+fn underscore_names_are_meant_to_be_unused() {
+    let path = script(
+        "warnings_underscore.lox",
+        "fun f(_unused) { var _z = 1; _z = 2; }\nf(1);\n",
+    );
+    let output = rustlox(&["--deny-warnings", &path]);
+    assert!(output.status.success(), "{}", stderr(&output));
+}